@@ -0,0 +1,27 @@
+# Constants are substituted wherever their name appears as a word outside of a
+# string, so they work in literals and in instruction operands
+.const LIMIT 10
+.const STEP 3
+.const COUNT 0    # the local the count is stored in
+
+$main 0:
+    .lit 0
+    .lit LIMIT
+    .lit STEP
+    .lit "LIMIT is not substituted here"
+    load_lit 0
+    store_loc COUNT
+
+top:
+    load_loc COUNT
+    load_lit 1
+    jmp_ge exit
+    load_loc COUNT
+    load_lit 2
+    add
+    store_loc COUNT
+    jmp top
+
+exit:
+    load_loc COUNT
+    ret_val     # return 12
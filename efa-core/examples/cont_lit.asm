@@ -0,0 +1,7 @@
+# The same 2d array as array_2d.asm, written as a literal
+$main 0:
+    .lit [[1, 2, 3], [4, 5, 6]]
+    load_lit 0
+    cont_get 1
+    cont_get 2
+    ret_val     # return 6
@@ -0,0 +1,19 @@
+# Any function in the file can load a .data literal with load_data, which adds it
+# to the function's litpool
+.data PRIMES [2, 3, 5, 7, 11]
+.data TWO 2
+
+$nth_prime 1:
+    load_data PRIMES
+    load_arg 0
+    cont_get
+    ret_val
+
+$main 0:
+    .lit 2
+    load_data TWO
+    load_dyn $nth_prime
+    call
+    load_data TWO
+    mul
+    ret_val     # return 10
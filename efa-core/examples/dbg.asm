@@ -0,0 +1,17 @@
+# dbg prints the top of the stack. With a message, it also prints the message and
+# where it is: the function and instruction offset.
+$main 0:
+    .lit 0
+    .lit 1
+    .lit 5
+
+    load_lit 0
+top:
+    dbg "before adding"
+    load_lit 1
+    add
+    dup
+    load_lit 2
+    jmp_lt top
+    dbg
+    ret_val     # return 5
@@ -0,0 +1,24 @@
+# Macros are expanded before parsing. Each argument replaces its parameter wherever
+# the parameter appears as a whole word in the macro's body.
+.macro inc slot
+    load_loc slot
+    load_lit 1
+    add
+    store_loc slot
+.endmacro
+
+# Macros can use macros defined before them
+.macro inc2 slot
+    inc slot
+    inc slot
+.endmacro
+
+$main 0:
+    .lit 0
+    .lit 1
+    load_lit 0
+    store_loc 0
+    inc2 0
+    inc 0
+    load_loc 0
+    ret_val     # return 3
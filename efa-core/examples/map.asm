@@ -0,0 +1,28 @@
+$main 0:
+    .lit {"a": 1, "b": 2}
+    .lit "a"
+    .lit "b"
+    .lit "c"
+    .lit 3
+
+    # m["c"] = 3
+    load_lit 0
+    load_lit 4
+    load_lit 3
+    map_set
+
+    # del m["a"]
+    load_lit 1
+    map_del
+    store_loc 0
+
+    # m["b"] + m["c"]
+    load_loc 0
+    load_lit 2
+    map_get
+    load_loc 0
+    load_lit 3
+    map_get
+    add
+
+    ret_val
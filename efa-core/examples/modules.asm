@@ -0,0 +1,31 @@
+# Functions after `.module math` are named math::square and math::sum_squares.
+# Within the module, load_dyn can leave the module off.
+.module math
+
+$square 1:
+    load_arg 0
+    load_arg 0
+    mul
+    ret_val
+
+$sum_squares 2:
+    load_arg 0
+    load_dyn $square
+    call
+    load_arg 1
+    load_dyn $square
+    call
+    add
+    ret_val
+
+# A bare .module goes back to the top level
+.module
+
+$main 0:
+    .lit 3
+    .lit 4
+    load_lit 0
+    load_lit 1
+    load_dyn $math::sum_squares
+    call
+    ret_val     # return 25
@@ -0,0 +1,48 @@
+# sum_squares.asm, with named arguments and locals
+$square 1:
+    .arg n
+    load_arg n
+    load_arg n
+    mul
+    ret_val
+
+$main 0:
+    .lit 0
+    .lit 1
+    .lit 5
+    .local i
+    .local end
+    .local total
+
+    load_lit 0
+    store_loc i
+
+    load_lit 2
+    store_loc end
+
+    load_lit 0
+    store_loc total
+
+top:
+    load_loc i
+    load_loc end
+    jmp_gt exit
+
+    load_loc i
+    load_dyn $square
+    call
+    load_loc total
+    add
+    store_loc total
+    pop
+
+    load_loc i
+    load_lit 1
+    add
+    store_loc i
+
+    jmp top
+
+exit:
+    load_loc total
+    ret_val     # return 55
@@ -0,0 +1,16 @@
+# Negative literals, including ones that only fit in wider integers
+$main 0:
+    .lit -5
+    .lit 12
+    .lit -10000000000
+    .lit 10000000000
+    load_lit 2
+    store_loc 0
+    load_loc 0
+    load_lit 3
+    add
+    pop
+    load_lit 0
+    load_lit 1
+    add
+    ret_val
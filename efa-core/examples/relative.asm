@@ -0,0 +1,26 @@
+# Sum 1..10 using relative jumps, by label and by offset
+$main 0:
+    .lit 0
+    .lit 10
+    .lit 1
+    load_lit 0
+    store_loc 0
+    load_lit 1
+    store_loc 1
+top:
+    load_loc 1
+    load_lit 0
+    eq
+    jmp_rel_t done
+    load_loc 0
+    load_loc 1
+    add
+    store_loc 0
+    load_loc 1
+    load_lit 2
+    sub
+    store_loc 1
+    jmp_rel -12
+done:
+    load_loc 0
+    ret_val
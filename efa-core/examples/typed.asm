@@ -0,0 +1,15 @@
+# Signatures are checked when the function is called and when it returns
+$add 2: (i32, i32) -> i32
+    load_arg 0
+    load_arg 1
+    add
+    ret_val
+
+$main 0:
+    .lit 3
+    .lit 4
+    load_lit 1
+    load_lit 0
+    load_dyn $add
+    call
+    ret_val
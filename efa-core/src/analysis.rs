@@ -0,0 +1,212 @@
+//! Analyses of code objects that are not needed to run them, for understanding
+//! what a function does
+
+use std::fmt::Write;
+
+use crate::bytecode::Bytecode;
+use crate::vm::CodeObject;
+
+/// A straight-line run of instructions, which is only entered at its first
+/// instruction and only left after its last
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Offset of the first instruction
+    pub start: usize,
+    /// Offset after the last instruction
+    pub end: usize,
+    /// Indices of the blocks that can execute next, in the order of the last
+    /// instruction's successors, i.e. falling through before jumping
+    pub successors: Vec<usize>,
+    /// Whether execution can run off the end of the code after this block
+    pub exits: bool,
+}
+
+/// The control-flow graph of a code object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    /// Split the code into basic blocks at labels, jump targets, and the
+    /// instructions after jumps and returns. Expects labels to be in bounds, i.e.
+    /// a verified code object.
+    pub fn new(obj: &CodeObject) -> Cfg {
+        let len = obj.code.len();
+        let mut leaders = vec![false; len + 1];
+        leaders[0] = true;
+        leaders[len] = true;
+        for &target in &obj.labels {
+            leaders[target.min(len)] = true;
+        }
+        for offset in 0..len {
+            let successors = obj.successors(offset);
+            if successors != [offset + 1] {
+                leaders[offset + 1] = true;
+                for target in successors.into_iter().filter(|&t| t <= len) {
+                    leaders[target] = true;
+                }
+            }
+        }
+
+        let starts = (0..len).filter(|&i| leaders[i]).collect::<Vec<_>>();
+        let block_of = |offset: usize| starts.binary_search(&offset).ok();
+        let blocks = starts
+            .iter()
+            .map(|&start| {
+                let end = (start + 1..=len).find(|&i| leaders[i]).unwrap();
+                let targets = obj.successors(end - 1);
+                BasicBlock {
+                    start,
+                    end,
+                    successors: targets.iter().filter_map(|&t| block_of(t)).collect(),
+                    exits: targets.contains(&len),
+                }
+            })
+            .collect();
+
+        Cfg { blocks }
+    }
+
+    /// Render the graph in Graphviz dot, one node per block listing its
+    /// instructions. Fall-through edges out of a conditional jump are dashed.
+    pub fn to_dot(&self, name: &str, obj: &CodeObject) -> String {
+        let code = Bytecode::format_with_labelnames(&obj.code);
+        let mut dot = String::new();
+        writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for (l, _) in obj
+                .labels
+                .iter()
+                .enumerate()
+                .filter(|(_, &t)| t == block.start)
+            {
+                write!(label, "L{l}:\\l").unwrap();
+            }
+            for (offset, instr) in
+                code.iter().enumerate().take(block.end).skip(block.start)
+            {
+                write!(label, "{offset}: {}\\l", escape(instr.trim())).unwrap();
+            }
+            writeln!(dot, "    b{i} [label=\"{label}\"];").unwrap();
+
+            let branches = block.successors.len() + block.exits as usize > 1;
+            for &succ in &block.successors {
+                let style = match branches && self.blocks[succ].start == block.end {
+                    true => " [style=dashed]",
+                    false => "",
+                };
+                writeln!(dot, "    b{i} -> b{succ}{style};").unwrap();
+            }
+            if block.exits {
+                writeln!(dot, "    b{i} -> exit;").unwrap();
+            }
+        }
+
+        if self.blocks.iter().any(|b| b.exits) {
+            writeln!(dot, "    exit [shape=point];").unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Escape a string for a quoted dot ID
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parser::Parser;
+
+    fn parse(source: &str) -> CodeObject {
+        Parser::parse_str(source).unwrap().remove(0).code_obj
+    }
+
+    #[test]
+    fn test_blocks() {
+        let source = [
+            "$count 1:",
+            "    .lit 0",
+            "    .lit 1",
+            "    load_lit 0",
+            "top:",
+            "    dup",
+            "    load_arg 0",
+            "    jmp_ge done",
+            "    load_lit 1",
+            "    add",
+            "    jmp top",
+            "done:",
+            "    ret_val",
+        ]
+        .join("\n");
+        let obj = parse(&source);
+        let cfg = Cfg::new(&obj);
+
+        let spans = cfg
+            .blocks
+            .iter()
+            .map(|b| (b.start, b.end, b.successors.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (0, 1, vec![1]),
+                (1, 4, vec![2, 3]),
+                (4, 7, vec![1]),
+                (7, 8, vec![]),
+            ]
+        );
+        assert!(cfg.blocks.iter().all(|b| !b.exits));
+
+        let dot = cfg.to_dot("count", &obj);
+        assert!(dot.starts_with("digraph \"count\" {\n"));
+        assert!(dot.contains(
+            "    b1 [label=\"L0:\\l1: dup\\l2: load_arg 0\\l3: jmp_ge L1\\l\"];\n"
+        ));
+        assert!(dot.contains("    b1 -> b2 [style=dashed];\n    b1 -> b3;\n"));
+        assert!(dot.contains("    b2 -> b1;\n"));
+        assert!(!dot.contains("exit"));
+    }
+
+    #[test]
+    fn test_exits() {
+        // Both blocks can run off the end of the code
+        let source = "$f 0:\n    .lit true\n    load_lit 0\n    jmp_rel_t 2\n    dbg\n";
+        let obj = parse(source);
+        let cfg = Cfg::new(&obj);
+        assert_eq!(cfg.blocks.len(), 2);
+        assert!(cfg.blocks[0].exits);
+        assert!(cfg.blocks[1].exits);
+
+        let dot = cfg.to_dot("f", &obj);
+        assert!(dot.contains("    b0 -> b1 [style=dashed];\n    b0 -> exit;\n"));
+        assert!(dot.contains("    exit [shape=point];\n"));
+    }
+
+    #[test]
+    fn test_examples() {
+        // Every instruction is in exactly one block, and blocks are in order
+        for name in ["primes", "fib", "relative", "compound_if"] {
+            let path = format!("examples/{name}.asm");
+            for parse in Parser::parse_file(&path).unwrap() {
+                let obj = parse.code_obj;
+                let cfg = Cfg::new(&obj);
+                let mut next = 0;
+                for block in &cfg.blocks {
+                    assert_eq!(block.start, next, "{path}");
+                    assert!(block.end > block.start);
+                    next = block.end;
+                }
+                assert_eq!(next, obj.code.len(), "{path}");
+                assert!(cfg.to_dot(&parse.func_name, &obj).ends_with("}\n"));
+            }
+        }
+    }
+}
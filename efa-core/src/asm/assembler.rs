@@ -0,0 +1,164 @@
+//! Assemble source files into a code database without running them, so that a
+//! library of functions can be built up one file at a time
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::parser::{Parse, Parser};
+use crate::db::Database;
+use crate::solver::resolve_dyn::DynCallResolver;
+use crate::Hash;
+
+pub struct Assembler<'a> {
+    db: &'a Database,
+}
+
+impl<'a> Assembler<'a> {
+    /// Assemble into `db`, which also provides the `.extern` functions
+    pub fn new(db: &'a Database) -> Self {
+        Assembler { db }
+    }
+
+    /// Parse, link, and verify the functions in a file, and insert them into the
+    /// database with their metadata, all or nothing. A function whose name is taken becomes its
+    /// latest version. Returns the name and hash of each function, sorted by name.
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<(String, Hash)>> {
+        self.assemble(Parser::parse_file(path)?)
+    }
+
+    /// Like `assemble_file`, for source text
+    pub fn assemble_str(&self, source: &str) -> Result<Vec<(String, Hash)>> {
+        self.assemble(Parser::parse_str(source)?)
+    }
+
+    fn assemble(&self, parses: Vec<Parse>) -> Result<Vec<(String, Hash)>> {
+        let metadata = parses
+            .iter()
+            .map(|parse| (parse.func_name.clone(), parse.metadata.clone()))
+            .collect::<HashMap<_, _>>();
+        let mut resolver = DynCallResolver::new(parses)?;
+        resolver.resolve_externs(self.db)?;
+        let mut functions = self
+            .db
+            .insert_parses_with_metadata(resolver.resolve_dyn_calls()?, &metadata)?
+            .into_iter()
+            .collect::<Vec<_>>();
+        functions.sort();
+        Ok(functions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let db = Database::temp().unwrap();
+        let assembler = Assembler::new(&db);
+
+        // A library without a main function
+        let lib = "$square 1:\n    load_arg 0\n    dup\n    mul\n    ret_val\n";
+        let functions = assembler.assemble_str(lib).unwrap();
+        let (name, hash) = &functions[0];
+        assert_eq!(name, "square");
+        assert_eq!(db.get_code_object_by_name("square").unwrap().0, *hash);
+        assert!(db.get_main_object().is_err());
+
+        // Assembling the same functions again changes nothing, and redefining them
+        // adds a version
+        assert_eq!(assembler.assemble_str(lib).unwrap(), functions);
+        assert_eq!(db.get_name_versions("square").unwrap().len(), 1);
+        let redefined = assembler
+            .assemble_str("$square 1:\n    load_arg 0\n    ret_val\n")
+            .unwrap();
+        assert_eq!(
+            db.get_code_object_by_name("square").unwrap().0,
+            redefined[0].1
+        );
+        assert_eq!(db.get_name_versions("square").unwrap().len(), 2);
+        db.rollback("square").unwrap();
+
+        // Later files can use earlier ones
+        let source = [
+            ".extern square",
+            "$main 0:",
+            "    .lit 7",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        assert_eq!(assembler.assemble_str(&source).unwrap()[0].0, "main");
+        assert!(db.get_main_object().is_ok());
+
+        // Nothing is inserted from a file that doesn't verify
+        let source = "$ok 0:\n    ret\n$bad 0:\n    add\n    ret\n";
+        assert!(assembler.assemble_str(source).is_err());
+        assert!(db.get_code_object_by_name("ok").is_err());
+
+        let db = Database::temp().unwrap();
+        let functions = Assembler::new(&db)
+            .assemble_file("examples/fib.asm")
+            .unwrap();
+        assert_eq!(functions.len(), 2);
+    }
+
+    #[test]
+    fn test_assemble_metadata() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    .doc \"Squares a number.\"",
+            "    .doc \"Any # number\"",
+            "    .author \"Ann\"",
+            "    .tag math pure",
+            "    .tag pure",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$plain 0:",
+            "    ret",
+        ]
+        .join("\n");
+        let functions = Assembler::new(&db).assemble_str(&source).unwrap();
+        let (_, plain) = &functions[0];
+        let (_, square) = &functions[1];
+
+        let metadata = db.get_metadata(square).unwrap().unwrap();
+        assert_eq!(
+            metadata.doc.as_deref(),
+            Some("Squares a number.\nAny # number")
+        );
+        assert_eq!(metadata.author.as_deref(), Some("Ann"));
+        assert_eq!(metadata.tags, vec!["math", "pure"]);
+        assert_eq!(db.get_metadata(plain).unwrap(), None);
+
+        // Annotations are written in the disassembly, and survive assembling it
+        let dis = db.disassemble().unwrap();
+        assert!(dis.contains("$square 1:\n    .doc \"Squares a number.\"\n"));
+        assert!(dis.contains("    .tag math pure\n"));
+        let other = Database::temp().unwrap();
+        Assembler::new(&other).assemble_str(&dis).unwrap();
+        assert_eq!(other.get_metadata(square).unwrap(), Some(metadata));
+
+        // The source file is recorded for files
+        let functions = Assembler::new(&db)
+            .assemble_file("examples/fib.asm")
+            .unwrap();
+        let metadata = db.get_metadata(&functions[0].1).unwrap().unwrap();
+        assert_eq!(metadata.source.as_deref(), Some("examples/fib.asm"));
+        assert!(db
+            .disassemble()
+            .unwrap()
+            .contains("# source: examples/fib.asm\n"));
+
+        assert!(Assembler::new(&db)
+            .assemble_str("$f 0:\n    .doc unquoted\n    ret\n")
+            .is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Readable differences between two code objects, instruction by instruction
+
+use std::fmt;
+
+use crate::asm::dis::format_lit;
+use crate::bytecode::Bytecode;
+use crate::vm::{CodeObject, Signature};
+
+/// The most cells in the table `diff_lines` finds a shortest edit with, e.g. about
+/// 2000 changed lines on each side. Past it, the changed lines are all removed and
+/// then all added.
+const MAX_TABLE: usize = 1 << 22;
+
+/// A line of a diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// How one code object differs from another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDiff {
+    /// The old and new argument counts, if they differ
+    pub argcount: Option<(usize, usize)>,
+    /// Whether the old and new ones are void, if they differ
+    pub is_void: Option<(bool, bool)>,
+    /// The old and new signatures, if they differ
+    pub signature: Option<(Option<Signature>, Option<Signature>)>,
+    /// The old and new bounds on the stack depth, if they differ
+    pub max_stack_depth: Option<(Option<usize>, Option<usize>)>,
+    /// Names of the arguments, then the locals
+    pub localnames: Vec<DiffLine>,
+    /// Instructions, with jumps to `L<n>` labels
+    pub code: Vec<DiffLine>,
+    /// Literals, in litpool order
+    pub litpool: Vec<DiffLine>,
+}
+
+impl FunctionDiff {
+    /// Whether the code objects are the same, apart from metadata and source
+    /// locations
+    pub fn is_empty(&self) -> bool {
+        let same =
+            |lines: &[DiffLine]| lines.iter().all(|l| matches!(l, DiffLine::Same(_)));
+        self.argcount.is_none()
+            && self.is_void.is_none()
+            && self.signature.is_none()
+            && self.max_stack_depth.is_none()
+            && same(&self.localnames)
+            && same(&self.code)
+            && same(&self.litpool)
+    }
+}
+
+/// The difference from `old` to `new`
+pub fn diff_functions(old: &CodeObject, new: &CodeObject) -> FunctionDiff {
+    let code = |obj: &CodeObject| {
+        Bytecode::format_with_labelnames(&obj.code)
+            .into_iter()
+            .map(|line| line.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+    let litpool =
+        |obj: &CodeObject| obj.litpool.iter().map(format_lit).collect::<Vec<_>>();
+
+    FunctionDiff {
+        argcount: changed(old.argcount, new.argcount),
+        is_void: changed(old.is_void, new.is_void),
+        signature: changed(old.signature.clone(), new.signature.clone()),
+        max_stack_depth: changed(old.max_stack_depth, new.max_stack_depth),
+        localnames: diff_lines(&old.localnames, &new.localnames),
+        code: diff_lines(&code(old), &code(new)),
+        litpool: diff_lines(&litpool(old), &litpool(new)),
+    }
+}
+
+/// The old and new values, if they differ
+fn changed<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    (old != new).then_some((old, new))
+}
+
+/// A shortest edit from `old` to `new`, by longest common subsequence of the lines
+/// between their common first and last lines. If there are too many of those to
+/// compare, see `MAX_TABLE`, the edit may not be the shortest.
+pub fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_changed, new_changed) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut lines = old[..prefix]
+        .iter()
+        .cloned()
+        .map(DiffLine::Same)
+        .collect::<Vec<_>>();
+    let cells = (old_changed.len() + 1).saturating_mul(new_changed.len() + 1);
+    if cells <= MAX_TABLE {
+        lines.extend(common_subsequence_diff(old_changed, new_changed));
+    } else {
+        lines.extend(old_changed.iter().cloned().map(DiffLine::Removed));
+        lines.extend(new_changed.iter().cloned().map(DiffLine::Added));
+    }
+    lines.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .cloned()
+            .map(DiffLine::Same),
+    );
+    lines
+}
+
+/// A shortest edit from `old` to `new`, by longest common subsequence
+fn common_subsequence_diff(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // common[i * width + j] is the longest common subsequence of old[i..] and
+    // new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = match old[i] == new[j] {
+                true => common[(i + 1) * width + j + 1] + 1,
+                false => common[(i + 1) * width + j].max(common[i * width + j + 1]),
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if i < old.len()
+            && (j == new.len()
+                || common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    lines
+}
+
+impl fmt::Display for DiffLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffLine::Same(line) => write!(f, "  {line}"),
+            DiffLine::Removed(line) => write!(f, "- {line}"),
+            DiffLine::Added(line) => write!(f, "+ {line}"),
+        }
+    }
+}
+
+impl fmt::Display for FunctionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((old, new)) = self.argcount {
+            writeln!(f, "argcount: {old} -> {new}")?;
+        }
+        if let Some((old, new)) = self.is_void {
+            writeln!(f, "void: {old} -> {new}")?;
+        }
+        if let Some((old, new)) = &self.signature {
+            let show = |signature: &Option<Signature>| match signature {
+                Some(signature) => signature.to_string(),
+                None => "none".to_string(),
+            };
+            writeln!(f, "signature: {} -> {}", show(old), show(new))?;
+        }
+        if let Some((old, new)) = self.max_stack_depth {
+            let show = |depth: Option<usize>| match depth {
+                Some(depth) => depth.to_string(),
+                None => "unknown".to_string(),
+            };
+            writeln!(f, "max stack depth: {} -> {}", show(old), show(new))?;
+        }
+        writeln!(f, "localnames:")?;
+        for line in &self.localnames {
+            writeln!(f, "{line}")?;
+        }
+        writeln!(f, "code:")?;
+        for line in &self.code {
+            writeln!(f, "{line}")?;
+        }
+        writeln!(f, "litpool:")?;
+        for line in &self.litpool {
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parser::Parser;
+
+    fn parse(source: &str) -> CodeObject {
+        Parser::parse_str(source).unwrap().remove(0).code_obj
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let lines = |s: &str| s.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let diff = diff_lines(&lines("a b c d"), &lines("a c e d"));
+        assert_eq!(
+            diff,
+            [
+                DiffLine::Same("a".to_string()),
+                DiffLine::Removed("b".to_string()),
+                DiffLine::Same("c".to_string()),
+                DiffLine::Added("e".to_string()),
+                DiffLine::Same("d".to_string()),
+            ]
+        );
+        assert!(diff_lines(&[], &[]).is_empty());
+
+        // Too many changed lines to compare, between ones that are the same
+        let many = |tag: &str| {
+            let mut lines = vec!["first".to_string()];
+            lines.extend((0..3000).map(|i| format!("{tag}{i}")));
+            lines.push("last".to_string());
+            lines
+        };
+        let diff = diff_lines(&many("a"), &many("b"));
+        assert_eq!(diff.len(), 6002);
+        assert_eq!(diff[0], DiffLine::Same("first".to_string()));
+        assert_eq!(diff[1], DiffLine::Removed("a0".to_string()));
+        assert_eq!(diff[3001], DiffLine::Added("b0".to_string()));
+        assert_eq!(diff[6001], DiffLine::Same("last".to_string()));
+    }
+
+    #[test]
+    fn test_diff_functions() {
+        let old = parse(
+            "$f 1:\n    .lit 2\n    load_arg 0\n    load_lit 0\n    mul\n    ret_val\n",
+        );
+        let new = parse(
+            "$f 1:\n    .lit 3\n    load_arg 0\n    load_lit 0\n    add\n    ret_val\n",
+        );
+
+        let diff = diff_functions(&old, &new);
+        assert!(!diff.is_empty());
+        assert_eq!(diff.argcount, None);
+        assert_eq!(
+            diff.litpool,
+            [
+                DiffLine::Removed("2".to_string()),
+                DiffLine::Added("3".to_string())
+            ]
+        );
+        assert!(diff.to_string().contains("\n- mul\n+ add\n  ret_val\n"));
+        assert!(diff_functions(&old, &old).is_empty());
+
+        // Differences outside the code and litpool count too
+        let typed = parse("$f 1: (i32) -> i32\n    load_arg 0\n    ret_val\n");
+        let untyped = parse("$f 1:\n    load_arg 0\n    ret_val\n");
+        let diff = diff_functions(&untyped, &typed);
+        assert!(!diff.is_empty());
+        assert!(diff
+            .to_string()
+            .starts_with("signature: none -> (i32) -> i32\n"));
+        let deeper = CodeObject {
+            max_stack_depth: Some(9),
+            ..untyped.clone()
+        };
+        assert!(!diff_functions(&untyped, &deeper).is_empty());
+        let renamed = CodeObject {
+            localnames: vec!["x".to_string()],
+            ..untyped.clone()
+        };
+        assert_ne!(untyped.localnames, renamed.localnames);
+        assert!(!diff_functions(&untyped, &renamed).is_empty());
+    }
+}
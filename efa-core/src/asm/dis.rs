@@ -1,63 +1,347 @@
 use std::fmt::Write;
 
-use crate::bytecode::Bytecode;
+use serde_json::{json, Value as Json};
+
+use crate::bytecode::{Bytecode, Instr, Operand};
+use crate::is_valid_name;
+use crate::verify::{stack_depths, stack_kinds, Depth};
 use crate::vm::CodeObject;
+use crate::vm::Metadata;
 use crate::vm::Value;
 use crate::Hash;
 
+/// How `load_func` instructions are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FuncRefs {
+    /// The hash, followed by a comment with the function's names, if it has any
+    #[default]
+    Annotated,
+    /// `load_dyn` of the function's name if it has exactly one, otherwise the hash
+    Named,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisOptions {
+    pub refs: FuncRefs,
+    /// Comment each instruction with the depth of the stack before it, and the
+    /// types of the values on it that are known
+    pub stack: bool,
+}
+
 pub fn disassemble_function(
     name: &str,
     hash: &Hash,
     obj: &CodeObject,
+) -> anyhow::Result<String> {
+    disassemble_function_with(name, hash, obj, None, &|_| vec![], DisOptions::default())
+}
+
+/// Disassemble a function as `opts` says, given its metadata and the names of
+/// each hash
+pub fn disassemble_function_with(
+    name: &str,
+    hash: &Hash,
+    obj: &CodeObject,
+    metadata: Option<&Metadata>,
+    names: &dyn Fn(&Hash) -> Vec<String>,
+    opts: DisOptions,
 ) -> anyhow::Result<String> {
     let mut dis = String::new();
 
     // Function header
-    writeln!(dis, "# 0x{}", hex::encode(hash))?;
-    writeln!(dis, "${name} {}:", obj.argcount)?;
+    writeln!(dis, "# {hash}")?;
+    let metadata = metadata.cloned().unwrap_or_default();
+    if let Some(source) = &metadata.source {
+        writeln!(dis, "# source: {source}")?;
+    }
+    let header_signature = obj.signature.as_ref().map(ToString::to_string);
+    match &metadata.signature {
+        Some(signature) if metadata.signature != header_signature => {
+            writeln!(dis, "# signature: {signature}")?
+        }
+        _ => (),
+    }
+    match &obj.signature {
+        Some(signature) => writeln!(dis, "${name} {}: {signature}", obj.argcount)?,
+        None => writeln!(dis, "${name} {}:", obj.argcount)?,
+    }
 
-    // Literals
-    obj.litpool.iter().try_for_each(|lit| {
+    // Annotations
+    for line in metadata.doc.iter().flat_map(|doc| doc.split('\n')) {
         writeln!(
             dis,
-            "    .lit {}",
-            match lit {
-                Value::String(s) => format!("\"{s}\""),
-                Value::Hash(h) => format!("0x{}", hex::encode(h)),
-                Value::I8(i) => format!("{i}"),
-                Value::U8(u) => format!("{u}"),
-                Value::I16(i) => format!("{i}"),
-                Value::U16(u) => format!("{u}"),
-                Value::I32(i) => format!("{i}"),
-                Value::U32(u) => format!("{u}"),
-                Value::I64(i) => format!("{i}"),
-                Value::U64(u) => format!("{u}"),
-                Value::I128(i) => format!("{i}"),
-                Value::U128(u) => format!("{u}"),
-                Value::Isize(i) => format!("{i}"),
-                Value::Usize(u) => format!("{u}"),
-
-                Value::F32(f) => format!("{f}"),
-                Value::F64(f) => format!("{f}"),
-
-                Value::Char(c) => format!("{c}"),
-                Value::Bool(b) => format!("{b}"),
-                Value::Container(_) => "<cont_obj>".to_string(), // TODO
-            }
-        )
-    })?;
+            "    .doc {}",
+            format_lit(&Value::String(line.to_string()))
+        )?;
+    }
+    if let Some(author) = metadata.author {
+        writeln!(dis, "    .author {}", format_lit(&Value::String(author)))?;
+    }
+    if !metadata.tags.is_empty() {
+        writeln!(dis, "    .tag {}", metadata.tags.join(" "))?;
+    }
+
+    // Literals
+    obj.litpool
+        .iter()
+        .try_for_each(|lit| writeln!(dis, "    .lit {}", format_lit(lit)))?;
+
+    // Argument and local names, if any differ from the default x0, x1, ...
+    let localnames = local_names(obj);
+    let named = localnames.is_some();
+    for (i, (name, original)) in localnames.iter().flatten().enumerate() {
+        let directive = if i < obj.argcount { ".arg" } else { ".local" };
+        match original {
+            Some(original) => writeln!(dis, "    {directive} {name} # {original:?}")?,
+            None => writeln!(dis, "    {directive} {name}")?,
+        }
+    }
 
     // Rename labels in the jump instructions
     let mut code = Bytecode::format_with_labelnames(&obj.code);
+    if named {
+        for (offset, instr) in obj.code.iter().enumerate() {
+            let (mnemonic, index) = match *instr {
+                Instr::LoadArg(i) => ("load_arg", i),
+                Instr::LoadLocal(i) => ("load_loc", obj.argcount + i),
+                Instr::StoreLocal(i) => ("store_loc", obj.argcount + i),
+                _ => continue,
+            };
+            if let Some((name, _)) = localnames.iter().flatten().nth(index) {
+                code[offset] = format!("    {mnemonic} {name}");
+            }
+        }
+    }
+
+    // Comments to write after each instruction
+    let mut comments = match opts.stack {
+        true => stack_comments(obj),
+        false => vec![vec![]; obj.code.len()],
+    };
+    for (offset, instr) in obj.code.iter().enumerate() {
+        let Instr::LoadFunc(hash) = instr else {
+            continue;
+        };
+        match (opts.refs, &names(hash)[..]) {
+            (_, []) => (),
+            (FuncRefs::Named, [name]) => code[offset] = format!("    load_dyn ${name}"),
+            (_, names) => comments[offset].insert(0, names.join(", ")),
+        }
+    }
+
+    let mut labels = obj
+        .labels
+        .iter()
+        .enumerate()
+        .map(|(i, &offset)| (offset, format!("L{i}")))
+        .collect::<Vec<_>>();
+
+    // Relative jumps go to the label at their target, if there is one. The parser
+    // turns a relative jump to a label back into an offset. Other targets stay
+    // offsets, since a new label would change the code object.
+    for (offset, instr) in obj.code.iter().enumerate() {
+        let Some(target) = instr
+            .jump_delta()
+            .and_then(|delta| offset.checked_add_signed(delta))
+            .filter(|&target| target <= obj.code.len())
+        else {
+            continue;
+        };
+        let Some((_, label)) = labels.iter().find(|(o, _)| *o == target) else {
+            continue;
+        };
+        let mnemonic = instr.to_string();
+        let mnemonic = mnemonic.split_whitespace().next().unwrap_or_default();
+        code[offset] = format!("    {mnemonic} {label}");
+    }
+
+    for (line, comments) in code.iter_mut().zip(comments) {
+        if !comments.is_empty() {
+            *line = format!("{line} # {}", comments.join("; "));
+        }
+    }
 
     // Insert the labels into the bytecode
-    obj.labels.iter().enumerate().fold(0, |k, (i, label)| {
-        code.insert(label + k, format!("L{i}:"));
-        k + 1
-    });
+    labels.sort_by_key(|(offset, _)| *offset);
+    for (k, (offset, name)) in labels.into_iter().enumerate() {
+        code.insert(offset + k, format!("{name}:"));
+    }
 
     // Write out
     let code = code.as_slice().join("\n");
     writeln!(dis, "{}", code)?;
     Ok(dis)
 }
+
+/// For each instruction, a comment with the stack before it, like `stack 2: ?, i32`
+/// for a stack of two values with an `i32` on top. Nothing if the stack can't be
+/// simulated because the code doesn't verify.
+fn stack_comments(obj: &CodeObject) -> Vec<Vec<String>> {
+    let Ok(depths) = stack_depths(obj) else {
+        return vec![vec![]; obj.code.len()];
+    };
+    depths
+        .into_iter()
+        .zip(stack_kinds(obj))
+        .map(|(depth, kinds)| {
+            let comment = match (depth, kinds) {
+                (None, _) => "unreachable".to_string(),
+                (Some(Depth::Unknown), _) => "stack ?".to_string(),
+                (Some(Depth::Known(n)), Some(kinds))
+                    if kinds.len() == n && kinds.iter().any(Option::is_some) =>
+                {
+                    let kinds = kinds
+                        .iter()
+                        .map(|kind| kind.unwrap_or("?"))
+                        .collect::<Vec<_>>();
+                    format!("stack {n}: {}", kinds.join(", "))
+                }
+                (Some(Depth::Known(n)), _) => format!("stack {n}"),
+            };
+            vec![comment]
+        })
+        .collect()
+}
+
+/// The names to declare for a function's arguments and locals, or `None` if it has
+/// no locals and its arguments are the default x0, x1, ... the parser would infer.
+/// Locals are always declared, since the parser may merge the slots of undeclared
+/// ones. A name the parser would reject is replaced by its default, or another
+/// unused name, along with the original name to write in a comment.
+pub(super) fn local_names(obj: &CodeObject) -> Option<Vec<(String, Option<&str>)>> {
+    let kept = |i: usize| {
+        let name = &obj.localnames[i];
+        is_valid_name(name) && !obj.localnames[..i].contains(name)
+    };
+    let mut names: Vec<(String, Option<&str>)> = vec![];
+    for (i, name) in obj.localnames.iter().enumerate() {
+        if kept(i) {
+            names.push((name.clone(), None));
+            continue;
+        }
+        // A replacement can't clash with another name, like `x1` in `[x1, "?"]`
+        let taken = |candidate: &String| {
+            names.iter().any(|(n, _)| n == candidate)
+                || (i..obj.localnames.len())
+                    .any(|j| kept(j) && obj.localnames[j] == *candidate)
+        };
+        let replacement = std::iter::once(format!("x{i}"))
+            .chain((1..).map(|n| format!("x{i}_{n}")))
+            .find(|candidate| !taken(candidate))
+            .unwrap();
+        names.push((replacement, Some(name.as_str())));
+    }
+
+    let is_default = names
+        .iter()
+        .enumerate()
+        .all(|(i, (name, original))| *name == format!("x{i}") && original.is_none());
+    let has_locals = names.len() > obj.argcount;
+    (!is_default || has_locals).then_some(names)
+}
+
+/// Describe a function as JSON, for tools that would rather not parse assembly.
+/// Literals have their type, their value in plain JSON (null if it has none), and
+/// their `.lit` syntax. Instructions have their offset, mnemonic, and operand.
+pub fn disassemble_function_json(
+    name: &str,
+    hash: &Hash,
+    obj: &CodeObject,
+) -> anyhow::Result<Json> {
+    let literals = obj
+        .litpool
+        .iter()
+        .map(|lit| {
+            json!({
+                "type": lit.type_name(),
+                "value": crate::json::to_json(lit).unwrap_or(Json::Null),
+                "text": format_lit(lit),
+            })
+        })
+        .collect::<Vec<_>>();
+    let labels = obj
+        .labels
+        .iter()
+        .enumerate()
+        .map(|(i, offset)| json!({ "name": format!("L{i}"), "offset": offset }))
+        .collect::<Vec<_>>();
+    let instructions = obj
+        .code
+        .iter()
+        .enumerate()
+        .map(|(offset, instr)| {
+            let text = instr.to_string();
+            let mnemonic = text.split_whitespace().next().unwrap_or_default();
+            let operand = match instr.operand() {
+                None => Json::Null,
+                Some(Operand::Label(label)) => json!({
+                    "label": label,
+                    "target": obj.labels.get(label),
+                }),
+                Some(Operand::Relative(delta)) => json!({
+                    "relative": delta,
+                    "target": offset.checked_add_signed(delta),
+                }),
+                Some(Operand::Lit(i)) => json!({ "lit": i }),
+                Some(Operand::Arg(i)) => json!({ "arg": i }),
+                Some(Operand::Local(i)) => json!({ "local": i }),
+                Some(Operand::Hash(hash)) => json!({ "hash": hash.to_string() }),
+                Some(Operand::Name(name)) => json!({ "name": name }),
+                Some(Operand::Index(i)) => json!({ "index": i }),
+            };
+            json!({ "offset": offset, "mnemonic": mnemonic, "operand": operand })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "name": name,
+        "hash": hash.to_string(),
+        "argcount": obj.argcount,
+        "signature": obj.signature.as_ref().map(ToString::to_string),
+        "literals": literals,
+        "labels": labels,
+        "instructions": instructions,
+    }))
+}
+
+/// Format a literal in the syntax accepted by the parser's `.lit` directive, so that
+/// it parses back to the same type
+pub(crate) fn format_lit(lit: &Value) -> String {
+    match lit {
+        // Debug formatting quotes and escapes the string the way the parser expects
+        Value::String(s) => format!("{s:?}"),
+        Value::Hash(h) => format!("{h}"),
+        Value::I8(i) => format!("{i}i8"),
+        Value::U8(u) => format!("{u}u8"),
+        Value::I16(i) => format!("{i}i16"),
+        Value::U16(u) => format!("{u}u16"),
+        Value::I32(i) => format!("{i}"),
+        Value::U32(u) => format!("{u}u32"),
+        Value::I64(i) => format!("{i}i64"),
+        Value::U64(u) => format!("{u}u64"),
+        Value::I128(i) => format!("{i}i128"),
+        Value::U128(u) => format!("{u}u128"),
+        Value::Isize(i) => format!("{i}isize"),
+        Value::Usize(u) => format!("{u}usize"),
+        Value::BigInt(i) => format!("{i}bigint"),
+
+        // Debug formatting always includes a `.` or exponent, e.g. `1.0`, or is
+        // `NaN`, `inf`, or `-inf`, all of which parse back
+        Value::F32(f) => format!("{f:?}f32"),
+        Value::F64(f) => format!("{f:?}"),
+
+        Value::Char(c) => format!("{c:?}"),
+        Value::Bool(b) => format!("{b}"),
+        Value::Container(c) => {
+            let items = c.iter().map(format_lit).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Map(m) => {
+            let entries = m
+                .iter()
+                .map(|(k, v)| format!("{}: {}", format_lit(k), format_lit(v)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
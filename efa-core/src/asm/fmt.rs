@@ -0,0 +1,370 @@
+//! Re-emit assembly source in a canonical style: top-level items, function
+//! definitions, and labels start at column 0, everything in a function or macro
+//! is indented by four spaces, and each function's `.arg`, `.local`, and `.lit`
+//! directives come first. Comments are kept with the line they are on or
+//! precede, and lines with `/* ... */` comments are only re-indented.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::parser::Parser;
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Blank,
+    Comment,
+    /// `#include`, `.module`, `.extern`, `.const`, `.data`, `.macro`, and `.endmacro`
+    TopLevel,
+    FuncDef,
+    Label,
+    /// A function directive, ordered `.arg`, `.local`, then `.lit`
+    Directive(u8),
+    Instr,
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    kind: Kind,
+    code: String,
+    comment: Option<String>,
+}
+
+/// Format a file, failing if it does not parse
+pub fn format_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    Parser::parse_file(&path)?;
+    Ok(format_source(&fs::read_to_string(path)?))
+}
+
+/// Format assembly source. Formatting formatted source leaves it unchanged.
+pub fn format_source(source: &str) -> String {
+    let lines = classify(source);
+
+    // Split into the lines before the first function, and each function. A
+    // function starts at the comments directly above its definition.
+    let mut starts = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        if line.kind == Kind::FuncDef {
+            let mut start = i;
+            while start > 0 && lines[start - 1].kind == Kind::Comment {
+                start -= 1;
+            }
+            starts.push(start);
+        }
+    }
+    let ends = starts.iter().skip(1).copied().chain([lines.len()]);
+    let preamble = &lines[..starts.first().copied().unwrap_or(lines.len())];
+
+    let mut out = collapse_blanks(preamble);
+    for (start, end) in starts.iter().copied().zip(ends) {
+        if !out.is_empty() {
+            out.push(blank());
+        }
+        let def = start
+            + lines[start..]
+                .iter()
+                .position(|l| l.kind == Kind::FuncDef)
+                .unwrap();
+        out.extend_from_slice(&lines[start..=def]);
+        out.extend(format_body(&lines[def + 1..end]));
+    }
+
+    render(&out)
+}
+
+/// Hoist the directives of a function body, keeping each with the comments
+/// directly above it
+fn format_body(body: &[Line]) -> Vec<Line> {
+    let mut directives: Vec<(u8, Vec<Line>)> = vec![];
+    let mut rest = vec![];
+    let mut comments = vec![];
+    for line in body {
+        match line.kind {
+            Kind::Comment => comments.push(line.clone()),
+            Kind::Directive(rank) => {
+                comments.push(line.clone());
+                directives.push((rank, std::mem::take(&mut comments)));
+            }
+            _ => {
+                rest.append(&mut comments);
+                rest.push(line.clone());
+            }
+        }
+    }
+    rest.append(&mut comments);
+    directives.sort_by_key(|(rank, _)| *rank);
+
+    let mut out = directives
+        .into_iter()
+        .flat_map(|(_, lines)| lines)
+        .collect::<Vec<_>>();
+    let rest = collapse_blanks(&rest);
+    if !out.is_empty() && !rest.is_empty() {
+        out.push(blank());
+    }
+    out.extend(rest);
+    out
+}
+
+/// Remove blank lines at the start and end, and turn runs of blank lines into one
+fn collapse_blanks(lines: &[Line]) -> Vec<Line> {
+    let mut out: Vec<Line> = vec![];
+    for line in lines {
+        let prev_blank = out.last().is_none_or(|l| l.kind == Kind::Blank);
+        if line.kind != Kind::Blank || !prev_blank {
+            out.push(line.clone());
+        }
+    }
+    if out.last().is_some_and(|l| l.kind == Kind::Blank) {
+        out.pop();
+    }
+    out
+}
+
+fn blank() -> Line {
+    Line {
+        kind: Kind::Blank,
+        code: String::new(),
+        comment: None,
+    }
+}
+
+/// Split each line into its normalized code and its comment
+fn classify(source: &str) -> Vec<Line> {
+    let mut in_macro = false;
+    let mut in_block = false;
+    source
+        .lines()
+        .map(|line| {
+            let was_in_block = in_block;
+            let masked = Parser::blank_block_comments(line, &mut in_block);
+            let (kind, code, comment) = classify_line(&masked, &mut in_macro);
+            if !was_in_block && masked == line {
+                return (kind, code, comment);
+            }
+
+            // Lines with block comments are kept as they are
+            let trimmed = line.trim().to_string();
+            match kind {
+                Kind::Blank | Kind::Comment => {
+                    (Kind::Comment, String::new(), Some(trimmed))
+                }
+                kind => (kind, trimmed, None),
+            }
+        })
+        .map(|(kind, code, comment)| Line {
+            kind,
+            code,
+            comment,
+        })
+        .collect()
+}
+
+fn classify_line(line: &str, in_macro: &mut bool) -> (Kind, String, Option<String>) {
+    let trimmed = line.trim();
+    if Parser::get_include(line).is_some() {
+        return (Kind::TopLevel, trimmed.to_string(), None);
+    }
+
+    let code = Parser::strip_comment(line);
+    let comment = trimmed[code.len()..].trim();
+    let comment = (!comment.is_empty()).then(|| comment.to_string());
+    let first = code.split_whitespace().next().unwrap_or_default();
+    let rest = code[first.len()..].trim();
+
+    let (kind, code) = match first {
+        "" if comment.is_some() => (Kind::Comment, String::new()),
+        "" => (Kind::Blank, String::new()),
+        ".macro" | ".endmacro" | ".module" | ".extern" => {
+            *in_macro = first == ".macro";
+            (Kind::TopLevel, words(&code))
+        }
+        ".const" | ".data" => (Kind::TopLevel, format_directive(first, rest)),
+        _ if code.ends_with(':') && !code.contains(char::is_whitespace) => {
+            (Kind::Label, code)
+        }
+        // Directives inside a macro stay where they are
+        ".doc" | ".author" | ".tag" | ".arg" | ".local" | ".lit" if !*in_macro => {
+            let rank = [".doc", ".author", ".tag", ".arg", ".local", ".lit"]
+                .iter()
+                .position(|d| *d == first);
+            (
+                Kind::Directive(rank.unwrap() as u8),
+                format_directive(first, rest),
+            )
+        }
+        ".doc" | ".author" | ".tag" | ".arg" | ".local" | ".lit" | "dbg" => {
+            (Kind::Instr, format_directive(first, rest))
+        }
+        _ => match Parser::is_func_def(&code) {
+            Some(Ok((name, arity, signature))) => {
+                let code = match signature {
+                    Some(signature) => format!("${name} {arity}: {signature}"),
+                    None => format!("${name} {arity}:"),
+                };
+                (Kind::FuncDef, code)
+            }
+            _ => (Kind::Instr, words(&code)),
+        },
+    };
+    (kind, code, comment)
+}
+
+/// Separate words with single spaces
+fn words(code: &str) -> String {
+    code.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// A directive and its argument, which may contain spaces in a string
+fn format_directive(directive: &str, arg: &str) -> String {
+    match arg {
+        "" => directive.to_string(),
+        arg => format!("{directive} {arg}"),
+    }
+}
+
+fn render(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for (i, line) in lines.iter().enumerate() {
+        // Comments on their own line are indented like the code below them
+        let kind = match line.kind {
+            Kind::Comment => lines[i..]
+                .iter()
+                .map(|l| l.kind)
+                .find(|k| !matches!(k, Kind::Comment | Kind::Blank))
+                .unwrap_or(Kind::TopLevel),
+            kind => kind,
+        };
+        let mut text = line.code.clone();
+        if let Some(comment) = &line.comment {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(comment);
+        }
+        if matches!(kind, Kind::Directive(_) | Kind::Instr) && !text.is_empty() {
+            out.push_str(INDENT);
+        }
+        out.push_str(&text);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = [
+            "# Adds one",
+            "",
+            "",
+            "$inc   1:  ",
+            "  load_arg   0 # the argument",
+            "",
+            "  .lit 1",
+            "  # name it",
+            ".arg n",
+            "  .doc   \"Adds  one\"",
+            "top:",
+            "       load_lit 0",
+            "add",
+            "\tret_val",
+            "",
+            "",
+            "# Entry point",
+            "$main 0:",
+            "    .lit \"a  # b\"",
+            "    ret",
+            "",
+        ]
+        .join("\n");
+
+        let expected = [
+            "# Adds one",
+            "",
+            "$inc 1:",
+            "    .doc \"Adds  one\"",
+            "    # name it",
+            "    .arg n",
+            "    .lit 1",
+            "",
+            "    load_arg 0 # the argument",
+            "",
+            "top:",
+            "    load_lit 0",
+            "    add",
+            "    ret_val",
+            "",
+            "# Entry point",
+            "$main 0:",
+            "    .lit \"a  # b\"",
+            "",
+            "    ret",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(format_source(&source), expected);
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let source = [
+            "/* Adds",
+            "",
+            "     one */",
+            "$inc 1:",
+            "  load_arg  0 /* n */",
+            "    /* .lit 2",
+            "  */",
+            "  .lit 1",
+            "load_lit 0",
+            "add",
+            "ret_val",
+        ]
+        .join("\n");
+
+        let expected = [
+            "/* Adds",
+            "",
+            "one */",
+            "$inc 1:",
+            "    /* .lit 2",
+            "    */",
+            "    .lit 1",
+            "",
+            "    load_arg  0 /* n */",
+            "    load_lit 0",
+            "    add",
+            "    ret_val",
+            "",
+        ]
+        .join("\n");
+        let formatted = format_source(&source);
+        assert_eq!(formatted, expected);
+        assert_eq!(format_source(&formatted), formatted);
+    }
+
+    #[test]
+    fn test_examples() {
+        for entry in fs::read_dir("examples/").unwrap() {
+            let path = entry.unwrap().path();
+            let formatted = format_file(&path).unwrap();
+            assert_eq!(format_source(&formatted), formatted, "{}", path.display());
+
+            // Formatting doesn't change the code
+            let before = Parser::parse_file(&path).unwrap();
+            let after = Parser::parse_str(&formatted).unwrap();
+            for (a, b) in before.iter().zip(&after) {
+                assert_eq!(a.func_name, b.func_name);
+                assert_eq!(a.code_obj.hash().unwrap(), b.code_obj.hash().unwrap());
+            }
+            assert_eq!(before.len(), after.len());
+        }
+    }
+}
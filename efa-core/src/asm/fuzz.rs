@@ -0,0 +1,300 @@
+//! Property tests for the assembler. Arbitrary and assembly-like text must parse
+//! or fail without panicking, and random code objects must survive being
+//! disassembled and parsed again with the same hash.
+
+use proptest::prelude::*;
+
+use super::dis::{disassemble_function, local_names};
+use super::parser::Parser;
+use crate::bytecode::{BinOp, Bytecode, Instr, UnaryOp};
+use crate::vm::{CodeObject, Value};
+use crate::Hash;
+
+/// Words that the parser treats specially, or that are easy to get wrong
+const TOKENS: &[&str] = &[
+    "",
+    "0",
+    "1",
+    "-1",
+    "+2",
+    "255u8",
+    "1.5",
+    "-0.0f32",
+    "99999999999999999999999",
+    "true",
+    "x",
+    "x0",
+    "top",
+    "top:",
+    "main",
+    "$main",
+    "$",
+    "$f",
+    "a::b",
+    "::",
+    ":",
+    "'",
+    "'a'",
+    "' '",
+    "'ab'",
+    "\"",
+    "\"\"",
+    "\"a b\"",
+    "\"\\",
+    "\"\\u{41}\"",
+    "[",
+    "[1,",
+    "[]",
+    "[1, [2]]",
+    "{",
+    "{}",
+    "{1: 2}",
+    "/*",
+    "*/",
+    "#",
+    "é",
+    "\t",
+];
+
+const WORDS: &[&str] = &[
+    "load_arg",
+    "load_loc",
+    "load_lit",
+    "store_loc",
+    "load_data",
+    "load_dyn",
+    "load_func",
+    "call",
+    "ret",
+    "ret_val",
+    "jmp",
+    "jmp_t",
+    "jmp_lt",
+    "jmp_rel",
+    "jmp_rel_f",
+    "add",
+    "cont_make",
+    "cont_get",
+    "map_new",
+    "dbg",
+    "pop",
+    "dup",
+    ".lit",
+    ".doc",
+    ".tag",
+    ".arg",
+    ".local",
+    ".const",
+    ".data",
+    ".extern",
+    ".module",
+    ".macro",
+    ".endmacro",
+    "#include",
+];
+
+fn token() -> impl Strategy<Value = String> {
+    prop_oneof![
+        proptest::sample::select(TOKENS).prop_map(str::to_string),
+        "[a-z0-9_$:'\"\\[\\]{}.,+-]{1,6}",
+    ]
+}
+
+/// A line that looks like assembly
+fn line() -> impl Strategy<Value = String> {
+    prop_oneof![
+        (
+            proptest::sample::select(WORDS),
+            proptest::collection::vec(token(), 0..3)
+        )
+            .prop_map(|(word, args)| format!("    {word} {}", args.join(" "))),
+        ("[a-z]{1,3}", 0..3usize).prop_map(|(name, arity)| format!("${name} {arity}:")),
+        token().prop_map(|label| format!("{label}:")),
+        proptest::collection::vec(token(), 0..4).prop_map(|tokens| tokens.join(" ")),
+    ]
+}
+
+fn value() -> impl Strategy<Value = Value> {
+    let scalar = prop_oneof![
+        any::<i32>().prop_map(Value::I32),
+        any::<u8>().prop_map(Value::U8),
+        any::<i64>().prop_map(Value::I64),
+        any::<bool>().prop_map(Value::Bool),
+        any::<char>().prop_map(Value::Char),
+        any::<String>().prop_map(Value::String),
+        (-1e9..1e9f64).prop_map(Value::F64),
+    ];
+    scalar.prop_recursive(2, 8, 4, |inner| {
+        prop_oneof![
+            proptest::collection::vec(inner.clone(), 0..4).prop_map(Value::Container),
+            proptest::collection::vec((inner.clone(), inner), 0..3).prop_map(Value::Map),
+        ]
+    })
+}
+
+/// Argument and local names, which may be invalid or repeated
+const NAMES: &[&str] = &["x0", "x1", "x2", "n", "acc", "loop", "a.b", ""];
+
+/// Names the parser accepts
+const VALID_NAMES: &[&str] = &["x0", "x1", "x2", "x3", "x4", "n", "acc", "i"];
+
+/// Five argument and local names, which may be invalid or repeated
+fn names() -> impl Strategy<Value = Vec<&'static str>> {
+    proptest::collection::vec(proptest::sample::select(NAMES), 5)
+}
+
+/// Five distinct names the parser accepts
+fn valid_names() -> impl Strategy<Value = Vec<&'static str>> {
+    proptest::sample::subsequence(VALID_NAMES, 5).prop_shuffle()
+}
+
+/// A code object whose operands are all in range, though it may not verify
+fn code_object(
+    names: impl Strategy<Value = Vec<&'static str>>,
+) -> impl Strategy<Value = CodeObject> {
+    (
+        0..3usize,
+        0..3usize,
+        proptest::collection::vec(value(), 1..4),
+        1..16usize,
+        names,
+    )
+        .prop_flat_map(|(argcount, locals, litpool, len, names)| {
+            let lits = litpool.len();
+            let instr = prop_oneof![
+                (0..argcount.max(1)).prop_map(move |i| if argcount > 0 {
+                    Instr::LoadArg(i)
+                } else {
+                    Instr::Nop
+                }),
+                (0..lits).prop_map(Instr::LoadLit),
+                (0..lits).prop_map(Instr::DbgMsg),
+                (0..locals.max(1)).prop_map(move |i| if locals > 0 {
+                    Instr::StoreLocal(i)
+                } else {
+                    Instr::Nop
+                }),
+                (0..locals.max(1)).prop_map(move |i| if locals > 0 {
+                    Instr::LoadLocal(i)
+                } else {
+                    Instr::Nop
+                }),
+                (0..len).prop_map(Instr::Jump),
+                (0..len).prop_map(Instr::JumpT),
+                (0..len).prop_map(Instr::JumpGe),
+                (-(len as isize)..len as isize).prop_map(Instr::JumpRel),
+                (0..3usize).prop_map(Instr::ContMakeS),
+                Just(Instr::BinOp(BinOp::Add)),
+                Just(Instr::BinOp(BinOp::Mul)),
+                Just(Instr::UnaryOp(UnaryOp::Neg)),
+                Just(Instr::Cmp),
+                Just(Instr::Pop),
+                Just(Instr::Dup),
+                Just(Instr::Dbg),
+                Just(Instr::Nop),
+                Just(Instr::ContGet),
+                Just(Instr::MapNew),
+                Just(Instr::LoadDyn("main".to_string())),
+                Just(Instr::CallSelf),
+                Just(Instr::Return),
+                Just(Instr::ReturnVal),
+            ];
+            (
+                Just((argcount, locals, litpool, names)),
+                proptest::collection::vec(instr, len),
+                proptest::collection::vec(0..=len, len),
+            )
+        })
+        .prop_map(
+            |((argcount, locals, litpool, names), mut code, mut labels)| {
+                // The parser numbers labels in the order they are defined
+                labels.sort();
+                // Relative jumps stay in the function
+                for (offset, instr) in code.iter_mut().enumerate() {
+                    if let Instr::JumpRel(delta) = instr {
+                        *delta = (*delta)
+                            .clamp(-(offset as isize), (labels.len() - offset) as isize);
+                    }
+                }
+                let code = Bytecode::new(code);
+                CodeObject {
+                    litpool,
+                    argcount,
+                    is_void: code.is_void(),
+                    localnames: names[..argcount + locals]
+                        .iter()
+                        .map(|name| name.to_string())
+                        .collect(),
+                    labels,
+                    max_stack_depth: None,
+                    signature: None,
+                    debug_info: None,
+                    code,
+                }
+            },
+        )
+}
+
+/// A code object as the parser would optimize it. Locals the disassembly declares
+/// keep their slots.
+fn optimized(obj: &CodeObject) -> CodeObject {
+    let named = match local_names(obj) {
+        Some(_) => obj.localnames.len() - obj.argcount,
+        None => 0,
+    };
+    let mut obj = obj.clone();
+    Parser::optimize(&mut obj, named);
+    obj
+}
+
+/// Whether the parser accepts the disassembly of `obj`: it rejects loads of
+/// locals that are never stored, before optimizing and again after, since
+/// removing dead code can remove the only store
+fn parses(obj: &CodeObject, optimized: &CodeObject) -> bool {
+    [obj, optimized]
+        .iter()
+        .all(|obj| Parser::check_indices(&obj.code, obj.argcount, &obj.litpool).is_ok())
+}
+
+/// Disassemble and parse a code object
+fn roundtrip(obj: &CodeObject) -> anyhow::Result<CodeObject> {
+    let dis = disassemble_function("main", &Hash::digest(b""), obj)?;
+    Ok(Parser::parse_str(&dis)?.remove(0).code_obj)
+}
+
+proptest! {
+    #[test]
+    fn parse_arbitrary_text(source in any::<String>()) {
+        let _ = Parser::parse_str(&source);
+    }
+
+    #[test]
+    fn parse_assembly_like_text(lines in proptest::collection::vec(line(), 0..12)) {
+        let _ = Parser::parse_str(&lines.join("\n"));
+    }
+
+    #[test]
+    fn disassemble_and_parse(obj in code_object(valid_names())) {
+        // The parser optimizes, so the round trip gives the optimized object. Locals
+        // the disassembly declares keep their slots.
+        let expected = optimized(&obj);
+        prop_assume!(parses(&obj, &expected));
+
+        let parsed = roundtrip(&obj).unwrap();
+        prop_assert_eq!(parsed.hash().unwrap(), expected.hash().unwrap());
+        prop_assert_eq!(parsed.localnames, expected.localnames);
+    }
+
+    #[test]
+    fn disassemble_and_parse_bad_names(obj in code_object(names())) {
+        // Names the parser would reject are replaced, and after that the round trip
+        // is stable
+        let expected = optimized(&obj);
+        prop_assume!(parses(&obj, &expected));
+
+        let parsed = roundtrip(&obj).unwrap();
+        let again = roundtrip(&parsed).unwrap();
+        prop_assert_eq!(parsed.hash().unwrap(), again.hash().unwrap());
+        prop_assert_eq!(parsed.localnames, again.localnames);
+    }
+}
@@ -1,2 +1,7 @@
+pub mod assembler;
+pub mod diff;
 pub mod dis;
+pub mod fmt;
+#[cfg(test)]
+mod fuzz;
 pub mod parser;
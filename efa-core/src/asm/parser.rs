@@ -1,15 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Ok, Result};
-use regex::Regex;
+use anyhow::{Context, Ok, Result};
+use num_bigint::BigInt;
 
 use crate::bytecode::{BinOp, Bytecode, Instr, UnaryOp};
-use crate::hash_from_str;
-use crate::is_valid_name;
-use crate::vm::{CodeObject, Value};
+use crate::opt;
+use crate::verify::{max_stack_depth, verify};
+use crate::vm::{CodeObject, DebugInfo, Metadata, Signature, Value};
+use crate::Hash;
+use crate::{is_valid_name, is_valid_qualified_name};
 
 pub struct Parser;
 
@@ -19,6 +22,13 @@ struct PartialParse {
     labels: Vec<usize>,
     num_locals: usize,
     literals: Vec<Value>,
+    /// Names declared with `.arg` and `.local`, in order
+    arg_names: Vec<String>,
+    local_names: Vec<String>,
+    /// Given by `.doc`, `.author`, and `.tag`
+    metadata: Metadata,
+    /// Source line of each instruction token
+    instr_lines: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -37,18 +47,143 @@ pub enum ParseError {
 
     /// Unknown instruction mnemonic, or bad arguments (missing/present)
     UnknownInstr(String),
-    UnknownLabel,
+    UnknownLabel(String),
+    /// A name used by `load_arg`, `load_loc`, or `store_loc` was not declared
+    UnknownName(String),
+    DuplicateName(String),
+    /// More `.arg` names than the function has arguments
+    TooManyArgNames,
+    /// An argument, local, or literal index that can never be valid
+    InvalidIndex {
+        instr: String,
+        reason: String,
+    },
 
     NoFunctionDef,
-    RegexError(String),
+
+    /// An `#include` without a quoted path
+    InvalidInclude,
+    /// A file includes itself, directly or through other files
+    IncludeCycle(String),
+    /// An `#include` in source that isn't from a file, so has nothing to be
+    /// relative to
+    IncludeWithoutFile(String),
+    /// A `.macro` without a matching `.endmacro`
+    UnterminatedMacro(String),
+    /// A macro used with the wrong number of arguments
+    MacroArgs(String),
+    /// A `/*` without a matching `*/`
+    UnterminatedComment,
 
     Error(anyhow::Error),
+
+    /// Every error found in a file, when there is more than one
+    Multiple(Vec<ParseError>),
+
+    /// An error at a location in the source
+    Spanned {
+        file: String,
+        span: Span,
+        source_line: String,
+        error: Box<ParseError>,
+    },
+}
+
+/// Where in a source file an error is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column of the first character
+    pub column: usize,
+    /// Length in characters, at least 1
+    pub len: usize,
+}
+
+/// The file being parsed, for locating errors
+struct SourceFile<'a> {
+    path: &'a str,
+    text: &'a str,
+}
+
+impl SourceFile<'_> {
+    /// Attach the location of an error on source line `line` (1-based). Points at
+    /// the text the error names if it is on the line, otherwise the whole line.
+    fn locate(&self, error: ParseError, line: usize) -> ParseError {
+        if let ParseError::Spanned { .. } | ParseError::Multiple(_) = error {
+            return error;
+        }
+
+        let source_line = self.text.lines().nth(line - 1).unwrap_or_default();
+        // `#include` lines are comments to everything but the preprocessor
+        let code = match Parser::strip_comment(source_line) {
+            code if code.is_empty() => source_line.trim().to_string(),
+            code => code,
+        };
+        let culprit = match &error {
+            ParseError::UnknownInstr(s)
+            | ParseError::UnknownLabel(s)
+            | ParseError::UnknownName(s)
+            | ParseError::DuplicateName(s)
+            | ParseError::InvalidIdent(s)
+            | ParseError::InvalidLabelName(s)
+            | ParseError::IncludeCycle(s)
+            | ParseError::UnterminatedMacro(s)
+                if !s.is_empty() && source_line.contains(s.as_str()) =>
+            {
+                s.as_str()
+            }
+            ParseError::UnterminatedComment if source_line.contains("/*") => "/*",
+            _ => code.as_str(),
+        };
+        let start = source_line.find(culprit).unwrap_or(0);
+
+        ParseError::Spanned {
+            file: self.path.to_string(),
+            span: Span {
+                line,
+                column: source_line[..start].chars().count() + 1,
+                len: culprit.chars().count().max(1),
+            },
+            source_line: source_line.to_string(),
+            error: Box::new(error),
+        }
+    }
 }
 
+/// Lines of a file, each with its 1-based source line
+type SourceLines = Vec<(usize, String)>;
+
+/// A file after preprocessing
+struct Preprocessed {
+    /// Each remaining line, with the 1-based source line it came from
+    lines: SourceLines,
+    /// The path of each `#include`, with its source line
+    includes: SourceLines,
+    /// The module set by each `.module` directive (`None` for a bare `.module`),
+    /// with its source line
+    modules: Vec<(usize, Option<String>)>,
+    /// Literals defined with `.data`, which any function can load with `load_data`
+    data: HashMap<String, Value>,
+    /// Functions declared with `.extern`
+    externs: HashSet<String>,
+}
+
+/// An assembler macro, defined by `.macro name params...` and `.endmacro`
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    /// Lines with the parameters not yet substituted
+    body: Vec<String>,
+}
+
+/// Name, arity, and signature of a function
+type FuncDef = (String, usize, Option<Signature>);
+
 #[derive(Debug)]
 enum ParseToken {
-    /// Function definition: name, arity
-    FuncDef(String, usize),
+    /// Function definition
+    FuncDef(FuncDef),
     /// An instruction
     Instr(Instr),
     /// A label
@@ -59,46 +194,246 @@ enum ParseToken {
 pub struct Parse {
     pub func_name: String,
     pub code_obj: CodeObject,
+    /// Functions declared with `.extern` that this function calls with `load_dyn`,
+    /// which must be resolved against a code database
+    pub externs: Vec<String>,
+    /// Metadata from annotations, with the signature and source file
+    pub metadata: Metadata,
 }
 
 impl Parser {
+    /// Parse the functions in a file, after those in the files it includes
     pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Vec<Parse>> {
-        let contents = fs::read_to_string(&path)?;
-        let contents = Self::preprocess(&contents);
+        Self::parse_included(path.as_ref(), &mut vec![], &mut HashSet::new())
+    }
+
+    /// Parse the functions in a string. It can't `#include` files, since there is
+    /// no file for them to be relative to; see `parse_source_at`.
+    pub fn parse_str(source: &str) -> Result<Vec<Parse>> {
+        Self::parse_source(source, None, &mut vec![], &mut HashSet::new())
+    }
+
+    /// Parse `source` as if it were the contents of the file at `path`, e.g. an
+    /// editor buffer with unsaved changes
+    pub fn parse_source_at<P: AsRef<Path>>(source: &str, path: P) -> Result<Vec<Parse>> {
+        Self::parse_source(
+            source,
+            Some(path.as_ref()),
+            &mut vec![],
+            &mut HashSet::new(),
+        )
+    }
+
+    /// Parse the functions read from `reader`, like `parse_str`
+    pub fn parse_reader<R: Read>(mut reader: R) -> Result<Vec<Parse>> {
+        let mut source = String::new();
+        reader.read_to_string(&mut source)?;
+        Self::parse_str(&source)
+    }
+
+    /// Parse one literal, written as it would be after `.lit`, e.g. `42u8`, `"hi"`
+    /// or `[1, 2]`
+    pub fn parse_literal(lit: &str) -> Result<Value> {
+        match Self::parse_nested_lit(lit)? {
+            (value, "") => Ok(value),
+            _ => Err(ParseError::InvalidLiteral.into()),
+        }
+    }
+
+    /// Parse a file and the files it includes. `including` holds the files whose
+    /// includes are being parsed, to detect cycles, and `parsed` every file parsed
+    /// so far, so that a file included twice is only parsed once.
+    fn parse_included(
+        path: &Path,
+        including: &mut Vec<PathBuf>,
+        parsed: &mut HashSet<PathBuf>,
+    ) -> Result<Vec<Parse>> {
+        let source = fs::read_to_string(path)?;
+        let canonical = path.canonicalize()?;
+        including.push(canonical.clone());
+        parsed.insert(canonical);
+        let parses = Self::parse_source(&source, Some(path), including, parsed)?;
+        including.pop();
+        Ok(parses)
+    }
+
+    /// Parse source code read from `path`, if it came from a file
+    fn parse_source(
+        source: &str,
+        path: Option<&Path>,
+        including: &mut Vec<PathBuf>,
+        parsed: &mut HashSet<PathBuf>,
+    ) -> Result<Vec<Parse>> {
+        let file = path.map(|path| path.display().to_string());
+        let src = SourceFile {
+            path: file.as_deref().unwrap_or("<input>"),
+            text: source,
+        };
+        let Preprocessed {
+            lines,
+            includes,
+            modules,
+            data,
+            externs,
+        } = Self::preprocess(&src).map_err(anyhow::Error::msg)?;
+
+        // Included paths are relative to the including file
+        let mut parses = vec![];
+        for (line, include) in includes {
+            let Some(dir) = path.map(|path| path.parent().unwrap_or(Path::new("")))
+            else {
+                let error = src.locate(ParseError::IncludeWithoutFile(include), line);
+                return Err(anyhow::Error::msg(error));
+            };
+            let included = dir.join(&include);
+            let canonical = included.canonicalize().with_context(|| {
+                format!("{}:{line}: cannot include '{include}'", src.path)
+            })?;
+            if including.contains(&canonical) {
+                let error = src.locate(ParseError::IncludeCycle(include), line);
+                return Err(anyhow::Error::msg(error));
+            }
+            if !parsed.contains(&canonical) {
+                parses.extend(Self::parse_included(&included, including, parsed)?);
+            }
+        }
+
+        let contents = lines
+            .iter()
+            .map(|(_, line)| line.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
         let functions = Self::split_functions(&contents).map_err(anyhow::Error::msg)?;
-        functions
+
+        // Preprocessing drops and expands lines, so track which source line each
+        // remaining line came from
+        let mut source_lines = lines.iter().map(|(n, _)| *n);
+        let own = functions.into_iter().map(|func| {
+            let lines = source_lines
+                .by_ref()
+                .take(func.lines().count())
+                .collect::<Vec<usize>>();
+            let module = modules
+                .iter()
+                .rev()
+                .find(|(n, _)| *n < lines[0])
+                .and_then(|(_, module)| module.clone());
+            Self::parse_function(&func, &lines, &src, &data)
+                .and_then(|partial| Self::finalize_parse(partial, &src, file.as_deref()))
+                .map(|parse| (module, parse))
+                // Errors about the whole function point at its definition
+                .map_err(|e| src.locate(e, lines[0]))
+        });
+        let own = Self::collect_errors(own).map_err(anyhow::Error::msg)?;
+        let own = Self::qualify_names(own);
+
+        // Functions defined in the file are used over externs with the same name
+        let defined = own
+            .iter()
+            .map(|p| p.func_name.clone())
+            .collect::<HashSet<_>>();
+        for mut parse in own {
+            for instr in parse.code_obj.code.iter() {
+                if let Instr::LoadDyn(name) = instr {
+                    if externs.contains(name)
+                        && !defined.contains(name)
+                        && !parse.externs.contains(name)
+                    {
+                        parse.externs.push(name.clone());
+                    }
+                }
+            }
+            parses.push(parse);
+        }
+        Ok(parses)
+    }
+
+    /// Collect the values of `results`, or all of their errors so that they can be
+    /// reported at once
+    fn collect_errors<T>(
+        results: impl IntoIterator<Item = Result<T, ParseError>>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut values = vec![];
+        let mut errors = vec![];
+        for result in results {
+            match result {
+                Result::Ok(value) => values.push(value),
+                Err(ParseError::Multiple(more)) => errors.extend(more),
+                Err(error) => errors.push(error),
+            }
+        }
+
+        match errors.len() {
+            0 => Result::Ok(values),
+            1 => Err(errors.remove(0)),
+            _ => Err(ParseError::Multiple(errors)),
+        }
+    }
+
+    /// Name each function in a module `module::name`. Within a module, `load_dyn`
+    /// can leave off the module of functions in the same module.
+    fn qualify_names(parses: Vec<(Option<String>, Parse)>) -> Vec<Parse> {
+        let defined = parses
+            .iter()
+            .map(|(module, parse)| (module.clone(), parse.func_name.clone()))
+            .collect::<HashSet<_>>();
+
+        parses
             .into_iter()
-            .map(|func| {
-                Self::parse_function(&func)
-                    .and_then(Self::finalize_parse)
-                    .map_err(anyhow::Error::msg)
+            .map(|(module, mut parse)| {
+                let Some(module) = module else {
+                    return parse;
+                };
+                let code = parse
+                    .code_obj
+                    .code
+                    .iter()
+                    .map(|instr| match instr {
+                        Instr::LoadDyn(name)
+                            if defined
+                                .contains(&(Some(module.clone()), name.clone())) =>
+                        {
+                            Instr::LoadDyn(format!("{module}::{name}"))
+                        }
+                        instr => instr.clone(),
+                    })
+                    .collect();
+                parse.code_obj.code = Bytecode::new(code);
+                parse.func_name = format!("{module}::{}", parse.func_name);
+                parse
             })
-            .collect::<Result<Vec<Parse>>>()
+            .collect()
     }
 
-    fn is_func_def(line: &str) -> Option<Result<(String, usize), ParseError>> {
-        let parts = line.split_whitespace().collect::<Vec<&str>>();
-        if parts.len() != 2 {
+    /// Parse a function definition line, `$name arity:`, optionally followed by a
+    /// signature like `(i32, i32) -> i32`
+    pub(crate) fn is_func_def(line: &str) -> Option<Result<FuncDef, ParseError>> {
+        let (head, signature) = line.split_once(':')?;
+        let parts = head.split_whitespace().collect::<Vec<&str>>();
+        if parts.len() != 2 || !parts[0].starts_with('$') {
             return None;
         }
 
-        let name = parts[0];
-        let arg = parts[1];
+        // Now it should be a function def line
+        let name = &parts[0][1..];
+        let parsed_arity = parts[1].parse::<usize>();
 
-        if name.starts_with('$') && arg.ends_with(':') {
-            // Now it should be a function def line
-            let name = &name[1..];
-            let arity = &arg[..arg.len() - 1];
-            let parsed_arity = arity.parse::<usize>();
+        let signature = match signature.trim() {
+            "" => None,
+            sig => match sig.parse::<Signature>() {
+                Result::Ok(sig) => Some(sig),
+                Err(e) => return Some(Err(ParseError::Error(e))),
+            },
+        };
 
-            match parsed_arity {
-                Result::Ok(arity) if is_valid_name(name) => {
-                    Some(Result::Ok((name.to_string(), arity)))
-                }
-                _ => Some(Err(ParseError::InvalidFuncDef)),
+        match parsed_arity {
+            Result::Ok(arity)
+                if is_valid_name(name)
+                    && signature.as_ref().is_none_or(|s| s.params.len() == arity) =>
+            {
+                Some(Result::Ok((name.to_string(), arity, signature)))
             }
-        } else {
-            None
+            _ => Some(Err(ParseError::InvalidFuncDef)),
         }
     }
 
@@ -124,6 +459,8 @@ impl Parser {
 
     fn get_labels(
         function: &str,
+        lines: &[usize],
+        src: &SourceFile,
     ) -> Result<(HashMap<String, usize>, Vec<usize>), ParseError> {
         // Want a map from label names (L0, L1, etc) to label number
         // And an array of offsets (where index is label number)
@@ -146,12 +483,16 @@ impl Parser {
 
                 let label = &word[0..word.len() - 1];
                 if !is_valid_name(label) {
-                    return Some(Result::Err(ParseError::InvalidLabelName(
-                        label.to_string(),
-                    )));
+                    let e = ParseError::InvalidLabelName(label.to_string());
+                    return Some(Result::Err(src.locate(e, lines[i])));
                 }
                 j += 1;
-                Some(Result::Ok((label.to_string(), i - j)))
+                // The first line is the function definition, unless the code is
+                // outside a function
+                match i.checked_sub(j) {
+                    Some(offset) => Some(Result::Ok((label.to_string(), offset))),
+                    None => Some(Err(src.locate(ParseError::NoFunctionDef, lines[i]))),
+                }
             })
             .collect::<Result<Vec<(String, usize)>, ParseError>>()?;
 
@@ -164,53 +505,318 @@ impl Parser {
         Result::Ok((label_names, label_offsets))
     }
 
-    fn get_literals(function: &str) -> Result<Vec<Value>, ParseError> {
-        let code = function.lines();
+    fn get_literals(
+        function: &str,
+        lines: &[usize],
+        src: &SourceFile,
+    ) -> Result<Vec<Value>, ParseError> {
+        let code = function.lines().zip(lines);
+
+        Self::collect_errors(
+            code.filter(|(line, _)| !line.is_empty())
+                .filter(|(line, _)| line.starts_with('.'))
+                .filter(|(line, _)| Self::get_name_directive(line).is_none())
+                .filter(|(line, _)| Self::get_metadata_directive(line).is_none())
+                .map(|(line, &n)| Self::get_literal(line).map_err(|e| src.locate(e, n))),
+        )
+    }
 
-        code.filter(|line| !line.is_empty())
-            .filter(|line| line.starts_with('.'))
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() < 2 {
-                    return Some(Err(ParseError::ExpectedArgument));
+    /// The names declared by `.arg NAME` and `.local NAME` lines, in order
+    fn get_names(
+        function: &str,
+        lines: &[usize],
+        src: &SourceFile,
+    ) -> Result<(Vec<String>, Vec<String>), ParseError> {
+        let mut arg_names = vec![];
+        let mut local_names = vec![];
+
+        for (line, &n) in function.lines().zip(lines) {
+            let (names, name) = match Self::get_name_directive(line) {
+                Some(("arg", name)) => (&mut arg_names, name),
+                Some((_, name)) => (&mut local_names, name),
+                None => continue,
+            };
+            let error = match name[..] {
+                [name] if !is_valid_name(name) => {
+                    ParseError::InvalidIdent(name.to_string())
+                }
+                [name] => {
+                    names.push(name.to_string());
+                    continue;
                 }
+                [] => ParseError::ExpectedArgument,
+                _ => ParseError::UnexpectedArgument,
+            };
+            return Err(src.locate(error, n));
+        }
 
-                let first = parts[0];
-                let arg = parts[1];
+        Result::Ok((arg_names, local_names))
+    }
 
-                let opcode = &first[1..];
-                if opcode != "lit" {
-                    return Some(Err(ParseError::InvalidLiteral));
-                }
+    /// If `line` is a `.arg` or `.local` directive, the directive and its arguments
+    fn get_name_directive(line: &str) -> Option<(&str, Vec<&str>)> {
+        let mut parts = line.split_whitespace();
+        let directive = match parts.next()? {
+            ".arg" => "arg",
+            ".local" => "local",
+            _ => return None,
+        };
+        Some((directive, parts.collect()))
+    }
 
-                // Bool case
-                if arg == "true" {
-                    return Some(Result::Ok(Value::Bool(true)));
+    /// The metadata given by `.doc "TEXT"`, `.author "NAME"`, and `.tag TAG...`
+    /// lines. The text of several `.doc` lines is joined with newlines.
+    fn get_metadata(
+        function: &str,
+        lines: &[usize],
+        src: &SourceFile,
+    ) -> Result<Metadata, ParseError> {
+        let mut metadata = Metadata::default();
+
+        for (line, &n) in function.lines().zip(lines) {
+            let Some((directive, arg)) = Self::get_metadata_directive(line) else {
+                continue;
+            };
+            let text = match directive {
+                "tag" if arg.is_empty() => Err(ParseError::ExpectedArgument),
+                "tag" => {
+                    for tag in arg.split_whitespace() {
+                        if !metadata.tags.iter().any(|t| t == tag) {
+                            metadata.tags.push(tag.to_string());
+                        }
+                    }
+                    continue;
                 }
-                if arg == "false" {
-                    return Some(Result::Ok(Value::Bool(false)));
+                _ => match Self::parse_nested_lit(arg) {
+                    Result::Ok((Value::String(text), "")) => Result::Ok(text),
+                    _ => Err(ParseError::InvalidStrLit),
+                },
+            };
+            let text = text.map_err(|e| src.locate(e, n))?;
+            match (directive, &mut metadata.doc) {
+                ("doc", Some(doc)) => {
+                    doc.push('\n');
+                    doc.push_str(&text);
                 }
+                ("doc", doc) => *doc = Some(text),
+                _ => metadata.author = Some(text),
+            }
+        }
 
-                // String case
-                if arg.starts_with('"') {
-                    let s = Self::get_str_lit(line).map(Value::String);
-                    return Some(s);
-                }
+        Result::Ok(metadata)
+    }
+
+    /// If `line` is a `.doc`, `.author`, or `.tag` directive, the directive and the
+    /// rest of the line
+    fn get_metadata_directive(line: &str) -> Option<(&str, &str)> {
+        let directive = line.split_whitespace().next()?;
+        match directive {
+            ".doc" | ".author" | ".tag" => {
+                Some((&directive[1..], line.trim()[directive.len()..].trim()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a `.lit` line
+    fn get_literal(line: &str) -> Result<Value, ParseError> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 {
+            return Err(ParseError::ExpectedArgument);
+        }
+
+        let first = parts[0];
+        let arg = parts[1];
+
+        let opcode = &first[1..];
+        if opcode != "lit" {
+            return Err(ParseError::InvalidLiteral);
+        }
+
+        // String case
+        if arg.starts_with('"') {
+            return match Self::parse_nested_lit(line[first.len()..].trim())? {
+                (s @ Value::String(_), "") => Result::Ok(s),
+                _ => Err(ParseError::InvalidStrLit),
+            };
+        }
+
+        // Map case
+        if arg.starts_with('{') {
+            let lit = line[first.len()..].trim();
+            return Self::get_map_lit(lit);
+        }
+
+        // Container case
+        if arg.starts_with('[') {
+            return match Self::parse_nested_lit(line[first.len()..].trim())? {
+                (c @ Value::Container(_), "") => Result::Ok(c),
+                _ => Err(ParseError::InvalidLiteral),
+            };
+        }
+
+        // Char case, which may be a space
+        if arg.starts_with('\'') {
+            return match Self::parse_nested_lit(line[first.len()..].trim())? {
+                (c @ Value::Char(_), "") => Result::Ok(c),
+                _ => Err(ParseError::InvalidLiteral),
+            };
+        }
+
+        // Anything else is an error, rather than being skipped and shifting the
+        // indices of the literals after it
+        Self::get_scalar_lit(arg).ok_or(ParseError::InvalidLiteral)?
+    }
 
-                // Hash case
-                if arg.len() >= 2 && arg.starts_with("0x") {
-                    let h = hash_from_str(arg).map(Value::Hash);
-                    return Some(h.map_err(ParseError::Error));
+    /// Parse a literal that is a single token: a bool, hash, (possibly signed)
+    /// integer, float, or a number with an explicit type like `42u8` or `1.5f32`
+    fn get_scalar_lit(arg: &str) -> Option<Result<Value, ParseError>> {
+        // Bool case
+        if arg == "true" {
+            return Some(Result::Ok(Value::Bool(true)));
+        }
+        if arg == "false" {
+            return Some(Result::Ok(Value::Bool(false)));
+        }
+
+        // Hash case
+        if arg.len() >= 2 && arg.starts_with("0x") {
+            let h = arg.parse::<Hash>().map(Value::Hash);
+            return Some(h.map_err(ParseError::Error));
+        }
+
+        // Explicitly typed case
+        if let Some(typed) = Self::get_typed_num(arg) {
+            return Some(typed);
+        }
+
+        // Int case, widening to I128 and then BigInt when the literal does not fit
+        if let Result::Ok(int) = arg.parse::<i32>() {
+            return Some(Result::Ok(Value::I32(int)));
+        }
+        if let Result::Ok(int) = arg.parse::<i128>() {
+            return Some(Result::Ok(Value::I128(int)));
+        }
+        if let Result::Ok(int) = arg.parse::<BigInt>() {
+            return Some(Result::Ok(Value::BigInt(int)));
+        }
+
+        // Float case, defaulting to F64
+        if let Result::Ok(float) = arg.parse::<f64>() {
+            return Some(Result::Ok(Value::F64(float)));
+        }
+
+        None
+    }
+
+    /// Parse a number with a type suffix, like `42u8` or `7bigint`. `None` if there
+    /// is no suffix.
+    fn get_typed_num(arg: &str) -> Option<Result<Value, ParseError>> {
+        const SUFFIXES: [&str; 15] = [
+            "i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64", "i128", "u128",
+            "isize", "usize", "bigint", "f32", "f64",
+        ];
+        let (num, suffix) = SUFFIXES
+            .iter()
+            .find_map(|suffix| Some((arg.strip_suffix(suffix)?, *suffix)))
+            .filter(|(num, _)| !num.is_empty())?;
+
+        let value = match suffix {
+            "i8" => num.parse().ok().map(Value::I8),
+            "u8" => num.parse().ok().map(Value::U8),
+            "i16" => num.parse().ok().map(Value::I16),
+            "u16" => num.parse().ok().map(Value::U16),
+            "i32" => num.parse().ok().map(Value::I32),
+            "u32" => num.parse().ok().map(Value::U32),
+            "i64" => num.parse().ok().map(Value::I64),
+            "u64" => num.parse().ok().map(Value::U64),
+            "i128" => num.parse().ok().map(Value::I128),
+            "u128" => num.parse().ok().map(Value::U128),
+            "isize" => num.parse().ok().map(Value::Isize),
+            "usize" => num.parse().ok().map(Value::Usize),
+            "bigint" => num.parse().ok().map(Value::BigInt),
+            "f32" => num.parse().ok().map(Value::F32),
+            _ => num.parse().ok().map(Value::F64),
+        };
+        Some(value.ok_or(ParseError::InvalidLiteral))
+    }
+
+    /// Parse a map literal of the form `{k: v, ...}`, where keys and values are
+    /// themselves literals
+    fn get_map_lit(lit: &str) -> Result<Value, ParseError> {
+        match Self::parse_nested_lit(lit)? {
+            (map @ Value::Map(_), "") => Result::Ok(map),
+            _ => Err(ParseError::InvalidLiteral),
+        }
+    }
+
+    /// Parse one literal from the front of `lit`, returning the rest of the input.
+    /// Maps (`{k: v}`) and containers (`[a, b]`) can be nested.
+    fn parse_nested_lit(lit: &str) -> Result<(Value, &str), ParseError> {
+        let lit = lit.trim_start();
+
+        if let Some(mut rest) = lit.strip_prefix('{') {
+            let mut map = vec![];
+            loop {
+                rest = rest.trim_start();
+                if let Some(rest) = rest.strip_prefix('}') {
+                    return Result::Ok((Value::Map(map), rest.trim_start()));
                 }
 
-                // Int case
-                if let Result::Ok(int) = arg.parse::<i32>() {
-                    return Some(Result::Ok(Value::I32(int)));
+                let (key, after_key) = Self::parse_nested_lit(rest)?;
+                let after_colon = after_key
+                    .strip_prefix(':')
+                    .ok_or(ParseError::InvalidLiteral)?;
+                let (val, after_val) = Self::parse_nested_lit(after_colon)?;
+                map.push((key, val));
+
+                rest = match after_val.strip_prefix(',') {
+                    Some(rest) => rest,
+                    None if after_val.starts_with('}') => after_val,
+                    None => return Err(ParseError::InvalidLiteral),
+                };
+            }
+        }
+
+        if let Some(mut rest) = lit.strip_prefix('[') {
+            let mut items = vec![];
+            loop {
+                rest = rest.trim_start();
+                if let Some(rest) = rest.strip_prefix(']') {
+                    return Result::Ok((Value::Container(items), rest.trim_start()));
                 }
 
-                None
-            })
-            .collect::<Result<Vec<Value>, ParseError>>()
+                let (item, after_item) = Self::parse_nested_lit(rest)?;
+                items.push(item);
+
+                rest = match after_item.strip_prefix(',') {
+                    Some(rest) => rest,
+                    None if after_item.starts_with(']') => after_item,
+                    None => return Err(ParseError::InvalidLiteral),
+                };
+            }
+        }
+
+        if let Some(rest) = lit.strip_prefix('"') {
+            let (s, rest) = Self::parse_quoted(rest, '"')?;
+            return Result::Ok((Value::String(s), rest.trim_start()));
+        }
+
+        if let Some(rest) = lit.strip_prefix('\'') {
+            let (s, rest) = Self::parse_quoted(rest, '\'')?;
+            let mut chars = s.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) => Result::Ok((Value::Char(c), rest.trim_start())),
+                _ => Err(ParseError::InvalidLiteral),
+            };
+        }
+
+        let end = lit
+            .find(|c: char| c.is_whitespace() || matches!(c, ',' | ':' | '}' | ']'))
+            .unwrap_or(lit.len());
+        let val =
+            Self::get_scalar_lit(&lit[..end]).ok_or(ParseError::InvalidLiteral)??;
+        Result::Ok((val, lit[end..].trim_start()))
     }
 
     fn get_num_locals(tokens: &[ParseToken]) -> Result<usize, ParseError> {
@@ -227,162 +833,310 @@ impl Parser {
         Result::Ok(num)
     }
 
-    fn get_str_lit(line: &str) -> Result<String, ParseError> {
-        let pattern = r#"\.lit\s*\"([^\"]*)\""#;
-        let re =
-            Regex::new(pattern).map_err(|e| ParseError::RegexError(e.to_string()))?;
-        let matches: Vec<String> = re
-            .captures_iter(line)
-            .filter_map(|cap| cap.get(1))
-            .map(|m| m.as_str().to_string())
-            .collect();
+    /// Parse the contents of a quoted string or char, starting after the opening
+    /// `quote`, and return them with the input after the closing quote. Supports
+    /// the escapes `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'`, and `\u{...}`.
+    fn parse_quoted(input: &str, quote: char) -> Result<(String, &str), ParseError> {
+        let mut contents = String::new();
+        let mut rest = input;
+
+        loop {
+            let mut chars = rest.chars();
+            let c = chars.next().ok_or(ParseError::InvalidStrLit)?;
+            rest = chars.as_str();
+            if c == quote {
+                return Result::Ok((contents, rest));
+            }
+            if c != '\\' {
+                contents.push(c);
+                continue;
+            }
 
-        if matches.len() == 1 {
-            Result::Ok(matches[0].clone())
-        } else {
-            Err(ParseError::InvalidStrLit)
+            let escaped = chars.next().ok_or(ParseError::InvalidStrLit)?;
+            rest = chars.as_str();
+            contents.push(match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' | '"' | '\'' => escaped,
+                'u' => {
+                    let (hex, after) = rest
+                        .strip_prefix('{')
+                        .and_then(|rest| rest.split_once('}'))
+                        .ok_or(ParseError::InvalidStrLit)?;
+                    rest = after;
+                    u32::from_str_radix(hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(ParseError::InvalidStrLit)?
+                }
+                _ => return Err(ParseError::InvalidStrLit),
+            });
         }
     }
 
-    /// Parse the bytecode of a single function
-    fn parse_function(function: &str) -> Result<PartialParse, ParseError> {
-        let literals = Self::get_literals(function)?;
-        let code = function
+    /// Parse a function. `lines` holds the source line of each line of `function`.
+    fn parse_function(
+        function: &str,
+        lines: &[usize],
+        src: &SourceFile,
+        data: &HashMap<String, Value>,
+    ) -> Result<PartialParse, ParseError> {
+        let literals = Self::get_literals(function, lines, src);
+        // `.data` entries used by `load_data` are added to the end of the litpool
+        let own_literals = literals.as_deref().unwrap_or_default();
+        let mut data_literals: Vec<Value> = vec![];
+        let (arg_names, local_names) = Self::get_names(function, lines, src)?;
+        let metadata = Self::get_metadata(function, lines, src)?;
+        let index_of = |names: &[String], name: &str| {
+            names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| ParseError::UnknownName(name.to_string()))
+        };
+        let (code, code_lines): (Vec<&str>, Vec<usize>) = function
             .lines()
-            .filter(|line| !line.contains("."))
-            .collect::<Vec<&str>>()
-            .join("\n");
-        let (label_names, label_offsets) = Self::get_labels(&code)?;
+            .zip(lines)
+            .filter(|(line, _)| !line.contains("."))
+            .unzip();
+        let code = code.join("\n");
+        let (label_names, label_offsets) = Self::get_labels(&code, &code_lines, src)?;
         let code = code.lines();
 
-        let tokens = code
-            .map(|line| {
-                let parts = line.split_whitespace().collect::<Vec<&str>>();
-                if parts.len() > 2 {
-                    return Err(ParseError::UnexpectedArgument);
+        // Offset of the next instruction, to resolve relative jumps to labels
+        let mut offset = 0;
+        let mut parse_line = |line: &str| {
+            // Line is a function definition, or an incorrect function definition
+            match Self::is_func_def(line) {
+                Some(Result::Ok(def)) => {
+                    return Result::Ok(ParseToken::FuncDef(def));
                 }
-
-                let base = parts[0];
-                let argument = parts.get(1);
-
-                // Line is a function definition, or an incorrect function definition
-                match Self::is_func_def(line) {
-                    Some(Result::Ok((name, arity))) => {
-                        return Result::Ok(ParseToken::FuncDef(name, arity));
-                    }
-                    Some(Err(e)) => return Err(e),
-                    None => {}
+                Some(Err(e)) => return Err(e),
+                None => {}
+            };
+
+            // A dbg message is a string literal, which may contain spaces
+            if let Some(message) = line
+                .strip_prefix("dbg")
+                .map(str::trim)
+                .filter(|message| message.starts_with('"'))
+            {
+                let value = match Self::parse_nested_lit(message)? {
+                    (s @ Value::String(_), "") => s,
+                    _ => return Err(ParseError::InvalidStrLit),
                 };
+                offset += 1;
+                let index = Self::add_literal(own_literals, &mut data_literals, value);
+                return Result::Ok(ParseToken::Instr(Instr::DbgMsg(index)));
+            }
 
-                // Line is a label
-                // Code previous ran already finds labels, so we can ignore
-                if argument.is_none() && base.ends_with(':') {
-                    return Result::Ok(ParseToken::Label);
-                }
+            let parts = line.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() > 2 {
+                return Err(ParseError::UnexpectedArgument);
+            }
 
-                // Line is an instruction
+            let base = parts[0];
+            let argument = parts.get(1);
 
-                // Setup arguments
-                let int_argument = argument.and_then(|a| a.parse::<usize>().ok());
-                let str_argument = match int_argument {
-                    Some(_) => None,
-                    None => argument,
-                };
+            // Line is a label
+            // Code previous ran already finds labels, so we can ignore
+            if argument.is_none() && base.ends_with(':') {
+                return Result::Ok(ParseToken::Label);
+            }
 
-                // dbg!(&line);
-                // dbg!(&argument);
-                // dbg!(&int_argument);
-                // dbg!(&str_argument);
-
-                // Decode instruction
-                let instr = match (base, int_argument, str_argument) {
-                    // Basic stack management and variables
-                    ("load_arg", Some(arg), None) => Instr::LoadArg(arg),
-                    ("load_loc", Some(arg), None) => Instr::LoadLocal(arg),
-                    ("load_lit", Some(arg), None) => Instr::LoadLit(arg),
-                    ("store_loc", Some(arg), None) => Instr::StoreLocal(arg),
-                    ("pop", None, None) => Instr::Pop,
-                    ("dup", None, None) => Instr::Dup,
-
-                    // TODO: fix
-                    ("load_func", None, Some(hash)) => {
-                        Instr::LoadFunc(hash_from_str(hash).map_err(ParseError::Error)?)
-                    }
-                    ("load_func", None, None) => {
-                        return Err(ParseError::ExpectedArgument);
-                    }
-                    ("load_dyn", None, Some(arg)) => {
-                        let func_name = &arg[1..];
-                        Instr::LoadDyn(func_name.to_string())
-                    }
+            // Line is an instruction
+
+            // Setup arguments
+            let int_argument = argument.and_then(|a| a.parse::<usize>().ok());
+            let str_argument = match int_argument {
+                Some(_) => None,
+                None => argument,
+            };
+
+            // dbg!(&line);
+            // dbg!(&argument);
+            // dbg!(&int_argument);
+            // dbg!(&str_argument);
+
+            // Decode instruction
+            let instr = match (base, int_argument, str_argument) {
+                // Basic stack management and variables
+                ("load_arg", Some(arg), None) => Instr::LoadArg(arg),
+                ("load_loc", Some(arg), None) => Instr::LoadLocal(arg),
+                ("load_lit", Some(arg), None) => Instr::LoadLit(arg),
+                ("store_loc", Some(arg), None) => Instr::StoreLocal(arg),
+                ("load_arg", None, Some(name)) => {
+                    Instr::LoadArg(index_of(&arg_names, name)?)
+                }
+                ("load_loc", None, Some(name)) => {
+                    Instr::LoadLocal(index_of(&local_names, name)?)
+                }
+                ("store_loc", None, Some(name)) => {
+                    Instr::StoreLocal(index_of(&local_names, name)?)
+                }
+                ("pop", None, None) => Instr::Pop,
+                ("dup", None, None) => Instr::Dup,
 
-                    // Jump instructions
-                    (op, None, Some(arg)) if op.starts_with("jmp") => {
-                        Self::get_jump_instr(op, &label_names, arg)?
+                // TODO: fix
+                ("load_func", None, Some(hash)) => {
+                    Instr::LoadFunc(hash.parse().map_err(ParseError::Error)?)
+                }
+                ("load_func", None, None) => {
+                    return Err(ParseError::ExpectedArgument);
+                }
+                ("load_data", None, Some(name)) => {
+                    let value = data
+                        .get(*name)
+                        .ok_or_else(|| ParseError::UnknownName(name.to_string()))?;
+                    Instr::LoadLit(Self::add_literal(
+                        own_literals,
+                        &mut data_literals,
+                        value.clone(),
+                    ))
+                }
+                ("load_dyn", None, Some(arg)) => {
+                    let func_name = arg
+                        .strip_prefix('$')
+                        .ok_or_else(|| ParseError::InvalidIdent(arg.to_string()))?;
+                    Instr::LoadDyn(func_name.to_string())
+                }
+
+                // Jump instructions. Relative jumps take an offset or a label.
+                (op @ ("jmp_rel" | "jmp_rel_t" | "jmp_rel_f"), _, _) => {
+                    let arg = argument.ok_or(ParseError::ExpectedArgument)?;
+                    let delta = match arg.parse::<isize>() {
+                        Result::Ok(delta) => delta,
+                        Err(_) => {
+                            let label = label_names
+                                .get(*arg)
+                                .ok_or(ParseError::UnknownLabel(arg.to_string()))?;
+                            label_offsets[*label] as isize - offset as isize
+                        }
+                    };
+                    match op {
+                        "jmp_rel" => Instr::JumpRel(delta),
+                        "jmp_rel_t" => Instr::JumpRelT(delta),
+                        _ => Instr::JumpRelF(delta),
                     }
+                }
+                (op, None, Some(arg)) if op.starts_with("jmp") => {
+                    Self::get_jump_instr(op, &label_names, arg)?
+                }
 
-                    // Calling and returning
-                    ("call", None, None) => Instr::Call,
-                    ("call_self", None, None) => Instr::CallSelf,
-                    ("ret", None, None) => Instr::Return,
-                    ("ret_val", None, None) => Instr::ReturnVal,
-
-                    // ALU Operations
-                    ("add", None, None) => Instr::BinOp(BinOp::Add),
-                    ("mul", None, None) => Instr::BinOp(BinOp::Mul),
-                    ("div", None, None) => Instr::BinOp(BinOp::Div),
-                    ("sub", None, None) => Instr::BinOp(BinOp::Sub),
-                    ("mod", None, None) => Instr::BinOp(BinOp::Mod),
-                    ("shl", None, None) => Instr::BinOp(BinOp::Shl),
-                    ("shr", None, None) => Instr::BinOp(BinOp::Shr),
-                    ("and", None, None) => Instr::BinOp(BinOp::And),
-                    ("or", None, None) => Instr::BinOp(BinOp::Or),
-                    ("eq", None, None) => Instr::BinOp(BinOp::Eq),
-                    // Unary
-                    ("not", None, None) => Instr::UnaryOp(UnaryOp::Not),
-                    ("neg", None, None) => Instr::UnaryOp(UnaryOp::Neg),
-
-                    // Containers
-                    ("cont_make", Some(n), None) => Instr::ContMakeS(n),
-                    ("cont_make", None, None) => Instr::ContMake,
-                    ("cont_ins", Some(i), None) => Instr::ContInsertS(i),
-                    ("cont_ins", None, None) => Instr::ContInsert,
-                    ("cont_get", Some(i), None) => Instr::ContGetS(i),
-                    ("cont_get", None, None) => Instr::ContGet,
-                    ("cont_set", Some(i), None) => Instr::ContSetS(i),
-                    ("cont_set", None, None) => Instr::ContSet,
-
-                    ("car", None, None) => Instr::ContHead,
-                    ("cdr", None, None) => Instr::ContTail,
-                    ("cont_ext", None, None) => Instr::ContExt,
-                    ("cont_len", None, None) => Instr::ContLen,
-
-                    // Misc
-                    ("nop", None, None) => Instr::Nop,
-                    ("dbg", None, None) => Instr::Dbg,
-                    _ => return Err(ParseError::UnknownInstr(line.to_string())),
-                };
+                // Calling and returning
+                ("call", None, None) => Instr::Call,
+                ("call_self", None, None) => Instr::CallSelf,
+                ("ret", None, None) => Instr::Return,
+                ("ret_val", None, None) => Instr::ReturnVal,
+
+                // ALU Operations
+                ("add", None, None) => Instr::BinOp(BinOp::Add),
+                ("mul", None, None) => Instr::BinOp(BinOp::Mul),
+                ("div", None, None) => Instr::BinOp(BinOp::Div),
+                ("sub", None, None) => Instr::BinOp(BinOp::Sub),
+                ("mod", None, None) => Instr::BinOp(BinOp::Mod),
+                ("shl", None, None) => Instr::BinOp(BinOp::Shl),
+                ("shr", None, None) => Instr::BinOp(BinOp::Shr),
+                ("and", None, None) => Instr::BinOp(BinOp::And),
+                ("or", None, None) => Instr::BinOp(BinOp::Or),
+                ("eq", None, None) => Instr::BinOp(BinOp::Eq),
+                ("cmp", None, None) => Instr::Cmp,
+                // Unary
+                ("not", None, None) => Instr::UnaryOp(UnaryOp::Not),
+                ("neg", None, None) => Instr::UnaryOp(UnaryOp::Neg),
+
+                // Containers
+                ("cont_make", Some(n), None) => Instr::ContMakeS(n),
+                ("cont_make", None, None) => Instr::ContMake,
+                ("cont_ins", Some(i), None) => Instr::ContInsertS(i),
+                ("cont_ins", None, None) => Instr::ContInsert,
+                ("cont_get", Some(i), None) => Instr::ContGetS(i),
+                ("cont_get", None, None) => Instr::ContGet,
+                ("cont_set", Some(i), None) => Instr::ContSetS(i),
+                ("cont_set", None, None) => Instr::ContSet,
+
+                ("car", None, None) => Instr::ContHead,
+                ("cdr", None, None) => Instr::ContTail,
+                ("cont_ext", None, None) => Instr::ContExt,
+                ("cont_len", None, None) => Instr::ContLen,
+
+                // Maps
+                ("map_new", None, None) => Instr::MapNew,
+                ("map_get", None, None) => Instr::MapGet,
+                ("map_set", None, None) => Instr::MapSet,
+                ("map_del", None, None) => Instr::MapDel,
+                ("map_len", None, None) => Instr::MapLen,
+                ("map_keys", None, None) => Instr::MapKeys,
+
+                // Misc
+                ("nop", None, None) => Instr::Nop,
+                ("dbg", None, None) => Instr::Dbg,
+                ("dbg", Some(i), None) => Instr::DbgMsg(i),
+                _ => return Err(ParseError::UnknownInstr(line.to_string())),
+            };
+
+            offset += 1;
+            Result::Ok(ParseToken::Instr(instr))
+        };
+        let tokens = Self::collect_errors(
+            code.zip(&code_lines)
+                .map(|(line, &n)| parse_line(line).map_err(|e| src.locate(e, n))),
+        );
 
-                Result::Ok(ParseToken::Instr(instr))
-            })
-            .collect::<Result<Vec<ParseToken>, ParseError>>()?;
+        // Report bad literals and bad instructions together
+        let (literals, tokens) = match (literals, tokens) {
+            (Result::Ok(mut literals), Result::Ok(tokens)) => {
+                literals.extend(data_literals);
+                (literals, tokens)
+            }
+            (literals, tokens) => {
+                let errors = [literals.err(), tokens.err()].into_iter().flatten();
+                return Err(Self::collect_errors::<()>(errors.map(Err)).unwrap_err());
+            }
+        };
 
-        let num_locals = Self::get_num_locals(&tokens)?;
+        let num_locals = Self::get_num_locals(&tokens)?.max(local_names.len());
+        let instr_lines = tokens
+            .iter()
+            .zip(code_lines)
+            .filter(|(token, _)| matches!(token, ParseToken::Instr(_)))
+            .map(|(_, line)| line)
+            .collect();
 
         Result::Ok(PartialParse {
             tokens,
             labels: label_offsets,
             num_locals,
             literals,
+            arg_names,
+            local_names,
+            metadata,
+            instr_lines,
         })
     }
 
+    /// The index of `value` in a function's literals, followed by the literals added
+    /// by its instructions, adding it to `added` if it is in neither
+    fn add_literal(literals: &[Value], added: &mut Vec<Value>, value: Value) -> usize {
+        literals
+            .iter()
+            .chain(added.iter())
+            .position(|lit| *lit == value)
+            .unwrap_or_else(|| {
+                added.push(value);
+                literals.len() + added.len() - 1
+            })
+    }
+
     fn get_jump_instr(
         op: &str,
         label_names: &HashMap<String, usize>,
         arg: &str,
     ) -> Result<Instr, ParseError> {
-        let label_idx = label_names.get(arg).ok_or(ParseError::UnknownLabel)?;
+        let label_idx = label_names
+            .get(arg)
+            .ok_or_else(|| ParseError::UnknownLabel(arg.to_string()))?;
         match op {
             "jmp" => Result::Ok(Instr::Jump(*label_idx)),
             "jmp_t" => Result::Ok(Instr::JumpT(*label_idx)),
@@ -397,49 +1151,348 @@ impl Parser {
         }
     }
 
-    // TODO: add imports like #include in C
-    fn preprocess(contents: &str) -> String {
-        // Remove the comments
-        contents
-            .lines()
-            .map(|line| {
-                let mut inside_string = false;
-                let mut result = String::new();
-                let chars = line.chars().peekable();
-
-                // Special care taken here to allow .lit "#not a comment"
-                for c in chars {
-                    if c == '"' || c == '\'' {
-                        inside_string = !inside_string;
-                        result.push(c);
-                    } else if c == '#' && !inside_string {
-                        break;
-                    } else {
-                        result.push(c);
+    /// Strip comments and blank lines, substitute constants, expand macros, and
+    /// collect the `#include "path"`, `.module`, `.data`, and `.extern` lines
+    fn preprocess(src: &SourceFile) -> Result<Preprocessed, ParseError> {
+        let mut lines = vec![];
+        let mut includes = vec![];
+        let mut modules = vec![];
+        let mut macros: HashMap<String, Macro> = HashMap::new();
+        let mut consts: HashMap<String, String> = HashMap::new();
+        let mut data = HashMap::new();
+        let mut externs = HashSet::new();
+        // The macro being defined, with the line it starts on
+        let mut defining: Option<(usize, String, Macro)> = None;
+
+        let text =
+            Self::strip_block_comments(src.text).map_err(|(n, e)| src.locate(e, n))?;
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            if let Some(include) = Self::get_include(line) {
+                includes.push((n, include.map_err(|e| src.locate(e, n))?));
+                continue;
+            }
+            let line = Self::strip_comment(line);
+            let line = match line.split_whitespace().next() {
+                Some(".const") => line,
+                _ => Self::substitute_words(&line, |word| consts.get(word).cloned()),
+            };
+            let mut words = line.split_whitespace();
+            match (words.next(), &mut defining) {
+                (None, _) => (),
+                (Some(".const"), None) => {
+                    let (name, value) = Self::get_const_def(&line[6..], &consts)
+                        .map_err(|e| src.locate(e, n))?;
+                    consts.insert(name, value);
+                }
+                (Some(".extern"), None) => match words.collect::<Vec<&str>>()[..] {
+                    [name] if is_valid_qualified_name(name) => {
+                        externs.insert(name.to_string());
+                    }
+                    [name] => {
+                        let error = ParseError::InvalidIdent(name.to_string());
+                        return Err(src.locate(error, n));
                     }
+                    [] => return Err(src.locate(ParseError::ExpectedArgument, n)),
+                    _ => return Err(src.locate(ParseError::UnexpectedArgument, n)),
+                },
+                (Some(".data"), None) => {
+                    let (name, value) = Self::get_data_def(&line[5..], &data)
+                        .map_err(|e| src.locate(e, n))?;
+                    data.insert(name, value);
+                }
+                (Some(".macro"), None) => {
+                    let (name, params) = Self::get_macro_def(words.collect())
+                        .map_err(|e| src.locate(e, n))?;
+                    defining = Some((
+                        n,
+                        name,
+                        Macro {
+                            params,
+                            body: vec![],
+                        },
+                    ));
                 }
+                (Some(".endmacro"), Some(_)) => {
+                    let (_, name, mac) = defining.take().unwrap();
+                    macros.insert(name, mac);
+                }
+                (Some(".module"), None) => match words.collect::<Vec<&str>>()[..] {
+                    [] => modules.push((n, None)),
+                    [module] if is_valid_qualified_name(module) => {
+                        modules.push((n, Some(module.to_string())))
+                    }
+                    [module] => {
+                        let error = ParseError::InvalidIdent(module.to_string());
+                        return Err(src.locate(error, n));
+                    }
+                    _ => return Err(src.locate(ParseError::UnexpectedArgument, n)),
+                },
+                (
+                    Some(
+                        ".macro" | ".endmacro" | ".const" | ".data" | ".extern"
+                        | ".module",
+                    ),
+                    _,
+                ) => {
+                    return Err(src.locate(ParseError::SyntaxError, n));
+                }
+                // Macros used in a macro are expanded when it is defined
+                (_, Some((_, _, mac))) => mac.body.extend(
+                    Self::expand_macro(&line, &macros).map_err(|e| src.locate(e, n))?,
+                ),
+                (_, None) => lines.extend(
+                    Self::expand_macro(&line, &macros)
+                        .map_err(|e| src.locate(e, n))?
+                        .into_iter()
+                        .map(|line| (n, line)),
+                ),
+            }
+        }
 
-                result.trim().to_string()
-            })
-            .filter(|line| !line.is_empty())
-            .collect::<Vec<String>>()
-            .join("\n")
+        if let Some((n, name, _)) = defining {
+            return Err(src.locate(ParseError::UnterminatedMacro(name), n));
+        }
+        Result::Ok(Preprocessed {
+            lines,
+            includes,
+            modules,
+            data,
+            externs,
+        })
+    }
+
+    /// Parse an `#include "path"` line, which would otherwise be a comment
+    pub(crate) fn get_include(line: &str) -> Option<Result<String, ParseError>> {
+        let rest = line.trim_start().strip_prefix("#include")?;
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let path = Self::strip_comment(rest)
+            .strip_prefix('"')
+            .and_then(|quoted| match Self::parse_quoted(quoted, '"') {
+                Result::Ok((path, "")) if !path.is_empty() => Some(path),
+                _ => None,
+            });
+        Some(path.ok_or(ParseError::InvalidInclude))
     }
 
-    fn finalize_parse(partial: PartialParse) -> Result<Parse, ParseError> {
-        let (name, argcount) = partial
+    /// Parse the name and parameters after `.macro`
+    fn get_macro_def(words: Vec<&str>) -> Result<(String, Vec<String>), ParseError> {
+        let (name, params) = words.split_first().ok_or(ParseError::ExpectedArgument)?;
+        if let Some(invalid) = words.iter().find(|word| !is_valid_name(word)) {
+            return Err(ParseError::InvalidIdent(invalid.to_string()));
+        }
+        Result::Ok((
+            name.to_string(),
+            params.iter().map(|param| param.to_string()).collect(),
+        ))
+    }
+
+    /// If `line` uses a macro, its body with the arguments substituted for the
+    /// parameters. Otherwise, just `line`.
+    fn expand_macro(
+        line: &str,
+        macros: &HashMap<String, Macro>,
+    ) -> Result<Vec<String>, ParseError> {
+        let mut words = line.split_whitespace();
+        let Some(mac) = words.next().and_then(|name| macros.get(name)) else {
+            return Result::Ok(vec![line.to_string()]);
+        };
+        let args = words.collect::<Vec<&str>>();
+        if args.len() != mac.params.len() {
+            return Err(ParseError::MacroArgs(line.to_string()));
+        }
+
+        let arg = |word: &str| {
+            let i = mac.params.iter().position(|param| param == word)?;
+            Some(args[i].to_string())
+        };
+        Result::Ok(
+            mac.body
+                .iter()
+                .map(|line| Self::substitute_words(line, arg))
+                .collect(),
+        )
+    }
+
+    /// Parse the name and value after `.const`. The value must be a literal, and
+    /// may use constants defined before it.
+    fn get_const_def(
+        def: &str,
+        consts: &HashMap<String, String>,
+    ) -> Result<(String, String), ParseError> {
+        let def = def.trim();
+        let (name, value) = def.split_once(char::is_whitespace).unwrap_or((def, ""));
+        if !is_valid_name(name) {
+            return Err(ParseError::InvalidIdent(name.to_string()));
+        }
+        if consts.contains_key(name) {
+            return Err(ParseError::DuplicateName(name.to_string()));
+        }
+
+        let value =
+            Self::substitute_words(value.trim(), |word| consts.get(word).cloned());
+        if value.is_empty() {
+            return Err(ParseError::ExpectedArgument);
+        }
+        Self::get_literal(&format!(".lit {value}"))?;
+        Result::Ok((name.to_string(), value))
+    }
+
+    /// Parse the name and literal after `.data`
+    fn get_data_def(
+        def: &str,
+        data: &HashMap<String, Value>,
+    ) -> Result<(String, Value), ParseError> {
+        let def = def.trim();
+        let (name, value) = def.split_once(char::is_whitespace).unwrap_or((def, ""));
+        if !is_valid_name(name) {
+            return Err(ParseError::InvalidIdent(name.to_string()));
+        }
+        if data.contains_key(name) {
+            return Err(ParseError::DuplicateName(name.to_string()));
+        }
+        let value = Self::get_literal(&format!(".lit {}", value.trim()))?;
+        Result::Ok((name.to_string(), value))
+    }
+
+    /// Replace the words of `line` outside of strings and chars that `lookup`
+    /// returns a replacement for. A word is a run of letters, digits, `_`, and `$`.
+    fn substitute_words(line: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+        let mut result = String::new();
+        let mut word = String::new();
+        let mut quote = None;
+        let mut escaped = false;
+
+        for c in line.chars() {
+            if quote.is_none() && (c.is_alphanumeric() || c == '_' || c == '$') {
+                word.push(c);
+                continue;
+            }
+            result.push_str(&lookup(&word).unwrap_or_else(|| word.clone()));
+            word.clear();
+
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => (),
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None => (),
+            }
+            result.push(c);
+        }
+
+        result.push_str(&lookup(&word).unwrap_or(word));
+        result
+    }
+
+    /// Blank out the `/* ... */` comments in `text`, keeping line breaks and
+    /// columns so that errors are reported where they are. Fails with the 1-based
+    /// line of a comment that is never closed.
+    pub(crate) fn strip_block_comments(
+        text: &str,
+    ) -> Result<String, (usize, ParseError)> {
+        let mut in_block = false;
+        // The line the open comment starts on
+        let mut start = 0;
+        let mut lines = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if !in_block {
+                start = i + 1;
+            }
+            lines.push(Self::blank_block_comments(line, &mut in_block));
+        }
+
+        if in_block {
+            return Err((start, ParseError::UnterminatedComment));
+        }
+        Result::Ok(lines.join("\n"))
+    }
+
+    /// Replace the parts of `line` inside `/* ... */` comments with spaces.
+    /// `in_block` is whether the line starts inside a comment, and is set to
+    /// whether it ends inside one. A comment is not started inside a string or char
+    /// literal, or after a `#` comment.
+    pub(crate) fn blank_block_comments(line: &str, in_block: &mut bool) -> String {
+        let mut result = String::with_capacity(line.len());
+        let mut chars = line.chars().peekable();
+        let mut quote = None;
+        let mut escaped = false;
+        let mut line_comment = false;
+
+        while let Some(c) = chars.next() {
+            if *in_block {
+                if c == '*' && chars.next_if_eq(&'/').is_some() {
+                    *in_block = false;
+                    result.push(' ');
+                }
+                result.push(' ');
+                continue;
+            }
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => (),
+                None if line_comment => (),
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '#' => line_comment = true,
+                None if c == '/' && chars.next_if_eq(&'*').is_some() => {
+                    *in_block = true;
+                    result.push_str("  ");
+                    continue;
+                }
+                None => (),
+            }
+            result.push(c);
+        }
+        result
+    }
+
+    /// Remove a line's comment and surrounding whitespace
+    pub(crate) fn strip_comment(line: &str) -> String {
+        // The quote of the string or char we are inside, if any
+        let mut quote = None;
+        let mut escaped = false;
+        let mut result = String::new();
+
+        // Special care taken here to allow .lit "#not a comment"
+        for c in line.chars() {
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => (),
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if c == '#' => break,
+                None => (),
+            }
+            result.push(c);
+        }
+
+        result.trim().to_string()
+    }
+
+    fn finalize_parse(
+        partial: PartialParse,
+        src: &SourceFile,
+        file: Option<&str>,
+    ) -> Result<Parse, ParseError> {
+        let (name, argcount, signature) = partial
             .tokens
             .iter()
             .find_map(|tok| {
-                if let ParseToken::FuncDef(name, arity) = tok {
-                    Some((name, *arity))
+                if let ParseToken::FuncDef(def) = tok {
+                    Some(def.clone())
                 } else {
                     None
                 }
             })
             .ok_or(ParseError::NoFunctionDef)?;
 
-        let code = partial
+        let code: Vec<Instr> = partial
             .tokens
             .iter()
             .filter_map(|token| match token {
@@ -448,27 +1501,111 @@ impl Parser {
             })
             .collect();
 
-        let localnames = (0..argcount + partial.num_locals)
-            .collect::<Vec<usize>>()
-            .into_iter()
-            .map(|t| format!("x{t}"))
-            .collect();
+        // Undeclared arguments and locals are named x0, x1, ...
+        if partial.arg_names.len() > argcount {
+            return Err(ParseError::TooManyArgNames);
+        }
+        let named_locals = partial.local_names.len();
+        let localnames = (0..argcount)
+            .map(|i| partial.arg_names.get(i))
+            .chain((0..partial.num_locals).map(|i| partial.local_names.get(i)))
+            .enumerate()
+            .map(|(t, name)| name.cloned().unwrap_or_else(|| format!("x{t}")))
+            .collect::<Vec<String>>();
+        for (i, name) in localnames.iter().enumerate() {
+            if localnames[..i].contains(name) {
+                return Err(ParseError::DuplicateName(name.clone()));
+            }
+        }
+        Self::check_indices(&code, argcount, &partial.literals)
+            .map_err(|(offset, e)| src.locate(e, partial.instr_lines[offset]))?;
+
+        let mut code_obj = CodeObject {
+            litpool: partial.literals,
+            argcount,
+            is_void: !code.contains(&Instr::ReturnVal),
+            localnames,
+            labels: partial.labels,
+            max_stack_depth: None,
+            signature,
+            debug_info: Some(DebugInfo {
+                file: file.map(str::to_string),
+                lines: partial.instr_lines,
+            }),
+            code: Bytecode::new(code),
+        };
+        Self::optimize(&mut code_obj, named_locals);
+        // Removing dead code can remove the only store of a local
+        Self::check_indices(&code_obj.code, argcount, &code_obj.litpool).map_err(
+            |(offset, e)| {
+                let line = code_obj.debug_info.as_ref().and_then(|d| d.line(offset));
+                src.locate(e, line.unwrap_or(1))
+            },
+        )?;
 
         Result::Ok(Parse {
             func_name: name.to_owned(),
-            code_obj: CodeObject {
-                litpool: partial.literals,
-                argcount,
-                localnames,
-                labels: partial.labels,
-                code: Bytecode::new(code),
+            metadata: Metadata {
+                signature: code_obj.signature.as_ref().map(ToString::to_string),
+                source: file.map(str::to_string),
+                ..partial.metadata
             },
+            code_obj,
+            externs: vec![],
         })
     }
 }
 
-impl Display for ParseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Parser {
+    /// Optimize a parsed code object, and compute its stack depth if it verifies.
+    /// The first `named_locals` locals were named in the source.
+    pub(super) fn optimize(code_obj: &mut CodeObject, named_locals: usize) {
+        opt::fold_constants(code_obj);
+        if verify(code_obj).is_ok() {
+            opt::eliminate_dead_code(code_obj);
+            // Dropping unused labels can let more literals fold, and folding them
+            // now means parsing the disassembly doesn't fold them later
+            opt::fold_constants(code_obj);
+            opt::reuse_local_slots(code_obj, named_locals);
+            code_obj.is_void = code_obj.code.is_void();
+            code_obj.max_stack_depth = max_stack_depth(code_obj);
+        }
+    }
+
+    /// Check that every argument and literal an instruction loads exists, and that
+    /// every local it loads is stored somewhere. Returns the offset of the first bad
+    /// instruction.
+    pub(super) fn check_indices(
+        code: &[Instr],
+        argcount: usize,
+        litpool: &[Value],
+    ) -> Result<(), (usize, ParseError)> {
+        for (offset, instr) in code.iter().enumerate() {
+            let reason = match *instr {
+                Instr::LoadArg(i) if i >= argcount => {
+                    format!("the function has {argcount} arguments")
+                }
+                Instr::LoadLit(i) | Instr::DbgMsg(i) if i >= litpool.len() => {
+                    format!("the function has {} literals", litpool.len())
+                }
+                Instr::LoadLocal(i) if !code.contains(&Instr::StoreLocal(i)) => {
+                    "the local is never stored".to_string()
+                }
+                _ => continue,
+            };
+            let error = ParseError::InvalidIndex {
+                instr: instr.to_string(),
+                reason,
+            };
+            return Err((offset, error));
+        }
+        Result::Ok(())
+    }
+}
+
+impl ParseError {
+    /// The error without its location
+    pub fn message(&self) -> String {
         let msg = match self {
             ParseError::UnexpectedArgument => "unexpected argument",
             ParseError::ExpectedArgument => "expected an argument",
@@ -480,15 +1617,72 @@ impl Display for ParseError {
             ParseError::InvalidIdent(s) => &format!("invalid identifier '{s}'"),
             ParseError::InvalidLabelName(s) => &format!("invalid label name '{s}'"),
             ParseError::InvalidHash => "invalid hash",
-            ParseError::UnknownLabel => "reference to undefined label",
+            ParseError::UnknownName(s) => &format!("undeclared argument or local '{s}'"),
+            ParseError::DuplicateName(s) => &format!("'{s}' is declared twice"),
+            ParseError::TooManyArgNames => "more .arg names than arguments",
+            ParseError::InvalidIndex { instr, reason } => {
+                &format!("invalid index in '{instr}': {reason}")
+            }
+            ParseError::UnknownLabel(s) => &format!("reference to undefined label '{s}'"),
             ParseError::NoFunctionDef => "no function definition",
+            ParseError::InvalidInclude => "expected #include \"path\"",
+            ParseError::IncludeCycle(s) => &format!("'{s}' includes itself"),
+            ParseError::IncludeWithoutFile(s) => {
+                &format!("cannot include '{s}': the source is not from a file")
+            }
+            ParseError::UnterminatedMacro(s) => &format!("macro '{s}' has no .endmacro"),
+            ParseError::MacroArgs(s) => {
+                &format!("wrong number of macro arguments: '{s}'")
+            }
+            ParseError::UnterminatedComment => "block comment has no closing */",
             ParseError::InvalidFuncDef => "invalid function definition",
             ParseError::InvalidLiteral => "invalid literal definition",
             ParseError::InvalidStrLit => "invalid string literal",
-            ParseError::RegexError(e) => &format!("regex: {e}"),
             ParseError::Error(e) => &format!("{e}"),
+            ParseError::Spanned { error, .. } => &error.message(),
+            ParseError::Multiple(errors) => &format!("{} errors", errors.len()),
         };
-        write!(f, "parser error: {msg}")
+        msg.to_string()
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let ParseError::Multiple(errors) = self {
+            for error in errors {
+                write!(f, "{error}\n\n")?;
+            }
+        }
+        write!(f, "parser error: {}", self.message())?;
+
+        // Point at the error in an excerpt of the source:
+        //   --> file.asm:5:5
+        //    |
+        //  5 |     lod_lit 0
+        //    |     ^^^^^^^
+        if let ParseError::Spanned {
+            file,
+            span,
+            source_line,
+            ..
+        } = self
+        {
+            let gutter = " ".repeat(span.line.to_string().len());
+            let indent = source_line
+                .chars()
+                .take(span.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            write!(
+                f,
+                "\n{gutter}--> {file}:{}:{}\n{gutter} |\n{} | {source_line}\n{gutter} | {indent}{}",
+                span.line,
+                span.column,
+                span.line,
+                "^".repeat(span.len)
+            )?;
+        }
+        std::fmt::Result::Ok(())
     }
 }
 
@@ -497,6 +1691,7 @@ impl std::error::Error for ParseError {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::asm::dis::{disassemble_function, format_lit};
 
     fn dbg_f(path: &str) {
         let parse = Parser::parse_file(path).unwrap();
@@ -514,6 +1709,426 @@ mod tests {
         dbg_f("./examples/main.asm");
     }
 
+    #[test]
+    fn test_debug_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lines.asm");
+        fs::write(
+            &path,
+            "# header\n\n$main 0:\n    .lit 1\n    load_lit 0 # one\n\n    ret_val\n",
+        )
+        .unwrap();
+
+        let parse = Parser::parse_file(&path).unwrap().remove(0);
+        let debug_info = parse.code_obj.debug_info.unwrap();
+        assert_eq!(debug_info.lines, vec![5, 7]);
+        assert_eq!(
+            debug_info.location(1),
+            Some(format!("{}:7", path.display()))
+        );
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("typo.asm");
+        let parse = |source: &str| {
+            fs::write(&path, source).unwrap();
+            Parser::parse_file(&path).unwrap_err().to_string()
+        };
+
+        let err = parse("$main 0:\n    .lit 1\n\n    lod_lit 0 # typo\n    ret_val\n");
+        let file = path.display();
+        assert_eq!(
+            err,
+            [
+                "parser error: unknown instruction or invalid arguments: 'lod_lit 0'",
+                &format!(" --> {file}:4:5"),
+                "  |",
+                "4 |     lod_lit 0 # typo",
+                "  |     ^^^^^^^^^",
+            ]
+            .join("\n")
+        );
+
+        let err = parse("$main 0:\n    jmp nowhere\n");
+        assert!(err.contains(&format!("{file}:2:9")));
+        assert!(err.ends_with("|         ^^^^^^^"));
+
+        let err = parse("$main 0:\n    .lit 1 2 3\n    .lat 1\n");
+        assert!(err.contains(&format!("{file}:3:5")));
+
+        let err = parse("é:\n$main 0:\n    ret\n");
+        assert!(err.contains("no function definition"));
+        assert!(err.contains(&format!("{file}:1:1")));
+        let err = parse("$main 0:\n    load_dyn é\n    ret\n");
+        assert!(err.contains("invalid identifier 'é'"));
+    }
+
+    #[test]
+    fn test_named_locals() {
+        let parse = Parser::parse_file("examples/named.asm").unwrap();
+        assert_eq!(parse[0].code_obj.localnames, vec!["n".to_string()]);
+        assert_eq!(parse[0].code_obj.code[0], Instr::LoadArg(0));
+        assert!(parse[1].code_obj.localnames.contains(&"total".to_string()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("names.asm");
+        let parse = |source: &str| {
+            fs::write(&path, source).unwrap();
+            Parser::parse_file(&path)
+        };
+
+        // Undeclared locals keep their default names
+        let source = [
+            "$f 1:",
+            "    .local a",
+            "    load_arg 0",
+            "    store_loc 0",
+            "    load_arg 0",
+            "    store_loc 1",
+            "    load_loc 0",
+            "    load_loc 1",
+            "    add",
+            "    ret_val",
+        ];
+        let obj = &parse(&source.join("\n")).unwrap()[0];
+        assert_eq!(obj.code_obj.localnames, vec!["x0", "a", "x2"]);
+
+        assert!(parse("$f 0:\n    load_loc a\n    ret_val\n").is_err());
+        assert!(parse("$f 0:\n    .local a\n    .local a\n    ret\n").is_err());
+        assert!(parse("$f 1:\n    .arg a\n    .arg b\n    ret\n").is_err());
+        assert!(parse("$f 0:\n    .local loop\n    ret\n").is_err());
+
+        // Names survive disassembly, and names the parser would reject become
+        // comments
+        let source = "$f 2:\n    .arg n\n    .arg acc\n    .local t\n    load_arg 0\n    \
+                      store_loc 0\n    load_loc 0\n    load_arg 1\n    add\n    ret_val\n";
+        let mut obj = parse(source).unwrap().remove(0).code_obj;
+        let roundtrip = |obj: &CodeObject| {
+            let dis = disassemble_function("f", &obj.hash().unwrap(), obj).unwrap();
+            (dis.clone(), parse(&dis).unwrap().remove(0).code_obj)
+        };
+        assert_eq!(roundtrip(&obj).1.localnames, vec!["n", "acc", "t"]);
+
+        obj.localnames = vec!["n".into(), "a.b".into(), "n".into()];
+        let (dis, parsed) = roundtrip(&obj);
+        assert!(dis.contains(".arg x1 # \"a.b\"\n"));
+        assert!(dis.contains(".local x2 # \"n\"\n"));
+        assert!(dis.contains("load_loc x2\n"));
+        assert_eq!(parsed.localnames, vec!["n", "x1", "x2"]);
+        assert_eq!(parsed.hash().unwrap(), roundtrip(&parsed).1.hash().unwrap());
+
+        // Locals with the default names are declared too, so their slots aren't
+        // merged
+        let source = "$f 0:\n    .local a\n    .local b\n    .lit 1\n    load_lit 0\n    \
+                      store_loc a\n    load_loc a\n    store_loc b\n    load_loc b\n    ret_val\n";
+        let mut obj = parse(source).unwrap().remove(0).code_obj;
+        obj.localnames = vec!["x0".into(), "x1".into()];
+        let (dis, parsed) = roundtrip(&obj);
+        assert!(dis.contains(".local x1\n"));
+        assert_eq!(parsed.hash().unwrap(), obj.hash().unwrap());
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, source: &str| {
+            fs::create_dir_all(dir.path().join(name).parent().unwrap()).unwrap();
+            fs::write(dir.path().join(name), source).unwrap();
+            dir.path().join(name)
+        };
+        let names = |parses: Vec<Parse>| {
+            parses.into_iter().map(|p| p.func_name).collect::<Vec<_>>()
+        };
+
+        // Paths are relative to the including file, and a file included twice is
+        // only parsed once
+        write(
+            "lib/square.asm",
+            "$square 1:\n    load_arg 0\n    ret_val\n",
+        );
+        write(
+            "lib/both.asm",
+            "#include \"square.asm\"\n$both 0:\n    ret\n",
+        );
+        let main = write(
+            "main.asm",
+            "#include \"lib/square.asm\" # helpers\n#include \"lib/both.asm\"\n\n$main 0:\n    ret\n",
+        );
+        let parses = Parser::parse_file(&main).unwrap();
+        assert_eq!(names(parses), vec!["square", "both", "main"]);
+
+        // Line numbers in debug info and errors are per file
+        let both = dir.path().join("lib/both.asm");
+        let parses = Parser::parse_file(&both).unwrap();
+        let debug_info = parses[1].code_obj.debug_info.as_ref().unwrap();
+        assert_eq!(
+            debug_info.location(0),
+            Some(format!("{}:3", both.display()))
+        );
+
+        write("a.asm", "#include \"b.asm\"\n");
+        let b = write("b.asm", "\n#include \"a.asm\"\n");
+        let err = Parser::parse_file(&b).unwrap_err().to_string();
+        assert!(err.contains("'b.asm' includes itself"));
+        assert!(err.contains("a.asm:1:11"));
+
+        let bad = write("bad.asm", "#include other.asm\n");
+        assert!(Parser::parse_file(&bad).is_err());
+        let missing = write("missing.asm", "#include \"nowhere.asm\"\n");
+        assert!(Parser::parse_file(&missing).is_err());
+
+        // Not an include
+        let comment = write("comment.asm", "#included below\n$main 0:\n    ret\n");
+        assert!(Parser::parse_file(&comment).is_ok());
+
+        // Source that isn't from a file can't include, rather than resolving
+        // against the current directory
+        let source = "#include \"examples/fib.asm\"\n$main 0:\n    ret\n";
+        let err = Parser::parse_str(source).unwrap_err().to_string();
+        assert!(err.contains("the source is not from a file"), "{err}");
+        assert!(Parser::parse_reader(source.as_bytes()).is_err());
+        assert!(Parser::parse_source_at(source, "main.asm").is_ok());
+    }
+
+    #[test]
+    fn test_macros() {
+        let parse = Parser::parse_file("examples/macros.asm").unwrap().remove(0);
+        assert_eq!(parse.code_obj.code.len(), 16);
+        // Expanded instructions are attributed to the line that used the macro
+        let debug_info = parse.code_obj.debug_info.unwrap();
+        assert_eq!(debug_info.line(2), Some(21));
+        assert_eq!(debug_info.line(13), Some(22));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("macros.asm");
+        let parse = |source: &str| {
+            fs::write(&path, source).unwrap();
+            Parser::parse_file(&path).map_err(|e| e.to_string())
+        };
+
+        let err =
+            parse(".macro m a\n    load_arg a\n.endmacro\n$f 1:\n    m\n").unwrap_err();
+        assert!(err.contains("wrong number of macro arguments: 'm'"));
+        assert!(err.contains(":5:5"));
+        assert!(parse(".macro m\n$f 0:\n    ret\n").is_err());
+        assert!(parse(".endmacro\n$f 0:\n    ret\n").is_err());
+        assert!(parse(".macro\n.endmacro\n").is_err());
+        assert!(parse(".macro m loop\n.endmacro\n").is_err());
+    }
+
+    #[test]
+    fn test_consts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consts.asm");
+        let parse = |source: &str| {
+            fs::write(&path, source).unwrap();
+            Parser::parse_file(&path).map(|mut parses| parses.remove(0).code_obj)
+        };
+
+        let obj = parse(
+            &[
+                ".const N 3",
+                ".const ITEMS [N, 'N', \"N\"]",
+                ".const SECOND 1",
+                ".macro get i",
+                "    load_lit i",
+                ".endmacro",
+                "$main 0:",
+                "    .lit ITEMS",
+                "    .lit N",
+                "    load_lit 0",
+                "    get SECOND",
+                "    cont_get",
+                "    ret_val",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            obj.litpool[0],
+            Value::Container(vec![
+                Value::I32(3),
+                Value::Char('N'),
+                Value::String("N".to_string())
+            ])
+        );
+        assert_eq!(obj.code[1], Instr::LoadLit(1));
+
+        assert!(parse(".const N 1\n.const N 2\n$main 0:\n    ret\n").is_err());
+        assert!(parse(".const N\n$main 0:\n    ret\n").is_err());
+        assert!(parse(".const N nothing\n$main 0:\n    ret\n").is_err());
+        assert!(parse(".const 1 1\n$main 0:\n    ret\n").is_err());
+    }
+
+    #[test]
+    fn test_modules() {
+        let parses = Parser::parse_file("examples/modules.asm").unwrap();
+        let names = parses
+            .iter()
+            .map(|p| p.func_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["math::square", "math::sum_squares", "main"]);
+        assert!(parses[1]
+            .code_obj
+            .code
+            .contains(&Instr::LoadDyn("math::square".to_string())));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("modules.asm");
+        let parse = |source: &str| {
+            fs::write(&path, source).unwrap();
+            Parser::parse_file(&path)
+        };
+        assert!(parse(".module a::b\n$f 0:\n    ret\n").is_ok());
+        assert!(parse(".module a:b\n$f 0:\n    ret\n").is_err());
+        assert!(parse(".module a b\n$f 0:\n    ret\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_str() {
+        let source = fs::read_to_string("examples/fib.asm").unwrap();
+        let from_file = Parser::parse_file("examples/fib.asm").unwrap();
+        let from_str = Parser::parse_str(&source).unwrap();
+        let from_reader = Parser::parse_reader(source.as_bytes()).unwrap();
+        for (a, b) in from_file
+            .iter()
+            .zip(&from_str)
+            .chain(from_file.iter().zip(&from_reader))
+        {
+            assert_eq!(a.func_name, b.func_name);
+            assert_eq!(a.code_obj.hash().unwrap(), b.code_obj.hash().unwrap());
+        }
+        assert_eq!(from_str[0].code_obj.debug_info.as_ref().unwrap().file, None);
+
+        let err = Parser::parse_str("$main 0:\n    lod_lit 0\n").unwrap_err();
+        assert!(err.to_string().contains("<input>:2:5"));
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let source = [
+            "/* a comment",
+            "   $f 0:",
+            "   #include \"nothing.asm\" */",
+            "$main 0: /* entry */",
+            "    .lit \"/* not */ a comment\" # /* nor this",
+            "    .lit '/' /* slash */",
+            "    load_lit /* the string */ 0 /* and",
+            "    pop",
+            "    */ pop",
+            "    load_lit 1",
+            "    ret_val",
+        ]
+        .join("\n");
+        let parse = Parser::parse_str(&source).unwrap().remove(0);
+        assert_eq!(parse.func_name, "main");
+        assert_eq!(
+            parse.code_obj.litpool,
+            vec![Value::string("/* not */ a comment"), Value::Char('/')]
+        );
+        assert_eq!(parse.code_obj.code.len(), 4);
+        assert_eq!(parse.code_obj.code[1], Instr::Pop);
+        assert_eq!(parse.code_obj.debug_info.unwrap().line(1), Some(9));
+
+        let err = Parser::parse_str("$main 0:\n    ret /* oops\n").unwrap_err();
+        assert!(err.to_string().contains("block comment has no closing */"));
+        assert!(err.to_string().contains("<input>:2:9"));
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        let source = [
+            "$f 0:",
+            "    .lit nothing",
+            "    lod_lit 0",
+            "    ret",
+            "$main 0:",
+            "    jmp nowhere",
+            "    ret",
+        ]
+        .join("\n");
+        let err = Parser::parse_str(&source).unwrap_err().to_string();
+        for location in ["<input>:2:5", "<input>:3:5", "<input>:6:9"] {
+            assert!(err.contains(location), "{err}");
+        }
+        assert!(err.ends_with("parser error: 3 errors"));
+
+        // A single error is reported on its own
+        let err = Parser::parse_str("$main 0:\n    lod_lit 0\n").unwrap_err();
+        assert!(!err.to_string().contains("errors"));
+    }
+
+    #[test]
+    fn test_data() {
+        let parses = Parser::parse_file("examples/data.asm").unwrap();
+        let primes = Value::Container([2, 3, 5, 7, 11].map(Value::I32).to_vec());
+        assert_eq!(parses[0].code_obj.litpool, vec![primes]);
+        // Uses the function's own literal, and adds a shared one only once
+        assert_eq!(parses[1].code_obj.litpool, vec![Value::I32(2)]);
+
+        assert!(Parser::parse_str("$main 0:\n    load_data NOPE\n    ret\n").is_err());
+        assert!(Parser::parse_str(".data A 1\n.data A 2\n").is_err());
+        assert!(Parser::parse_str(".data A\n").is_err());
+    }
+
+    #[test]
+    fn test_dbg_message() {
+        let parse = Parser::parse_file("examples/dbg.asm").unwrap().remove(0);
+        assert_eq!(parse.code_obj.code[1], Instr::DbgMsg(1));
+        assert_eq!(parse.code_obj.litpool[1], Value::string("before adding"));
+
+        // Messages are shared with the function's literals, and can be given by index
+        let source = [
+            "$main 0:",
+            "    .lit \"a\"",
+            "    load_lit 0",
+            "    dbg \"a\"",
+            "    dbg \"b  c\"",
+            "    dbg 0",
+            "    ret_val",
+        ]
+        .join("\n");
+        let obj = Parser::parse_str(&source).unwrap().remove(0).code_obj;
+        assert_eq!(obj.litpool, vec![Value::string("a"), Value::string("b  c")]);
+        assert_eq!(
+            obj.code[1..4],
+            [Instr::DbgMsg(0), Instr::DbgMsg(1), Instr::DbgMsg(0)]
+        );
+
+        assert!(Parser::parse_str("$main 0:\n    dbg \"a\" b\n    ret\n").is_err());
+        assert!(Parser::parse_str("$main 0:\n    dbg 1\n    ret\n").is_err());
+    }
+
+    #[test]
+    fn test_check_indices() {
+        let parse = |code: &str| {
+            let source = format!("$f 2:\n    .lit 1\n{code}\n    ret\n");
+            Parser::parse_str(&source).map_err(|e| e.to_string())
+        };
+        assert!(
+            parse("    load_arg 1\n    load_lit 0\n    store_loc 0\n    load_loc 0")
+                .is_ok()
+        );
+
+        let err = parse("    nop\n    load_arg 2").unwrap_err();
+        assert!(
+            err.contains("invalid index in 'load_arg 2': the function has 2 arguments")
+        );
+        assert!(err.contains("<input>:4:5"));
+        assert!(parse("    load_lit 1")
+            .unwrap_err()
+            .contains("has 1 literals"));
+        assert!(parse("    load_loc 0")
+            .unwrap_err()
+            .contains("never stored"));
+        // The only store is removed as dead code
+        let err = parse("    load_loc 0\n    pop\n    ret\n    store_loc 0").unwrap_err();
+        assert!(err.contains("never stored"));
+        assert!(err.contains("<input>:3:5"));
+    }
+
     #[test]
     fn test_is_funcdef() {
         assert!(matches!(
@@ -524,8 +2139,186 @@ mod tests {
             Parser::is_func_def("$fibb 33:"),
             Some(Result::Ok(_))
         ));
-        assert!(matches!(Parser::is_func_def("$fibb 33"), None));
-        assert!(matches!(Parser::is_func_def("fibb 99:"), None));
+        assert!(Parser::is_func_def("$fibb 33").is_none());
+        assert!(Parser::is_func_def("fibb 99:").is_none());
+
+        let (_, _, sig) = Parser::is_func_def("$add 2: (i32, i32) -> i32")
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig.unwrap().to_string(), "(i32, i32) -> i32");
+        assert!(matches!(
+            Parser::is_func_def("$add 1: (i32, i32)"),
+            Some(Err(ParseError::InvalidFuncDef))
+        ));
+        assert!(matches!(Parser::is_func_def("$add 1: (int)"), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_int_lits() {
+        let lit = |s: &str| Parser::get_scalar_lit(s).unwrap().unwrap();
+        assert_eq!(lit("7"), Value::I32(7));
+        assert_eq!(lit("10000000000"), Value::I128(10000000000));
+        assert_eq!(lit("-7"), Value::I32(-7));
+        assert_eq!(lit("-10000000000"), Value::I128(-10000000000));
+        assert_eq!(
+            lit("-1000000000000000000000000000000000000000"),
+            Value::BigInt("-1000000000000000000000000000000000000000".parse().unwrap())
+        );
+
+        assert!(Parser::get_literal(".lit -").is_err());
+        assert!(Parser::get_literal(".lit 300u8").is_err());
+        assert!(Parser::get_literal(".lit 'ab'").is_err());
+        assert!(Parser::get_literal(".lit [1, 2").is_err());
+        assert!(Parser::get_literal(".lit [1 2]").is_err());
+        assert!(Parser::get_literal(".lit [1] 2").is_err());
+        assert!(Parser::get_literal(".lit 5x").is_err());
+        assert_eq!(
+            lit("1000000000000000000000000000000000000000"),
+            Value::BigInt("1000000000000000000000000000000000000000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_typed_lits() {
+        let values = [
+            Value::U8(42),
+            Value::I8(-3),
+            Value::U16(7),
+            Value::I16(-7),
+            Value::U32(7),
+            Value::I64(10),
+            Value::U64(u64::MAX),
+            Value::I128(-1),
+            Value::U128(1),
+            Value::Isize(-2),
+            Value::Usize(2),
+            Value::F32(1.5),
+            Value::F64(3.0),
+            Value::F64(-0.25),
+            Value::F64(1e300),
+            Value::Char('c'),
+            Value::Char(' '),
+            Value::Char(':'),
+            Value::Map(vec![(Value::Char(','), Value::F32(-2.0))]),
+            Value::Container(vec![]),
+            Value::Container(vec![
+                Value::I32(1),
+                Value::string("x, y]"),
+                Value::Container(vec![Value::Char(']'), Value::Map(vec![])]),
+                Value::Map(vec![(Value::I32(1), Value::Container(vec![Value::U8(2)]))]),
+            ]),
+        ];
+        for value in values {
+            let line = format!(".lit {}", format_lit(&value));
+            assert_eq!(Parser::get_literal(&line).unwrap(), value, "{line}");
+        }
+
+        assert_eq!(Parser::get_literal(".lit 2.5").unwrap(), Value::F64(2.5));
+        assert_eq!(Parser::get_literal(".lit 10i64").unwrap(), Value::I64(10));
+    }
+
+    #[test]
+    fn test_lit_roundtrip() {
+        // Every variant, at the edges of its range
+        let values = [
+            Value::I8(i8::MIN),
+            Value::U8(u8::MAX),
+            Value::I16(i16::MIN),
+            Value::U16(u16::MAX),
+            Value::I32(i32::MIN),
+            Value::I32(i32::MAX),
+            Value::U32(u32::MAX),
+            Value::I64(i64::MIN),
+            Value::U64(u64::MAX),
+            Value::I128(i128::MIN),
+            Value::I128(0),
+            Value::U128(u128::MAX),
+            Value::Isize(isize::MIN),
+            Value::Usize(usize::MAX),
+            Value::BigInt(0.into()),
+            Value::BigInt((-5).into()),
+            Value::BigInt(BigInt::from(u128::MAX) * 3),
+            Value::F32(f32::MIN_POSITIVE),
+            Value::F32(f32::MAX),
+            Value::F32(-0.1),
+            Value::F64(f64::MIN_POSITIVE / 4.0),
+            Value::F64(f64::MAX),
+            Value::F64(0.1 + 0.2),
+            Value::F64(f64::INFINITY),
+            Value::F32(f32::NEG_INFINITY),
+            Value::Char('\n'),
+            Value::Char('\''),
+            Value::Char('"'),
+            Value::Char('\u{301}'),
+            Value::Char('é'),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Hash(Hash::digest(b"x")),
+            Value::string(""),
+            Value::string("\"quoted\" \\ 'single' # not a comment /* nor this */"),
+            Value::string("line\nbreak\ttab\u{0}\u{1b}"),
+            Value::Container(vec![Value::Container(vec![]), Value::Char(',')]),
+            Value::Map(vec![
+                (Value::string("}"), Value::BigInt(1.into())),
+                (Value::Hash(Hash::digest(b"y")), Value::Map(vec![])),
+            ]),
+        ];
+        for value in values {
+            let line = format!(".lit {}", format_lit(&value));
+            assert_eq!(Parser::get_literal(&line).unwrap(), value, "{line}");
+        }
+
+        // NaN isn't equal to itself
+        for value in [Value::F64(f64::NAN), Value::F32(f32::NAN)] {
+            let line = format!(".lit {}", format_lit(&value));
+            let parsed = Parser::get_literal(&line).unwrap();
+            assert_eq!(parsed.type_name(), value.type_name());
+            assert_eq!(format_lit(&parsed), format_lit(&value));
+        }
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            Parser::get_literal(r#".lit "a\"b\\c\n\t\u{e9}'""#).unwrap(),
+            Value::string("a\"b\\c\n\té'")
+        );
+        assert_eq!(
+            Parser::get_literal(r".lit '\''").unwrap(),
+            Value::Char('\'')
+        );
+        assert!(Parser::get_literal(r#".lit "\q""#).is_err());
+        assert!(Parser::get_literal(r#".lit "\u{d800}""#).is_err());
+        assert!(Parser::get_literal(r#".lit "unterminated\""#).is_err());
+        assert!(Parser::get_literal(r#".lit "a" "b""#).is_err());
+
+        for s in ["\"#\"", "tab\there", "\0\r\u{7f}", "it's"] {
+            let line = format!(".lit {}", format_lit(&Value::string(s)));
+            assert_eq!(Parser::get_literal(&line).unwrap(), Value::string(s));
+        }
+
+        assert_eq!(
+            Parser::strip_comment(r#".lit "a\" # b" # comment"#),
+            r#".lit "a\" # b""#
+        );
+    }
+
+    #[test]
+    fn test_map_lit() {
+        assert_eq!(Parser::get_map_lit("{}").unwrap(), Value::Map(vec![]));
+        assert_eq!(
+            Parser::get_map_lit(r#"{"a": 1, 2: {true: "x y"}}"#).unwrap(),
+            Value::Map(vec![
+                (Value::string("a"), Value::I32(1)),
+                (
+                    Value::I32(2),
+                    Value::Map(vec![(Value::Bool(true), Value::string("x y"))])
+                ),
+            ])
+        );
+        assert!(Parser::get_map_lit("{1: 2").is_err());
+        assert!(Parser::get_map_lit("{1 2}").is_err());
+        assert!(Parser::get_map_lit("{1: 2} 3").is_err());
     }
 
     #[test]
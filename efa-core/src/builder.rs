@@ -0,0 +1,289 @@
+//! Build code objects programmatically, without counting label offsets or
+//! managing the litpool by hand.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::bytecode::{BinOp, Bytecode, Instr};
+use crate::vm::{CodeObject, Value};
+
+/// A jump target created by `BytecodeBuilder::label`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Default)]
+pub struct BytecodeBuilder {
+    argcount: usize,
+    localnames: Vec<String>,
+    litpool: Vec<Value>,
+    /// Offset of each label, once it is bound
+    labels: Vec<Option<usize>>,
+    named_labels: HashMap<String, Label>,
+    code: Vec<Instr>,
+}
+
+impl BytecodeBuilder {
+    /// Start building a function that takes `argcount` arguments
+    pub fn new(argcount: usize) -> BytecodeBuilder {
+        BytecodeBuilder {
+            argcount,
+            localnames: (0..argcount).map(|i| format!("x{i}")).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a label. It can be jumped to before or after it is bound to an
+    /// offset with `bind`.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// The label called `name`, creating it the first time it is used
+    pub fn named_label(&mut self, name: &str) -> Label {
+        if let Some(label) = self.named_labels.get(name) {
+            return *label;
+        }
+        let label = self.label();
+        self.named_labels.insert(name.to_string(), label);
+        label
+    }
+
+    /// Bind `label` to the offset of the next instruction
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        self.labels[label.0] = Some(self.code.len());
+        self
+    }
+
+    pub fn instr(&mut self, instr: Instr) -> &mut Self {
+        self.code.push(instr);
+        self
+    }
+
+    /// Unconditionally jump to `label`
+    pub fn jump_to(&mut self, label: Label) -> &mut Self {
+        self.branch(Instr::Jump, label)
+    }
+
+    /// Emit a jump instruction to `label`, e.g. `branch(Instr::JumpT, label)`
+    pub fn branch(&mut self, jump: fn(usize) -> Instr, label: Label) -> &mut Self {
+        self.instr(jump(label.0))
+    }
+
+    /// Load a literal, adding it to the litpool if it isn't there yet
+    pub fn lit(&mut self, value: impl Into<Value>) -> &mut Self {
+        let value = value.into();
+        let index = match self.litpool.iter().position(|lit| *lit == value) {
+            Some(index) => index,
+            None => self.add_lit(value),
+        };
+        self.instr(Instr::LoadLit(index))
+    }
+
+    /// Append a literal to the litpool without loading it, returning its index
+    pub fn add_lit(&mut self, value: impl Into<Value>) -> usize {
+        self.litpool.push(value.into());
+        self.litpool.len() - 1
+    }
+
+    pub fn arg(&mut self, index: usize) -> &mut Self {
+        self.instr(Instr::LoadArg(index))
+    }
+
+    /// Load the local called `name`, declaring it if needed
+    pub fn load(&mut self, name: &str) -> &mut Self {
+        let index = self.local_index(name);
+        self.instr(Instr::LoadLocal(index))
+    }
+
+    /// Store to the local called `name`, declaring it if needed
+    pub fn store(&mut self, name: &str) -> &mut Self {
+        let index = self.local_index(name);
+        self.instr(Instr::StoreLocal(index))
+    }
+
+    pub fn op(&mut self, op: BinOp) -> &mut Self {
+        self.instr(Instr::BinOp(op))
+    }
+
+    fn local_index(&mut self, name: &str) -> usize {
+        let locals = &self.localnames[self.argcount..];
+        match locals.iter().position(|local| local == name) {
+            Some(index) => index,
+            None => {
+                self.localnames.push(name.to_string());
+                self.localnames.len() - self.argcount - 1
+            }
+        }
+    }
+
+    /// Resolve labels and produce the code object. Fails if a label was never
+    /// bound.
+    pub fn build(&self) -> Result<CodeObject> {
+        let labels = self
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(i, offset)| match offset {
+                Some(offset) => Ok(*offset),
+                None => bail!("label {i} is never bound"),
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        Ok(CodeObject {
+            litpool: self.litpool.clone(),
+            argcount: self.argcount,
+            is_void: !self.code.contains(&Instr::ReturnVal),
+            localnames: self.localnames.clone(),
+            labels,
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
+            code: Bytecode::new(self.code.clone()),
+        })
+    }
+}
+
+/// Build a `CodeObject` (as a `Result`) from a list of items, each ending in `;`:
+///
+/// - `args N;` (optional, first) sets the number of arguments
+/// - `.lit VALUE;` appends a literal to the litpool
+/// - `label NAME:` binds a label to the next instruction
+/// - `jump Instr::JumpT => NAME;` jumps to a label with the given jump instruction
+/// - any other expression is an instruction
+///
+/// ```
+/// # use efa_core::{code_object, bytecode::Instr};
+/// let obj = code_object! {
+///     args 1;
+///     .lit 0;
+///     Instr::LoadArg(0);
+///     jump Instr::JumpT => nonzero;
+///     Instr::LoadLit(0);
+///     Instr::ReturnVal;
+///     label nonzero:
+///     Instr::LoadArg(0);
+///     Instr::ReturnVal;
+/// }
+/// .unwrap();
+/// ```
+#[macro_export]
+macro_rules! code_object {
+    (@items $b:ident;) => {};
+    (@items $b:ident; .lit $value:expr; $($rest:tt)*) => {
+        $b.add_lit($value);
+        $crate::code_object!(@items $b; $($rest)*);
+    };
+    (@items $b:ident; label $name:ident: $($rest:tt)*) => {
+        let label = $b.named_label(stringify!($name));
+        $b.bind(label);
+        $crate::code_object!(@items $b; $($rest)*);
+    };
+    (@items $b:ident; jump $jump:path => $name:ident; $($rest:tt)*) => {
+        let label = $b.named_label(stringify!($name));
+        $b.branch($jump, label);
+        $crate::code_object!(@items $b; $($rest)*);
+    };
+    (@items $b:ident; $instr:expr; $($rest:tt)*) => {
+        $b.instr($instr);
+        $crate::code_object!(@items $b; $($rest)*);
+    };
+    (args $argcount:expr; $($items:tt)*) => {{
+        let mut builder = $crate::builder::BytecodeBuilder::new($argcount);
+        $crate::code_object!(@items builder; $($items)*);
+        builder.build()
+    }};
+    ($($items:tt)*) => {
+        $crate::code_object!(args 0; $($items)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_builder() {
+        let mut b = BytecodeBuilder::new(1);
+        let base_case = b.label();
+        b.arg(0)
+            .lit(1)
+            .branch(Instr::JumpLe, base_case)
+            .arg(0)
+            .lit(1)
+            .op(BinOp::Sub)
+            .instr(Instr::CallSelf)
+            .arg(0)
+            .lit(2)
+            .op(BinOp::Sub)
+            .instr(Instr::CallSelf)
+            .op(BinOp::Add)
+            .instr(Instr::ReturnVal)
+            .bind(base_case)
+            .arg(0)
+            .instr(Instr::ReturnVal);
+        let fib = b.build().unwrap();
+
+        assert_eq!(fib.litpool, vec![Value::I32(1), Value::I32(2)]);
+        assert_eq!(fib.labels, vec![13]);
+
+        let mut vm = Vm::new().unwrap();
+        let hash = vm.db.insert_code_object_with_name(&fib, "fib").unwrap();
+
+        let mut b = BytecodeBuilder::new(0);
+        b.lit(20)
+            .store("n")
+            .load("n")
+            .instr(Instr::LoadFunc(hash))
+            .instr(Instr::Call)
+            .instr(Instr::ReturnVal);
+        let main = b.build().unwrap();
+        assert_eq!(main.localnames, vec!["n".to_string()]);
+
+        vm.db.insert_code_object_with_name(&main, "main").unwrap();
+        assert_eq!(vm.run_main_function().unwrap(), 6765);
+    }
+
+    #[test]
+    fn test_unbound_label() {
+        let mut b = BytecodeBuilder::new(0);
+        let label = b.label();
+        b.jump_to(label);
+        assert!(b.build().is_err());
+    }
+
+    #[test]
+    fn test_code_object_macro() {
+        let obj = code_object! {
+            args 1;
+            .lit 0;
+            .lit 0;
+            Instr::LoadArg(0);
+            jump Instr::JumpT => nonzero;
+            jump Instr::Jump => end;
+            label nonzero:
+            Instr::LoadLit(1);
+            label end:
+            Instr::Return;
+        }
+        .unwrap();
+
+        assert_eq!(obj.argcount, 1);
+        assert_eq!(obj.litpool, vec![Value::I32(0), Value::I32(0)]);
+        assert_eq!(obj.labels, vec![3, 4]);
+        assert_eq!(
+            obj.code.to_vec(),
+            vec![
+                Instr::LoadArg(0),
+                Instr::JumpT(0),
+                Instr::Jump(1),
+                Instr::LoadLit(1),
+                Instr::Return
+            ]
+        );
+
+        assert!(code_object! { jump Instr::Jump => nowhere; }.is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use std::fmt;
 use std::ops::Deref;
 
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::Hash;
+use crate::{Hash, HASH_SIZE};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BinOp {
@@ -56,10 +57,18 @@ pub enum Instr {
     JumpGe(usize),
     JumpLt(usize),
     JumpLe(usize),
+    /// Jumps by an offset relative to the jump instruction itself, instead of
+    /// through the labels table, so code can be moved without fixing up labels
+    JumpRel(isize),
+    JumpRelT(isize),
+    JumpRelF(isize),
 
     // ALU ops
     BinOp(BinOp),
     UnaryOp(UnaryOp),
+    /// Push -1, 0, or 1 as the second-from-top is less than, equal to, or greater
+    /// than the top of the stack
+    Cmp,
 
     /* Containers
      * The S suffix (static) is to specify the index statically.
@@ -82,11 +91,53 @@ pub enum Instr {
     ContExt,
     ContLen,
 
+    // Maps
+    MapNew,
+    MapGet,
+    MapSet,
+    MapDel,
+    MapLen,
+    MapKeys,
+
     // Misc
     Dbg,
+    /// Like `Dbg`, also printing the literal at the index as a message
+    DbgMsg(usize),
     Nop,
 }
 
+/// What an instruction's operand refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand<'a> {
+    /// Index into the code object's labels
+    Label(usize),
+    /// Jump offset relative to the instruction
+    Relative(isize),
+    /// Index into the litpool
+    Lit(usize),
+    /// Argument index
+    Arg(usize),
+    /// Local index, not counting arguments
+    Local(usize),
+    /// A function hash
+    Hash(&'a Hash),
+    /// A function name, resolved at link time
+    Name(&'a str),
+    /// A static container size or index
+    Index(usize),
+}
+
+/// An instruction in context, as yielded by `Bytecode::walk`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step<'a> {
+    pub offset: usize,
+    pub instr: &'a Instr,
+    pub operand: Option<Operand<'a>>,
+    /// Offsets that can execute next. May include the code length when execution
+    /// runs off the end.
+    pub successors: Vec<usize>,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Bytecode {
     code: Vec<Instr>,
@@ -101,20 +152,410 @@ impl Bytecode {
         bytecode
             .code
             .iter()
-            .map(|instr| match instr {
-                Instr::Jump(i) => format!("    jmp L{i}"),
-                Instr::JumpT(i) => format!("    jmp_t L{i}"),
-                Instr::JumpF(i) => format!("    jmp_f L{i}"),
-                Instr::JumpEq(i) => format!("    jmp_eq L{i}"),
-                Instr::JumpNe(i) => format!("    jmp_ne L{i}"),
-                Instr::JumpGt(i) => format!("    jmp_gt L{i}"),
-                Instr::JumpGe(i) => format!("    jmp_ge L{i}"),
-                Instr::JumpLt(i) => format!("    jmp_lt L{i}"),
-                Instr::JumpLe(i) => format!("    jmp_le L{i}"),
+            .map(|instr| match instr.operand() {
+                Some(Operand::Label(label)) => {
+                    let formatted = instr.to_string();
+                    let mnemonic =
+                        formatted.split_whitespace().next().unwrap_or_default();
+                    format!("    {mnemonic} L{label}")
+                }
                 _ => format!("    {instr}"),
             })
             .collect()
     }
+
+    /// Whether the code never returns a value, i.e. has no `ReturnVal`
+    pub fn is_void(&self) -> bool {
+        !self.code.contains(&Instr::ReturnVal)
+    }
+
+    /// Iterate over the instructions with their offsets, operands, and successors,
+    /// given the code object's label offsets
+    pub fn walk<'a>(&'a self, labels: &'a [usize]) -> impl Iterator<Item = Step<'a>> {
+        self.code.iter().enumerate().map(|(offset, instr)| Step {
+            offset,
+            instr,
+            operand: instr.operand(),
+            successors: instr.successors(offset, labels),
+        })
+    }
+
+    /// Encode the bytecode compactly: each instruction is a one-byte opcode
+    /// followed by its operands. Indices are LEB128 varints, hashes are raw bytes,
+    /// and strings are a varint length followed by UTF-8.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.code.len() * 2);
+
+        for instr in &self.code {
+            out.push(instr.opcode());
+            match instr {
+                Instr::LoadFunc(hash) => out.extend_from_slice(hash.as_bytes()),
+                Instr::LoadDyn(name) => {
+                    write_varint(&mut out, name.len());
+                    out.extend_from_slice(name.as_bytes());
+                }
+                Instr::BinOp(op) => out.push(op.clone() as u8),
+                Instr::UnaryOp(op) => out.push(op.clone() as u8),
+                Instr::LoadArg(n)
+                | Instr::LoadLocal(n)
+                | Instr::LoadLit(n)
+                | Instr::DbgMsg(n)
+                | Instr::StoreLocal(n)
+                | Instr::ContMakeS(n)
+                | Instr::ContInsertS(n)
+                | Instr::ContGetS(n)
+                | Instr::ContSetS(n) => write_varint(&mut out, *n),
+                Instr::JumpRel(delta)
+                | Instr::JumpRelT(delta)
+                | Instr::JumpRelF(delta) => write_signed_varint(&mut out, *delta),
+                instr => {
+                    if let Some(label) = instr.jump_label() {
+                        write_varint(&mut out, label);
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Decode bytecode produced by `Bytecode::encode`
+    pub fn decode(bytes: &[u8]) -> Result<Bytecode> {
+        let mut decoder = Decoder { bytes, pos: 0 };
+        let mut code = vec![];
+
+        while decoder.pos < bytes.len() {
+            let opcode = decoder.byte()?;
+            code.push(match opcode {
+                0x00 => Instr::LoadArg(decoder.varint()?),
+                0x01 => Instr::LoadLocal(decoder.varint()?),
+                0x02 => Instr::LoadLit(decoder.varint()?),
+                0x03 => Instr::StoreLocal(decoder.varint()?),
+                0x04 => Instr::Pop,
+                0x05 => Instr::Dup,
+
+                0x10 => Instr::LoadFunc(Hash::from_slice(decoder.take(HASH_SIZE)?)?),
+                0x11 => {
+                    let len = decoder.varint()?;
+                    Instr::LoadDyn(String::from_utf8(decoder.take(len)?.to_vec())?)
+                }
+                0x12 => Instr::Call,
+                0x13 => Instr::CallSelf,
+                0x14 => Instr::Return,
+                0x15 => Instr::ReturnVal,
+
+                0x20 => Instr::Jump(decoder.varint()?),
+                0x21 => Instr::JumpT(decoder.varint()?),
+                0x22 => Instr::JumpF(decoder.varint()?),
+                0x23 => Instr::JumpEq(decoder.varint()?),
+                0x24 => Instr::JumpNe(decoder.varint()?),
+                0x25 => Instr::JumpGt(decoder.varint()?),
+                0x26 => Instr::JumpGe(decoder.varint()?),
+                0x27 => Instr::JumpLt(decoder.varint()?),
+                0x28 => Instr::JumpLe(decoder.varint()?),
+                0x29 => Instr::JumpRel(decoder.signed_varint()?),
+                0x2a => Instr::JumpRelT(decoder.signed_varint()?),
+                0x2b => Instr::JumpRelF(decoder.signed_varint()?),
+
+                0x30 => Instr::BinOp(match decoder.byte()? {
+                    0 => BinOp::Add,
+                    1 => BinOp::Mul,
+                    2 => BinOp::Div,
+                    3 => BinOp::Sub,
+                    4 => BinOp::Mod,
+                    5 => BinOp::Shl,
+                    6 => BinOp::Shr,
+                    7 => BinOp::And,
+                    8 => BinOp::Or,
+                    9 => BinOp::Eq,
+                    op => bail!("invalid binary operation {op}"),
+                }),
+                0x31 => Instr::UnaryOp(match decoder.byte()? {
+                    0 => UnaryOp::Not,
+                    1 => UnaryOp::Neg,
+                    op => bail!("invalid unary operation {op}"),
+                }),
+                0x32 => Instr::Cmp,
+
+                0x40 => Instr::ContMakeS(decoder.varint()?),
+                0x41 => Instr::ContMake,
+                0x42 => Instr::ContInsertS(decoder.varint()?),
+                0x43 => Instr::ContInsert,
+                0x44 => Instr::ContGetS(decoder.varint()?),
+                0x45 => Instr::ContGet,
+                0x46 => Instr::ContSetS(decoder.varint()?),
+                0x47 => Instr::ContSet,
+                0x48 => Instr::ContHead,
+                0x49 => Instr::ContTail,
+                0x4a => Instr::ContExt,
+                0x4b => Instr::ContLen,
+
+                0x50 => Instr::MapNew,
+                0x51 => Instr::MapGet,
+                0x52 => Instr::MapSet,
+                0x53 => Instr::MapDel,
+                0x54 => Instr::MapLen,
+                0x55 => Instr::MapKeys,
+
+                0xf0 => Instr::Dbg,
+                0xf1 => Instr::Nop,
+                0xf2 => Instr::DbgMsg(decoder.varint()?),
+
+                op => bail!("invalid opcode {op:#04x} at byte {}", decoder.pos - 1),
+            });
+        }
+
+        Ok(Bytecode::new(code))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Zigzag-encode so that small negative numbers stay small
+fn write_signed_varint(out: &mut Vec<u8>, n: isize) {
+    write_varint(out, ((n << 1) ^ (n >> (isize::BITS - 1))) as usize);
+}
+
+/// Reads operands for `Bytecode::decode`
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| anyhow!("unexpected end of bytecode"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let mut n: usize = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as usize)
+                .checked_shl(shift)
+                .ok_or_else(|| anyhow!("varint overflow"))?;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        bail!("varint overflow")
+    }
+
+    fn signed_varint(&mut self) -> Result<isize> {
+        let n = self.varint()?;
+        Ok((n >> 1) as isize ^ -((n & 1) as isize))
+    }
+}
+
+impl Instr {
+    /// The first byte of the instruction's compact encoding
+    fn opcode(&self) -> u8 {
+        match self {
+            Instr::LoadArg(_) => 0x00,
+            Instr::LoadLocal(_) => 0x01,
+            Instr::LoadLit(_) => 0x02,
+            Instr::StoreLocal(_) => 0x03,
+            Instr::Pop => 0x04,
+            Instr::Dup => 0x05,
+
+            Instr::LoadFunc(_) => 0x10,
+            Instr::LoadDyn(_) => 0x11,
+            Instr::Call => 0x12,
+            Instr::CallSelf => 0x13,
+            Instr::Return => 0x14,
+            Instr::ReturnVal => 0x15,
+
+            Instr::Jump(_) => 0x20,
+            Instr::JumpT(_) => 0x21,
+            Instr::JumpF(_) => 0x22,
+            Instr::JumpEq(_) => 0x23,
+            Instr::JumpNe(_) => 0x24,
+            Instr::JumpGt(_) => 0x25,
+            Instr::JumpGe(_) => 0x26,
+            Instr::JumpLt(_) => 0x27,
+            Instr::JumpLe(_) => 0x28,
+            Instr::JumpRel(_) => 0x29,
+            Instr::JumpRelT(_) => 0x2a,
+            Instr::JumpRelF(_) => 0x2b,
+
+            Instr::BinOp(_) => 0x30,
+            Instr::UnaryOp(_) => 0x31,
+            Instr::Cmp => 0x32,
+
+            Instr::ContMakeS(_) => 0x40,
+            Instr::ContMake => 0x41,
+            Instr::ContInsertS(_) => 0x42,
+            Instr::ContInsert => 0x43,
+            Instr::ContGetS(_) => 0x44,
+            Instr::ContGet => 0x45,
+            Instr::ContSetS(_) => 0x46,
+            Instr::ContSet => 0x47,
+            Instr::ContHead => 0x48,
+            Instr::ContTail => 0x49,
+            Instr::ContExt => 0x4a,
+            Instr::ContLen => 0x4b,
+
+            Instr::MapNew => 0x50,
+            Instr::MapGet => 0x51,
+            Instr::MapSet => 0x52,
+            Instr::MapDel => 0x53,
+            Instr::MapLen => 0x54,
+            Instr::MapKeys => 0x55,
+
+            Instr::Dbg => 0xf0,
+            Instr::Nop => 0xf1,
+            Instr::DbgMsg(_) => 0xf2,
+        }
+    }
+
+    /// The number of values the instruction pops from and then pushes onto the
+    /// stack, or `None` if that depends on runtime state (e.g. the callee of a
+    /// `Call`).
+    pub fn stack_effect(&self) -> Option<(usize, usize)> {
+        Some(match self {
+            Instr::LoadArg(_) | Instr::LoadLocal(_) | Instr::LoadLit(_) => (0, 1),
+            Instr::StoreLocal(_) | Instr::Pop => (1, 0),
+            Instr::Dup => (1, 2),
+
+            Instr::LoadFunc(_) | Instr::LoadDyn(_) => (0, 1),
+            Instr::Call | Instr::CallSelf => return None,
+            Instr::Return => (0, 0),
+            Instr::ReturnVal => (1, 0),
+
+            Instr::Jump(_) | Instr::JumpRel(_) => (0, 0),
+            Instr::JumpT(_) | Instr::JumpF(_) => (1, 0),
+            Instr::JumpRelT(_) | Instr::JumpRelF(_) => (1, 0),
+            Instr::JumpEq(_)
+            | Instr::JumpNe(_)
+            | Instr::JumpGt(_)
+            | Instr::JumpGe(_)
+            | Instr::JumpLt(_)
+            | Instr::JumpLe(_) => (2, 0),
+
+            Instr::BinOp(_) | Instr::Cmp => (2, 1),
+            Instr::UnaryOp(_) => (1, 1),
+
+            Instr::ContMakeS(n) => (*n, 1),
+            Instr::ContMake => return None,
+            Instr::ContInsertS(_) => (2, 1),
+            Instr::ContInsert => (3, 1),
+            Instr::ContGetS(_) => (1, 1),
+            Instr::ContGet => (2, 1),
+            Instr::ContSetS(_) => (2, 1),
+            Instr::ContSet => (3, 1),
+            Instr::ContHead | Instr::ContTail | Instr::ContLen => (1, 1),
+            Instr::ContExt => (2, 1),
+
+            Instr::MapNew => (0, 1),
+            Instr::MapGet | Instr::MapDel => (2, 1),
+            Instr::MapSet => (3, 1),
+            Instr::MapLen | Instr::MapKeys => (1, 1),
+
+            // Peeks at the top of the stack
+            Instr::Dbg | Instr::DbgMsg(_) => (1, 1),
+            Instr::Nop => (0, 0),
+        })
+    }
+
+    /// The instruction's operand, if it has one
+    pub fn operand(&self) -> Option<Operand<'_>> {
+        Some(match self {
+            Instr::LoadArg(i) => Operand::Arg(*i),
+            Instr::LoadLocal(i) | Instr::StoreLocal(i) => Operand::Local(*i),
+            Instr::LoadLit(i) | Instr::DbgMsg(i) => Operand::Lit(*i),
+            Instr::LoadFunc(hash) => Operand::Hash(hash),
+            Instr::LoadDyn(name) => Operand::Name(name),
+            Instr::ContMakeS(i)
+            | Instr::ContInsertS(i)
+            | Instr::ContGetS(i)
+            | Instr::ContSetS(i) => Operand::Index(*i),
+            Instr::JumpRel(delta) | Instr::JumpRelT(delta) | Instr::JumpRelF(delta) => {
+                Operand::Relative(*delta)
+            }
+            instr => Operand::Label(instr.jump_label()?),
+        })
+    }
+
+    /// The offsets that can execute after this instruction at `offset`, given the
+    /// code object's label offsets. A jump to a label that doesn't exist has no
+    /// successor there.
+    pub fn successors(&self, offset: usize, labels: &[usize]) -> Vec<usize> {
+        match self {
+            Instr::Return | Instr::ReturnVal => vec![],
+            Instr::Jump(label) => labels.get(*label).copied().into_iter().collect(),
+            Instr::JumpRel(delta) => vec![offset.saturating_add_signed(*delta)],
+            Instr::JumpRelT(delta) | Instr::JumpRelF(delta) => {
+                vec![offset + 1, offset.saturating_add_signed(*delta)]
+            }
+            instr => {
+                let target = instr.jump_label().and_then(|label| labels.get(label));
+                [offset + 1].into_iter().chain(target.copied()).collect()
+            }
+        }
+    }
+
+    /// The relative offset this instruction may jump by, if it is a relative jump
+    pub fn jump_delta(&self) -> Option<isize> {
+        match self {
+            Instr::JumpRel(d) | Instr::JumpRelT(d) | Instr::JumpRelF(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    /// Change the offset of a relative jump. Does nothing for other instructions.
+    pub fn set_jump_delta(&mut self, delta: isize) {
+        if let Instr::JumpRel(d) | Instr::JumpRelT(d) | Instr::JumpRelF(d) = self {
+            *d = delta;
+        }
+    }
+
+    /// The label this instruction may jump to, if it is a jump
+    pub fn jump_label(&self) -> Option<usize> {
+        match self {
+            Instr::Jump(l)
+            | Instr::JumpT(l)
+            | Instr::JumpF(l)
+            | Instr::JumpEq(l)
+            | Instr::JumpNe(l)
+            | Instr::JumpGt(l)
+            | Instr::JumpGe(l)
+            | Instr::JumpLt(l)
+            | Instr::JumpLe(l) => Some(*l),
+            _ => None,
+        }
+    }
+
+    /// Point a jump instruction at a different label. Does nothing for other
+    /// instructions.
+    pub fn set_jump_label(&mut self, label: usize) {
+        match self {
+            Instr::Jump(l)
+            | Instr::JumpT(l)
+            | Instr::JumpF(l)
+            | Instr::JumpEq(l)
+            | Instr::JumpNe(l)
+            | Instr::JumpGt(l)
+            | Instr::JumpGe(l)
+            | Instr::JumpLt(l)
+            | Instr::JumpLe(l) => *l = label,
+            _ => (),
+        }
+    }
 }
 
 impl Deref for Bytecode {
@@ -156,8 +597,8 @@ impl fmt::Display for Instr {
                 Instr::Pop => "pop".to_string(),
                 Instr::Dup => "dup".to_string(),
 
-                Instr::LoadFunc(h) => format!("load_func 0x{}", hex::encode(h)),
-                Instr::LoadDyn(s) => format!("load_dyn {s}"),
+                Instr::LoadFunc(h) => format!("load_func {h}"),
+                Instr::LoadDyn(s) => format!("load_dyn ${s}"),
                 Instr::Call => "call".to_string(),
                 Instr::CallSelf => "call_self".to_string(),
                 Instr::Return => "ret".to_string(),
@@ -172,9 +613,13 @@ impl fmt::Display for Instr {
                 Instr::JumpGe(i) => format!("jmp_ge {i}"),
                 Instr::JumpLt(i) => format!("jmp_lt {i}"),
                 Instr::JumpLe(i) => format!("jmp_le {i}"),
+                Instr::JumpRel(d) => format!("jmp_rel {d:+}"),
+                Instr::JumpRelT(d) => format!("jmp_rel_t {d:+}"),
+                Instr::JumpRelF(d) => format!("jmp_rel_f {d:+}"),
 
                 Instr::BinOp(op) => format!("{op}"),
                 Instr::UnaryOp(op) => format!("{op}"),
+                Instr::Cmp => "cmp".to_string(),
 
                 Instr::ContMakeS(n) => format!("cont_make {n}"),
                 Instr::ContMake => "cont_make".to_string(),
@@ -193,7 +638,15 @@ impl fmt::Display for Instr {
                 Instr::ContExt => "cont_ext".to_string(),
                 Instr::ContLen => "cont_len".to_string(),
 
+                Instr::MapNew => "map_new".to_string(),
+                Instr::MapGet => "map_get".to_string(),
+                Instr::MapSet => "map_set".to_string(),
+                Instr::MapDel => "map_del".to_string(),
+                Instr::MapLen => "map_len".to_string(),
+                Instr::MapKeys => "map_keys".to_string(),
+
                 Instr::Dbg => "dbg".to_string(),
+                Instr::DbgMsg(i) => format!("dbg {i}"),
                 Instr::Nop => "nop".to_string(),
             }
         )
@@ -244,4 +697,58 @@ mod tests {
         bytecode![Instr::Nop];
         bytecode![Instr::Nop, Instr::BinOp(BinOp::Add)];
     }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let code = bytecode![
+            Instr::LoadArg(0),
+            Instr::LoadLit(300),
+            Instr::StoreLocal(usize::MAX),
+            Instr::LoadFunc(Hash::digest(b"f")),
+            Instr::LoadDyn("fib".to_string()),
+            Instr::Call,
+            Instr::JumpLe(2),
+            Instr::JumpRel(-1),
+            Instr::JumpRelT(isize::MIN),
+            Instr::JumpRelF(isize::MAX),
+            Instr::BinOp(BinOp::Eq),
+            Instr::BinOp(BinOp::Shr),
+            Instr::UnaryOp(UnaryOp::Neg),
+            Instr::ContSetS(1),
+            Instr::MapKeys,
+            Instr::DbgMsg(7),
+            Instr::Nop
+        ];
+        let encoded = code.encode();
+        assert_eq!(&encoded[..4], &[0x00, 0, 0x02, 0xac]);
+        assert_eq!(Bytecode::decode(&encoded).unwrap().to_vec(), code.to_vec());
+
+        // Truncated operand, bad opcode
+        assert!(Bytecode::decode(&[0x02]).is_err());
+        assert!(Bytecode::decode(&[0xee]).is_err());
+    }
+
+    #[test]
+    fn test_walk() {
+        let code = bytecode![
+            Instr::LoadLit(0),
+            Instr::JumpT(0),
+            Instr::LoadDyn("f".to_string()),
+            Instr::ReturnVal
+        ];
+        let steps = code.walk(&[3]).collect::<Vec<_>>();
+
+        assert_eq!(steps[0].operand, Some(Operand::Lit(0)));
+        assert_eq!(steps[1].operand, Some(Operand::Label(0)));
+        assert_eq!(steps[1].successors, vec![2, 3]);
+        assert_eq!(steps[2].operand, Some(Operand::Name("f")));
+        assert_eq!(steps[3].operand, None);
+        assert!(steps[3].successors.is_empty());
+
+        // Missing labels are no successor rather than a panic
+        let code = bytecode![Instr::JumpT(4), Instr::Jump(7), Instr::ReturnVal];
+        let steps = code.walk(&[]).collect::<Vec<_>>();
+        assert_eq!(steps[0].successors, vec![1]);
+        assert!(steps[1].successors.is_empty());
+    }
 }
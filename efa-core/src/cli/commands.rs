@@ -1,61 +1,803 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::prelude::*;
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value as Json};
 
-use crate::asm::parser;
-use crate::db::Database;
-use crate::solver::resolve_dyn::DynCallResolver;
-use crate::vm::Vm;
+use crate::asm::assembler::Assembler;
+use crate::asm::dis::{DisOptions, FuncRefs};
+use crate::asm::{diff, fmt, parser};
+use crate::bytecode::Instr;
+use crate::db::{
+    Database, FunctionEntry, FunctionFilter, IntegrityReport, NameConflict, NameDiff,
+    DEFAULT_ENTRY_POINT,
+};
+use crate::efb;
+use crate::json;
+use crate::solver::{self, resolve_dyn::DynCallResolver};
+use crate::sync::{self, SyncReport};
+use crate::vm::{
+    exit_code, CodeObject, DbgOutput, RunProfile, TraceOptions, Tracer, Value, Vm,
+};
+use crate::Hash;
+
+/// Options that apply to every command
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Upgrade databases made by older versions when opening them. Without it
+    /// they are refused instead, e.g. to keep them usable by those versions.
+    pub migrate: bool,
+    pub format: Format,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            migrate: true,
+            format: Format::Text,
+        }
+    }
+}
+
+/// How `run`, `ls`, `verify`, and `graph` print their results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// For other tools to read. What a program prints with `dbg` goes to stderr,
+    /// so that stdout is only JSON.
+    Json,
+}
+
+/// Print a command's error as JSON, for tools that read `Format::Json` output
+pub fn print_error_json(e: &anyhow::Error) -> Result<()> {
+    print_json(&json!({ "error": format!("{e:#}") }))
+}
+
+fn print_json(json: &Json) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(json)?);
+    Ok(())
+}
+
+fn open_database(options: &Options, db_path: &str) -> Result<Database> {
+    match options.migrate {
+        true => Database::open(db_path),
+        false => Database::open_without_migrating(db_path),
+    }
+}
+
+/// Parse a bytecode assembly file and resolve its dyn calls, or load the functions
+/// of an already-assembled .efb file. Externs are looked up in `db`.
+fn load_functions(
+    file: &str,
+    db: Option<&Database>,
+) -> Result<HashMap<String, CodeObject>> {
+    if Path::new(file).extension().is_some_and(|ext| ext == "efb") {
+        let f = fs::File::open(file)?;
+        return Ok(efb::read_efb(std::io::BufReader::new(f))?
+            .into_iter()
+            .collect());
+    }
 
-/// Run a bytecode assembly file.
-/// Parse a file, run the DAG solver, hash and insert everything into a
-/// code database, and find and run the main function.
-pub fn run_scratch_file(file: &str, db_path: Option<&str>) -> Result<i32> {
     let objs = parser::Parser::parse_file(file)?;
+    let mut resolver = DynCallResolver::new(objs)?;
+    if let Some(db) = db {
+        resolver.resolve_externs(db)?;
+    }
+    resolver.resolve_dyn_calls()
+}
 
-    let resolver = DynCallResolver::new(objs)?;
-    let resolved = resolver.resolve_dyn_calls()?;
+/// Run a bytecode assembly file (or .efb file).
+/// Parse a file, run the DAG solver, hash and insert everything into a
+/// code database, and find and run the main function. An existing database at
+/// `db_path` is added to, and can provide `.extern` functions.
+pub fn run_scratch_file(
+    options: &Options,
+    file: &str,
+    db_path: Option<&str>,
+) -> Result<i32> {
+    run_file_entry(options, file, db_path, DEFAULT_ENTRY_POINT)
+}
 
-    let mut vm = if let Some(path) = db_path {
-        Vm::persistent(path)?
-    } else {
-        Vm::new()?
+/// Run a file as `run_scratch_file` does, starting at the entry point `entry` of
+/// the database rather than the default one
+pub fn run_file_entry(
+    options: &Options,
+    file: &str,
+    db_path: Option<&str>,
+    entry: &str,
+) -> Result<i32> {
+    let (mut vm, main) = load_file(options, file, db_path)?;
+    let start = Instant::now();
+    let code = match main {
+        Some(main) if entry == DEFAULT_ENTRY_POINT => {
+            exit_code(vm.run_code_object(&main, vec![])?)?
+        }
+        _ => vm.run_entry_point(entry)?,
     };
+    if options.format == Format::Json {
+        print_json(&run_json("exit_code", json!(code), start))?;
+    }
+    Ok(code)
+}
+
+/// What `run` prints as JSON: what the run ended with, under `key`, and how long
+/// it took
+fn run_json(key: &str, result: Json, start: Instant) -> Json {
+    json!({ key: result, "seconds": start.elapsed().as_secs_f64() })
+}
 
-    resolved
+/// Run a file as `run_scratch_file` does, but start at the entry point or function
+/// `entry`, passing it `args`, and print what it returns. Each argument is a
+/// literal as written after `.lit`, e.g. `3`, `1.5`, `true` or `"hi"`, or else a
+/// string.
+pub fn run_file_function(
+    options: &Options,
+    file: &str,
+    db_path: Option<&str>,
+    entry: &str,
+    args: &[String],
+) -> Result<Option<Value>> {
+    let args = args
+        .iter()
+        .map(|arg| {
+            parser::Parser::parse_literal(arg).unwrap_or_else(|_| Value::string(arg))
+        })
+        .collect();
+    let (mut vm, main) = load_file(options, file, db_path)?;
+    let start = Instant::now();
+    let returned = match main {
+        Some(main) if entry == DEFAULT_ENTRY_POINT => vm.run_code_object(&main, args)?,
+        _ => vm.run_function(entry, args)?,
+    };
+    if options.format == Format::Json {
+        let value = returned.as_ref().map(json::to_json).transpose()?;
+        print_json(&run_json("value", json!(value), start))?;
+    } else if let Some(value) = &returned {
+        println!("{}", value.to_display_string());
+    }
+    Ok(returned)
+}
+
+/// Run a bytecode assembly file (or .efb file), or the code database at `path`,
+/// from the entry point `entry`, writing each instruction that runs to `output`,
+/// or else stderr. Returns the exit code.
+pub fn trace(
+    options: &Options,
+    path: &str,
+    entry: &str,
+    opts: TraceOptions,
+    output: Option<&str>,
+) -> Result<i32> {
+    let mut vm = load_path(options, path)?;
+    let out: Box<dyn Write + Send> = match output {
+        Some(output) => Box::new(std::io::BufWriter::new(fs::File::create(output)?)),
+        None => Box::new(std::io::stderr()),
+    };
+    vm.set_observer(Tracer::new(out, &vm.db, opts)?);
+    let code = vm.run_entry_point(entry);
+    // Flush what was traced, even if the run failed
+    drop(vm.take_observer());
+    code
+}
+
+/// Run a bytecode assembly file (or .efb file), or the code database at `path`,
+/// from the entry point `entry` while profiling it. Prints the calls, instructions
+/// and time of each function, and writes the stacks that ran to `folded`, to draw
+/// as a flame graph. Returns the exit code and what was profiled.
+pub fn profile(
+    options: &Options,
+    path: &str,
+    entry: &str,
+    folded: &str,
+) -> Result<(i32, RunProfile)> {
+    let mut vm = load_path(options, path)?;
+    vm.set_profiling(true);
+    let code = vm.run_entry_point(entry)?;
+    let profile = vm.last_profile().cloned().unwrap_or_default();
+
+    let name = |hash: &Hash| match vm.db.get_name_of_hash(hash) {
+        Ok(Some(name)) => name,
+        _ => hash.abbrev(),
+    };
+    let mut hashes = profile.profiles.keys().collect::<Vec<_>>();
+    hashes.sort_by_key(|hash| (std::cmp::Reverse(profile.self_time[*hash]), name(hash)));
+    println!(
+        "{:<24} {:>8} {:>12} {:>12} {:>12}",
+        "function", "calls", "instructions", "self", "total"
+    );
+    for hash in hashes {
+        let stats = &profile.profiles[hash];
+        println!(
+            "{:<24} {:>8} {:>12} {:>12} {:>12}",
+            format!("${}", name(hash)),
+            stats.calls,
+            stats.instructions,
+            format!("{:?}", profile.self_time[hash]),
+            format!("{:?}", stats.time)
+        );
+    }
+    fs::write(folded, profile.folded(name))?;
+    println!("wrote stacks to {folded}");
+    Ok((code, profile))
+}
+
+/// A VM for a bytecode assembly file (or .efb file), with its functions inserted
+/// in memory, or else for the code database at `path`
+fn load_path(options: &Options, path: &str) -> Result<Vm> {
+    let is_file = Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext == "asm" || ext == "efb");
+    match is_file {
+        true => Ok(load_file(options, path, None)?.0),
+        false => Ok(Vm::from_database(open_database(options, path)?)),
+    }
+}
+
+/// A VM for the database at `db_path`, or an in-memory one, with the functions of
+/// `file` inserted. Also returns the hash of the file's main function, if it has
+/// one, which is run instead of the database's default entry point.
+fn load_file(
+    options: &Options,
+    file: &str,
+    db_path: Option<&str>,
+) -> Result<(Vm, Option<Hash>)> {
+    let mut vm = match db_path {
+        Some(path) if Path::new(path).exists() => {
+            Vm::from_database(open_database(options, path)?)
+        }
+        Some(path) => Vm::persistent(path)?,
+        None => Vm::new()?,
+    };
+    if options.format == Format::Json {
+        vm.set_dbg_output(DbgOutput::Stderr);
+    }
+
+    let functions = load_functions(file, Some(&vm.db))?;
+    let mut hashes = vm
+        .db
+        .insert_parses(functions)?
         .into_iter()
-        .map(|(name, obj)| vm.db.insert_code_object_with_name(&obj, &name))
+        .collect::<Vec<_>>();
+    hashes.sort();
+    warn_unresolved(&vm.db, &hashes)?;
+
+    let main = hashes
+        .iter()
+        .find(|(name, _)| name == "main")
+        .map(|(_, hash)| *hash);
+    Ok((vm, main))
+}
+
+/// Assemble a bytecode assembly file into the code database at `db_path`, creating
+/// it if needed, without running anything. The file doesn't need a main function.
+pub fn assemble_file(
+    options: &Options,
+    file: &str,
+    db_path: &str,
+) -> Result<Vec<(String, Hash)>> {
+    let db = if Path::new(db_path).exists() {
+        open_database(options, db_path)?
+    } else {
+        Database::new(db_path)?
+    };
+
+    let functions = Assembler::new(&db).assemble_file(file)?;
+    for (name, hash) in &functions {
+        println!("{hash} ${name}");
+    }
+    warn_unresolved(&db, &functions)?;
+    Ok(functions)
+}
+
+/// Warn about the calls in the given functions that the solver can't follow, so
+/// are missing from their dependencies
+fn warn_unresolved(db: &Database, functions: &[(String, Hash)]) -> Result<()> {
+    for (name, hash) in functions {
+        for call in solver::unresolved_calls(db, &db.get_code_object(hash)?)? {
+            eprintln!("warning: ${name}: {call}");
+        }
+    }
+    Ok(())
+}
+
+/// Assemble a bytecode assembly file into a binary .efb object file.
+pub fn emit_efb(file: &str, out_file: &str) -> Result<()> {
+    let mut functions = load_functions(file, None)?.into_iter().collect::<Vec<_>>();
+    functions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let f = fs::File::create(out_file)?;
+    efb::write_efb(std::io::BufWriter::new(f), &functions)
+}
+
+/// Write the named functions in a code database, and everything they call, to a
+/// pack file
+pub fn export_pack(
+    options: &Options,
+    db_path: &str,
+    out_file: &str,
+    roots: &[String],
+) -> Result<()> {
+    let roots = roots.iter().map(String::as_str).collect::<Vec<_>>();
+    let f = fs::File::create(out_file)?;
+    open_database(options, db_path)?.export_pack(&roots, std::io::BufWriter::new(f))
+}
+
+/// Print the named functions in a code database that match `filter`, one per line
+/// with an abbreviated hash, arity, instruction count, and when it was named
+pub fn list_functions(
+    options: &Options,
+    db_path: &str,
+    filter: &FunctionFilter,
+) -> Result<Vec<FunctionEntry>> {
+    let db = open_database(options, db_path)?;
+    let entries = db.list_entries(filter)?;
+    if options.format == Format::Json {
+        print_json(&entries_json(&db, &entries)?)?;
+        return Ok(entries);
+    }
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    for entry in &entries {
+        let obj = db.get_code_object(&entry.hash)?;
+        println!(
+            "{:<width$}  {}  {:>2}  {:>5}  {}",
+            entry.name,
+            entry.hash.abbrev(),
+            obj.argcount,
+            obj.code.len(),
+            entry.time,
+        );
+    }
+    Ok(entries)
+}
+
+/// What `ls` prints as JSON: an array of the functions of `entries`
+fn entries_json(db: &Database, entries: &[FunctionEntry]) -> Result<Json> {
+    let functions = entries
+        .iter()
+        .map(|entry| {
+            let obj = db.get_code_object(&entry.hash)?;
+            Ok(json!({
+                "name": entry.name,
+                "hash": entry.hash.to_string(),
+                "argcount": obj.argcount,
+                "len": obj.code.len(),
+                "time": entry.time,
+            }))
+        })
         .collect::<Result<Vec<_>>>()?;
+    Ok(Json::Array(functions))
+}
 
-    let code = vm.run_main_function()?;
+/// What `remove` removes besides its target, and whether it removes what other
+/// functions still use
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RemoveOptions {
+    /// Also remove the code object of a name, if it has no other names
+    pub object: bool,
+    /// Remove it even if other functions load it, leaving their calls unresolved
+    pub force: bool,
+    /// Also remove the functions that load it, and the functions that load those
+    pub cascade: bool,
+    /// Only print what would be removed
+    pub dry_run: bool,
+}
 
-    Ok(code)
+/// Remove a function from a code database by name or hash prefix (starting with
+/// 0x), printing what is removed. A name is removed alone, and its code object
+/// too with `object` if it has no other names. A hash prefix removes the code
+/// object and every name of it. Refuses if other functions load what is removed,
+/// with `load_func` or by name with `load_dyn`, unless `force` or `cascade` is set.
+/// Returns the hashes of the code objects removed.
+pub fn remove(
+    options: &Options,
+    db_path: &str,
+    target: &str,
+    opts: RemoveOptions,
+) -> Result<Vec<Hash>> {
+    let db = open_database(options, db_path)?;
+    let hash = db.resolve(target)?;
+    let (mut names, mut removed) = match db.has_name(target)? {
+        true => {
+            let last = db.get_names_of_hash(&hash)?.len() == 1;
+            let removed = match opts.object && last {
+                true => vec![hash],
+                false => vec![],
+            };
+            (vec![target.to_string()], removed)
+        }
+        false => (db.get_names_of_hash(&hash)?, vec![hash]),
+    };
+
+    loop {
+        let users = users(&db, &names, &removed)?;
+        let Some(user) = users.first() else {
+            break;
+        };
+        if opts.cascade {
+            for user in users {
+                names.extend(db.get_names_of_hash(&user)?);
+                removed.push(user);
+            }
+        } else if opts.force {
+            break;
+        } else {
+            let name = db
+                .get_name_of_hash(user)?
+                .unwrap_or_else(|| user.to_string());
+            bail!("cannot remove '{target}': it is used by '{name}'");
+        }
+    }
+
+    let verb = match opts.dry_run {
+        true => "would remove",
+        false => "removed",
+    };
+    // A name whose code object stays
+    let unnamed = (!removed.contains(&hash)).then_some(target);
+    if let Some(name) = unnamed {
+        println!("{verb} ${name}");
+    }
+    for hash in &removed {
+        let names = db.get_names_of_hash(hash)?;
+        if names.is_empty() {
+            println!("{verb} {hash}");
+        }
+        for name in names {
+            println!("{verb} {hash} ${name}");
+        }
+    }
+
+    if !opts.dry_run {
+        if let Some(name) = unnamed {
+            db.unname(name)?;
+        }
+        db.remove_code_objects(&removed)?;
+    }
+    Ok(removed)
 }
 
-pub fn disassemble_db(db_path: &str) -> Result<String> {
-    let dis = Database::open(db_path)?.disassemble()?;
+/// The stored code objects, other than `removed`, that load one of `removed` or
+/// load one of `names` by name
+fn users(db: &Database, names: &[String], removed: &[Hash]) -> Result<Vec<Hash>> {
+    let mut users = vec![];
+    for hash in db.get_hashes()? {
+        if removed.contains(&hash) {
+            continue;
+        }
+        let uses = db
+            .get_code_object(&hash)?
+            .code
+            .iter()
+            .any(|instr| match instr {
+                Instr::LoadDyn(name) => names.contains(name),
+                Instr::LoadFunc(hash) => removed.contains(hash),
+                _ => false,
+            });
+        if uses {
+            users.push(hash);
+        }
+    }
+    users.sort();
+    Ok(users)
+}
+
+/// Give a function of the code database at `db_path`, by name or hash prefix,
+/// another name
+pub fn alias(options: &Options, db_path: &str, name: &str, target: &str) -> Result<Hash> {
+    let db = open_database(options, db_path)?;
+    let hash = db.resolve(target)?;
+    db.create_alias(name, &hash)?;
+    println!("{hash} ${name}");
+    Ok(hash)
+}
+
+/// Print every name of a function of the code database at `db_path`, by name or
+/// hash prefix
+pub fn list_aliases(
+    options: &Options,
+    db_path: &str,
+    target: &str,
+) -> Result<Vec<String>> {
+    let db = open_database(options, db_path)?;
+    let hash = db.resolve(target)?;
+    let names = db.get_names_of_hash(&hash)?;
+    for name in &names {
+        println!("{hash} ${name}");
+    }
+    Ok(names)
+}
+
+/// Print the call graph of the code database at `db_path` in Graphviz dot. With
+/// a `root` function, only what it reaches within `depth` calls is drawn.
+pub fn graph(
+    options: &Options,
+    db_path: &str,
+    root: Option<&str>,
+    depth: Option<usize>,
+) -> Result<String> {
+    let db = open_database(options, db_path)?;
+    let g = solver::DepGraph::from_database(&db)?;
+    let root = root
+        .map(|name| {
+            g.node(name)
+                .ok_or_else(|| anyhow!("no function named '{name}' to graph from"))
+        })
+        .transpose()?;
+    if options.format == Format::Json {
+        let json = serde_json::to_string_pretty(&g.export_within(root, depth))?;
+        println!("{json}");
+        return Ok(json);
+    }
+    let dot = g.to_dot(root, depth);
+    print!("{dot}");
+    Ok(dot)
+}
+
+/// Print the difference between the function `old` of the code database at
+/// `db_path` and the function `new` of the one at `new_db_path`, each by name or
+/// hash prefix
+pub fn diff_functions(
+    options: &Options,
+    db_path: &str,
+    old: &str,
+    new_db_path: &str,
+    new: &str,
+) -> Result<diff::FunctionDiff> {
+    let old_db = open_database(options, db_path)?;
+    let new_db = open_database(options, new_db_path)?;
+    let (old_hash, new_hash) = (old_db.resolve(old)?, new_db.resolve(new)?);
+    let diff = diff::diff_functions(
+        &old_db.get_code_object(&old_hash)?,
+        &new_db.get_code_object(&new_hash)?,
+    );
+    println!("--- {old_hash}");
+    println!("+++ {new_hash}");
+    print!("{diff}");
+    Ok(diff)
+}
+
+/// Print the names added, removed, and changed from the code database at
+/// `db_path` to the one at `new_db_path`
+pub fn diff_databases(
+    options: &Options,
+    db_path: &str,
+    new_db_path: &str,
+) -> Result<NameDiff> {
+    let diff = open_database(options, db_path)?
+        .diff_names(&open_database(options, new_db_path)?)?;
+    for (name, hash) in &diff.added {
+        println!("+ ${name} {hash}");
+    }
+    for (name, hash) in &diff.removed {
+        println!("- ${name} {hash}");
+    }
+    for (name, old, new) in &diff.changed {
+        println!("~ ${name} {old} -> {new}");
+    }
+    Ok(diff)
+}
+
+/// Point an entry point of the code database at `db_path` at a function, by name
+/// or hash prefix
+pub fn set_entry_point(
+    options: &Options,
+    db_path: &str,
+    entry: &str,
+    target: &str,
+) -> Result<Hash> {
+    let hash = open_database(options, db_path)?.set_named_entry_point(entry, target)?;
+    println!("{hash} {entry}");
+    Ok(hash)
+}
+
+/// Import a pack file into the code database at `db_path`, creating it if needed
+pub fn import_pack(
+    options: &Options,
+    pack_file: &str,
+    db_path: &str,
+    on_conflict: NameConflict,
+) -> Result<Vec<(String, Hash)>> {
+    let db = if Path::new(db_path).exists() {
+        open_database(options, db_path)?
+    } else {
+        Database::new(db_path)?
+    };
+
+    let f = fs::File::open(pack_file)?;
+    let functions = db.import_pack(std::io::BufReader::new(f), on_conflict)?;
+    for (name, hash) in &functions {
+        println!("{hash} ${name}");
+    }
+    Ok(functions)
+}
+
+/// Serve the code database at `db_path` for `push` and `pull` until killed,
+/// accepting pushes only from clients with `token`
+pub fn serve(
+    options: &Options,
+    db_path: &str,
+    addr: &str,
+    token: Option<&str>,
+) -> Result<()> {
+    let db = open_database(options, db_path)?;
+    let listener = TcpListener::bind(addr)?;
+    let mode = match token {
+        Some(_) => "",
+        None => " read-only",
+    };
+    println!(
+        "serving {db_path}{mode} on http://{}",
+        listener.local_addr()?
+    );
+    sync::serve(&db, &listener, token)
+}
+
+/// Send the code objects and names in `db_path` that the server at `url` lacks
+pub fn push(
+    options: &Options,
+    db_path: &str,
+    url: &str,
+    token: &str,
+    force: bool,
+) -> Result<SyncReport> {
+    let report = sync::push(&open_database(options, db_path)?, url, token, force)?;
+    print_report(&report);
+    Ok(report)
+}
+
+/// Fetch the code objects and names from the server at `url` into `db_path`,
+/// creating it if needed
+pub fn pull(
+    options: &Options,
+    db_path: &str,
+    url: &str,
+    on_conflict: NameConflict,
+) -> Result<SyncReport> {
+    let db = if Path::new(db_path).exists() {
+        open_database(options, db_path)?
+    } else {
+        Database::new(db_path)?
+    };
+    let report = sync::pull(&db, url, on_conflict)?;
+    print_report(&report);
+    Ok(report)
+}
+
+fn print_report(report: &SyncReport) {
+    println!("{} code objects", report.objects);
+    for (name, hash) in &report.names {
+        println!("{hash} ${name}");
+    }
+}
+
+/// Check a code database for corruption, printing each problem found
+pub fn check_db(
+    options: &Options,
+    db_path: &str,
+    bytecode: bool,
+) -> Result<IntegrityReport> {
+    let report = open_database(options, db_path)?.verify(bytecode)?;
+    for (hash, e) in &report.undecodable {
+        println!("{hash}: cannot decode: {e}");
+    }
+    for (hash, actual) in &report.hash_mismatches {
+        println!("{hash}: stored code object has hash {actual}");
+    }
+    for (name, hash) in &report.dangling_names {
+        println!("${name}: points to missing code object {hash}");
+    }
+    for (hash, e) in &report.unverified {
+        println!("{hash}: {e}");
+    }
+    println!(
+        "checked {} code objects: {}",
+        report.objects,
+        match report.is_ok() {
+            true => "ok",
+            false => "corrupt",
+        }
+    );
+    Ok(report)
+}
+
+/// Run the bytecode verifier and link checks on every function of a bytecode
+/// assembly file or code database, printing whether each passed and why not
+pub fn verify(options: &Options, path: &str) -> Result<Vec<solver::FunctionCheck>> {
+    let checks = match Path::new(path).extension().is_some_and(|ext| ext == "asm") {
+        true => solver::check_parses(&parser::Parser::parse_file(path)?),
+        false => solver::check_database(&open_database(options, path)?)?,
+    };
+    if options.format == Format::Json {
+        print_json(&checks_json(&checks))?;
+        return Ok(checks);
+    }
+    let failed = checks.iter().filter(|check| !check.is_ok()).count();
+    for check in &checks {
+        match check.is_ok() {
+            true => println!("ok   ${}", check.name),
+            false => println!("FAIL ${}", check.name),
+        }
+        for problem in &check.problems {
+            println!("    {problem}");
+        }
+    }
+    println!("verified {} functions: {failed} failed", checks.len());
+    Ok(checks)
+}
+
+/// What `verify` prints as JSON: each check, and how many failed
+fn checks_json(checks: &[solver::FunctionCheck]) -> Json {
+    let functions = checks
+        .iter()
+        .map(|check| {
+            json!({
+                "name": check.name,
+                "ok": check.is_ok(),
+                "problems": check.problems,
+            })
+        })
+        .collect::<Vec<_>>();
+    let failed = checks.iter().filter(|check| !check.is_ok()).count();
+    json!({ "functions": functions, "failed": failed })
+}
+
+pub fn disassemble_db(
+    options: &Options,
+    db_path: &str,
+    opts: DisOptions,
+) -> Result<String> {
+    let dis = open_database(options, db_path)?.disassemble_with(opts)?;
     print!("{dis}");
     Ok(dis)
 }
 
+/// Print a JSON description of every function in a code database
+pub fn disassemble_db_json(options: &Options, db_path: &str) -> Result<String> {
+    let json = serde_json::to_string_pretty(
+        &open_database(options, db_path)?.disassemble_json()?,
+    )?;
+    println!("{json}");
+    Ok(json)
+}
+
+/// Format a bytecode assembly file, printing the result or rewriting the file
+pub fn format_file(file: &str, write: bool) -> Result<String> {
+    let formatted = fmt::format_file(file)?;
+    if write {
+        fs::write(file, &formatted)?;
+    } else {
+        print!("{formatted}");
+    }
+    Ok(formatted)
+}
+
 // TODO: support run flag
-pub fn roundtrip_file(file: &str, _run: bool) -> Result<()> {
+pub fn roundtrip_file(options: &Options, file: &str, _run: bool) -> Result<()> {
     let tmp = tempfile::tempdir()?;
     let db_file = tmp.path().join("test.db").display().to_string();
     let dis_file = tmp.path().join("dis.asm").display().to_string();
 
     // Run the original file
-    let ret_val = run_scratch_file(file, Some(&db_file))?;
+    let ret_val = run_scratch_file(options, file, Some(&db_file))?;
 
-    // Disassemble the db and write the disassembled contents to a file
-    let dis = disassemble_db(&db_file)?;
-    let mut f = fs::File::create(&dis_file)?;
-    f.write_all(dis.as_bytes())?;
+    let named = DisOptions {
+        refs: FuncRefs::Named,
+        stack: true,
+    };
+    for opts in [DisOptions::default(), named] {
+        // Disassemble the db and write the disassembled contents to a file
+        let dis = disassemble_db(options, &db_file, opts)?;
+        let mut f = fs::File::create(&dis_file)?;
+        f.write_all(dis.as_bytes())?;
 
-    // Run the dis file
-    let ret_val_dis = run_scratch_file(&dis_file, None)?;
-    assert_eq!(ret_val, ret_val_dis);
+        // Run the dis file
+        let ret_val_dis = run_scratch_file(options, &dis_file, None)?;
+        assert_eq!(ret_val, ret_val_dis);
+    }
 
     Ok(())
 }
@@ -68,7 +810,8 @@ mod integration_test {
 
     macro_rules! run {
         ($file:expr) => {
-            run_scratch_file($file, None).expect(&format!("ERROR {}", $file))
+            run_scratch_file(&Options::default(), $file, None)
+                .expect(&format!("ERROR {}", $file))
         };
     }
 
@@ -86,6 +829,407 @@ mod integration_test {
         assert_eq!(run!("examples/main.asm"), 1);
         assert_eq!(run!("examples/array_2d.asm"), 6);
         assert_eq!(run!("examples/array_map.asm"), 90);
+        assert_eq!(run!("examples/map.asm"), 5);
+        assert_eq!(run!("examples/typed.asm"), 7);
+        assert_eq!(run!("examples/relative.asm"), 55);
+        assert_eq!(run!("examples/negative.asm"), 7);
+        assert_eq!(run!("examples/cont_lit.asm"), 6);
+        assert_eq!(run!("examples/named.asm"), 55);
+        assert_eq!(run!("examples/macros.asm"), 3);
+        assert_eq!(run!("examples/consts.asm"), 12);
+        assert_eq!(run!("examples/modules.asm"), 25);
+        assert_eq!(run!("examples/data.asm"), 10);
+        assert_eq!(run!("examples/dbg.asm"), 5);
+    }
+
+    #[test]
+    fn test_extern() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let file = tmp.path().join("extern.asm").display().to_string();
+        std::fs::write(
+            &file,
+            [
+                ".extern fib",
+                "$main 0:",
+                "    .lit 10",
+                "    load_lit 0",
+                "    load_dyn $fib",
+                "    call",
+                "    ret_val",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        // fib is only found once it is in the database
+        let db = Database::new(&db_file).unwrap();
+        assert!(run_scratch_file(&Options::default(), &file, Some(&db_file)).is_err());
+        let fib = parser::Parser::parse_file("examples/fib.asm")
+            .unwrap()
+            .remove(0);
+        db.insert_code_object_with_name(&fib.code_obj, "fib")
+            .unwrap();
+        assert_eq!(
+            run_scratch_file(&Options::default(), &file, Some(&db_file)).unwrap(),
+            55
+        );
+
+        // Without a database, there is nothing to resolve against
+        assert!(run_scratch_file(&Options::default(), &file, None).is_err());
+    }
+
+    #[test]
+    fn test_assemble_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let lib = tmp.path().join("lib.asm").display().to_string();
+        let file = tmp.path().join("main.asm").display().to_string();
+        std::fs::write(
+            &lib,
+            "$double 1:\n    load_arg 0\n    dup\n    add\n    ret_val\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &file,
+            ".extern double\n$main 0:\n    .lit 21\n    load_lit 0\n    load_dyn $double\n    call\n    ret_val\n",
+        )
+        .unwrap();
+
+        // The library is built up without a main function, then used by one
+        let functions = assemble_file(&Options::default(), &lib, &db_file).unwrap();
+        assert_eq!(functions[0].0, "double");
+        assemble_file(&Options::default(), &file, &db_file).unwrap();
+        assert_eq!(Vm::open(&db_file).unwrap().run_main_function().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let file = tmp.path().join("main.asm").display().to_string();
+        std::fs::write(
+            &file,
+            "$main 0:\n    .lit 1\n    load_lit 0\n    ret_val\n$start 0:\n    .lit 2\n    load_lit 0\n    ret_val\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            run_scratch_file(&Options::default(), &file, Some(&db_file)).unwrap(),
+            1
+        );
+        assert!(
+            run_file_entry(&Options::default(), &file, Some(&db_file), "other").is_err()
+        );
+        set_entry_point(&Options::default(), &db_file, "other", "start").unwrap();
+        assert_eq!(
+            run_file_entry(&Options::default(), &file, Some(&db_file), "other").unwrap(),
+            2
+        );
+        set_entry_point(&Options::default(), &db_file, DEFAULT_ENTRY_POINT, "start")
+            .unwrap();
+
+        // The file's own main comes first, then the stored entry point
+        assert_eq!(
+            run_scratch_file(&Options::default(), &file, Some(&db_file)).unwrap(),
+            1
+        );
+        let lib = tmp.path().join("lib.asm").display().to_string();
+        std::fs::write(&lib, "$three 0:\n    .lit 3\n    load_lit 0\n    ret_val\n")
+            .unwrap();
+        assert_eq!(
+            run_scratch_file(&Options::default(), &lib, Some(&db_file)).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_run_function() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("main.asm").display().to_string();
+        std::fs::write(
+            &file,
+            "$main 0:\n    .lit 1\n    load_lit 0\n    ret_val\n$pick 3:\n    load_arg 0\n    jmp_t yes\n    load_arg 2\n    ret_val\nyes:\n    load_arg 1\n    ret_val\n",
+        )
+        .unwrap();
+        let run = |args: &[&str]| {
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            run_file_function(&Options::default(), &file, None, "pick", &args)
+        };
+
+        assert_eq!(run(&["true", "1.5", "2"]).unwrap(), Some(Value::F64(1.5)));
+        assert_eq!(
+            run(&["false", "1", "\"a b\""]).unwrap(),
+            Some(Value::string("a b"))
+        );
+        assert_eq!(
+            run(&["false", "1", "word"]).unwrap(),
+            Some(Value::string("word"))
+        );
+        assert!(run(&["true", "1"]).is_err());
+        assert_eq!(
+            run_file_function(&Options::default(), &file, None, DEFAULT_ENTRY_POINT, &[])
+                .unwrap(),
+            Some(Value::int(1))
+        );
+    }
+
+    #[test]
+    fn test_list_functions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        assemble_file(&Options::default(), "examples/modules.asm", &db_file).unwrap();
+
+        let all =
+            list_functions(&Options::default(), &db_file, &FunctionFilter::default())
+                .unwrap();
+        assert!(all.iter().any(|entry| entry.name == "main"));
+        let filter = FunctionFilter {
+            prefix: Some("main".to_string()),
+            ..Default::default()
+        };
+        let main = list_functions(&Options::default(), &db_file, &filter).unwrap();
+        assert_eq!(main.len(), 1);
+        assert_eq!(
+            main[0].hash,
+            Vm::open(&db_file).unwrap().db.get_main_object().unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let functions =
+            assemble_file(&Options::default(), "examples/modules.asm", &db_file).unwrap();
+        let hash = |name: &str| functions.iter().find(|(n, _)| n == name).unwrap().1;
+        let square = hash("math::square");
+        let rm = |target: &str, opts: RemoveOptions| {
+            remove(&Options::default(), &db_file, target, opts)
+        };
+        let cascade = RemoveOptions {
+            cascade: true,
+            ..Default::default()
+        };
+
+        // Used by others, so refused, and a dry run changes nothing
+        let err = rm(&square.abbrev(), RemoveOptions::default()).unwrap_err();
+        assert!(
+            err.to_string().contains("used by 'math::sum_squares'"),
+            "{err}"
+        );
+        let dry_run = RemoveOptions {
+            dry_run: true,
+            ..cascade
+        };
+        let removed = rm(&square.abbrev(), dry_run).unwrap();
+        assert_eq!(removed.len(), 3);
+        assert_eq!(removed[0], square);
+        let db = Database::open(&db_file).unwrap();
+        assert_eq!(db.get_functions().unwrap().len(), 3);
+
+        // A name goes alone, even its last one, unless the object is asked for
+        db.create_alias("sq", &square).unwrap();
+        assert!(rm("sq", RemoveOptions::default()).unwrap().is_empty());
+        assert!(rm("math::square", RemoveOptions::default())
+            .unwrap()
+            .is_empty());
+        assert!(db.has_code_object(&square).unwrap());
+        db.create_alias("math::square", &square).unwrap();
+        let object = RemoveOptions {
+            object: true,
+            ..Default::default()
+        };
+        assert!(rm("math::square", object).is_err());
+        let object = RemoveOptions {
+            force: true,
+            ..object
+        };
+        assert_eq!(rm("math::square", object).unwrap(), [square]);
+        assert!(!db.has_code_object(&square).unwrap());
+
+        // Callers by name count as users too, and force leaves them in place
+        let late = parser::Parser::parse_str(
+            "$late 1:\n    load_arg 0\n    load_dyn $main\n    call\n    ret_val\n",
+        )
+        .unwrap()
+        .remove(0)
+        .code_obj;
+        let late = db.insert_code_object_with_name(&late, "late").unwrap();
+        let err = rm("main", RemoveOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("used by 'late'"), "{err}");
+        let force = RemoveOptions {
+            force: true,
+            ..Default::default()
+        };
+        assert!(rm("main", force).unwrap().is_empty());
+        assert!(db.get_code_object_by_name("late").is_ok());
+
+        // Cascading takes the callers by name with it
+        db.create_alias("main", &hash("main")).unwrap();
+        let removed = rm(&hash("main").abbrev(), cascade).unwrap();
+        assert_eq!(removed, [hash("main"), late]);
+        assert_eq!(db.get_functions().unwrap().len(), 1);
+        assert!(rm("main", RemoveOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_alias() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let functions =
+            assemble_file(&Options::default(), "examples/modules.asm", &db_file).unwrap();
+        let square = functions
+            .iter()
+            .find(|(name, _)| name == "math::square")
+            .unwrap()
+            .1;
+
+        assert_eq!(
+            alias(&Options::default(), &db_file, "sq", "math::square").unwrap(),
+            square
+        );
+        assert_eq!(
+            alias(&Options::default(), &db_file, "sq2", &square.abbrev()).unwrap(),
+            square
+        );
+        assert_eq!(
+            list_aliases(&Options::default(), &db_file, "sq").unwrap(),
+            ["math::square", "sq", "sq2"]
+        );
+
+        // Names that are taken or invalid, and missing targets
+        assert!(alias(&Options::default(), &db_file, "sq", "main").is_err());
+        assert!(alias(&Options::default(), &db_file, "not a name", "main").is_err());
+        assert!(alias(&Options::default(), &db_file, "other", "missing").is_err());
+        assert!(list_aliases(&Options::default(), &db_file, "missing").is_err());
+    }
+
+    #[test]
+    fn test_graph() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        assemble_file(&Options::default(), "examples/fib.asm", &db_file).unwrap();
+
+        let dot = graph(&Options::default(), &db_file, None, None).unwrap();
+        assert!(dot.contains("\"fib\" -> \"fib\";"));
+        assert!(dot.contains("\"main\" -> \"fib\";"));
+        let dot = graph(&Options::default(), &db_file, Some("main"), Some(0)).unwrap();
+        assert!(!dot.contains("->"));
+        assert!(graph(&Options::default(), &db_file, Some("missing"), None).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+        let file = tmp.path().join("main.asm").display().to_string();
+        std::fs::write(
+            &file,
+            "$main 0:\n    load_dyn $missing\n    call\n    ret_val\n",
+        )
+        .unwrap();
+
+        let checks = verify(&Options::default(), &file).unwrap();
+        assert_eq!(checks.len(), 1);
+        assert!(!checks[0].is_ok());
+
+        assemble_file(&Options::default(), "examples/fib.asm", &db_file).unwrap();
+        assert!(verify(&Options::default(), &db_file)
+            .unwrap()
+            .iter()
+            .all(|check| check.is_ok()));
+        assert!(verify(&Options::default(), "examples/fib.asm")
+            .unwrap()
+            .iter()
+            .all(|check| check.is_ok()));
+    }
+
+    #[test]
+    fn test_trace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("trace.txt").display().to_string();
+        let opts = TraceOptions {
+            function: Some("main".to_string()),
+            limit: None,
+        };
+        assert_eq!(
+            trace(
+                &Options::default(),
+                "examples/call.asm",
+                DEFAULT_ENTRY_POINT,
+                opts,
+                Some(&out)
+            )
+            .unwrap(),
+            7
+        );
+        let traced = std::fs::read_to_string(&out).unwrap();
+        assert!(traced.lines().count() > 0);
+        assert!(traced.lines().all(|line| line.starts_with("[$main @ ")));
+    }
+
+    #[test]
+    fn test_profile() {
+        let tmp = tempfile::tempdir().unwrap();
+        let folded = tmp.path().join("fib.folded").display().to_string();
+        let (code, profile) = profile(
+            &Options::default(),
+            "examples/fib.asm",
+            DEFAULT_ENTRY_POINT,
+            &folded,
+        )
+        .unwrap();
+        assert_eq!(code, 6765);
+        assert_eq!(profile.profiles.len(), 2);
+
+        let stacks = std::fs::read_to_string(&folded).unwrap();
+        assert!(stacks.lines().all(|line| line.starts_with("main")));
+        assert!(stacks.contains("main;fib;fib "));
+    }
+
+    #[test]
+    fn test_diff() {
+        let tmp = tempfile::tempdir().unwrap();
+        let old_db = tmp.path().join("old.db").display().to_string();
+        let new_db = tmp.path().join("new.db").display().to_string();
+        assemble_file(&Options::default(), "examples/modules.asm", &old_db).unwrap();
+        assemble_file(&Options::default(), "examples/modules.asm", &new_db).unwrap();
+        assert!(diff_databases(&Options::default(), &old_db, &new_db)
+            .unwrap()
+            .is_empty());
+        assert!(
+            diff_functions(&Options::default(), &old_db, "main", &new_db, "main")
+                .unwrap()
+                .is_empty()
+        );
+
+        // Another function under an existing name, and a new name
+        let file = tmp.path().join("main.asm").display().to_string();
+        std::fs::write(&file, "$main 0:\n    .lit 2\n    load_lit 0\n    ret_val\n")
+            .unwrap();
+        assemble_file(&Options::default(), &file, &new_db).unwrap();
+        alias(&Options::default(), &new_db, "start", "main").unwrap();
+        let diff = diff_databases(&Options::default(), &old_db, &new_db).unwrap();
+        assert_eq!(diff.added[0].0, "start");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].0, "main");
+
+        let diff = diff_functions(&Options::default(), &old_db, "main", &new_db, "main")
+            .unwrap();
+        assert!(!diff.is_empty());
+        assert!(
+            diff_functions(&Options::default(), &old_db, "missing", &new_db, "main")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_efb() {
+        let tmp = tempfile::tempdir().unwrap();
+        let efb_file = tmp.path().join("fib.efb").display().to_string();
+        emit_efb("examples/fib.asm", &efb_file).unwrap();
+        assert_eq!(run!(&efb_file), 6765);
     }
 
     #[test]
@@ -96,7 +1240,52 @@ mod integration_test {
             .collect::<Result<Vec<_>, std::io::Error>>()
             .unwrap()
             .into_iter()
-            .try_for_each(|ref f| roundtrip_file(f, true))
+            .try_for_each(|ref f| roundtrip_file(&Options::default(), f, true))
             .unwrap();
     }
+
+    #[test]
+    fn test_json() {
+        let options = Options {
+            format: Format::Json,
+            ..Options::default()
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let db_file = tmp.path().join("test.db").display().to_string();
+
+        // dbg output goes to stderr, so the run's JSON is all of stdout
+        assert_eq!(
+            run_file_entry(&options, "examples/dbg.asm", None, "main").unwrap(),
+            5
+        );
+        let value =
+            run_file_function(&options, "examples/modules.asm", None, "main", &[])
+                .unwrap()
+                .unwrap();
+        let json = run_json("value", json::to_json(&value).unwrap(), Instant::now());
+        assert_eq!(json["value"], 25);
+        assert!(json["seconds"].is_f64());
+
+        assemble_file(&options, "examples/call.asm", &db_file).unwrap();
+        let entries =
+            list_functions(&options, &db_file, &FunctionFilter::default()).unwrap();
+        let db = Database::open(&db_file).unwrap();
+        let json = entries_json(&db, &entries).unwrap();
+        let functions = json.as_array().unwrap();
+        assert_eq!(functions.len(), entries.len());
+        assert_eq!(functions[0]["name"], entries[0].name);
+        assert_eq!(functions[0]["hash"], entries[0].hash.to_string());
+        assert!(functions[0]["argcount"].is_u64());
+
+        let checks = verify(&options, &db_file).unwrap();
+        let json = checks_json(&checks);
+        assert_eq!(json["failed"], 0);
+        assert_eq!(json["functions"].as_array().unwrap().len(), checks.len());
+
+        let graph: Json =
+            serde_json::from_str(&graph(&options, &db_file, None, None).unwrap())
+                .unwrap();
+        assert!(graph["nodes"].is_array());
+        assert!(graph["edges"].is_array());
+    }
 }
@@ -0,0 +1,14 @@
+use anyhow::Result;
+use clap::Parser;
+
+#[derive(Parser)]
+/// Language server for efa bytecode assembly, over stdin and stdout
+struct Args {
+    /// A code database to look up functions in
+    db_path: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    efa_core::lsp::run(args.db_path.as_deref())
+}
@@ -1,25 +1,293 @@
+use std::path::Path;
+
 use anyhow::Result;
 use clap::{ArgAction, Parser, Subcommand};
 
+use efa_core::asm::dis::{DisOptions, FuncRefs};
 use efa_core::cli::commands as cli;
+use efa_core::db::{FunctionFilter, FunctionOrder, NameConflict, DEFAULT_ENTRY_POINT};
+use efa_core::vm::TraceOptions;
 
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
     cmd: Command,
+
+    /// Fail instead of upgrading databases made by older versions
+    #[clap(long, global = true)]
+    no_migrate: bool,
+
+    /// Print the results of `run`, `ls`, `verify`, and `graph` as text or JSON
+    #[clap(long, global = true, default_value = "text", value_parser = ["text", "json"])]
+    format: String,
 }
 
 #[derive(Debug, Subcommand)]
 // #[command(version, about, long_about = None)]
 enum Command {
-    /// Run a bytecode assembly file
+    /// Run a bytecode assembly file or .efb object file
     Run {
         input_file: String,
         db_path: Option<String>,
+
+        /// Entry point of the database, or function, to start at
+        #[clap(long, default_value = DEFAULT_ENTRY_POINT)]
+        entry: String,
+
+        /// Arguments to pass to the entry, after `--`, e.g. `3`, `1.5`, `true` or
+        /// `"hi"`. Its return value is printed instead of used as the exit code.
+        #[clap(last = true)]
+        args: Vec<String>,
     },
 
     /// Disassemble a code database
-    Dis { db_path: String },
+    Dis {
+        db_path: String,
+
+        /// Write calls to named functions as `load_dyn` instead of by hash
+        #[clap(long, short)]
+        names: bool,
+
+        /// Comment each instruction with the stack before it
+        #[clap(long, short)]
+        stack: bool,
+
+        /// Print a JSON description of each function instead of assembly
+        #[clap(long, conflicts_with_all = ["names", "stack"])]
+        json: bool,
+    },
+
+    /// List the named functions in a code database
+    Ls {
+        db_path: String,
+
+        /// Order to list them in
+        #[clap(long, default_value = "name", value_parser = ["name", "oldest", "newest"])]
+        sort: String,
+
+        /// Only list names that start with this, e.g. `math::`
+        #[clap(long)]
+        prefix: Option<String>,
+    },
+
+    /// Remove a function from a code database
+    Rm {
+        db_path: String,
+
+        /// Name or hash prefix (starting with 0x) of the function
+        target: String,
+
+        /// Also remove the code object of a name that has no other names
+        #[clap(long)]
+        object: bool,
+
+        /// Remove it even if other functions load it
+        #[clap(long, short)]
+        force: bool,
+
+        /// Also remove the functions that load it, and so on
+        #[clap(long)]
+        cascade: bool,
+
+        /// Print what would be removed without removing it
+        #[clap(long, short = 'n')]
+        dry_run: bool,
+    },
+
+    /// Give a function of a code database another name, or list its names
+    Alias {
+        db_path: String,
+
+        /// New name for the function
+        #[clap(required_unless_present = "list")]
+        name: Option<String>,
+
+        /// Name or hash prefix (starting with 0x) of the function
+        #[clap(required_unless_present = "list")]
+        target: Option<String>,
+
+        /// List the names of this function, by name or hash prefix, instead
+        #[clap(long, conflicts_with_all = ["name", "target"])]
+        list: Option<String>,
+    },
+
+    /// Print the call graph of a code database in Graphviz dot
+    Graph {
+        db_path: String,
+
+        /// Only draw the functions this one reaches
+        #[clap(long)]
+        root: Option<String>,
+
+        /// Only draw functions at most this many calls from the root
+        #[clap(long, requires = "root")]
+        depth: Option<usize>,
+    },
+
+    /// Assemble a bytecode assembly file into a code database without running it
+    Asm { input_file: String, db_path: String },
+
+    /// Assemble a bytecode assembly file into a binary .efb object file
+    Emit {
+        input_file: String,
+        output_file: String,
+    },
+
+    /// Write functions from a code database, and everything they call, to a pack
+    Export {
+        db_path: String,
+        output_file: String,
+
+        /// Names or hash prefixes (starting with 0x) of the functions to export
+        #[clap(required = true)]
+        names: Vec<String>,
+    },
+
+    /// Point an entry point of a code database at a function, to run with
+    /// `run --entry`
+    Entry {
+        db_path: String,
+
+        /// Name or hash prefix (starting with 0x) of the function
+        target: String,
+
+        /// Entry point to set
+        #[clap(long, default_value = DEFAULT_ENTRY_POINT)]
+        name: String,
+    },
+
+    /// Import a pack into a code database
+    Import {
+        pack_file: String,
+        db_path: String,
+
+        /// Keep existing functions whose names are taken by the pack
+        #[clap(long, conflicts_with = "replace")]
+        keep: bool,
+
+        /// Replace existing functions whose names are taken by the pack
+        #[clap(long)]
+        replace: bool,
+    },
+
+    /// Serve a code database for other databases to push to and pull from
+    Serve {
+        db_path: String,
+
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:7070")]
+        addr: String,
+
+        /// Accept pushes from clients with this token. Without one, the database
+        /// is served read-only.
+        #[clap(long)]
+        token: Option<String>,
+    },
+
+    /// Send the functions a served database lacks, e.g. to http://host:7070
+    Push {
+        db_path: String,
+        url: String,
+
+        /// The token the server was started with
+        #[clap(long)]
+        token: String,
+
+        /// Replace functions on the server whose names are taken
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Fetch the functions a code database lacks from a served database
+    Pull {
+        db_path: String,
+        url: String,
+
+        /// Keep existing functions whose names are taken on the server
+        #[clap(long, conflicts_with = "replace")]
+        keep: bool,
+
+        /// Replace existing functions whose names are taken on the server
+        #[clap(long)]
+        replace: bool,
+    },
+
+    /// Check a code database for corruption, exiting with 1 if any is found
+    Fsck {
+        db_path: String,
+
+        /// Also run the bytecode verifier on every code object
+        #[clap(long)]
+        bytecode: bool,
+    },
+
+    /// Verify every function of a bytecode assembly file or code database, exiting
+    /// with 1 if any is invalid
+    Verify { path: String },
+
+    /// Run a bytecode assembly file or code database, writing each instruction that
+    /// runs to stderr
+    Trace {
+        path: String,
+
+        /// Entry point of the database to start at
+        #[clap(long, default_value = DEFAULT_ENTRY_POINT)]
+        entry: String,
+
+        /// Stop writing after this many instructions
+        #[clap(long)]
+        limit: Option<usize>,
+
+        /// Only write the instructions of this function
+        #[clap(long)]
+        filter: Option<String>,
+
+        /// Write to this file instead of stderr
+        #[clap(long, short)]
+        output: Option<String>,
+    },
+
+    /// Run a bytecode assembly file or code database while profiling it, printing
+    /// the time spent in each function
+    Profile {
+        path: String,
+
+        /// Entry point of the database to start at
+        #[clap(long, default_value = DEFAULT_ENTRY_POINT)]
+        entry: String,
+
+        /// Where to write the stacks that ran, in the folded format of flame graph
+        /// tools. Defaults to the path with a .folded extension.
+        #[clap(long)]
+        folded: Option<String>,
+    },
+
+    /// Compare two functions, by instruction and literal, or the names of two code
+    /// databases
+    Diff {
+        db_path: String,
+
+        /// Name or hash prefix (starting with 0x) of the old function. Without one,
+        /// the names of the databases are compared.
+        old: Option<String>,
+
+        /// Name or hash prefix of the new function. Defaults to the old one.
+        new: Option<String>,
+
+        /// Code database of the new function, or the new database to compare names
+        /// with. Defaults to the first one.
+        #[clap(long, required_unless_present = "old")]
+        against: Option<String>,
+    },
+
+    /// Format a bytecode assembly file in the canonical style
+    Fmt {
+        input_file: String,
+
+        /// Rewrite the file instead of printing it
+        #[clap(long, short)]
+        write: bool,
+    },
 
     /// Roundtrip a bytecode assembly file
     Rt {
@@ -31,24 +299,285 @@ enum Command {
     },
 }
 
+fn conflict_policy(keep: bool, replace: bool) -> NameConflict {
+    match (keep, replace) {
+        (true, _) => NameConflict::Keep,
+        (_, true) => NameConflict::Replace,
+        _ => NameConflict::Fail,
+    }
+}
+
+/// The error of a failed `run`. Without JSON output, it panics with the file name
+/// instead.
+fn run_failed(
+    options: &cli::Options,
+    input_file: &str,
+    e: anyhow::Error,
+) -> anyhow::Error {
+    match options.format {
+        cli::Format::Json => e,
+        cli::Format::Text => panic!("ERROR {}\n{}", input_file, e),
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
+    let options = cli::Options {
+        migrate: !args.no_migrate,
+        format: match args.format.as_str() {
+            "json" => cli::Format::Json,
+            _ => cli::Format::Text,
+        },
+    };
 
-    let code = match args.cmd {
+    let code = match run(args.cmd, options) {
+        Ok(code) => code,
+        Err(e) if options.format == cli::Format::Json => {
+            cli::print_error_json(&e)?;
+            1
+        }
+        Err(e) => return Err(e),
+    };
+    std::process::exit(code)
+}
+
+fn run(cmd: Command, options: cli::Options) -> Result<i32> {
+    let code = match cmd {
+        Command::Run {
+            input_file,
+            db_path,
+            entry,
+            args,
+        } if !args.is_empty() => {
+            cli::run_file_function(
+                &options,
+                &input_file,
+                db_path.as_deref(),
+                &entry,
+                &args,
+            )
+            .map_err(|e| run_failed(&options, &input_file, e))?;
+            0
+        }
         Command::Run {
             input_file,
             db_path,
-        } => cli::run_scratch_file(&input_file, db_path.as_deref())
-            .unwrap_or_else(|e| panic!("ERROR {}\n{}", input_file, e)),
-        Command::Dis { db_path } => {
-            cli::disassemble_db(&db_path)?;
+            entry,
+            ..
+        } => cli::run_file_entry(&options, &input_file, db_path.as_deref(), &entry)
+            .map_err(|e| run_failed(&options, &input_file, e))?,
+        Command::Dis {
+            db_path,
+            names,
+            stack,
+            json,
+        } => {
+            let refs = match names {
+                true => FuncRefs::Named,
+                false => FuncRefs::Annotated,
+            };
+            match json {
+                true => cli::disassemble_db_json(&options, &db_path)?,
+                false => {
+                    cli::disassemble_db(&options, &db_path, DisOptions { refs, stack })?
+                }
+            };
+            0
+        }
+        Command::Ls {
+            db_path,
+            sort,
+            prefix,
+        } => {
+            let order = match sort.as_str() {
+                "oldest" => FunctionOrder::Oldest,
+                "newest" => FunctionOrder::Newest,
+                _ => FunctionOrder::Name,
+            };
+            let filter = FunctionFilter {
+                prefix,
+                order,
+                ..Default::default()
+            };
+            cli::list_functions(&options, &db_path, &filter)?;
+            0
+        }
+        Command::Rm {
+            db_path,
+            target,
+            object,
+            force,
+            cascade,
+            dry_run,
+        } => {
+            let opts = cli::RemoveOptions {
+                object,
+                force,
+                cascade,
+                dry_run,
+            };
+            cli::remove(&options, &db_path, &target, opts)?;
+            0
+        }
+        Command::Alias {
+            db_path,
+            name,
+            target,
+            list,
+        } => {
+            match (list, name, target) {
+                (Some(target), _, _) => {
+                    cli::list_aliases(&options, &db_path, &target)?;
+                }
+                (None, Some(name), Some(target)) => {
+                    cli::alias(&options, &db_path, &name, &target)?;
+                }
+                _ => unreachable!("clap requires a name and target without --list"),
+            }
+            0
+        }
+        Command::Graph {
+            db_path,
+            root,
+            depth,
+        } => {
+            cli::graph(&options, &db_path, root.as_deref(), depth)?;
+            0
+        }
+        Command::Asm {
+            input_file,
+            db_path,
+        } => {
+            cli::assemble_file(&options, &input_file, &db_path)?;
+            0
+        }
+        Command::Emit {
+            input_file,
+            output_file,
+        } => {
+            cli::emit_efb(&input_file, &output_file)?;
+            0
+        }
+        Command::Export {
+            db_path,
+            output_file,
+            names,
+        } => {
+            cli::export_pack(&options, &db_path, &output_file, &names)?;
+            0
+        }
+        Command::Entry {
+            db_path,
+            target,
+            name,
+        } => {
+            cli::set_entry_point(&options, &db_path, &name, &target)?;
+            0
+        }
+        Command::Import {
+            pack_file,
+            db_path,
+            keep,
+            replace,
+        } => {
+            cli::import_pack(
+                &options,
+                &pack_file,
+                &db_path,
+                conflict_policy(keep, replace),
+            )?;
+            0
+        }
+        Command::Serve {
+            db_path,
+            addr,
+            token,
+        } => {
+            cli::serve(&options, &db_path, &addr, token.as_deref())?;
+            0
+        }
+        Command::Push {
+            db_path,
+            url,
+            token,
+            force,
+        } => {
+            cli::push(&options, &db_path, &url, &token, force)?;
+            0
+        }
+        Command::Pull {
+            db_path,
+            url,
+            keep,
+            replace,
+        } => {
+            cli::pull(&options, &db_path, &url, conflict_policy(keep, replace))?;
+            0
+        }
+        Command::Fsck { db_path, bytecode } => {
+            match cli::check_db(&options, &db_path, bytecode)?.is_ok() {
+                true => 0,
+                false => 1,
+            }
+        }
+        Command::Verify { path } => {
+            match cli::verify(&options, &path)?.iter().all(|c| c.is_ok()) {
+                true => 0,
+                false => 1,
+            }
+        }
+        Command::Trace {
+            path,
+            entry,
+            limit,
+            filter,
+            output,
+        } => {
+            let opts = TraceOptions {
+                function: filter,
+                limit,
+            };
+            cli::trace(&options, &path, &entry, opts, output.as_deref())?
+        }
+        Command::Profile {
+            path,
+            entry,
+            folded,
+        } => {
+            let folded = folded.unwrap_or_else(|| {
+                Path::new(&path)
+                    .with_extension("folded")
+                    .display()
+                    .to_string()
+            });
+            cli::profile(&options, &path, &entry, &folded)?.0
+        }
+        Command::Diff {
+            db_path,
+            old,
+            new,
+            against,
+        } => {
+            let new_db_path = against.unwrap_or_else(|| db_path.clone());
+            match old {
+                Some(old) => {
+                    let new = new.as_deref().unwrap_or(&old);
+                    cli::diff_functions(&options, &db_path, &old, &new_db_path, new)?;
+                }
+                None => {
+                    cli::diff_databases(&options, &db_path, &new_db_path)?;
+                }
+            }
+            0
+        }
+        Command::Fmt { input_file, write } => {
+            cli::format_file(&input_file, write)?;
             0
         }
         Command::Rt { input_file, run } => {
-            cli::roundtrip_file(&input_file, run)?;
+            cli::roundtrip_file(&options, &input_file, run)?;
             0
         }
     };
-
-    std::process::exit(code)
+    Ok(code)
 }
@@ -0,0 +1,106 @@
+//! A cache of decoded code objects, so that functions that are called often are
+//! not queried and decoded on every call. Code objects are content-addressed, so
+//! a cached object is never out of date, only possibly removed since.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::vm::CodeObject;
+use crate::Hash;
+
+/// Default for `Database::set_cache_capacity`
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// How well the code object cache is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Code objects in the cache
+    pub len: usize,
+    pub capacity: usize,
+}
+
+/// Least-recently-used cache of code objects by hash
+#[derive(Debug)]
+pub(super) struct CodeCache {
+    /// Each object, with when it was last used
+    entries: HashMap<Hash, (CodeObject, u64)>,
+    /// Hashes by when they were last used
+    order: BTreeMap<u64, Hash>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl CodeCache {
+    pub fn new(capacity: usize) -> CodeCache {
+        CodeCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A copy of the cached object, counting a hit or a miss
+    pub fn get(&mut self, hash: &Hash) -> Option<CodeObject> {
+        self.clock += 1;
+        let Some((obj, used)) = self.entries.get_mut(hash) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.order.remove(used);
+        *used = self.clock;
+        self.order.insert(self.clock, *hash);
+        self.stats.hits += 1;
+        Some(obj.clone())
+    }
+
+    pub fn insert(&mut self, hash: Hash, obj: CodeObject) {
+        if self.stats.capacity == 0 {
+            return;
+        }
+        self.remove(&hash);
+        while self.entries.len() >= self.stats.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.entries.insert(hash, (obj, self.clock));
+        self.order.insert(self.clock, hash);
+    }
+
+    pub fn remove(&mut self, hash: &Hash) {
+        if let Some((_, used)) = self.entries.remove(hash) {
+            self.order.remove(&used);
+        }
+    }
+
+    /// Empty the cache, keeping the statistics
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    /// Change the capacity, evicting the least recently used objects to fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.stats.capacity = capacity;
+        while self.entries.len() > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            len: self.entries.len(),
+            ..self.stats
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Which names differ between two databases. Functions are content addressed, so a
+//! name that changed points to another hash.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use super::Database;
+use crate::Hash;
+
+/// The names of one database compared to another's, each sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameDiff {
+    /// Names only in the new database
+    pub added: Vec<(String, Hash)>,
+    /// Names only in the old database
+    pub removed: Vec<(String, Hash)>,
+    /// Names in both that point to different code objects, with the old hash and
+    /// then the new one
+    pub changed: Vec<(String, Hash, Hash)>,
+}
+
+impl NameDiff {
+    /// Whether both databases have the same names for the same code objects
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Database {
+    /// How the named functions of `new` differ from this database's
+    pub fn diff_names(&self, new: &Database) -> Result<NameDiff> {
+        let old = self
+            .get_functions()?
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let new = new.get_functions()?.into_iter().collect::<BTreeMap<_, _>>();
+
+        let mut diff = NameDiff::default();
+        for (name, &hash) in &old {
+            match new.get(name) {
+                None => diff.removed.push((name.clone(), hash)),
+                Some(&other) if other != hash => {
+                    diff.changed.push((name.clone(), hash, other))
+                }
+                Some(_) => {}
+            }
+        }
+        diff.added = new
+            .into_iter()
+            .filter(|(name, _)| !old.contains_key(name))
+            .collect();
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_diff_names() {
+        let old = Database::temp().unwrap();
+        let new = Database::temp().unwrap();
+        let a = init_code_obj(bytecode![Instr::Return]);
+        let b = init_code_obj(bytecode![Instr::Nop, Instr::Return]);
+        let a_hash = old.insert_code_object_with_name(&a, "same").unwrap();
+        old.insert_code_object_with_name(&a, "gone").unwrap();
+        old.insert_code_object_with_name(&a, "edited").unwrap();
+        new.insert_code_object_with_name(&a, "same").unwrap();
+        let b_hash = new.insert_code_object_with_name(&b, "edited").unwrap();
+        new.insert_code_object_with_name(&b, "fresh").unwrap();
+
+        let diff = old.diff_names(&new).unwrap();
+        assert_eq!(diff.added, [("fresh".to_string(), b_hash)]);
+        assert_eq!(diff.removed, [("gone".to_string(), a_hash)]);
+        assert_eq!(diff.changed, [("edited".to_string(), a_hash, b_hash)]);
+        assert!(old.diff_names(&old).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! How code objects are stored in the `code_objs.code_obj` column. Each row records
+//! the format version it was written with in `code_objs.format_version`:
+//!
+//! - 0: msgpack of the `CodeObject` fields litpool, argcount, localnames, labels,
+//!   and code
+//! - 1: the bytecode uses the compact `Bytecode::encode` encoding, and everything
+//!   else (including literals) is msgpack
+//! - 2: as 1, but `debug_info`, `signature`, and `is_void` are always written after
+//!   the other fields. Rows from version 1 may be missing them.
+//!
+//! Rows keep the hash they were first stored under, even when migrated. For
+//! version 0 that is the hash of its msgpack encoding (see `hash_matches`).
+//!
+//! Bump `FORMAT_VERSION` whenever `Instr` or `CodeObject` change in a way that
+//! breaks decoding, and keep a decoder for every older version so that
+//! `Database::migrate` can upgrade old databases.
+//!
+//! Version 1 .efb files hold msgpack of `CodeObject` from before it had `is_void`,
+//! which is decoded here too (see `decode_before_is_void`).
+//!
+//! Independently of the format, the blob may be compressed, as recorded in
+//! `code_objs.compression`: 0 for none, or 1 for lz4 with the uncompressed length
+//! prepended. Rows from before compression have 0.
+
+use std::borrow::Cow;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::bytecode::Bytecode;
+use crate::vm::{CodeObject, DebugInfo, Signature, Value};
+use crate::Hash;
+
+/// The format new code objects are written in
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct StoredCodeObject {
+    litpool: Vec<Value>,
+    argcount: usize,
+    localnames: Vec<String>,
+    labels: Vec<usize>,
+    max_stack_depth: Option<usize>,
+    #[serde(with = "serde_bytes")]
+    code: Vec<u8>,
+    // Added after version 1 was introduced, so these must stay last and optional
+    #[serde(default)]
+    debug_info: Option<DebugInfo>,
+    #[serde(default)]
+    signature: Option<Signature>,
+    /// Inferred from the code if missing
+    #[serde(default)]
+    is_void: Option<bool>,
+}
+
+pub(crate) fn encode_code_object(obj: &CodeObject) -> Result<Vec<u8>> {
+    let stored = StoredCodeObject {
+        litpool: obj.litpool.clone(),
+        argcount: obj.argcount,
+        localnames: obj.localnames.clone(),
+        labels: obj.labels.clone(),
+        max_stack_depth: obj.max_stack_depth,
+        code: obj.code.encode(),
+        debug_info: obj.debug_info.clone(),
+        signature: obj.signature.clone(),
+        is_void: Some(obj.is_void),
+    };
+    Ok(rmp_serde::to_vec(&stored)?)
+}
+
+/// How new code object blobs are compressed. Blobs that would not get smaller
+/// are stored uncompressed either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[default]
+    Lz4,
+}
+
+impl Compression {
+    /// The value of `code_objs.compression` for blobs compressed this way
+    fn id(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    fn from_id(id: u32) -> Result<Compression> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => bail!("code object has unknown compression {id}"),
+        }
+    }
+}
+
+/// Encode a code object with the current format, then compress it if that makes
+/// it smaller. Returns the blob and its `code_objs.compression`.
+pub(crate) fn encode_stored(
+    obj: &CodeObject,
+    compression: Compression,
+) -> Result<(Vec<u8>, u32)> {
+    let blob = encode_code_object(obj)?;
+    if compression == Compression::Lz4 {
+        let compressed = lz4_flex::compress_prepend_size(&blob);
+        if compressed.len() < blob.len() {
+            return Ok((compressed, Compression::Lz4.id()));
+        }
+    }
+    Ok((blob, Compression::None.id()))
+}
+
+/// Decompress a blob from `code_objs`, then decode it with its format version
+pub(crate) fn decode_stored(
+    version: u32,
+    compression: u32,
+    blob: &[u8],
+) -> Result<CodeObject> {
+    decode_code_object(version, &decompress(compression, blob)?)
+}
+
+fn decompress(compression: u32, blob: &[u8]) -> Result<Cow<'_, [u8]>> {
+    Ok(match Compression::from_id(compression)? {
+        Compression::None => Cow::Borrowed(blob),
+        Compression::Lz4 => Cow::Owned(lz4_flex::decompress_size_prepended(blob)?),
+    })
+}
+
+/// The length of a blob from `code_objs` once decompressed
+pub(crate) fn decompressed_len(compression: u32, blob: &[u8]) -> Result<usize> {
+    Ok(match Compression::from_id(compression)? {
+        Compression::None => blob.len(),
+        Compression::Lz4 => match blob.get(..4) {
+            Some(len) => u32::from_le_bytes(len.try_into()?) as usize,
+            None => bail!("compressed code object is truncated"),
+        },
+    })
+}
+
+/// The layout of format version 0
+#[derive(Serialize, Deserialize)]
+struct StoredCodeObjectV0 {
+    litpool: Vec<Value>,
+    argcount: usize,
+    localnames: Vec<String>,
+    labels: Vec<usize>,
+    code: Bytecode,
+}
+
+/// Decode a code object stored with the given format version
+pub(crate) fn decode_code_object(version: u32, blob: &[u8]) -> Result<CodeObject> {
+    match version {
+        0 => decode_v0(blob),
+        1 | 2 => decode_v1(blob),
+        _ => bail!(
+            "code object has format version {version}, but only versions up to {FORMAT_VERSION} are supported"
+        ),
+    }
+}
+
+fn decode_v0(blob: &[u8]) -> Result<CodeObject> {
+    let stored: StoredCodeObjectV0 = rmp_serde::from_slice(blob)?;
+    Ok(CodeObject {
+        litpool: stored.litpool,
+        argcount: stored.argcount,
+        is_void: stored.code.is_void(),
+        localnames: stored.localnames,
+        labels: stored.labels,
+        max_stack_depth: None,
+        signature: None,
+        debug_info: None,
+        code: stored.code,
+    })
+}
+
+/// How `CodeObject` was serialized before it had `is_void`: as in format version
+/// 0, then with `max_stack_depth`, then also with `signature`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CodeObjectBeforeIsVoid {
+    V0(StoredCodeObjectV0),
+    StackDepth(
+        Vec<Value>,
+        usize,
+        Vec<String>,
+        Vec<usize>,
+        Option<usize>,
+        Bytecode,
+    ),
+    Signature(
+        Vec<Value>,
+        usize,
+        Vec<String>,
+        Vec<usize>,
+        Option<usize>,
+        Option<Signature>,
+        Bytecode,
+    ),
+}
+
+/// Decode msgpack of a `CodeObject` from before it had `is_void`, inferring that
+/// from the code
+pub(crate) fn decode_before_is_void(blob: &[u8]) -> Result<CodeObject> {
+    let (litpool, argcount, localnames, labels, max_stack_depth, signature, code) =
+        match rmp_serde::from_slice(blob)? {
+            CodeObjectBeforeIsVoid::V0(v0) => {
+                let StoredCodeObjectV0 {
+                    litpool,
+                    argcount,
+                    localnames,
+                    labels,
+                    code,
+                } = v0;
+                (litpool, argcount, localnames, labels, None, None, code)
+            }
+            CodeObjectBeforeIsVoid::StackDepth(
+                lits,
+                args,
+                names,
+                labels,
+                depth,
+                code,
+            ) => (lits, args, names, labels, depth, None, code),
+            CodeObjectBeforeIsVoid::Signature(
+                lits,
+                args,
+                names,
+                labels,
+                depth,
+                sig,
+                code,
+            ) => (lits, args, names, labels, depth, sig, code),
+        };
+    Ok(CodeObject {
+        litpool,
+        argcount,
+        is_void: code.is_void(),
+        localnames,
+        labels,
+        max_stack_depth,
+        signature,
+        debug_info: None,
+        code,
+    })
+}
+
+/// Encode in format version 0, which is also how its hash was computed
+pub(crate) fn encode_v0(obj: &CodeObject) -> Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec(&StoredCodeObjectV0 {
+        litpool: obj.litpool.clone(),
+        argcount: obj.argcount,
+        localnames: obj.localnames.clone(),
+        labels: obj.labels.clone(),
+        code: obj.code.clone(),
+    })?)
+}
+
+/// Whether a decoded code object belongs under `hash`: its hash, or, for one from
+/// format version 0 that has none of the fields added since, its hash from then
+pub(crate) fn hash_matches(obj: &CodeObject, hash: &Hash) -> Result<bool> {
+    if obj.hash()? == *hash {
+        return Ok(true);
+    }
+    let legacy = obj.max_stack_depth.is_none() && obj.signature.is_none();
+    Ok(legacy && Hash::digest(&encode_v0(obj)?) == *hash)
+}
+
+fn decode_v1(blob: &[u8]) -> Result<CodeObject> {
+    let stored: StoredCodeObject = rmp_serde::from_slice(blob)?;
+    let code = Bytecode::decode(&stored.code)?;
+    Ok(CodeObject {
+        is_void: stored.is_void.unwrap_or_else(|| code.is_void()),
+        litpool: stored.litpool,
+        argcount: stored.argcount,
+        localnames: stored.localnames,
+        labels: stored.labels,
+        max_stack_depth: stored.max_stack_depth,
+        signature: stored.signature,
+        debug_info: stored.debug_info,
+        code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parser::Parser;
+
+    #[test]
+    fn test_roundtrip() {
+        let parses = Parser::parse_file("examples/fib.asm").unwrap();
+        for parse in parses {
+            let blob = encode_code_object(&parse.code_obj).unwrap();
+            let decoded = decode_code_object(FORMAT_VERSION, &blob).unwrap();
+            assert_eq!(decoded.hash().unwrap(), parse.code_obj.hash().unwrap());
+            assert_eq!(decoded.debug_info, parse.code_obj.debug_info);
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let parse = Parser::parse_file("examples/fib.asm").unwrap().remove(0);
+        let legacy = encode_v0(&parse.code_obj).unwrap();
+        let decoded = decode_code_object(0, &legacy).unwrap();
+        assert_eq!(decoded.code.to_vec(), parse.code_obj.code.to_vec());
+        assert_eq!(decoded.litpool, parse.code_obj.litpool);
+
+        // Version 0 objects keep their old hash
+        let legacy_hash = Hash::digest(&legacy);
+        assert_ne!(decoded.hash().unwrap(), legacy_hash);
+        assert!(hash_matches(&decoded, &legacy_hash).unwrap());
+        assert!(hash_matches(&decoded, &decoded.hash().unwrap()).unwrap());
+        assert!(!hash_matches(&decoded, &Hash::digest(b"")).unwrap());
+
+        assert!(decode_code_object(FORMAT_VERSION + 1, &legacy).is_err());
+
+        // The same layout is the oldest of `CodeObject` in .efb files
+        let decoded = decode_before_is_void(&legacy).unwrap();
+        assert_eq!(decoded.code.to_vec(), parse.code_obj.code.to_vec());
+        assert_eq!(decoded.is_void, parse.code_obj.is_void);
+    }
+
+    #[test]
+    fn test_compression() {
+        // Long literals compress well, and short code objects are left alone
+        let mut parse = Parser::parse_file("examples/fib.asm").unwrap().remove(0);
+        let (blob, compression) =
+            encode_stored(&parse.code_obj, Compression::Lz4).unwrap();
+        assert_eq!(compression, Compression::None.id());
+        assert_eq!(blob, encode_code_object(&parse.code_obj).unwrap());
+
+        parse
+            .code_obj
+            .litpool
+            .push(Value::string(&"efa ".repeat(100)));
+        let plain = encode_code_object(&parse.code_obj).unwrap();
+        let (blob, compression) =
+            encode_stored(&parse.code_obj, Compression::Lz4).unwrap();
+        assert_eq!(compression, Compression::Lz4.id());
+        assert!(blob.len() < plain.len());
+        assert_eq!(decompressed_len(compression, &blob).unwrap(), plain.len());
+        let decoded = decode_stored(FORMAT_VERSION, compression, &blob).unwrap();
+        assert_eq!(decoded.hash().unwrap(), parse.code_obj.hash().unwrap());
+
+        assert_eq!(
+            encode_stored(&parse.code_obj, Compression::None).unwrap(),
+            (plain, 0)
+        );
+        assert!(decode_stored(FORMAT_VERSION, 2, &blob).is_err());
+    }
+
+    #[test]
+    fn test_size_reduction() {
+        let (mut msgpack, mut compact) = (0, 0);
+        for entry in std::fs::read_dir("examples/").unwrap() {
+            for parse in Parser::parse_file(entry.unwrap().path()).unwrap() {
+                msgpack += rmp_serde::to_vec(&parse.code_obj).unwrap().len();
+                compact += encode_code_object(&parse.code_obj).unwrap().len();
+            }
+        }
+        assert!(
+            compact < msgpack,
+            "{compact} bytes compact, {msgpack} as msgpack"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Entry points: the code objects a VM starts running, by name. The default entry
+//! point is the one `Vm::run_main_function` runs; until it is set, it is whatever
+//! the name `main` points to.
+
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{params, OptionalExtension};
+
+use super::Database;
+use crate::vm::CodeObject;
+use crate::{is_valid_qualified_name, Hash};
+
+/// The name of the entry point that `Vm::run_main_function` runs
+pub const DEFAULT_ENTRY_POINT: &str = "main";
+
+impl Database {
+    /// Make a function, by name or hash prefix (starting with 0x), the default
+    /// entry point
+    pub fn set_entry_point(&self, target: &str) -> Result<Hash> {
+        self.set_named_entry_point(DEFAULT_ENTRY_POINT, target)
+    }
+
+    /// Point the entry point `entry` at a function, by name or hash prefix
+    /// (starting with 0x). The entry point keeps its code object if the name is
+    /// later pointed elsewhere.
+    pub fn set_named_entry_point(&self, entry: &str, target: &str) -> Result<Hash> {
+        if !is_valid_qualified_name(entry) {
+            bail!("cannot set entry point: invalid name '{entry}'");
+        }
+        let hash = self.resolve(target)?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO entry_points (name, hash, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
+            params![entry, hash],
+        )?;
+        Ok(hash)
+    }
+
+    /// The code object of the default entry point
+    pub fn get_entry_point(&self) -> Result<(Hash, CodeObject)> {
+        self.get_named_entry_point(DEFAULT_ENTRY_POINT)
+    }
+
+    pub fn get_named_entry_point(&self, entry: &str) -> Result<(Hash, CodeObject)> {
+        let hash: Option<Hash> = self
+            .conn()
+            .query_row(
+                "SELECT hash FROM entry_points WHERE name = ?1;",
+                [entry],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match hash {
+            Some(hash) => Ok((hash, self.get_code_object(&hash)?)),
+            None if entry == DEFAULT_ENTRY_POINT => self.get_main_object(),
+            None => Err(anyhow!("query failed: no entry point named '{entry}'")),
+        }
+    }
+
+    /// The entry points that have been set, sorted by name
+    pub fn get_entry_points(&self) -> Result<Vec<(String, Hash)>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT name, hash FROM entry_points ORDER BY name;")?;
+        let entries = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(entries.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Remove an entry point, returning whether there was one. Removing the
+    /// default one makes `main` the default again.
+    pub fn remove_entry_point(&self, entry: &str) -> Result<bool> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM entry_points WHERE name = ?1;", [entry])?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_entry_points() {
+        let db = Database::temp().unwrap();
+        assert!(db.get_entry_point().is_err());
+
+        let main = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let main = db.insert_code_object_with_name(&main, "main").unwrap();
+        let start = init_code_obj(bytecode![Instr::LoadArg(1), Instr::ReturnVal]);
+        let start = db.insert_code_object_with_name(&start, "start").unwrap();
+        assert_eq!(db.get_entry_point().unwrap().0, main);
+        assert!(db.get_entry_points().unwrap().is_empty());
+
+        // The default entry point no longer depends on the name `main`
+        assert_eq!(db.set_entry_point("start").unwrap(), start);
+        assert_eq!(db.get_entry_point().unwrap().0, start);
+        let prefix = format!("0x{}", &main.to_hex()[..8]);
+        db.set_named_entry_point("test", &prefix).unwrap();
+        assert_eq!(db.get_named_entry_point("test").unwrap().0, main);
+        assert_eq!(
+            db.get_entry_points().unwrap(),
+            vec![("main".to_string(), start), ("test".to_string(), main)]
+        );
+        assert!(db.get_named_entry_point("bench").is_err());
+        assert!(db.set_named_entry_point("bench", "missing").is_err());
+        assert!(db.set_named_entry_point("bad name", "main").is_err());
+
+        assert!(db.remove_entry_point("main").unwrap());
+        assert!(!db.remove_entry_point("main").unwrap());
+        assert_eq!(db.get_entry_point().unwrap().0, main);
+
+        // Entry points go with their code objects
+        db.remove_code_object(&main, false).unwrap();
+        assert!(db.get_named_entry_point("test").is_err());
+        assert!(db.get_entry_points().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! Telling subscribers about changes to the code objects and names in a database,
+//! once they are committed. Only changes made through the same `Database` are
+//! seen, not those made by other connections to the file.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rusqlite::Connection;
+
+use super::Database;
+use crate::Hash;
+
+/// A committed change, from `Database::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A code object was stored
+    Inserted(Hash),
+    /// A name was added, or set to point to a code object
+    Named { name: String, hash: Hash },
+    /// A name was removed
+    Unnamed(String),
+    /// A code object was removed
+    Removed(Hash),
+}
+
+/// Changes waiting for their transaction to commit, and who to send them to
+#[derive(Debug, Default)]
+pub(super) struct Changes {
+    subscribers: Vec<Sender<Change>>,
+    pending: Vec<Change>,
+}
+
+impl Changes {
+    fn send(&mut self) {
+        for change in self.pending.drain(..) {
+            self.subscribers
+                .retain(|subscriber| subscriber.send(change.clone()).is_ok());
+        }
+    }
+
+    /// Send the changes made in a transaction when it commits, and drop them if it
+    /// rolls back
+    pub fn hook(changes: &Arc<Mutex<Changes>>, conn: &Connection) {
+        let committed = Arc::clone(changes);
+        conn.commit_hook(Some(move || {
+            committed.lock().send();
+            false
+        }));
+        let rolled_back = Arc::clone(changes);
+        conn.rollback_hook(Some(move || rolled_back.lock().pending.clear()));
+    }
+}
+
+impl Database {
+    /// Receive every change committed from now on. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&self) -> Receiver<Change> {
+        let (sender, receiver) = mpsc::channel();
+        self.changes.lock().subscribers.push(sender);
+        receiver
+    }
+
+    pub(super) fn notify_named(&self, name: &str, hash: &Hash) {
+        self.notify(Change::Named {
+            name: name.to_string(),
+            hash: *hash,
+        });
+    }
+
+    /// Record a change, sending it now unless it was made in a transaction
+    pub(super) fn notify(&self, change: Change) {
+        // Take the connection first, as a commit does before sending
+        let conn = self.conn();
+        let mut changes = self.changes.lock();
+        changes.pending.push(change);
+        if conn.is_autocommit() {
+            changes.send();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_subscribe() {
+        let db = Database::temp().unwrap();
+        let changes = db.subscribe();
+        let f = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let g = init_code_obj(bytecode![Instr::LoadArg(1), Instr::ReturnVal]);
+
+        let f = db.insert_code_object_with_name(&f, "f").unwrap();
+        db.create_alias("h", &f).unwrap();
+        db.rename("h", "i").unwrap();
+        db.remove_alias("i").unwrap();
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            vec![
+                Change::Inserted(f),
+                Change::Named {
+                    name: "f".to_string(),
+                    hash: f
+                },
+                Change::Named {
+                    name: "h".to_string(),
+                    hash: f
+                },
+                Change::Unnamed("h".to_string()),
+                Change::Named {
+                    name: "i".to_string(),
+                    hash: f
+                },
+                Change::Unnamed("i".to_string()),
+            ]
+        );
+
+        // Nothing is sent for a batch that fails
+        let batch = [
+            ("g".to_string(), g.clone()),
+            ("bad name".to_string(), g.clone()),
+        ];
+        assert!(db.insert_batch(&batch).is_err());
+        assert!(changes.try_recv().is_err());
+
+        let g = db.insert_code_object_with_name(&g, "f").unwrap();
+        db.remove_code_object(&f, false).unwrap();
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            vec![
+                Change::Inserted(g),
+                Change::Named {
+                    name: "f".to_string(),
+                    hash: g
+                },
+                Change::Removed(f),
+            ]
+        );
+
+        // Dropped receivers are forgotten
+        drop(changes);
+        db.remove_name("f", false).unwrap();
+        assert!(db.changes.lock().subscribers.is_empty());
+    }
+}
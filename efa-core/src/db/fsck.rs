@@ -0,0 +1,158 @@
+//! Checking a database for corruption
+
+use anyhow::Result;
+
+use super::encoding::{decode_stored, hash_matches};
+use super::Database;
+use crate::Hash;
+
+/// What `Database::verify` found wrong. Each problem is listed once, by the hash
+/// in the `hash` column of the row that has it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Code objects checked
+    pub objects: usize,
+    /// Code objects whose blob can't be decoded, with the error
+    pub undecodable: Vec<(Hash, String)>,
+    /// Code objects whose blob hashes to something else, with that hash
+    pub hash_mismatches: Vec<(Hash, Hash)>,
+    /// Names that point to code objects that aren't stored
+    pub dangling_names: Vec<(String, Hash)>,
+    /// Code objects the bytecode verifier rejects, with the error, if it was run
+    pub unverified: Vec<(Hash, String)>,
+}
+
+impl IntegrityReport {
+    /// Whether nothing is wrong
+    pub fn is_ok(&self) -> bool {
+        self.undecodable.is_empty()
+            && self.hash_mismatches.is_empty()
+            && self.dangling_names.is_empty()
+            && self.unverified.is_empty()
+    }
+}
+
+impl Database {
+    /// Check that every stored blob decodes to a code object with the hash it is
+    /// stored under (or its legacy hash, see `encoding::hash_matches`), and that
+    /// every name points to a stored code object. With `bytecode` set, also run
+    /// the bytecode verifier on each code object.
+    pub fn verify(&self, bytecode: bool) -> Result<IntegrityReport> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash, format_version, compression, code_obj FROM code_objs ORDER BY hash;",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(Hash, u32, u32, Vec<u8>)>>>()?;
+
+        let mut report = IntegrityReport {
+            objects: rows.len(),
+            ..Default::default()
+        };
+        for (hash, version, compression, blob) in rows {
+            let obj = match decode_stored(version, compression, &blob) {
+                Ok(obj) => obj,
+                Err(e) => {
+                    report.undecodable.push((hash, e.to_string()));
+                    continue;
+                }
+            };
+            if !hash_matches(&obj, &hash)? {
+                report.hash_mismatches.push((hash, obj.hash()?));
+            }
+            if bytecode {
+                if let Err(e) = crate::verify::verify(&obj) {
+                    report.unverified.push((hash, e.to_string()));
+                }
+            }
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT name, hash FROM names WHERE hash NOT IN (SELECT hash FROM code_objs) ORDER BY name;",
+        )?;
+        report.dangling_names = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::db::encoding::encode_code_object;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_verify() {
+        let db = Database::temp().unwrap();
+        let f = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let g = init_code_obj(bytecode![Instr::LoadArg(1), Instr::ReturnVal]);
+        let f = db.insert_code_object_with_name(&f, "f").unwrap();
+        let g = db.insert_code_object_with_name(&g, "g").unwrap();
+        let report = db.verify(true).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.objects, 2);
+
+        // Swap the blobs, and break a name and a blob
+        let conn = db.conn();
+        let blob = |hash: &Hash| -> Vec<u8> {
+            conn.query_row(
+                "SELECT code_obj FROM code_objs WHERE hash = ?1;",
+                [hash],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let (f_blob, g_blob) = (blob(&f), blob(&g));
+        conn.execute("UPDATE code_objs SET code_obj = NULL;", [])
+            .unwrap();
+        for (hash, blob) in [(&f, g_blob), (&g, f_blob)] {
+            conn.execute(
+                "UPDATE code_objs SET code_obj = ?1 WHERE hash = ?2;",
+                params![blob, hash],
+            )
+            .unwrap();
+        }
+        let dangling = Hash::digest(b"");
+        conn.execute("UPDATE names SET hash = ?1 WHERE name = 'g';", [dangling])
+            .unwrap();
+        let mut report = db.verify(false).unwrap();
+        assert!(!report.is_ok());
+        report
+            .hash_mismatches
+            .sort_by_key(|(hash, _)| hash.to_hex());
+        let mut swapped = vec![(f, g), (g, f)];
+        swapped.sort_by_key(|(hash, _)| hash.to_hex());
+        assert_eq!(report.hash_mismatches, swapped);
+        assert_eq!(report.dangling_names, vec![("g".to_string(), dangling)]);
+
+        conn.execute(
+            "UPDATE code_objs SET code_obj = ?1 WHERE hash = ?2;",
+            params![b"garbage".to_vec(), f],
+        )
+        .unwrap();
+        let report = db.verify(false).unwrap();
+        assert_eq!(report.undecodable.len(), 1);
+        assert_eq!(report.undecodable[0].0, f);
+
+        // The bytecode verifier only runs when asked
+        let bad = init_code_obj(bytecode![Instr::LoadLit(10), Instr::ReturnVal]);
+        conn.execute(
+            "UPDATE code_objs SET code_obj = ?1, hash = ?2, compression = 0 WHERE hash = ?3;",
+            params![encode_code_object(&bad).unwrap(), bad.hash().unwrap(), g],
+        )
+        .unwrap();
+        assert!(db.verify(false).unwrap().unverified.is_empty());
+        let report = db.verify(true).unwrap();
+        assert_eq!(report.unverified.len(), 1);
+        assert_eq!(report.unverified[0].0, bad.hash().unwrap());
+    }
+}
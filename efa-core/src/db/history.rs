@@ -0,0 +1,260 @@
+//! Version history of names. Every hash a name has pointed to is kept in the
+//! `name_versions` table, oldest first, while `names` holds the one it points to
+//! now: the latest, unless the name has been pinned to an earlier version.
+
+use anyhow::{bail, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::Database;
+use crate::Hash;
+
+/// A hash a name has pointed to, and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameVersion {
+    pub hash: Hash,
+    pub time: String,
+}
+
+impl Database {
+    /// Point a name at a hash, adding a version if it pointed somewhere else. A
+    /// pinned name gets the new version but keeps pointing where it was. Returns
+    /// whether the name now points at `hash`.
+    pub(super) fn set_name(conn: &Connection, name: &str, hash: &Hash) -> Result<bool> {
+        let current = conn
+            .query_row(
+                "SELECT hash, pinned FROM names WHERE name = ?1;",
+                [name],
+                |row| Ok((row.get::<_, Hash>(0)?, row.get::<_, bool>(1)?)),
+            )
+            .optional()?;
+
+        if let Some((old, pinned)) = current {
+            if old == *hash {
+                return Ok(true);
+            }
+            Self::add_version(conn, name, hash)?;
+            if pinned {
+                return Ok(false);
+            }
+            Self::point_name(conn, name, hash, &old)?;
+        } else {
+            conn.execute(
+                "INSERT INTO names (name, hash, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
+                params![name, hash],
+            )?;
+            Self::add_version(conn, name, hash)?;
+            Self::update_is_main(conn, hash)?;
+        }
+        Ok(true)
+    }
+
+    fn add_version(conn: &Connection, name: &str, hash: &Hash) -> Result<()> {
+        conn.execute(
+            "INSERT INTO name_versions (name, hash, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
+            params![name, hash],
+        )?;
+        Ok(())
+    }
+
+    /// Move an existing name from `old` to `hash`, without adding a version
+    fn point_name(conn: &Connection, name: &str, hash: &Hash, old: &Hash) -> Result<()> {
+        conn.execute(
+            "UPDATE names SET hash = ?1, time = CURRENT_TIMESTAMP WHERE name = ?2;",
+            params![hash, name],
+        )?;
+        Self::update_is_main(conn, old)?;
+        Self::update_is_main(conn, hash)?;
+        Ok(())
+    }
+
+    /// Every hash a name has pointed to, oldest first. Empty if there is no such
+    /// name.
+    pub fn get_name_versions(&self, name: &str) -> Result<Vec<NameVersion>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash, time FROM name_versions WHERE name = ?1 ORDER BY id;",
+        )?;
+        let versions = stmt.query_map([name], |row| {
+            Ok(NameVersion {
+                hash: row.get(0)?,
+                time: row.get(1)?,
+            })
+        })?;
+        Ok(versions.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Whether a name is pinned to a version, rather than following the latest
+    pub fn is_pinned(&self, name: &str) -> Result<bool> {
+        match self
+            .conn()
+            .query_row("SELECT pinned FROM names WHERE name = ?1;", [name], |row| {
+                row.get(0)
+            })
+            .optional()?
+        {
+            Some(pinned) => Ok(pinned),
+            None => bail!("query failed: no code object with name '{name}'"),
+        }
+    }
+
+    /// Point a name at one of its versions, and keep it there when new versions
+    /// are added, until `unpin`
+    pub fn pin(&self, name: &str, hash: &Hash) -> Result<()> {
+        let conn = self.conn();
+        let (old, _) = self.get_code_object_by_name(name)?;
+        if !self
+            .get_name_versions(name)?
+            .iter()
+            .any(|v| v.hash == *hash)
+        {
+            bail!("cannot pin '{name}' to {hash}: it is not a version of the name");
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        Self::point_name(&tx, name, hash, &old)?;
+        tx.execute("UPDATE names SET pinned = 1 WHERE name = ?1;", [name])?;
+        self.notify_named(name, hash);
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Let a pinned name follow its latest version again, returning its hash
+    pub fn unpin(&self, name: &str) -> Result<Hash> {
+        let conn = self.conn();
+        let (old, _) = self.get_code_object_by_name(name)?;
+        let latest = self.latest_version(name)?;
+
+        let tx = conn.unchecked_transaction()?;
+        Self::point_name(&tx, name, &latest, &old)?;
+        tx.execute("UPDATE names SET pinned = 0 WHERE name = ?1;", [name])?;
+        self.notify_named(name, &latest);
+        tx.commit()?;
+
+        Ok(latest)
+    }
+
+    /// Forget the latest version of a name and point it back at the one before,
+    /// returning its hash. The code object of the forgotten version is kept.
+    pub fn rollback(&self, name: &str) -> Result<Hash> {
+        let conn = self.conn();
+        let (old, _) = self.get_code_object_by_name(name)?;
+        if self.is_pinned(name)? {
+            bail!("cannot roll back '{name}': it is pinned to {old}");
+        }
+        let versions = self.get_name_versions(name)?;
+        let [.., previous, _] = versions.as_slice() else {
+            bail!("cannot roll back '{name}': it has no earlier version");
+        };
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM name_versions WHERE id = (SELECT MAX(id) FROM name_versions WHERE name = ?1);",
+            [name],
+        )?;
+        Self::point_name(&tx, name, &previous.hash, &old)?;
+        self.notify_named(name, &previous.hash);
+        tx.commit()?;
+
+        Ok(previous.hash)
+    }
+
+    fn latest_version(&self, name: &str) -> Result<Hash> {
+        match self.get_name_versions(name)?.pop() {
+            Some(version) => Ok(version.hash),
+            None => bail!("query failed: no code object with name '{name}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+    use crate::vm::CodeObject;
+
+    /// A different function for each `i` below 4
+    fn version(i: usize) -> CodeObject {
+        let load = match i {
+            0 | 1 => Instr::LoadLit(i),
+            _ => Instr::LoadArg(i - 2),
+        };
+        init_code_obj(bytecode![load, Instr::ReturnVal])
+    }
+
+    #[test]
+    fn test_versions() {
+        let db = Database::temp().unwrap();
+        let hashes = (0..3)
+            .map(|i| db.insert_code_object_with_name(&version(i), "f").unwrap())
+            .collect::<Vec<_>>();
+
+        // Lookups get the latest version, and the same hash again is no new version
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, hashes[2]);
+        db.insert_code_object_with_name(&version(2), "f").unwrap();
+        let versions = db.get_name_versions("f").unwrap();
+        assert_eq!(versions.iter().map(|v| v.hash).collect::<Vec<_>>(), hashes);
+        assert!(db.get_name_versions("g").unwrap().is_empty());
+
+        // A pinned name can't be moved by inserting under it
+        db.pin("f", &hashes[0]).unwrap();
+        assert!(db.is_pinned("f").unwrap());
+        let err = db
+            .insert_code_object_with_name(&version(3), "f")
+            .unwrap_err();
+        assert!(err.to_string().contains("the name is pinned"), "{err}");
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, hashes[0]);
+        assert_eq!(db.get_name_versions("f").unwrap().len(), 3);
+        assert!(db.rollback("f").is_err());
+        assert!(db.pin("f", &Hash::digest(b"")).is_err());
+
+        assert_eq!(db.unpin("f").unwrap(), hashes[2]);
+        assert!(!db.is_pinned("f").unwrap());
+        let newest = db.insert_code_object_with_name(&version(3), "f").unwrap();
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, newest);
+
+        // Rolling back forgets versions, but keeps their code objects
+        assert_eq!(db.rollback("f").unwrap(), hashes[2]);
+        assert_eq!(db.rollback("f").unwrap(), hashes[1]);
+        assert_eq!(db.rollback("f").unwrap(), hashes[0]);
+        assert!(db.rollback("f").is_err());
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, hashes[0]);
+        assert!(db.get_code_object(&newest).is_ok());
+    }
+
+    #[test]
+    fn test_versions_follow_names() {
+        let db = Database::temp().unwrap();
+        let old = db
+            .insert_code_object_with_name(&version(0), "main")
+            .unwrap();
+        let new = db
+            .insert_code_object_with_name(&version(1), "main")
+            .unwrap();
+        assert_eq!(db.get_main_object().unwrap().0, new);
+
+        // The main object follows pins and rollbacks
+        db.pin("main", &old).unwrap();
+        assert_eq!(db.get_main_object().unwrap().0, old);
+        db.unpin("main").unwrap();
+        assert_eq!(db.get_main_object().unwrap().0, new);
+
+        // History moves with a rename, and goes with the name
+        db.rename("main", "entry").unwrap();
+        assert!(db.get_main_object().is_err());
+        assert_eq!(db.get_name_versions("entry").unwrap().len(), 2);
+        assert!(db.get_name_versions("main").unwrap().is_empty());
+        db.create_alias("other", &new).unwrap();
+        db.remove_alias("entry").unwrap();
+        assert!(db.get_name_versions("entry").unwrap().is_empty());
+
+        // Removing a code object removes it from every history
+        db.create_alias("entry", &old).unwrap();
+        db.insert_code_object_with_name(&version(2), "entry")
+            .unwrap();
+        db.remove_code_object(&old, false).unwrap();
+        assert_eq!(db.get_name_versions("entry").unwrap().len(), 1);
+        assert!(db.rollback("entry").is_err());
+    }
+}
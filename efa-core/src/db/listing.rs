@@ -0,0 +1,209 @@
+//! Listing the named functions in a database a page at a time, filtered in SQL
+//! rather than after loading every name
+
+use anyhow::Result;
+use rusqlite::types::Value;
+
+use super::Database;
+use crate::Hash;
+
+/// How `list_functions` sorts what it finds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FunctionOrder {
+    #[default]
+    Name,
+    /// Least recently named first, then by name
+    Oldest,
+    /// Most recently named first, then by name
+    Newest,
+}
+
+/// What `list_functions` returns. A function is listed if it meets every
+/// condition that is set, so the default filter lists everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionFilter {
+    /// The start of the name, e.g. `math::` for a module
+    pub prefix: Option<String>,
+    /// Only names set strictly after this time, as SQLite writes it, e.g.
+    /// `2025-03-01 12:00:00`
+    pub after: Option<String>,
+    /// Only main functions, or only the others
+    pub is_main: Option<bool>,
+    pub order: FunctionOrder,
+    /// The most functions to list
+    pub limit: Option<usize>,
+    /// How many matching functions to skip first
+    pub offset: usize,
+}
+
+/// A named function found by `list_entries`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionEntry {
+    pub name: String,
+    pub hash: Hash,
+    /// When the name was pointed at the hash, as SQLite writes it
+    pub time: String,
+}
+
+impl Database {
+    /// The names and hashes of the named functions that match `filter`, in its
+    /// order
+    pub fn list_functions(&self, filter: &FunctionFilter) -> Result<Vec<(String, Hash)>> {
+        Ok(self
+            .list_entries(filter)?
+            .into_iter()
+            .map(|entry| (entry.name, entry.hash))
+            .collect())
+    }
+
+    /// Like `list_functions`, with when each name was set
+    pub fn list_entries(&self, filter: &FunctionFilter) -> Result<Vec<FunctionEntry>> {
+        let mut conditions = vec![];
+        let mut params = vec![];
+        if let Some(prefix) = &filter.prefix {
+            params.push(Value::Text(prefix.clone()));
+            conditions.push(format!("instr(names.name, ?{}) = 1", params.len()));
+        }
+        if let Some(after) = &filter.after {
+            params.push(Value::Text(after.clone()));
+            conditions.push(format!("names.time > ?{}", params.len()));
+        }
+        if let Some(is_main) = filter.is_main {
+            params.push(Value::Integer(is_main as i64));
+            conditions.push(format!(
+                "coalesce(code_objs.is_main, 0) = ?{}",
+                params.len()
+            ));
+        }
+        let conditions = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let order = match filter.order {
+            FunctionOrder::Name => "names.name",
+            FunctionOrder::Oldest => "names.time, names.name",
+            FunctionOrder::Newest => "names.time DESC, names.name",
+        };
+        // SQLite takes a negative limit as no limit
+        params.push(Value::Integer(
+            filter.limit.map_or(-1, |limit| limit as i64),
+        ));
+        params.push(Value::Integer(filter.offset as i64));
+        let sql = format!(
+            "SELECT names.name, names.hash, names.time FROM names LEFT JOIN code_objs ON code_objs.hash = names.hash {conditions} ORDER BY {order} LIMIT ?{} OFFSET ?{};",
+            params.len() - 1,
+            params.len(),
+        );
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&sql)?;
+        let functions = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Ok(FunctionEntry {
+                    name: row.get(0)?,
+                    hash: row.get(1)?,
+                    time: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(functions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+
+    fn names(found: Vec<(String, Hash)>) -> Vec<String> {
+        found.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_list_functions() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$main 0:",
+            "    .lit 0",
+            "    load_lit 0",
+            "    ret_val",
+            ".module math",
+            "$one 0:",
+            "    .lit 1",
+            "    load_lit 0",
+            "    ret_val",
+            "$two 0:",
+            "    .lit 2",
+            "    load_lit 0",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        for (name, time) in [
+            ("main", "2025-01-01 00:00:00"),
+            ("math::one", "2025-01-03 00:00:00"),
+            ("math::two", "2025-01-02 00:00:00"),
+        ] {
+            db.conn()
+                .execute("UPDATE names SET time = ?1 WHERE name = ?2;", [time, name])
+                .unwrap();
+        }
+
+        let list = |filter: FunctionFilter| names(db.list_functions(&filter).unwrap());
+        assert_eq!(
+            list(FunctionFilter::default()),
+            ["main", "math::one", "math::two"]
+        );
+        assert_eq!(db.get_functions().unwrap().len(), 3);
+        assert_eq!(
+            list(FunctionFilter {
+                prefix: Some("math::".to_string()),
+                order: FunctionOrder::Oldest,
+                ..Default::default()
+            }),
+            ["math::two", "math::one"]
+        );
+        assert_eq!(
+            list(FunctionFilter {
+                after: Some("2025-01-01 00:00:00".to_string()),
+                ..Default::default()
+            }),
+            ["math::one", "math::two"]
+        );
+        assert_eq!(
+            list(FunctionFilter {
+                is_main: Some(true),
+                ..Default::default()
+            }),
+            ["main"]
+        );
+        assert_eq!(
+            list(FunctionFilter {
+                is_main: Some(false),
+                order: FunctionOrder::Newest,
+                ..Default::default()
+            }),
+            ["math::one", "math::two"]
+        );
+
+        // Pages
+        let page = |offset| {
+            list(FunctionFilter {
+                limit: Some(2),
+                offset,
+                ..Default::default()
+            })
+        };
+        assert_eq!(page(0), ["main", "math::one"]);
+        let entries = db
+            .list_entries(&FunctionFilter {
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(entries[0].time, "2025-01-01 00:00:00");
+        assert_eq!(page(2), ["math::two"]);
+        assert!(page(4).is_empty());
+    }
+}
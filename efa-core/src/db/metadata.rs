@@ -0,0 +1,122 @@
+//! Descriptive metadata about code objects, stored beside them by hash. It is not
+//! part of a code object, so it does not change the hash.
+
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::Database;
+use crate::vm::Metadata;
+use crate::Hash;
+
+impl Database {
+    /// Replace the metadata of a stored code object. Empty metadata removes it.
+    pub fn set_metadata(&self, hash: &Hash, metadata: &Metadata) -> Result<()> {
+        self.get_code_object(hash)?;
+        Self::write_metadata(&self.conn(), hash, metadata)
+    }
+
+    /// Replace the metadata of a code object, without checking that it is stored
+    pub(super) fn write_metadata(
+        conn: &Connection,
+        hash: &Hash,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        if metadata.is_empty() {
+            conn.execute("DELETE FROM metadata WHERE hash = ?1;", [hash])?;
+            return Ok(());
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO metadata (hash, doc, author, signature, tags, source, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6, CURRENT_TIMESTAMP);",
+            params![
+                hash,
+                metadata.doc,
+                metadata.author,
+                metadata.signature,
+                serde_json::to_string(&metadata.tags)?,
+                metadata.source,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The metadata of a code object, if it has any
+    pub fn get_metadata(&self, hash: &Hash) -> Result<Option<Metadata>> {
+        let row = self
+            .conn()
+            .query_row(
+                "SELECT doc, author, signature, tags, source FROM metadata WHERE hash = ?1;",
+                [hash],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let Some((doc, author, signature, tags, source)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Metadata {
+            doc,
+            author,
+            signature,
+            tags: serde_json::from_str(&tags)?,
+            source,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_metadata() {
+        let db = Database::temp().unwrap();
+        let obj = init_code_obj(bytecode![Instr::Return]);
+        let hash = db.insert_code_object_with_name(&obj, "f").unwrap();
+        assert_eq!(db.get_metadata(&hash).unwrap(), None);
+
+        let metadata = Metadata {
+            doc: Some("Does nothing,\nquickly".to_string()),
+            author: Some("me".to_string()),
+            signature: None,
+            tags: vec!["fast".to_string(), "a, b".to_string()],
+            source: Some("f.asm".to_string()),
+        };
+        db.set_metadata(&hash, &metadata).unwrap();
+        assert_eq!(db.get_metadata(&hash).unwrap(), Some(metadata.clone()));
+
+        // Setting it again replaces it, and empty metadata removes it
+        let doc_only = Metadata {
+            doc: metadata.doc,
+            ..Default::default()
+        };
+        db.set_metadata(&hash, &doc_only).unwrap();
+        assert_eq!(db.get_metadata(&hash).unwrap(), Some(doc_only));
+        db.set_metadata(&hash, &Metadata::default()).unwrap();
+        assert_eq!(db.get_metadata(&hash).unwrap(), None);
+
+        // Only stored code objects have metadata, and it goes with them
+        assert!(db
+            .set_metadata(&Hash::digest(b""), &Metadata::default())
+            .is_err());
+        db.set_metadata(
+            &hash,
+            &Metadata {
+                author: Some("me".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.remove_code_object(&hash, false).unwrap();
+        assert_eq!(db.get_metadata(&hash).unwrap(), None);
+    }
+}
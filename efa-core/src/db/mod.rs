@@ -1,18 +1,96 @@
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use crate::asm::dis::disassemble_function;
-use crate::{hash_from_vec, is_valid_name, vm::CodeObject, Hash};
+use crate::asm::dis::{
+    disassemble_function_json, disassemble_function_with, DisOptions, FuncRefs,
+};
+use crate::solver;
+use crate::verify::verify;
+use crate::{is_valid_qualified_name, vm::CodeObject, Hash, HashPrefix};
+
+use anyhow::{bail, Context, Result};
+use parking_lot::{Mutex, ReentrantMutex, ReentrantMutexGuard};
+use rusqlite::{params, Connection, DatabaseName, OpenFlags, OptionalExtension};
+
+mod cache;
+mod diff;
+mod encoding;
+mod entry;
+mod events;
+mod fsck;
+mod history;
+mod listing;
+mod metadata;
+mod pack;
+mod profiles;
+mod query;
+mod schema;
+mod signing;
+mod snapshots;
+mod types;
+
+use cache::CodeCache;
+pub use cache::{CacheStats, DEFAULT_CACHE_CAPACITY};
+pub use diff::NameDiff;
+pub(crate) use encoding::decode_before_is_void;
+use encoding::{decode_stored, decompressed_len, encode_stored, hash_matches};
+pub use encoding::{Compression, FORMAT_VERSION};
+pub use entry::DEFAULT_ENTRY_POINT;
+pub use events::Change;
+use events::Changes;
+pub use fsck::IntegrityReport;
+pub use history::NameVersion;
+pub use listing::{FunctionEntry, FunctionFilter, FunctionOrder};
+pub use pack::NameConflict;
+pub use profiles::Profile;
+pub use query::{NamePattern, Query};
+pub use schema::SCHEMA_VERSION;
+pub use signing::{Signature, SignaturePolicy, SigningKey, VerifyingKey};
+
+pub use crate::vm::Metadata;
 
-use anyhow::{bail, Result};
-use rusqlite::{params, Connection, DatabaseName, OpenFlags};
+/// How `Database::open_with` opens a database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenMode {
+    Migrate,
+    NoMigrate,
+    ReadOnly,
+}
+
+/// How long to wait for another connection to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// A code database. It can be shared between threads, which take turns using its
+/// connection: each method holds the connection until it returns, so that what
+/// it checks still holds when it writes.
 #[derive(Debug)]
 pub struct Database {
     path: Option<PathBuf>,
-    conn: Connection,
+    conn: ReentrantMutex<Connection>,
+    verify_on_load: bool,
+    compression: Compression,
+    cache: Mutex<CodeCache>,
+    /// The keys that validly signed each code object checked by
+    /// `check_signatures`, or `None` if one of its signatures is invalid
+    signers: Mutex<HashMap<Hash, Option<Vec<VerifyingKey>>>>,
+    changes: Arc<Mutex<Changes>>,
+}
+
+/// Sizes of the stored code objects, from `Database::storage_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    pub objects: usize,
+    /// Objects stored compressed
+    pub compressed: usize,
+    /// Size of the blobs as stored
+    pub stored_bytes: usize,
+    /// Size of the blobs once decompressed
+    pub uncompressed_bytes: usize,
 }
 
 impl Database {
@@ -22,174 +100,464 @@ impl Database {
             bail!("cannot create new database: already exists");
         }
 
-        let db = Self {
-            path: Some(path.as_ref().to_path_buf()),
-            conn: Connection::open(path)?,
-        };
+        let db = Self::from_connection(
+            Some(path.as_ref().to_path_buf()),
+            Self::configure(Connection::open(path)?)?,
+        );
 
-        Database::build_schema(&db.conn)?;
+        Self::migrate_schema(&db.conn())?;
 
         Ok(db)
     }
 
-    fn build_schema(conn: &Connection) -> Result<()> {
-        // Create name table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS names (
-                id INTEGER PRIMARY KEY,
-                name VARCHAR(255) UNIQUE,
-                hash BLOB,
-                time DATETIME
-            );
-        "#,
-            [],
-        )?;
+    fn from_connection(path: Option<PathBuf>, conn: Connection) -> Self {
+        let changes = Arc::default();
+        Changes::hook(&changes, &conn);
+        Self {
+            path,
+            conn: ReentrantMutex::new(conn),
+            verify_on_load: false,
+            compression: Compression::default(),
+            cache: Mutex::new(CodeCache::new(DEFAULT_CACHE_CAPACITY)),
+            signers: Mutex::default(),
+            changes,
+        }
+    }
 
-        conn.execute("CREATE INDEX IF NOT EXISTS name_idx ON names (name);", [])?;
+    /// Use write-ahead logging, so that other processes can read the database
+    /// while it is written, and wait for them rather than failing when it is busy
+    fn configure(conn: Connection) -> Result<Connection> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(conn)
+    }
 
-        // Create code object table
-        conn.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS code_objs (
-                id INTEGER PRIMARY KEY,
-                hash BLOB UNIQUE,
-                code_obj BLOB UNIQUE,
-                is_main INTEGER DEFAULT (0),
-                time DATETIME
-            );
-        "#,
-            [],
-        )?;
-        conn.execute(
-            "CREATE INDEX IF NOT EXISTS hash_idx ON code_objs (hash);",
-            [],
+    /// The connection, for the current thread only until the guard is dropped.
+    /// A thread can take it again while it holds it.
+    fn conn(&self) -> ReentrantMutexGuard<'_, Connection> {
+        self.conn.lock()
+    }
+
+    /// Open an existing database, upgrading its schema if it was made by an older
+    /// version.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, OpenMode::Migrate)
+    }
+
+    /// Open an existing database without changing its schema, failing if it needs
+    /// upgrading
+    pub fn open_without_migrating<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, OpenMode::NoMigrate)
+    }
+
+    /// Open an existing database that can only be read, e.g. one shared by several
+    /// runners. Anything that writes to it fails, but a VM can run its code, and
+    /// its schema must be up to date.
+    pub fn open_readonly<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, OpenMode::ReadOnly)
+    }
+
+    fn open_with<P: AsRef<Path>>(path: P, mode: OpenMode) -> Result<Self> {
+        // SQLite's own locking isn't needed, as only one thread uses the
+        // connection at a time
+        let access = match mode {
+            OpenMode::ReadOnly => OpenFlags::SQLITE_OPEN_READ_ONLY,
+            _ => OpenFlags::SQLITE_OPEN_READ_WRITE,
+        };
+        let conn = Connection::open_with_flags(
+            path.as_ref(),
+            access | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
+        let conn = match mode {
+            // The journal mode is part of the file, so it can't be set
+            OpenMode::ReadOnly => {
+                conn.busy_timeout(BUSY_TIMEOUT)?;
+                conn
+            }
+            _ => Self::configure(conn)?,
+        };
+        let db = Self::from_connection(Some(path.as_ref().to_path_buf()), conn);
+        match mode {
+            OpenMode::Migrate => {
+                Self::migrate_schema(&db.conn())?;
+            }
+            OpenMode::NoMigrate | OpenMode::ReadOnly => Self::check_schema(&db.conn())?,
+        }
+        Ok(db)
+    }
 
-        // TODO: Create type table
+    /// Rewrite every code object stored in an older format with the current
+    /// `FORMAT_VERSION`, returning how many were upgraded. Rows keep the hash they
+    /// are stored under, which for format version 0 is the hash of the old
+    /// encoding.
+    pub fn migrate(&self) -> Result<usize> {
+        self.rewrite_code_objects(false)
+    }
 
-        Ok(())
+    /// Rewrite every code object with the current compression setting, and
+    /// `FORMAT_VERSION`, returning how many were rewritten
+    pub fn recompress(&self) -> Result<usize> {
+        self.rewrite_code_objects(true)
     }
 
-    /// Open an existing database.
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self {
-            path: Some(path.as_ref().to_path_buf()),
-            conn: Connection::open_with_flags(
-                path,
-                OpenFlags::SQLITE_OPEN_READ_WRITE
-                    | OpenFlags::SQLITE_OPEN_URI
-                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
-            )?,
-        })
+    /// Re-encode the code objects in an older format, or all of them
+    fn rewrite_code_objects(&self, all: bool) -> Result<usize> {
+        // Read and write in one transaction, so that rows inserted or removed in
+        // between aren't lost or brought back
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let old = tx
+            .prepare(
+                "SELECT id, format_version, compression, code_obj FROM code_objs WHERE format_version < ?1 OR ?2;",
+            )?
+            .query_map(params![FORMAT_VERSION, all], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, u32, u32, Vec<u8>)>>>()?;
+        for (id, version, compression, blob) in &old {
+            let obj = decode_stored(*version, *compression, blob)?;
+            let (blob, compression) = encode_stored(&obj, self.compression)?;
+            tx.execute(
+                "UPDATE code_objs SET code_obj = ?1, format_version = ?2, compression = ?3 WHERE id = ?4;",
+                params![blob, FORMAT_VERSION, compression, id],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(old.len())
+    }
+
+    /// How much space the stored code objects take, compressed and not
+    pub fn storage_stats(&self) -> Result<StorageStats> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT compression, code_obj FROM code_objs;")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(u32, Vec<u8>)>>>()?;
+
+        let mut stats = StorageStats::default();
+        for (compression, blob) in rows {
+            stats.objects += 1;
+            stats.compressed += (compression != 0) as usize;
+            stats.stored_bytes += blob.len();
+            stats.uncompressed_bytes += decompressed_len(compression, &blob)?;
+        }
+        Ok(stats)
     }
 
     /// Create an in-memory database.
     pub fn temp() -> Result<Self> {
-        let db = Self {
-            path: None,
-            conn: Connection::open_in_memory().unwrap(),
-        };
-        Self::build_schema(&db.conn)?;
+        let db = Self::from_connection(None, Connection::open_in_memory()?);
+        Self::migrate_schema(&db.conn())?;
         Ok(db)
     }
 
+    /// Also verify code objects when they are read back out of the database, e.g.
+    /// to catch objects stored by an older version without verification.
+    pub fn set_verify_on_load(&mut self, verify_on_load: bool) {
+        self.verify_on_load = verify_on_load;
+        // Cached objects may not have been verified
+        self.cache.lock().clear();
+    }
+
+    /// Compress code objects inserted from now on this way. Objects already stored
+    /// are rewritten by `recompress`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Keep up to `capacity` decoded code objects in memory, the most recently
+    /// used. Zero turns the cache off.
+    pub fn set_cache_capacity(&self, capacity: usize) {
+        self.cache.lock().set_capacity(capacity);
+    }
+
+    /// Hits and misses of the code object cache, counted by `get_code_object`
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().stats()
+    }
+
     /// Delete a database
     pub fn delete(self) -> Result<()> {
-        if let Some(path) = self.path {
+        // Closing the connection removes the write-ahead log
+        let Database { path, conn, .. } = self;
+        drop(conn);
+        if let Some(path) = path {
             fs::remove_file(path)?;
         }
-        // Now self gets dropped, closing self.conn
         Ok(())
     }
 
     fn insert_code_object(&self, code_obj: &CodeObject, is_main: bool) -> Result<Hash> {
-        let obj = rmp_serde::to_vec(code_obj)?;
+        verify(code_obj)?;
+
+        let (obj, compression) = encode_stored(code_obj, self.compression)?;
         let hash = code_obj.hash()?;
 
-        match self.conn.execute(
-            "INSERT INTO code_objs (hash, code_obj, is_main, time) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP);",
-            params![hash, obj, is_main as u8],
+        let conn = self.conn();
+        match conn.execute(
+            "INSERT INTO code_objs (hash, code_obj, is_main, time, format_version, compression) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP, ?4, ?5);",
+            params![hash, obj, is_main as u8, FORMAT_VERSION, compression],
         ) {
-            Ok(_) => Ok(()),
-            Err(e) if e.to_string().contains("UNIQUE constraint failed") => Ok(()),
-            Err(e) => Err(e)
-        }?;
+            Ok(_) => {
+                Self::insert_deps(&conn, code_obj)?;
+                self.notify(Change::Inserted(hash));
+            }
+            Err(e) if e.to_string().contains("UNIQUE constraint failed") => (),
+            Err(e) => return Err(e.into()),
+        }
 
         Ok(hash)
     }
 
+    /// Record the code objects that a code object loads
+    fn insert_deps(conn: &Connection, code_obj: &CodeObject) -> Result<()> {
+        let hash = code_obj.hash()?;
+        for dep in solver::references(code_obj) {
+            conn.execute(
+                "INSERT OR IGNORE INTO deps (hash, dep) VALUES (?1, ?2);",
+                params![hash, dep],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The stored code objects that load `hash`, from the recorded dependencies,
+    /// sorted. `find_functions` finds the named ones.
+    pub fn get_referrers(&self, hash: &Hash) -> Result<Vec<Hash>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM deps WHERE dep = ?1 AND hash != ?1 ORDER BY hash;",
+        )?;
+        let hashes = stmt.query_map([hash], |row| row.get(0))?;
+        Ok(hashes.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Insert a code object under a name. If the name is already taken by a
+    /// different code object, it points at this one now, and the other is kept as
+    /// an earlier version of the name. A pinned name can't be moved, so that is an
+    /// error.
     pub fn insert_code_object_with_name(
         &self,
         code_obj: &CodeObject,
         name: &str,
     ) -> Result<Hash> {
-        if !is_valid_name(name) {
-            bail!("cannot insert code object with invalid name '{name}'");
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let hash = self.insert_named(code_obj, name)?;
+        tx.commit()?;
+
+        Ok(hash)
+    }
+
+    /// Insert code objects under their names, as `insert_code_object_with_name`
+    /// does, returning their hashes. If any can't be inserted, none are.
+    pub fn insert_batch(&self, code_objs: &[(String, CodeObject)]) -> Result<Vec<Hash>> {
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let hashes = code_objs
+            .iter()
+            .map(|(name, code_obj)| self.insert_named(code_obj, name))
+            .collect::<Result<_>>()?;
+        tx.commit()?;
+
+        Ok(hashes)
+    }
+
+    /// Insert the functions of a parsed file, e.g. from `DynCallResolver`, in one
+    /// transaction, returning the hash of each name. Every name and code object is
+    /// checked first, so that an error says which function is bad.
+    pub fn insert_parses(
+        &self,
+        functions: impl IntoIterator<Item = (String, CodeObject)>,
+    ) -> Result<HashMap<String, Hash>> {
+        self.insert_parses_with_metadata(functions, &HashMap::new())
+    }
+
+    /// Like `insert_parses`, also setting the metadata of each function that has
+    /// some, in the same transaction
+    pub fn insert_parses_with_metadata(
+        &self,
+        functions: impl IntoIterator<Item = (String, CodeObject)>,
+        metadata: &HashMap<String, Metadata>,
+    ) -> Result<HashMap<String, Hash>> {
+        let mut functions = functions.into_iter().collect::<Vec<_>>();
+        functions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, code_obj) in &functions {
+            if !is_valid_qualified_name(name) {
+                bail!("cannot insert function with invalid name '{name}'");
+            }
+            verify(code_obj)
+                .with_context(|| format!("cannot insert function '{name}'"))?;
         }
 
-        let hash = self.insert_code_object(code_obj, name == "main")?;
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let hashes = functions
+            .iter()
+            .map(|(name, code_obj)| self.insert_named(code_obj, name))
+            .collect::<Result<Vec<_>>>()?;
+        for ((name, _), hash) in functions.iter().zip(&hashes) {
+            match metadata.get(name) {
+                Some(metadata) if !metadata.is_empty() => {
+                    Self::write_metadata(&tx, hash, metadata)?
+                }
+                _ => (),
+            }
+        }
+        tx.commit()?;
 
-        self.conn.execute(
-            "INSERT INTO names (name, hash, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
-            params![name, hash],
-        )?;
+        Ok(functions
+            .into_iter()
+            .map(|(name, _)| name)
+            .zip(hashes)
+            .collect())
+    }
 
+    /// Insert a code object under a name, outside of a transaction
+    fn insert_named(&self, code_obj: &CodeObject, name: &str) -> Result<Hash> {
+        if !is_valid_qualified_name(name) {
+            bail!("cannot insert code object with invalid name '{name}'");
+        }
+
+        let hash = self.insert_code_object(code_obj, false)?;
+        if !Self::set_name(&self.conn(), name, &hash)? {
+            bail!("cannot insert code object as '{name}': the name is pinned");
+        }
+        self.notify_named(name, &hash);
         Ok(hash)
     }
 
     /// Allow multiple names to point to the same hash.
     pub fn create_alias(&self, name: &str, hash: &Hash) -> Result<()> {
+        let conn = self.conn();
+        if !is_valid_qualified_name(name) {
+            bail!("cannot create alias with invalid name '{name}'");
+        }
+
         // Check that the hash is in the thing
         let obj = self.get_code_object(hash)?;
         if obj.hash()? != *hash {
-            bail!(
-                "cannot create alias to unknown code object 0x'{}'",
-                hex::encode(hash)
-            );
+            bail!("cannot create alias to unknown code object '{hash}'");
         }
 
-        self.conn.execute(
-            "INSERT INTO names (name, hash, time) VALUES (?1, ?2, CURRENT_TIMESTAMP)",
-            params![name, hash],
+        if self.get_code_object_by_name(name).is_ok() {
+            bail!("cannot create alias '{name}': the name is already taken");
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        Self::set_name(&tx, name, hash)?;
+        self.notify_named(name, hash);
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Give a function a new name, keeping its code object and any other names
+    pub fn rename(&self, old: &str, new: &str) -> Result<()> {
+        let conn = self.conn();
+        if !is_valid_qualified_name(new) {
+            bail!("cannot rename '{old}' to invalid name '{new}'");
+        }
+        let (hash, _) = self.get_code_object_by_name(old)?;
+        if self.get_code_object_by_name(new).is_ok() {
+            bail!("cannot rename '{old}' to '{new}': the name is already taken");
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "UPDATE names SET name = ?1, time = CURRENT_TIMESTAMP WHERE name = ?2;",
+            params![new, old],
         )?;
+        tx.execute(
+            "UPDATE name_versions SET name = ?1 WHERE name = ?2;",
+            params![new, old],
+        )?;
+        Self::update_is_main(&tx, &hash)?;
+        self.notify(Change::Unnamed(old.to_string()));
+        self.notify_named(new, &hash);
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Remove a name of a code object that has others. Use `remove_name` to
+    /// remove the last one.
+    pub fn remove_alias(&self, name: &str) -> Result<()> {
+        let _conn = self.conn();
+        let (hash, _) = self.get_code_object_by_name(name)?;
+        if self.get_names_of_hash(&hash)?.len() == 1 {
+            bail!("cannot remove alias '{name}': it is the only name of {hash}");
+        }
+        self.unname(name)
+    }
+
+    /// Remove a name, keeping its code object stored even if it has no other
+    pub fn unname(&self, name: &str) -> Result<()> {
+        let conn = self.conn();
+        let (hash, _) = self.get_code_object_by_name(name)?;
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM names WHERE name = ?1;", [name])?;
+        tx.execute("DELETE FROM name_versions WHERE name = ?1;", [name])?;
+        Self::update_is_main(&tx, &hash)?;
+        self.notify(Change::Unnamed(name.to_string()));
+        tx.commit()?;
 
         Ok(())
     }
 
+    /// A code object is the main object while one of its names is `main`
+    fn update_is_main(conn: &Connection, hash: &Hash) -> Result<()> {
+        conn.execute(
+            "UPDATE code_objs SET is_main = EXISTS (SELECT 1 FROM names WHERE name = 'main' AND hash = ?1) WHERE hash = ?1;",
+            [hash],
+        )?;
+        Ok(())
+    }
+
     pub fn get_code_object(&self, hash: &Hash) -> Result<CodeObject> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT code_obj FROM code_objs WHERE hash = (?1);")?;
+        if let Some(obj) = self.cache.lock().get(hash) {
+            return Ok(obj);
+        }
 
-        let query_result = stmt.query_map([hash], |row| {
-            let code_obj_blob: Vec<u8> = row.get(0)?;
-            Ok(rmp_serde::from_slice::<CodeObject>(&code_obj_blob))
-        })?;
+        let row = self
+            .conn()
+            .query_row(
+                "SELECT format_version, compression, code_obj FROM code_objs WHERE hash = (?1);",
+                [hash],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, Vec<u8>>(2)?)),
+            )
+            .optional()?;
+        // A row that is there but can't be decoded isn't missing
+        let Some((version, compression, code_obj_blob)) = row else {
+            bail!("query failed: no code object with hash {hash}");
+        };
+        let obj = decode_stored(version, compression, &code_obj_blob)
+            .with_context(|| format!("cannot decode code object {hash}"))?;
 
-        let obj = query_result
-            .into_iter()
-            .flatten()
-            .flatten()
-            .next()
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "query failed: no code object with hash 0x{}",
-                    hex::encode(hash)
-                )
-            });
-        obj
+        if !hash_matches(&obj, hash)? {
+            bail!("code object {hash} is corrupt: its contents have a different hash");
+        }
+        if self.verify_on_load {
+            verify(&obj)?;
+        }
+        self.cache.lock().insert(*hash, obj.clone());
+        Ok(obj)
     }
 
     pub fn get_main_object(&self) -> Result<(Hash, CodeObject)> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT hash, code_obj FROM code_objs WHERE is_main = TRUE;")?;
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash, format_version, compression, code_obj FROM code_objs WHERE is_main = TRUE;",
+        )?;
 
         let query_result = stmt.query_map([], |row| {
-            let hash: Vec<u8> = row.get(0)?;
-            let code_obj_blob: Vec<u8> = row.get(1)?;
-            Ok((hash, rmp_serde::from_slice::<CodeObject>(&code_obj_blob)))
+            let hash: Hash = row.get(0)?;
+            let version: u32 = row.get(1)?;
+            let compression: u32 = row.get(2)?;
+            let code_obj_blob: Vec<u8> = row.get(3)?;
+            Ok((hash, decode_stored(version, compression, &code_obj_blob)))
         })?;
 
         let (hash, obj) = query_result
@@ -197,17 +565,23 @@ impl Database {
             .flatten()
             .next()
             .ok_or_else(|| anyhow::anyhow!("query failed: no main object found"))?;
+        let obj = obj?;
 
-        Ok((hash_from_vec(hash)?, obj?))
+        if !hash_matches(&obj, &hash)? {
+            bail!("code object {hash} is corrupt: its contents have a different hash");
+        }
+        if self.verify_on_load {
+            verify(&obj)?;
+        }
+        Ok((hash, obj))
     }
 
     pub fn get_code_object_by_name(&self, name: &str) -> Result<(Hash, CodeObject)> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT hash FROM names WHERE name = ?1;")?;
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT hash FROM names WHERE name = ?1;")?;
 
         let query_result = stmt.query_map([name], |row| {
-            let hash: Vec<u8> = row.get(0)?;
+            let hash: Hash = row.get(0)?;
             Ok(hash)
         })?;
 
@@ -215,15 +589,15 @@ impl Database {
             Some(h) => h?,
             None => bail!("query failed: no code object with name '{name}'"),
         };
-        let hash = hash_from_vec(hash)?;
 
         Ok((hash, self.get_code_object(&hash)?))
     }
 
+    /// The first name given to a hash. See `get_names_of_hash` for all of them.
     pub fn get_name_of_hash(&self, hash: &Hash) -> Result<Option<String>> {
-        let mut stmt = self
-            .conn
-            .prepare("SELECT name FROM names WHERE hash = ?1;")?;
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT name FROM names WHERE hash = ?1 ORDER BY id;")?;
 
         let query_result = stmt.query_map([hash], |row| {
             let name = row.get(0)?;
@@ -234,38 +608,252 @@ impl Database {
         Ok(res?)
     }
 
-    pub fn get_functions(&self) -> Result<Vec<(String, Hash)>> {
-        let mut stmt = self.conn.prepare("SELECT name, hash FROM names;")?;
+    /// Every name of a hash, sorted
+    pub fn get_names_of_hash(&self, hash: &Hash) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT name FROM names WHERE hash = ?1 ORDER BY name;")?;
+        let names = stmt.query_map([hash], |row| row.get(0))?;
+        Ok(names.collect::<Result<_, _>>()?)
+    }
 
-        let query_result = stmt.query_map([], |row| {
-            let name = row.get(0)?;
-            let hash = row.get(1)?;
-            Ok((name, hash))
-        })?;
-        let res = query_result.collect::<rusqlite::Result<_>>()?;
-        Ok(res)
+    /// The hash of a function given by name, or by hash prefix starting with 0x
+    pub fn resolve(&self, name_or_hash: &str) -> Result<Hash> {
+        match self.get_code_object_by_name(name_or_hash) {
+            Ok((hash, _)) => Ok(hash),
+            Err(_) if name_or_hash.starts_with("0x") => {
+                self.resolve_hash_prefix(&name_or_hash.parse::<HashPrefix>()?)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Expand an abbreviated hash to the full hash of a stored code object. Fails if
+    /// the prefix matches no object or is ambiguous.
+    pub fn resolve_hash_prefix(&self, prefix: &HashPrefix) -> Result<Hash> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM code_objs WHERE lower(hex(hash)) LIKE ?1 || '%';",
+        )?;
+
+        let hashes = stmt
+            .query_map([prefix.as_hex()], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<Hash>>>()?;
+
+        match hashes.as_slice() {
+            [hash] => Ok(*hash),
+            [] => bail!("no code object with hash prefix {prefix}"),
+            _ => bail!(
+                "hash prefix {prefix} is ambiguous: matches {} code objects",
+                hashes.len()
+            ),
+        }
+    }
+
+    /// Whether a code object is stored under `hash`
+    pub fn has_code_object(&self, hash: &Hash) -> Result<bool> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT 1 FROM code_objs WHERE hash = ?1;")?;
+        Ok(stmt.exists([hash])?)
+    }
+
+    /// Whether `name` names a code object
+    pub fn has_name(&self, name: &str) -> Result<bool> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT 1 FROM names WHERE name = ?1;")?;
+        Ok(stmt.exists([name])?)
+    }
+
+    /// The hash of every stored code object, named or not
+    pub fn get_hashes(&self) -> Result<Vec<Hash>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT hash FROM code_objs;")?;
+        let hashes = stmt.query_map([], |row| row.get(0))?;
+        Ok(hashes.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Remove a code object, everything stored about it, and the versions of other
+    /// names that pointed to it, returning the hashes removed. Fails if another
+    /// stored code object loads it, unless `cascade` is set, in which case those
+    /// are removed too, and so on.
+    pub fn remove_code_object(&self, hash: &Hash, cascade: bool) -> Result<Vec<Hash>> {
+        let _conn = self.conn();
+        let removed = self.plan_removal(hash, cascade)?;
+        self.remove_code_objects(&removed)?;
+        Ok(removed)
+    }
+
+    /// Remove code objects as `remove_code_object` does, without checking whether
+    /// others load them
+    pub fn remove_code_objects(&self, removed: &[Hash]) -> Result<()> {
+        let conn = self.conn();
+        for hash in removed {
+            self.get_code_object(hash)?;
+        }
+        let tx = conn.unchecked_transaction()?;
+        for hash in removed {
+            for name in self.get_names_of_hash(hash)? {
+                self.notify(Change::Unnamed(name));
+            }
+            tx.execute("DELETE FROM names WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM name_versions WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM metadata WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM signatures WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM profiles WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM entry_points WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM deps WHERE hash = ?1;", [hash])?;
+            tx.execute("DELETE FROM code_objs WHERE hash = ?1;", [hash])?;
+            self.notify(Change::Removed(*hash));
+        }
+        tx.execute(
+            "DELETE FROM name_versions WHERE name NOT IN (SELECT name FROM names);",
+            [],
+        )?;
+        tx.commit()?;
+
+        let mut cache = self.cache.lock();
+        removed.iter().for_each(|hash| cache.remove(hash));
+        let mut signers = self.signers.lock();
+        removed.iter().for_each(|hash| {
+            signers.remove(hash);
+        });
+
+        Ok(())
+    }
+
+    /// The hashes `remove_code_object` would remove, without removing them, or the
+    /// error it would fail with
+    pub fn plan_removal(&self, hash: &Hash, cascade: bool) -> Result<Vec<Hash>> {
+        self.get_code_object(hash)?;
+
+        let mut removed = vec![*hash];
+        let mut i = 0;
+        while let Some(hash) = removed.get(i).copied() {
+            let referrers = solver::referrers(self, &hash)?
+                .into_iter()
+                .filter(|referrer| !removed.contains(referrer))
+                .collect::<Vec<_>>();
+            if !cascade {
+                if let Some(referrer) = referrers.first() {
+                    let name = self
+                        .get_name_of_hash(referrer)?
+                        .unwrap_or_else(|| referrer.to_string());
+                    bail!("cannot remove code object {hash}: it is used by '{name}'");
+                }
+            }
+            removed.extend(referrers);
+            i += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove a name. If it was the code object's last name, the code object is
+    /// removed too, as `remove_code_object` does, and the hashes removed are
+    /// returned.
+    pub fn remove_name(&self, name: &str, cascade: bool) -> Result<Vec<Hash>> {
+        // Held so that the names can't change between counting and removing them
+        let _conn = self.conn();
+        let (hash, _) = self.get_code_object_by_name(name)?;
+        if self.get_names_of_hash(&hash)?.len() == 1 {
+            return self.remove_code_object(&hash, cascade);
+        }
+        self.remove_alias(name)?;
+        Ok(vec![])
+    }
+
+    /// Every named function, sorted by name. Use `list_functions` to filter them
+    /// or take them a page at a time.
+    pub fn get_functions(&self) -> Result<Vec<(String, Hash)>> {
+        self.list_functions(&FunctionFilter::default())
     }
 
     pub fn save_to_disk<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        Ok(self.conn.backup(DatabaseName::Main, path, None)?)
+        Ok(self.conn().backup(DatabaseName::Main, path, None)?)
     }
 
     /// Print the contents of a database, in compilable form
     pub fn disassemble(&self) -> Result<String> {
+        self.disassemble_with(DisOptions::default())
+    }
+
+    /// Describe every named function as JSON, in an array, with its metadata if it
+    /// has any
+    pub fn disassemble_json(&self) -> Result<serde_json::Value> {
         self.get_functions()?
             .into_iter()
-            .try_fold(String::new(), |acc, (name, hash)| {
+            .map(|(name, hash)| {
+                let mut json = disassemble_function_json(
+                    &name,
+                    &hash,
+                    &self.get_code_object(&hash)?,
+                )?;
+                if let Some(metadata) = self.get_metadata(&hash)? {
+                    json["metadata"] = serde_json::to_value(metadata)?;
+                }
+                Ok(json)
+            })
+            .collect()
+    }
+
+    /// Like `disassemble`, with options for how to write the code
+    pub fn disassemble_with(&self, opts: DisOptions) -> Result<String> {
+        // Functions in a module are written after a `.module` directive
+        let mut module = None;
+        self.get_functions()?.into_iter().try_fold(
+            String::new(),
+            |mut acc, (name, hash)| {
+                let (func_module, name) = match name.rsplit_once("::") {
+                    Some((func_module, name)) => (Some(func_module.to_string()), name),
+                    None => (None, name.as_str()),
+                };
+                if func_module != module {
+                    match &func_module {
+                        Some(func_module) => acc += &format!(".module {func_module}\n\n"),
+                        None => acc += ".module\n\n",
+                    }
+                    module = func_module;
+                }
+
+                // In a module, `load_dyn` of an unqualified name refers to the
+                // module's function of that name if there is one, so a top-level
+                // function it shadows has to stay a hash
+                let names = |hash: &Hash| {
+                    let mut names = self.get_names_of_hash(hash).unwrap_or_default();
+                    if let (FuncRefs::Named, Some(module)) = (opts.refs, &module) {
+                        names.retain(|name| {
+                            name.contains("::")
+                                || self
+                                    .get_code_object_by_name(&format!("{module}::{name}"))
+                                    .is_err()
+                        });
+                    }
+                    names
+                };
+                let metadata = self.get_metadata(&hash)?;
                 self.get_code_object(&hash)
-                    .and_then(|obj| disassemble_function(&name, &hash, &obj))
+                    .and_then(|obj| {
+                        disassemble_function_with(
+                            name,
+                            &hash,
+                            &obj,
+                            metadata.as_ref(),
+                            &names,
+                            opts,
+                        )
+                    })
                     .map(|disassembled| acc + &disassembled + "\n")
-            })
+            },
+        )
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use crate::bytecode::Instr;
-    use crate::vm::tests::{init_code_obj, init_nondet_code_obj};
+    use crate::vm::tests::{
+        init_code_obj, init_code_obj_with_pool, init_nondet_code_obj,
+    };
+    use crate::vm::Value;
 
     use super::*;
 
@@ -277,7 +865,7 @@ pub mod tests {
         if path.exists() {
             fs::remove_file(path).unwrap();
         }
-        Database::new(&path).unwrap();
+        Database::new(path).unwrap();
     }
 
     #[test]
@@ -304,6 +892,28 @@ pub mod tests {
         db.insert_code_object(&obj, false).unwrap();
         let res = db.get_code_object(&obj.hash().unwrap()).unwrap();
         assert_eq!(res.hash().unwrap(), obj.hash().unwrap());
+
+        // A row that can't be decoded is reported as such, not as missing
+        let other = init_code_obj(bytecode![Instr::Nop, Instr::Nop]);
+        let hash = db.insert_code_object(&other, false).unwrap();
+        db.conn()
+            .execute(
+                "UPDATE code_objs SET format_version = 99 WHERE hash = ?1;",
+                [hash],
+            )
+            .unwrap();
+        let err = db.get_code_object(&hash).unwrap_err();
+        assert!(format!("{err:#}").contains("format version 99"), "{err:#}");
+        db.conn()
+            .execute(
+                "UPDATE code_objs SET code_obj = x'00', format_version = ?2 WHERE hash = ?1;",
+                params![hash, FORMAT_VERSION],
+            )
+            .unwrap();
+        let err = db.get_code_object(&hash).unwrap_err();
+        assert!(err.to_string().contains("cannot decode"), "{err}");
+        let missing = db.get_code_object(&Hash::digest(b"")).unwrap_err();
+        assert!(missing.to_string().contains("no code object"), "{missing}");
     }
 
     #[test]
@@ -324,6 +934,174 @@ pub mod tests {
         // invalid name case
     }
 
+    #[test]
+    fn test_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Database>();
+
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(dir.path().join("shared.db")).unwrap();
+        let mode: String = db
+            .conn()
+            .query_row("PRAGMA journal_mode;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        // Threads can write and read through the same handle
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let db = &db;
+                s.spawn(move || {
+                    let code = bytecode![Instr::LoadArg(i % 2), Instr::ReturnVal];
+                    let hash = db
+                        .insert_code_object_with_name(
+                            &init_code_obj(code),
+                            &format!("f{i}"),
+                        )
+                        .unwrap();
+                    for _ in 0..10 {
+                        assert_eq!(
+                            db.get_code_object(&hash).unwrap().hash().unwrap(),
+                            hash
+                        );
+                    }
+                });
+            }
+        });
+        assert_eq!(db.get_functions().unwrap().len(), 4);
+        assert_eq!(db.get_hashes().unwrap().len(), 2);
+
+        // And another connection sees what they wrote
+        let other = Database::open(dir.path().join("shared.db")).unwrap();
+        assert_eq!(other.get_functions().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_cache() {
+        let db = Database::temp().unwrap();
+        let objs = (0..3)
+            .map(|i| {
+                init_code_obj(bytecode![
+                    Instr::LoadArg(i % 2),
+                    Instr::LoadLit(i / 2),
+                    Instr::Pop,
+                    Instr::ReturnVal
+                ])
+            })
+            .collect::<Vec<_>>();
+        let hashes = objs
+            .iter()
+            .enumerate()
+            .map(|(i, obj)| {
+                db.insert_code_object_with_name(obj, &format!("f{i}"))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let stats = db.cache_stats();
+        for _ in 0..3 {
+            assert_eq!(
+                db.get_code_object(&hashes[0]).unwrap().hash().unwrap(),
+                hashes[0]
+            );
+        }
+        let after = db.cache_stats();
+        assert_eq!(after.misses - stats.misses, 1);
+        assert_eq!(after.hits - stats.hits, 2);
+
+        // The least recently used object is evicted
+        db.set_cache_capacity(2);
+        db.get_code_object(&hashes[1]).unwrap();
+        db.get_code_object(&hashes[0]).unwrap();
+        db.get_code_object(&hashes[2]).unwrap();
+        assert_eq!(db.cache_stats().len, 2);
+        let misses = db.cache_stats().misses;
+        db.get_code_object(&hashes[0]).unwrap();
+        assert_eq!(db.cache_stats().misses, misses);
+        db.get_code_object(&hashes[1]).unwrap();
+        assert_eq!(db.cache_stats().misses, misses + 1);
+
+        // Removed objects are not found in the cache
+        db.remove_code_object(&hashes[1], false).unwrap();
+        assert!(db.get_code_object(&hashes[1]).is_err());
+
+        db.set_cache_capacity(0);
+        assert_eq!(db.cache_stats().len, 0);
+        db.get_code_object(&hashes[0]).unwrap();
+        assert_eq!(db.cache_stats().len, 0);
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let db = Database::temp().unwrap();
+        let f = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let g = init_code_obj(bytecode![Instr::LoadArg(1), Instr::ReturnVal]);
+        let hashes = db
+            .insert_batch(&[("f".to_string(), f.clone()), ("m::g".to_string(), g)])
+            .unwrap();
+        assert_eq!(db.get_code_object_by_name("m::g").unwrap().0, hashes[1]);
+
+        // Nothing is inserted if one object doesn't verify, or has a bad name
+        let h = init_code_obj(bytecode![Instr::Return]);
+        let bad = init_code_obj(bytecode![Instr::LoadLit(10), Instr::ReturnVal]);
+        assert!(db
+            .insert_batch(&[("h".to_string(), h.clone()), ("bad".to_string(), bad)])
+            .is_err());
+        assert!(db
+            .insert_batch(&[("h".to_string(), h.clone()), ("b d".to_string(), f)])
+            .is_err());
+        assert!(db.get_code_object_by_name("h").is_err());
+        assert!(db.get_code_object(&h.hash().unwrap()).is_err());
+        assert_eq!(db.get_functions().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_insert_parses() {
+        let db = Database::temp().unwrap();
+        let f = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let g = init_code_obj(bytecode![Instr::LoadArg(1), Instr::ReturnVal]);
+        let parses = HashMap::from([("f".to_string(), f.clone()), ("g".to_string(), g)]);
+        let hashes = db.insert_parses(parses).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes["f"], f.hash().unwrap());
+        assert_eq!(db.get_code_object_by_name("g").unwrap().0, hashes["g"]);
+
+        // The error names the bad function, and nothing is inserted
+        let bad = init_code_obj(bytecode![Instr::LoadLit(10), Instr::ReturnVal]);
+        let h = init_code_obj(bytecode![Instr::Return]);
+        let err = db
+            .insert_parses([("h".to_string(), h.clone()), ("bad".to_string(), bad)])
+            .unwrap_err();
+        assert!(err.to_string().contains("'bad'"));
+        assert!(db
+            .insert_parses([("h".to_string(), h.clone()), ("b d".to_string(), f)])
+            .is_err());
+        assert!(db.get_code_object(&h.hash().unwrap()).is_err());
+
+        // Metadata is set in the same transaction
+        let metadata = Metadata {
+            doc: Some("Returns.".to_string()),
+            ..Default::default()
+        };
+        let metadata = HashMap::from([("h".to_string(), metadata)]);
+        db.conn()
+            .execute(
+                "CREATE TEMP TRIGGER no_metadata BEFORE INSERT ON metadata BEGIN SELECT RAISE(ABORT, 'no metadata'); END;",
+                [],
+            )
+            .unwrap();
+        assert!(db
+            .insert_parses_with_metadata([("h".to_string(), h.clone())], &metadata)
+            .is_err());
+        assert!(db.get_code_object(&h.hash().unwrap()).is_err());
+        db.conn().execute("DROP TRIGGER no_metadata;", []).unwrap();
+        let hashes = db
+            .insert_parses_with_metadata([("h".to_string(), h)], &metadata)
+            .unwrap();
+        let stored = db.get_metadata(&hashes["h"]).unwrap().unwrap();
+        assert_eq!(stored.doc, metadata["h"].doc);
+    }
+
     #[test]
     fn test_get_codeobj_name() {
         let db = Database::temp().unwrap();
@@ -345,6 +1123,41 @@ pub mod tests {
         assert_eq!(hash, get_hash);
     }
 
+    #[test]
+    fn test_rename() {
+        let db = Database::temp().unwrap();
+        let main = db
+            .insert_code_object_with_name(
+                &init_code_obj(bytecode![Instr::Return]),
+                "main",
+            )
+            .unwrap();
+        let obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::ReturnVal]);
+        let f = db.insert_code_object_with_name(&obj, "f").unwrap();
+
+        db.rename("f", "m::g").unwrap();
+        assert_eq!(db.get_code_object_by_name("m::g").unwrap().0, f);
+        assert!(db.get_code_object_by_name("f").is_err());
+        assert!(db.rename("m::g", "not valid").is_err());
+        assert!(db.rename("m::g", "main").is_err());
+        assert!(db.rename("f", "h").is_err());
+        assert!(db.create_alias("not valid", &f).is_err());
+
+        // The main object follows the name
+        db.rename("main", "entry").unwrap();
+        assert!(db.get_main_object().is_err());
+        db.create_alias("main", &main).unwrap();
+        assert_eq!(db.get_main_object().unwrap().0, main);
+
+        db.remove_alias("main").unwrap();
+        assert!(db.get_main_object().is_err());
+        assert_eq!(
+            db.get_names_of_hash(&main).unwrap(),
+            vec!["entry".to_string()]
+        );
+        assert!(db.remove_alias("entry").is_err());
+    }
+
     #[test]
     fn test_name_of_hash() {
         let db = Database::temp().unwrap();
@@ -354,5 +1167,328 @@ pub mod tests {
 
         let name = db.get_name_of_hash(&hash).unwrap();
         assert_eq!(name, Some("func_name".to_string()));
+
+        // Aliases are only in the list of every name
+        db.create_alias("a_alias", &hash).unwrap();
+        assert_eq!(db.get_name_of_hash(&hash).unwrap(), name);
+        assert_eq!(
+            db.get_names_of_hash(&hash).unwrap(),
+            vec!["a_alias".to_string(), "func_name".to_string()]
+        );
+        assert!(db.get_names_of_hash(&Hash::digest(b"")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_disassemble_func_refs() {
+        use crate::asm::assembler::Assembler;
+
+        let db = Database::temp().unwrap();
+        let source = [
+            "$one 0:",
+            "    .lit 1",
+            "    load_lit 0",
+            "    ret_val",
+            "$two 0:",
+            "    .lit 2",
+            "    load_lit 0",
+            "    ret_val",
+            ".module m",
+            "$one 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    ret_val",
+            ".module",
+            "$main 0:",
+            "    load_dyn $one",
+            "    call",
+            "    load_dyn $two",
+            "    call",
+            "    add",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let two = db.get_code_object_by_name("two").unwrap().0;
+        db.create_alias("deux", &two).unwrap();
+        let one = db.get_code_object_by_name("one").unwrap().0;
+        // A module function calling the top-level function it shadows
+        let source = format!("$three 0:\n    load_func {one}\n    call\n    ret_val\n");
+        let obj = crate::asm::parser::Parser::parse_str(&source).unwrap();
+        db.insert_code_object_with_name(&obj[0].code_obj, "m::three")
+            .unwrap();
+        assert_eq!(
+            db.get_names_of_hash(&two).unwrap(),
+            vec!["deux".to_string(), "two".to_string()]
+        );
+
+        let dis = db.disassemble().unwrap();
+        assert!(dis.contains(&format!("load_func {one} # one\n")));
+        assert!(dis.contains(&format!("load_func {two} # deux, two\n")));
+
+        // A function with more than one name, or one shadowed by a module
+        // function, stays a hash
+        let opts = DisOptions {
+            refs: FuncRefs::Named,
+            ..Default::default()
+        };
+        let dis = db.disassemble_with(opts).unwrap();
+        assert!(dis.contains("load_dyn $one\n"));
+        assert!(dis.contains(&format!("load_func {two} # deux, two\n")));
+        assert!(dis.contains(&format!("load_func {one}\n")));
+
+        let reparsed = Database::temp().unwrap();
+        Assembler::new(&reparsed).assemble_str(&dis).unwrap();
+        for (name, hash) in db.get_functions().unwrap() {
+            assert_eq!(reparsed.get_code_object_by_name(&name).unwrap().0, hash);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_stack() {
+        use crate::asm::assembler::Assembler;
+
+        let db = Database::temp().unwrap();
+        let source = [
+            "$inc 1:",
+            "    .lit 1",
+            "    load_arg 0",
+            "    load_lit 0",
+            "    add",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 2",
+            "    load_lit 0",
+            "    load_dyn $inc",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let inc = db.get_code_object_by_name("inc").unwrap().0;
+
+        let opts = DisOptions {
+            stack: true,
+            ..Default::default()
+        };
+        let dis = db.disassemble_with(opts).unwrap();
+        assert!(dis.contains("    load_arg 0 # stack 0\n"));
+        assert!(dis.contains("    add # stack 2: ?, i32\n"));
+        assert!(dis.contains(&format!("    load_func {inc} # inc; stack 1: i32\n")));
+        assert!(dis.contains("    ret_val # stack ?\n"));
+
+        // The comments don't change the code
+        let reparsed = Database::temp().unwrap();
+        Assembler::new(&reparsed).assemble_str(&dis).unwrap();
+        for (name, hash) in db.get_functions().unwrap() {
+            assert_eq!(reparsed.get_code_object_by_name(&name).unwrap().0, hash);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_json() {
+        use crate::asm::assembler::Assembler;
+
+        let db = Database::temp().unwrap();
+        let source = [
+            "$main 0:",
+            "    .lit 2u8",
+            "    .lit [1, \"a\"]",
+            "    load_lit 0",
+            "top:",
+            "    dup",
+            "    jmp_t top",
+            "    load_lit 1",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let hash = db.get_code_object_by_name("main").unwrap().0;
+
+        let json = db.disassemble_json().unwrap();
+        let main = &json[0];
+        assert_eq!(main["name"], "main");
+        assert_eq!(main["hash"], hash.to_string());
+        assert_eq!(main["argcount"], 0);
+        assert_eq!(
+            main["literals"],
+            serde_json::json!([
+                { "type": "u8", "value": 2, "text": "2u8" },
+                { "type": "container", "value": [1, "a"], "text": "[1, \"a\"]" },
+            ])
+        );
+        assert_eq!(
+            main["labels"],
+            serde_json::json!([{ "name": "L0", "offset": 1 }])
+        );
+        assert_eq!(
+            main["instructions"][2],
+            serde_json::json!({
+                "offset": 2,
+                "mnemonic": "jmp_t",
+                "operand": { "label": 0, "target": 1 },
+            })
+        );
+        assert_eq!(main["instructions"][4]["operand"], serde_json::json!(null));
+    }
+
+    #[test]
+    fn test_remove() {
+        use crate::asm::assembler::Assembler;
+
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$quad 1:",
+            "    load_arg 0",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $quad",
+            "    call",
+            "    ret_val",
+            "$unused 0:",
+            "    ret",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let hash = |name| db.get_code_object_by_name(name).unwrap().0;
+        let (square, quad, main) = (hash("square"), hash("quad"), hash("main"));
+        db.create_alias("sq", &square).unwrap();
+
+        let err = db.remove_code_object(&square, false).unwrap_err();
+        assert!(err.to_string().contains("used by 'quad'"), "{err}");
+        assert!(db.remove_name("square", false).is_ok_and(|r| r.is_empty()));
+        assert!(db.remove_name("sq", false).is_err());
+        assert!(db.get_code_object(&square).is_ok());
+
+        // The last name of an unused function takes it with it
+        let unused = hash("unused");
+        assert_eq!(db.remove_name("unused", false).unwrap(), vec![unused]);
+        assert!(db.get_code_object(&unused).is_err());
+
+        // The last name can go alone, leaving the code object unnamed
+        db.unname("sq").unwrap();
+        assert_eq!(db.get_name_of_hash(&square).unwrap(), None);
+        assert!(db.get_code_object(&square).is_ok());
+        assert!(db.unname("sq").is_err());
+
+        assert!(db.plan_removal(&square, false).is_err());
+        assert_eq!(
+            db.plan_removal(&square, true).unwrap(),
+            vec![square, quad, main]
+        );
+        assert!(db.get_code_object(&main).is_ok());
+        assert_eq!(
+            db.remove_code_object(&square, true).unwrap(),
+            vec![square, quad, main]
+        );
+        assert!(db.get_hashes().unwrap().is_empty());
+        assert!(db.get_functions().unwrap().is_empty());
+        assert!(db.remove_code_object(&square, true).is_err());
+    }
+
+    #[test]
+    fn test_resolve_hash_prefix() {
+        let db = Database::temp().unwrap();
+        let obj = init_code_obj(bytecode![Instr::Return]);
+        let hash = db.insert_code_object_with_name(&obj, "func_name").unwrap();
+
+        let prefix = hash.abbrev().parse().unwrap();
+        assert_eq!(db.resolve_hash_prefix(&prefix).unwrap(), hash);
+
+        let other = if hash.to_hex().starts_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        assert!(db.resolve_hash_prefix(&other.parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        let obj = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+
+        // A database from before format versions were recorded, keyed by the hash
+        // of the old encoding
+        let legacy = encoding::encode_v0(&obj).unwrap();
+        let hash = Hash::digest(&legacy);
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "CREATE TABLE code_objs (id INTEGER PRIMARY KEY, hash BLOB UNIQUE, code_obj BLOB UNIQUE, is_main INTEGER DEFAULT (0), time DATETIME);",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO code_objs (hash, code_obj) VALUES (?1, ?2);",
+            params![hash, legacy],
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = Database::open(&path).unwrap();
+        assert_eq!(
+            db.get_code_object(&hash).unwrap().code.to_vec(),
+            obj.code.to_vec()
+        );
+        assert_eq!(db.migrate().unwrap(), 1);
+        assert_eq!(db.migrate().unwrap(), 0);
+        assert_eq!(
+            db.get_code_object(&hash).unwrap().code.to_vec(),
+            obj.code.to_vec()
+        );
+
+        // The migrated row is still under its old hash, which isn't corruption
+        assert!(db.verify(true).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut db = Database::temp().unwrap();
+        db.set_cache_capacity(0);
+        db.set_compression(Compression::None);
+        let text = |s: &str| Value::string(&s.repeat(200));
+        let code = || bytecode![Instr::LoadLit(0), Instr::ReturnVal];
+        let obj = init_code_obj_with_pool(code(), vec![text("abc")]);
+        let hash = db.insert_code_object_with_name(&obj, "f").unwrap();
+
+        let stats = db.storage_stats().unwrap();
+        assert_eq!((stats.objects, stats.compressed), (1, 0));
+        assert_eq!(stats.stored_bytes, stats.uncompressed_bytes);
+
+        // Old rows are read as they are until recompressed
+        db.set_compression(Compression::Lz4);
+        let other = init_code_obj_with_pool(code(), vec![text("xyz")]);
+        let other = db.insert_code_object_with_name(&other, "g").unwrap();
+        assert_eq!(db.storage_stats().unwrap().compressed, 1);
+        assert_eq!(db.recompress().unwrap(), 2);
+        let compressed = db.storage_stats().unwrap();
+        assert_eq!(compressed.compressed, 2);
+        assert_eq!(compressed.uncompressed_bytes, 2 * stats.uncompressed_bytes);
+        assert!(compressed.stored_bytes < stats.stored_bytes);
+
+        assert_eq!(db.get_code_object(&hash).unwrap().litpool, obj.litpool);
+        assert_eq!(
+            db.get_code_object(&other).unwrap().litpool,
+            vec![text("xyz")]
+        );
+    }
+
+    #[test]
+    fn test_insert_unverified() {
+        let db = Database::temp().unwrap();
+        let obj = init_code_obj(bytecode![Instr::LoadLit(10), Instr::ReturnVal]);
+        assert!(db.insert_code_object_with_name(&obj, "bad").is_err());
+        assert!(db.get_code_object_by_name("bad").is_err());
     }
 }
@@ -0,0 +1,381 @@
+//! Pack files, for sharing code between databases. A pack is an .efb file holding
+//! a set of functions along with every code object they load, so that it can be
+//! imported into a database that has none of them. A code object with several
+//! names appears once per name, and one without a name appears with an empty name.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use super::Database;
+use crate::efb::{read_efb, write_efb};
+use crate::solver;
+use crate::vm::CodeObject;
+use crate::{is_valid_qualified_name, Hash};
+
+/// What to do when a name in a pack is already taken by a different code object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameConflict {
+    /// Import nothing
+    #[default]
+    Fail,
+    /// Keep the name pointing at the code object already in the database
+    Keep,
+    /// Point the name at the code object from the pack
+    Replace,
+}
+
+impl Database {
+    /// Write the functions with the given names or hash prefixes to a pack, along
+    /// with the code objects they depend on, dependencies first
+    pub fn export_pack<W: Write>(&self, roots: &[&str], w: W) -> Result<()> {
+        let mut order = vec![];
+        let mut seen = HashSet::new();
+        for root in roots {
+            let hash = self.resolve(root)?;
+            self.visit_dependencies(hash, &mut seen, &mut order)?;
+        }
+
+        let mut functions = vec![];
+        for (hash, obj) in order {
+            match &self.get_names_of_hash(&hash)?[..] {
+                [] => functions.push((String::new(), obj)),
+                names => {
+                    functions.extend(names.iter().map(|name| (name.clone(), obj.clone())))
+                }
+            }
+        }
+        write_efb(w, &functions)
+    }
+
+    /// Write every stored code object that is not in `have` to a pack, without
+    /// names, dependencies first. Objects in `have` are expected to be in the
+    /// database that imports the pack.
+    pub fn export_missing<W: Write>(&self, have: &HashSet<Hash>, w: W) -> Result<usize> {
+        let mut order = vec![];
+        let mut seen = have.clone();
+        let mut hashes = self.get_hashes()?;
+        hashes.sort_by_key(|hash| hash.to_hex());
+        for hash in hashes {
+            self.visit_dependencies(hash, &mut seen, &mut order)?;
+        }
+
+        let objects = order
+            .into_iter()
+            .map(|(_, obj)| (String::new(), obj))
+            .collect::<Vec<_>>();
+        write_efb(w, &objects)?;
+        Ok(objects.len())
+    }
+
+    /// Add `hash` to `order` after everything it loads, unless it is `seen`. Uses a
+    /// stack rather than recursion, since chains of calls can be long.
+    fn visit_dependencies(
+        &self,
+        hash: Hash,
+        seen: &mut HashSet<Hash>,
+        order: &mut Vec<(Hash, CodeObject)>,
+    ) -> Result<()> {
+        if !seen.insert(hash) {
+            return Ok(());
+        }
+        let mut stack = vec![self.with_dependencies(hash)?];
+        while let Some((_, _, deps)) = stack.last_mut() {
+            match deps.pop() {
+                Some(dep) => {
+                    if seen.insert(dep) {
+                        stack.push(self.with_dependencies(dep)?);
+                    }
+                }
+                None => {
+                    let (hash, obj, _) = stack.pop().unwrap();
+                    order.push((hash, obj));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A code object and the ones it loads, in reverse order of their hashes so
+    /// that they are popped in order
+    fn with_dependencies(&self, hash: Hash) -> Result<(Hash, CodeObject, Vec<Hash>)> {
+        let obj = self.get_code_object(&hash)?;
+        let mut deps = solver::references(&obj).into_iter().collect::<Vec<_>>();
+        deps.sort_by_key(|dep| std::cmp::Reverse(dep.to_hex()));
+        Ok((hash, obj, deps))
+    }
+
+    /// Insert the contents of a pack, returning the names it gave to code objects
+    /// from the pack, in pack order. Every code object is verified, and its hash
+    /// checked, before anything is inserted, and nothing is inserted if this fails.
+    pub fn import_pack<R: Read>(
+        &self,
+        r: R,
+        on_conflict: NameConflict,
+    ) -> Result<Vec<(String, Hash)>> {
+        self.import_pack_with_names(r, &[], on_conflict)
+    }
+
+    /// Insert the contents of a pack as `import_pack` does, then point `names` at
+    /// code objects from the pack or already stored, as `import_names` does, all
+    /// in one transaction. Returns the names that changed.
+    pub fn import_pack_with_names<R: Read>(
+        &self,
+        r: R,
+        names: &[(String, Hash)],
+        on_conflict: NameConflict,
+    ) -> Result<Vec<(String, Hash)>> {
+        let conn = self.conn();
+        let functions = read_efb(r)?;
+        let mut hashes = vec![];
+        for (name, obj) in &functions {
+            if !name.is_empty() && !is_valid_qualified_name(name) {
+                bail!("cannot import pack: '{name}' is not a valid function name");
+            }
+            crate::verify::verify(obj)?;
+            let hash = obj.hash()?;
+            let loads = solver::references(obj);
+            if let Some(dep) = loads
+                .iter()
+                .find(|dep| !hashes.contains(*dep) && self.get_code_object(dep).is_err())
+            {
+                bail!("cannot import pack: '{name}' loads {dep}, which is missing");
+            }
+            hashes.push(hash);
+        }
+        for (name, hash) in names {
+            if !hashes.contains(hash) && self.get_code_object(hash).is_err() {
+                bail!("cannot import '{name}': no code object with hash {hash}");
+            }
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        for (_, obj) in &functions {
+            self.insert_code_object(obj, false)?;
+        }
+        let names = functions
+            .iter()
+            .zip(hashes)
+            .filter(|((name, _), _)| !name.is_empty())
+            .map(|((name, _), hash)| (name.clone(), hash))
+            .chain(names.iter().cloned())
+            .collect::<Vec<_>>();
+        let imported = self.apply_names(&names, on_conflict)?;
+        tx.commit()?;
+
+        Ok(imported)
+    }
+
+    /// Point names at stored code objects, handling names that are taken as
+    /// `import_pack` does. Returns the names that changed, and does nothing if
+    /// any fails.
+    pub fn import_names(
+        &self,
+        names: &[(String, Hash)],
+        on_conflict: NameConflict,
+    ) -> Result<Vec<(String, Hash)>> {
+        for (name, hash) in names {
+            if self.get_code_object(hash).is_err() {
+                bail!("cannot import '{name}': no code object with hash {hash}");
+            }
+        }
+
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let imported = self.apply_names(names, on_conflict)?;
+        tx.commit()?;
+
+        Ok(imported)
+    }
+
+    fn apply_names(
+        &self,
+        names: &[(String, Hash)],
+        on_conflict: NameConflict,
+    ) -> Result<Vec<(String, Hash)>> {
+        let mut imported = vec![];
+        for (name, hash) in names {
+            if !is_valid_qualified_name(name) {
+                bail!("cannot import function with invalid name '{name}'");
+            }
+            match self.get_code_object_by_name(name) {
+                Ok((existing, _)) if existing == *hash => continue,
+                Ok(_) if on_conflict == NameConflict::Keep => continue,
+                Ok(_) if on_conflict == NameConflict::Fail => bail!(
+                    "cannot import '{name}': a different function named '{name}' is already in the database"
+                ),
+                _ => (),
+            }
+            // A replaced function is kept as an earlier version of the name, and a
+            // pinned name keeps pointing where it was
+            if Self::set_name(&self.conn(), name, hash)? {
+                self.notify_named(name, hash);
+                imported.push((name.clone(), *hash));
+            }
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    fn library() -> Database {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$quad 1:",
+            "    load_arg 0",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+            "$other 0:",
+            "    ret",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_pack_roundtrip() {
+        let db = library();
+        let (square, _) = db.get_code_object_by_name("square").unwrap();
+        db.create_alias("sq", &square).unwrap();
+
+        let mut pack = vec![];
+        db.export_pack(&["quad"], &mut pack).unwrap();
+
+        // Only quad and what it depends on, with every name
+        let other = Database::temp().unwrap();
+        let imported = other
+            .import_pack(pack.as_slice(), NameConflict::Fail)
+            .unwrap();
+        let names = imported.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["sq", "square", "quad"]);
+        assert!(other.get_code_object_by_name("other").is_err());
+        for (name, hash) in &imported {
+            assert_eq!(db.get_code_object_by_name(name).unwrap().0, *hash);
+        }
+
+        // Importing again changes nothing
+        let again = other
+            .import_pack(pack.as_slice(), NameConflict::Fail)
+            .unwrap();
+        assert!(again.is_empty());
+
+        // Hash prefixes work too
+        let prefix = format!("0x{}", &square.to_hex()[..8]);
+        let mut pack = vec![];
+        db.export_pack(&[&prefix], &mut pack).unwrap();
+        let functions = read_efb(pack.as_slice()).unwrap();
+        assert_eq!(functions.len(), 2);
+        assert_eq!(functions[0].1.hash().unwrap(), square);
+    }
+
+    #[test]
+    fn test_pack_conflicts() {
+        let db = library();
+        let mut pack = vec![];
+        db.export_pack(&["quad"], &mut pack).unwrap();
+
+        let other = Database::temp().unwrap();
+        Assembler::new(&other)
+            .assemble_str("$square 1:\n    load_arg 0\n    ret_val\n")
+            .unwrap();
+        let (mine, _) = other.get_code_object_by_name("square").unwrap();
+
+        let err = other
+            .import_pack(pack.as_slice(), NameConflict::Fail)
+            .unwrap_err();
+        assert!(err.to_string().contains("named 'square'"), "{err}");
+        assert!(other.get_code_object_by_name("quad").is_err());
+
+        other
+            .import_pack(pack.as_slice(), NameConflict::Keep)
+            .unwrap();
+        assert_eq!(other.get_code_object_by_name("square").unwrap().0, mine);
+        assert!(other.get_code_object_by_name("quad").is_ok());
+
+        other
+            .import_pack(pack.as_slice(), NameConflict::Replace)
+            .unwrap();
+        let (theirs, _) = db.get_code_object_by_name("square").unwrap();
+        assert_eq!(other.get_code_object_by_name("square").unwrap().0, theirs);
+
+        // A pack missing a dependency is rejected
+        let (quad, obj) = db.get_code_object_by_name("quad").unwrap();
+        let mut pack = vec![];
+        write_efb(&mut pack, &[("quad".to_string(), obj)]).unwrap();
+        let empty = Database::temp().unwrap();
+        let err = empty
+            .import_pack(pack.as_slice(), NameConflict::Fail)
+            .unwrap_err();
+        assert!(err.to_string().contains("missing"), "{err}");
+        assert!(empty.get_code_object(&quad).is_err());
+
+        // So is a pack with a name that could not be assembled
+        let (_, obj) = db.get_code_object_by_name("other").unwrap();
+        let mut pack = vec![];
+        write_efb(&mut pack, &[("bad name".to_string(), obj.clone())]).unwrap();
+        let err = empty
+            .import_pack(pack.as_slice(), NameConflict::Fail)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("not a valid function name"),
+            "{err}"
+        );
+        assert!(empty.get_code_object(&obj.hash().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_pack_long_chain() {
+        // Each function calls the one before it
+        let mut functions = vec![];
+        let mut obj = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![Instr::Return])
+        };
+        for i in 0..3000 {
+            let hash = obj.hash().unwrap();
+            functions.push((format!("f{i}"), obj));
+            obj = CodeObject {
+                argcount: 0,
+                ..init_code_obj(bytecode![
+                    Instr::LoadFunc(hash),
+                    Instr::Call,
+                    Instr::Return
+                ])
+            };
+        }
+        let db = Database::temp().unwrap();
+        db.insert_batch(&functions).unwrap();
+
+        // More calls deep than a small stack could recurse through
+        let pack = std::thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(move || {
+                let mut pack = vec![];
+                db.export_pack(&["f2999"], &mut pack).unwrap();
+                pack
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let exported = read_efb(pack.as_slice()).unwrap();
+        assert_eq!(exported.len(), 3000);
+        assert_eq!(exported[0].0, "f0");
+        assert_eq!(exported[2999].0, "f2999");
+    }
+}
@@ -0,0 +1,139 @@
+//! Execution statistics of code objects, added up over every profiled run
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension};
+
+use super::Database;
+use crate::Hash;
+
+/// How much a code object has run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub calls: u64,
+    /// Instructions run in the code object itself, not in what it calls
+    pub instructions: u64,
+    /// Time spent in calls to the code object, including what it calls. Recursive
+    /// calls are only counted once.
+    pub time: Duration,
+}
+
+impl Database {
+    /// Add the statistics of a run to the stored ones, in one transaction
+    pub fn record_profiles(&self, profiles: &HashMap<Hash, Profile>) -> Result<()> {
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        for (hash, profile) in profiles {
+            tx.execute(
+                r#"
+                INSERT INTO profiles (hash, calls, instructions, nanos, time)
+                VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)
+                ON CONFLICT (hash) DO UPDATE SET
+                    calls = calls + excluded.calls,
+                    instructions = instructions + excluded.instructions,
+                    nanos = nanos + excluded.nanos,
+                    time = excluded.time;
+                "#,
+                params![
+                    hash,
+                    profile.calls,
+                    profile.instructions,
+                    profile.time.as_nanos() as u64
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The statistics of a code object, if it has run while profiling
+    pub fn get_profile(&self, hash: &Hash) -> Result<Option<Profile>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT calls, instructions, nanos FROM profiles WHERE hash = ?1;",
+                [hash],
+                |row| {
+                    Ok(Profile {
+                        calls: row.get(0)?,
+                        instructions: row.get(1)?,
+                        time: Duration::from_nanos(row.get(2)?),
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Every code object that has run while profiling, with the most instructions
+    /// run first
+    pub fn get_profiles(&self) -> Result<Vec<(Hash, Profile)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT hash, calls, instructions, nanos FROM profiles ORDER BY instructions DESC, hash;",
+        )?;
+        let profiles = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                Profile {
+                    calls: row.get(1)?,
+                    instructions: row.get(2)?,
+                    time: Duration::from_nanos(row.get(3)?),
+                },
+            ))
+        })?;
+        Ok(profiles.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Forget all statistics, e.g. after the code has changed a lot
+    pub fn clear_profiles(&self) -> Result<()> {
+        self.conn().execute("DELETE FROM profiles;", [])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let db = Database::temp().unwrap();
+        let (f, g) = (Hash::digest(b"f"), Hash::digest(b"g"));
+        assert_eq!(db.get_profile(&f).unwrap(), None);
+
+        let run = HashMap::from([
+            (
+                f,
+                Profile {
+                    calls: 1,
+                    instructions: 10,
+                    time: Duration::from_micros(5),
+                },
+            ),
+            (
+                g,
+                Profile {
+                    calls: 3,
+                    instructions: 6,
+                    time: Duration::from_micros(2),
+                },
+            ),
+        ]);
+        db.record_profiles(&run).unwrap();
+        db.record_profiles(&run).unwrap();
+        let expected = Profile {
+            calls: 2,
+            instructions: 20,
+            time: Duration::from_micros(10),
+        };
+        assert_eq!(db.get_profile(&f).unwrap(), Some(expected));
+        let hottest = db.get_profiles().unwrap();
+        assert_eq!(hottest.len(), 2);
+        assert_eq!(hottest[0], (f, expected));
+
+        db.clear_profiles().unwrap();
+        assert!(db.get_profiles().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+//! Searching the named functions in a database
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+
+use super::Database;
+use crate::Hash;
+
+/// How `Query` matches names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamePattern {
+    /// The whole name, where `*` matches any characters and `?` matches one
+    Glob(String),
+    /// Anywhere in the name, unless anchored with `^` or `$`. Supports `.`, the
+    /// repetitions `*`, `+`, and `?`, and `\` escapes, but not groups,
+    /// alternation, or character classes.
+    Regex(String),
+}
+
+/// What `find_functions` looks for. A function matches if it meets every
+/// condition that is set, so the default query matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub name: Option<NamePattern>,
+    pub arity: Option<usize>,
+    /// The mnemonic of an instruction the function uses, e.g. `call`
+    pub uses: Option<String>,
+    /// A code object the function loads with `load_func`
+    pub references: Option<Hash>,
+}
+
+/// A character a `Matcher` matches
+#[derive(Debug, Clone, Copy)]
+enum Atom {
+    Char(char),
+    Any,
+}
+
+/// How many times in a row a `Matcher` matches an atom
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Repeat {
+    Once,
+    Optional,
+    Any,
+    Some,
+}
+
+/// A compiled `NamePattern`
+struct Matcher {
+    atoms: Vec<(Atom, Repeat)>,
+    start: bool,
+    end: bool,
+}
+
+impl NamePattern {
+    fn to_matcher(&self) -> Result<Matcher> {
+        match self {
+            NamePattern::Glob(glob) => Ok(Matcher {
+                atoms: glob
+                    .chars()
+                    .map(|c| match c {
+                        '*' => (Atom::Any, Repeat::Any),
+                        '?' => (Atom::Any, Repeat::Once),
+                        c => (Atom::Char(c), Repeat::Once),
+                    })
+                    .collect(),
+                start: true,
+                end: true,
+            }),
+            NamePattern::Regex(pattern) => Matcher::regex(pattern),
+        }
+    }
+}
+
+impl Matcher {
+    fn regex(pattern: &str) -> Result<Matcher> {
+        let mut chars = pattern.chars().peekable();
+        let start = chars.next_if_eq(&'^').is_some();
+        let mut atoms: Vec<(Atom, Repeat)> = vec![];
+        let mut end = false;
+        while let Some(c) = chars.next() {
+            if end {
+                bail!("invalid pattern '{pattern}': '$' must come last");
+            }
+            let atom = match c {
+                '.' => Atom::Any,
+                '$' => {
+                    end = true;
+                    continue;
+                }
+                '\\' => match chars.next() {
+                    Some(c) => Atom::Char(c),
+                    None => bail!("invalid pattern '{pattern}': trailing '\\'"),
+                },
+                '*' | '+' | '?' => {
+                    let repeat = match c {
+                        '*' => Repeat::Any,
+                        '+' => Repeat::Some,
+                        _ => Repeat::Optional,
+                    };
+                    match atoms.last_mut() {
+                        Some((_, last @ Repeat::Once)) => *last = repeat,
+                        _ => bail!("invalid pattern '{pattern}': nothing to repeat"),
+                    }
+                    continue;
+                }
+                '(' | ')' | '[' | ']' | '{' | '}' | '|' => {
+                    bail!("invalid pattern '{pattern}': '{c}' is not supported")
+                }
+                c => Atom::Char(c),
+            };
+            atoms.push((atom, Repeat::Once));
+        }
+        Ok(Matcher { atoms, start, end })
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        let name = name.chars().collect::<Vec<_>>();
+        if self.start {
+            return self.matches_at(&self.atoms, &name);
+        }
+        (0..=name.len()).any(|i| self.matches_at(&self.atoms, &name[i..]))
+    }
+
+    /// Whether `atoms` match the start of `name`, or all of it if anchored at the
+    /// end
+    fn matches_at(&self, atoms: &[(Atom, Repeat)], name: &[char]) -> bool {
+        let Some(((atom, repeat), rest)) = atoms.split_first() else {
+            return !self.end || name.is_empty();
+        };
+        let matches = |c: &char| match atom {
+            Atom::Char(atom) => atom == c,
+            Atom::Any => true,
+        };
+        let (min, max) = match repeat {
+            Repeat::Once => (1, 1),
+            Repeat::Optional => (0, 1),
+            Repeat::Any => (0, usize::MAX),
+            Repeat::Some => (1, usize::MAX),
+        };
+        let run = name.iter().take(max).take_while(|c| matches(c)).count();
+        (min..=run).rev().any(|n| self.matches_at(rest, &name[n..]))
+    }
+}
+
+impl Database {
+    /// The names and hashes of the named functions that match `query`, sorted by
+    /// name
+    pub fn find_functions(&self, query: &Query) -> Result<Vec<(String, Hash)>> {
+        let name = query
+            .name
+            .as_ref()
+            .map(NamePattern::to_matcher)
+            .transpose()?;
+        let referrers = match &query.references {
+            Some(hash) => Some(
+                self.get_referrers(hash)?
+                    .into_iter()
+                    .collect::<HashSet<_>>(),
+            ),
+            None => None,
+        };
+
+        let mut found = vec![];
+        for (func, hash) in self.get_functions()? {
+            if name.as_ref().is_some_and(|name| !name.is_match(&func))
+                || referrers.as_ref().is_some_and(|r| !r.contains(&hash))
+            {
+                continue;
+            }
+            if query.arity.is_some() || query.uses.is_some() {
+                let obj = self.get_code_object(&hash)?;
+                if query.arity.is_some_and(|arity| arity != obj.argcount) {
+                    continue;
+                }
+                if let Some(mnemonic) = &query.uses {
+                    let uses = obj.code.iter().any(|instr| {
+                        instr.to_string().split_whitespace().next() == Some(mnemonic)
+                    });
+                    if !uses {
+                        continue;
+                    }
+                }
+            }
+            found.push((func, hash));
+        }
+
+        found.sort();
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+
+    fn names(found: Vec<(String, Hash)>) -> Vec<String> {
+        found.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn test_find_functions() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$quad 1:",
+            "    load_arg 0",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+            ".module math",
+            "$sum 2:",
+            "    load_arg 0",
+            "    load_arg 1",
+            "    add",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let find = |query: Query| names(db.find_functions(&query).unwrap());
+
+        assert_eq!(find(Query::default()), vec!["math::sum", "quad", "square"]);
+        let glob = |glob: &str| Query {
+            name: Some(NamePattern::Glob(glob.to_string())),
+            ..Default::default()
+        };
+        assert_eq!(find(glob("math::*")), vec!["math::sum"]);
+        assert_eq!(find(glob("qua?")), vec!["quad"]);
+        assert!(find(glob("qua")).is_empty());
+        let regex = |regex: &str| Query {
+            name: Some(NamePattern::Regex(regex.to_string())),
+            ..Default::default()
+        };
+        assert_eq!(find(regex("ua")), vec!["quad", "square"]);
+        assert_eq!(find(regex("^s")), vec!["square"]);
+        assert_eq!(find(regex("d$")), vec!["quad"]);
+        assert_eq!(find(regex("^q.+d$")), vec!["quad"]);
+        assert_eq!(find(regex("^sq?u")), vec!["square"]);
+        assert_eq!(find(regex("h::s.*m")), vec!["math::sum"]);
+        assert!(find(regex("a\\.")).is_empty());
+        assert!(db.find_functions(&regex("(")).is_err());
+        assert!(db.find_functions(&regex("*a")).is_err());
+
+        let query = Query {
+            arity: Some(1),
+            uses: Some("mul".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(find(query), vec!["square"]);
+
+        // Calls to named functions are linked to `load_func` of their hash
+        let (square, _) = db.get_code_object_by_name("square").unwrap();
+        let query = Query {
+            references: Some(square),
+            ..Default::default()
+        };
+        assert_eq!(find(query.clone()), vec!["quad"]);
+        let query = Query {
+            name: Some(NamePattern::Glob("s*".to_string())),
+            ..query
+        };
+        assert!(find(query).is_empty());
+    }
+}
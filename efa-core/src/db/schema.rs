@@ -0,0 +1,425 @@
+//! The database schema, built by running migration steps in order. A database
+//! records how many steps it has run in `schema_version`, so opening it runs only
+//! the new ones, and a new database runs them all.
+//!
+//! To change the schema, add a step to the end of `MIGRATIONS`; never edit or
+//! reorder the steps that are there.
+
+use anyhow::{bail, Result};
+use rusqlite::{Connection, OptionalExtension};
+
+use super::encoding::decode_stored;
+use super::Database;
+
+/// One change to the schema
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // These were written before versions were recorded, so they check what is
+    // already there, and are safe to run on a database made by any older version
+    Migration {
+        description: "create names and code objects",
+        apply: create_base_tables,
+    },
+    Migration {
+        description: "record the format version of code objects",
+        apply: add_format_version,
+    },
+    Migration {
+        description: "add name history and pinning",
+        apply: add_name_history,
+    },
+    Migration {
+        description: "record the compression of code objects",
+        apply: add_compression,
+    },
+    Migration {
+        description: "record the dependencies of code objects",
+        apply: add_deps,
+    },
+    Migration {
+        description: "add metadata, types and signatures",
+        apply: add_metadata_types_signatures,
+    },
+    Migration {
+        description: "add VM snapshots",
+        apply: add_snapshots,
+    },
+    Migration {
+        description: "add execution profiles",
+        apply: add_profiles,
+    },
+    Migration {
+        description: "add named entry points",
+        apply: add_entry_points,
+    },
+];
+
+/// The schema version this build creates and expects
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+impl Database {
+    /// The number of migration steps the database has run. Databases from before
+    /// versions were recorded have version 0.
+    pub fn schema_version(&self) -> Result<u32> {
+        schema_version(&self.conn())
+    }
+
+    /// Run the migration steps the database hasn't, in one transaction, returning
+    /// how many ran
+    pub(super) fn migrate_schema(conn: &Connection) -> Result<usize> {
+        let version = schema_version(conn)?;
+        check_not_newer(version)?;
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER);",
+            [],
+        )?;
+        let pending = &MIGRATIONS[version as usize..];
+        for (i, migration) in pending.iter().enumerate() {
+            (migration.apply)(&tx).map_err(|e| {
+                let step = version as usize + i + 1;
+                e.context(format!(
+                    "migration {step} ({}) failed",
+                    migration.description
+                ))
+            })?;
+        }
+        tx.execute("DELETE FROM schema_version;", [])?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1);",
+            [SCHEMA_VERSION],
+        )?;
+        tx.commit()?;
+
+        Ok(pending.len())
+    }
+
+    /// Fail unless the database is at the current schema version
+    pub(super) fn check_schema(conn: &Connection) -> Result<()> {
+        let version = schema_version(conn)?;
+        check_not_newer(version)?;
+        if version < SCHEMA_VERSION {
+            bail!(
+                "database has schema version {version}, but version {SCHEMA_VERSION} is needed: open it with migrations to upgrade it"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn schema_version(conn: &Connection) -> Result<u32> {
+    if !table_exists(conn, "schema_version")? {
+        return Ok(0);
+    }
+    let version = conn
+        .query_row("SELECT version FROM schema_version;", [], |row| row.get(0))
+        .optional()?;
+    Ok(version.unwrap_or(0))
+}
+
+fn check_not_newer(version: u32) -> Result<()> {
+    if version > SCHEMA_VERSION {
+        bail!(
+            "database has schema version {version}, but only versions up to {SCHEMA_VERSION} are supported"
+        );
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    Ok(conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1;")?
+        .exists([table])?)
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2;")?
+        .exists([table, column])?)
+}
+
+fn create_base_tables(conn: &Connection) -> Result<()> {
+    // Create name table, with the hash each name points to now
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS names (
+            id INTEGER PRIMARY KEY,
+            name VARCHAR(255) UNIQUE,
+            hash BLOB,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS name_idx ON names (name);", [])?;
+
+    // Create code object table
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS code_objs (
+            id INTEGER PRIMARY KEY,
+            hash BLOB UNIQUE,
+            code_obj BLOB UNIQUE,
+            is_main INTEGER DEFAULT (0),
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS hash_idx ON code_objs (hash);",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Rows from before format versions were written with version 0
+fn add_format_version(conn: &Connection) -> Result<()> {
+    if !column_exists(conn, "code_objs", "format_version")? {
+        conn.execute(
+            "ALTER TABLE code_objs ADD COLUMN format_version INTEGER DEFAULT (0);",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Create name history table, with every hash each name has pointed to. Names
+/// without a history start with their current hash.
+fn add_name_history(conn: &Connection) -> Result<()> {
+    if !column_exists(conn, "names", "pinned")? {
+        conn.execute(
+            "ALTER TABLE names ADD COLUMN pinned INTEGER DEFAULT (0);",
+            [],
+        )?;
+    }
+    if table_exists(conn, "name_versions")? {
+        return Ok(());
+    }
+
+    conn.execute(
+        r#"
+        CREATE TABLE name_versions (
+            id INTEGER PRIMARY KEY,
+            name VARCHAR(255),
+            hash BLOB,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS name_version_idx ON name_versions (name);",
+        [],
+    )?;
+    conn.execute(
+        "INSERT INTO name_versions (name, hash, time) SELECT name, hash, time FROM names ORDER BY id;",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Rows from before compression are uncompressed
+fn add_compression(conn: &Connection) -> Result<()> {
+    if !column_exists(conn, "code_objs", "compression")? {
+        conn.execute(
+            "ALTER TABLE code_objs ADD COLUMN compression INTEGER DEFAULT (0);",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Create dependency table, with a row for each code object another loads, found
+/// from the stored code
+fn add_deps(conn: &Connection) -> Result<()> {
+    if table_exists(conn, "deps")? {
+        return Ok(());
+    }
+
+    conn.execute(
+        r#"
+        CREATE TABLE deps (
+            hash BLOB,
+            dep BLOB,
+            PRIMARY KEY (hash, dep)
+        );
+    "#,
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS dep_idx ON deps (dep);", [])?;
+
+    let mut stmt =
+        conn.prepare("SELECT format_version, compression, code_obj FROM code_objs;")?;
+    let blobs = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<(u32, u32, Vec<u8>)>>>()?;
+    for (version, compression, blob) in blobs {
+        Database::insert_deps(conn, &decode_stored(version, compression, &blob)?)?;
+    }
+    Ok(())
+}
+
+fn add_metadata_types_signatures(conn: &Connection) -> Result<()> {
+    // Create metadata table, with a row for each code object that has any
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS metadata (
+            hash BLOB PRIMARY KEY,
+            doc TEXT,
+            author TEXT,
+            signature TEXT,
+            tags TEXT,
+            source TEXT,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+
+    // Create type table, with struct definitions by hash
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS types (
+            id INTEGER PRIMARY KEY,
+            hash BLOB UNIQUE,
+            type_def BLOB,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+
+    // Create signature table, with a row for each key that signed a code object
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS signatures (
+            hash BLOB,
+            public_key BLOB,
+            signature BLOB,
+            time DATETIME,
+            PRIMARY KEY (hash, public_key)
+        );
+    "#,
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_snapshots(conn: &Connection) -> Result<()> {
+    // Create snapshot table, with the saved state of each named VM
+    conn.execute(
+        r#"
+        CREATE TABLE snapshots (
+            name TEXT PRIMARY KEY,
+            state BLOB,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_profiles(conn: &Connection) -> Result<()> {
+    // Create profile table, with the execution statistics of each code object
+    conn.execute(
+        r#"
+        CREATE TABLE profiles (
+            hash BLOB PRIMARY KEY,
+            calls INTEGER,
+            instructions INTEGER,
+            nanos INTEGER,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_entry_points(conn: &Connection) -> Result<()> {
+    // Create entry point table, with the code object each entry point runs
+    conn.execute(
+        r#"
+        CREATE TABLE entry_points (
+            name TEXT PRIMARY KEY,
+            hash BLOB,
+            time DATETIME
+        );
+    "#,
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_schema_version() {
+        let db = Database::temp().unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        // Migrating again does nothing
+        assert_eq!(Database::migrate_schema(&db.conn()).unwrap(), 0);
+        Database::check_schema(&db.conn()).unwrap();
+
+        db.conn()
+            .execute(
+                "UPDATE schema_version SET version = ?1;",
+                [SCHEMA_VERSION + 1],
+            )
+            .unwrap();
+        assert!(Database::check_schema(&db.conn()).is_err());
+        assert!(Database::migrate_schema(&db.conn()).is_err());
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.db");
+        let obj = init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal]);
+        let hash = Database::new(&path)
+            .unwrap()
+            .insert_code_object_with_name(&obj, "f")
+            .unwrap();
+
+        // A database from before versions were recorded, with some of the later
+        // tables and columns
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("DROP TABLE schema_version;", []).unwrap();
+        conn.execute("DROP TABLE deps;", []).unwrap();
+        conn.execute("DROP TABLE signatures;", []).unwrap();
+        conn.execute("DROP TABLE snapshots;", []).unwrap();
+        conn.execute("DROP TABLE profiles;", []).unwrap();
+        conn.execute("DROP TABLE entry_points;", []).unwrap();
+        drop(conn);
+
+        assert!(Database::open_without_migrating(&path).is_err());
+        let db = Database::open(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, hash);
+        assert_eq!(db.get_name_versions("f").unwrap().len(), 1);
+        assert!(db.get_signatures(&hash).unwrap().is_empty());
+        drop(db);
+        Database::open_without_migrating(&path).unwrap();
+
+        // A database from before snapshots only runs the steps since
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("DROP TABLE snapshots;", []).unwrap();
+        conn.execute("DROP TABLE profiles;", []).unwrap();
+        conn.execute("DROP TABLE entry_points;", []).unwrap();
+        conn.execute("UPDATE schema_version SET version = 6;", [])
+            .unwrap();
+        assert_eq!(Database::migrate_schema(&conn).unwrap(), 3);
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+}
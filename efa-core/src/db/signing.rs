@@ -0,0 +1,230 @@
+//! Ed25519 signatures of code objects, stored beside them by hash. A signature
+//! signs the hash, so it covers everything the hash does, and code objects keep
+//! the same hash whether they are signed or not.
+
+use anyhow::{anyhow, bail, Result};
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use ed25519_dalek::{Signer, Verifier};
+use rusqlite::params;
+
+use super::Database;
+use crate::vm::CodeObject;
+use crate::Hash;
+
+/// Which code objects a VM will run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Run everything, signed or not
+    #[default]
+    Any,
+    /// Only run code objects that are signed, and whose signatures are all valid
+    Signed,
+    /// Only run code objects that are signed by one of these keys, and whose
+    /// signatures are all valid
+    Trusted(Vec<VerifyingKey>),
+}
+
+impl Database {
+    /// Insert a code object under a name, as `insert_code_object_with_name` does,
+    /// and sign it with `key`
+    pub fn insert_signed(
+        &self,
+        code_obj: &CodeObject,
+        name: &str,
+        key: &SigningKey,
+    ) -> Result<Hash> {
+        let conn = self.conn();
+        let tx = conn.unchecked_transaction()?;
+        let hash = self.insert_named(code_obj, name)?;
+        self.sign(&hash, key)?;
+        tx.commit()?;
+
+        Ok(hash)
+    }
+
+    /// Sign a stored code object with `key`, replacing an earlier signature by the
+    /// same key
+    pub fn sign(&self, hash: &Hash, key: &SigningKey) -> Result<()> {
+        self.get_code_object(hash)?;
+
+        let signature = key.sign(hash.as_bytes());
+        self.conn().execute(
+            "INSERT OR REPLACE INTO signatures (hash, public_key, signature, time) VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP);",
+            params![hash, key.verifying_key().as_bytes(), signature.to_bytes()],
+        )?;
+        self.signers.lock().remove(hash);
+        Ok(())
+    }
+
+    /// The keys that signed a code object, with their signatures, whether or not
+    /// they are valid
+    pub fn get_signatures(&self, hash: &Hash) -> Result<Vec<(VerifyingKey, Signature)>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT public_key, signature FROM signatures WHERE hash = ?1 ORDER BY public_key;",
+        )?;
+        let rows = stmt
+            .query_map([hash], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(key, signature)| {
+                let key = VerifyingKey::try_from(key.as_slice())?;
+                let signature = Signature::from_slice(&signature)?;
+                Ok((key, signature))
+            })
+            .collect()
+    }
+
+    /// The hashes of the code objects with a signature that does not verify, or
+    /// whose stored contents don't match their hash, sorted. An empty list means
+    /// every stored signature is valid. Every signature is checked again, rather
+    /// than trusting the verdicts of `check_signatures`.
+    pub fn verify_signatures(&self) -> Result<Vec<Hash>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT DISTINCT hash FROM signatures;")?;
+        let hashes = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<Hash>>>()?;
+
+        let mut bad = vec![];
+        for hash in hashes {
+            self.signers.lock().remove(&hash);
+            if self.get_code_object(&hash).is_err()
+                || self.valid_signers(&hash)?.is_none()
+            {
+                bad.push(hash);
+            }
+        }
+        bad.sort_by_key(|hash| hash.to_hex());
+        Ok(bad)
+    }
+
+    /// Fail unless `policy` allows running the code object with this hash. A
+    /// signature signs the hash, so the stored contents are checked against it
+    /// too. The verdict on the signatures is kept until the code object is signed
+    /// again.
+    pub fn check_signatures(&self, hash: &Hash, policy: &SignaturePolicy) -> Result<()> {
+        if *policy == SignaturePolicy::Any {
+            return Ok(());
+        }
+
+        self.get_code_object(hash)
+            .map_err(|e| anyhow!("refusing to run code object {hash}: {e}"))?;
+        let Some(signers) = self.valid_signers(hash)? else {
+            bail!("refusing to run code object {hash}: it has an invalid signature");
+        };
+        if signers.is_empty() {
+            bail!("refusing to run code object {hash}: it is not signed");
+        }
+        if let SignaturePolicy::Trusted(trusted) = policy {
+            if !signers.iter().any(|key| trusted.contains(key)) {
+                bail!("refusing to run code object {hash}: it is not signed by a trusted key");
+            }
+        }
+        Ok(())
+    }
+
+    /// The keys that signed a code object, or `None` if any of its signatures is
+    /// invalid
+    fn valid_signers(&self, hash: &Hash) -> Result<Option<Vec<VerifyingKey>>> {
+        if let Some(signers) = self.signers.lock().get(hash) {
+            return Ok(signers.clone());
+        }
+
+        let signatures = self.get_signatures(hash)?;
+        let signers = signatures
+            .iter()
+            .all(|(key, signature)| key.verify(hash.as_bytes(), signature).is_ok())
+            .then(|| signatures.iter().map(|(key, _)| *key).collect());
+        self.signers.lock().insert(*hash, signers.clone());
+        Ok(signers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::db::encoding::encode_stored;
+    use crate::db::Compression;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_signatures() {
+        let db = Database::temp().unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+
+        let obj = init_code_obj(bytecode![Instr::Return]);
+        let hash = db.insert_signed(&obj, "f", &key).unwrap();
+        assert_eq!(hash, obj.hash().unwrap());
+        assert_eq!(db.get_code_object_by_name("f").unwrap().0, hash);
+        let signatures = db.get_signatures(&hash).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].0, key.verifying_key());
+        assert!(db.verify_signatures().unwrap().is_empty());
+
+        let unsigned =
+            init_code_obj(bytecode![Instr::LoadLit(0), Instr::Pop, Instr::Return]);
+        let unsigned = db.insert_code_object_with_name(&unsigned, "g").unwrap();
+        db.check_signatures(&unsigned, &SignaturePolicy::Any)
+            .unwrap();
+        assert!(db
+            .check_signatures(&unsigned, &SignaturePolicy::Signed)
+            .is_err());
+        db.check_signatures(&hash, &SignaturePolicy::Signed)
+            .unwrap();
+        let trusted = SignaturePolicy::Trusted(vec![other.verifying_key()]);
+        assert!(db.check_signatures(&hash, &trusted).is_err());
+        db.sign(&hash, &other).unwrap();
+        db.check_signatures(&hash, &trusted).unwrap();
+
+        // A signature of a different hash is found and refused
+        let forged = other.sign(unsigned.as_bytes());
+        db.conn()
+            .execute(
+                "UPDATE signatures SET signature = ?1 WHERE public_key = ?2;",
+                params![forged.to_bytes(), key.verifying_key().as_bytes()],
+            )
+            .unwrap();
+        assert_eq!(db.verify_signatures().unwrap(), vec![hash]);
+        assert!(db.check_signatures(&hash, &trusted).is_err());
+        db.check_signatures(&hash, &SignaturePolicy::Any).unwrap();
+
+        // Only stored code objects can be signed, and signatures go with them
+        assert!(db.sign(&Hash::digest(b""), &key).is_err());
+        db.remove_code_object(&hash, false).unwrap();
+        assert!(db.get_signatures(&hash).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tampered_contents() {
+        let db = Database::temp().unwrap();
+        db.set_cache_capacity(0);
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let obj = init_code_obj(bytecode![Instr::Return]);
+        let hash = db.insert_signed(&obj, "f", &key).unwrap();
+        db.check_signatures(&hash, &SignaturePolicy::Signed)
+            .unwrap();
+
+        // The signature is still valid, but it signs different contents
+        let other =
+            init_code_obj(bytecode![Instr::LoadLit(0), Instr::Pop, Instr::Return]);
+        let (blob, compression) = encode_stored(&other, Compression::default()).unwrap();
+        db.conn()
+            .execute(
+                "UPDATE code_objs SET code_obj = ?1, compression = ?2 WHERE hash = ?3;",
+                params![blob, compression, hash],
+            )
+            .unwrap();
+        let err = db
+            .check_signatures(&hash, &SignaturePolicy::Signed)
+            .unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{err}");
+        assert!(db.get_code_object(&hash).is_err());
+        assert_eq!(db.verify_signatures().unwrap(), vec![hash]);
+    }
+}
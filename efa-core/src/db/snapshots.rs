@@ -0,0 +1,67 @@
+//! Saved VM state, stored by name beside the code it runs. The database only
+//! stores the bytes; `Vm::save_snapshot` decides what is in them.
+
+use anyhow::{anyhow, Result};
+use rusqlite::{params, OptionalExtension};
+
+use super::Database;
+
+impl Database {
+    /// Store a snapshot under `name`, replacing any snapshot with that name
+    pub fn put_snapshot(&self, name: &str, state: &[u8]) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO snapshots (name, state, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
+            params![name, state],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_snapshot(&self, name: &str) -> Result<Vec<u8>> {
+        self.conn()
+            .query_row(
+                "SELECT state FROM snapshots WHERE name = ?1;",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("query failed: no snapshot named '{name}'"))
+    }
+
+    /// The names of the stored snapshots, sorted
+    pub fn get_snapshot_names(&self) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name FROM snapshots ORDER BY name;")?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        Ok(names.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Remove a snapshot, returning whether there was one
+    pub fn remove_snapshot(&self, name: &str) -> Result<bool> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM snapshots WHERE name = ?1;", [name])?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots() {
+        let db = Database::temp().unwrap();
+        assert!(db.get_snapshot("job").is_err());
+
+        db.put_snapshot("job", b"one").unwrap();
+        db.put_snapshot("other", b"").unwrap();
+        assert_eq!(db.get_snapshot("job").unwrap(), b"one");
+        db.put_snapshot("job", b"two").unwrap();
+        assert_eq!(db.get_snapshot("job").unwrap(), b"two");
+        assert_eq!(db.get_snapshot_names().unwrap(), vec!["job", "other"]);
+
+        assert!(db.remove_snapshot("job").unwrap());
+        assert!(!db.remove_snapshot("job").unwrap());
+        assert_eq!(db.get_snapshot_names().unwrap(), vec!["other"]);
+    }
+}
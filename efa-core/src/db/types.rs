@@ -0,0 +1,86 @@
+//! Struct type definitions, stored by hash like code objects
+
+use anyhow::{anyhow, bail, Result};
+use rusqlite::{params, OptionalExtension};
+
+use super::Database;
+use crate::vm::{FieldType, TypeDef};
+use crate::Hash;
+
+impl Database {
+    /// Insert a type definition, returning its hash. Struct fields must refer to
+    /// types that are already stored.
+    pub fn insert_type(&self, def: &TypeDef) -> Result<Hash> {
+        def.check()?;
+        for (field, ty) in &def.fields {
+            if let FieldType::Struct(hash) = ty {
+                if self.get_type(hash).is_err() {
+                    bail!(
+                        "cannot insert type '{}': field '{field}' has unknown type {hash}",
+                        def.name
+                    );
+                }
+            }
+        }
+
+        let hash = def.hash()?;
+        self.conn().execute(
+            "INSERT OR IGNORE INTO types (hash, type_def, time) VALUES (?1, ?2, CURRENT_TIMESTAMP);",
+            params![hash, rmp_serde::to_vec(def)?],
+        )?;
+        Ok(hash)
+    }
+
+    pub fn get_type(&self, hash: &Hash) -> Result<TypeDef> {
+        let blob: Vec<u8> = self
+            .conn()
+            .query_row(
+                "SELECT type_def FROM types WHERE hash = ?1;",
+                [hash],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow!("query failed: no type with hash {hash}"))?;
+        Ok(rmp_serde::from_slice(&blob)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::TypeTag;
+
+    #[test]
+    fn test_types() {
+        let db = Database::temp().unwrap();
+        let point = TypeDef {
+            name: "point".to_string(),
+            fields: vec![
+                ("x".to_string(), FieldType::Tag(TypeTag::F64)),
+                ("y".to_string(), FieldType::Tag(TypeTag::F64)),
+            ],
+        };
+        let hash = db.insert_type(&point).unwrap();
+        assert_eq!(hash, point.hash().unwrap());
+        assert_eq!(db.get_type(&hash).unwrap(), point);
+        assert_eq!(db.insert_type(&point).unwrap(), hash);
+
+        // Structs refer to the types of their fields by hash
+        let line = TypeDef {
+            name: "geo::line".to_string(),
+            fields: vec![
+                ("start".to_string(), FieldType::Struct(hash)),
+                ("end".to_string(), FieldType::Struct(hash)),
+            ],
+        };
+        let line_hash = db.insert_type(&line).unwrap();
+        assert_eq!(db.get_type(&line_hash).unwrap(), line);
+
+        let unknown = TypeDef {
+            name: "wrapper".to_string(),
+            fields: vec![("inner".to_string(), FieldType::Struct(Hash::digest(b"")))],
+        };
+        assert!(db.insert_type(&unknown).is_err());
+        assert!(db.get_type(&unknown.hash().unwrap()).is_err());
+    }
+}
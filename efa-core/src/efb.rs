@@ -0,0 +1,191 @@
+//! The efa binary object file format (.efb), for shipping assembled code without a
+//! code database.
+//!
+//! All integers are little-endian. A file is laid out as:
+//!
+//! ```text
+//! magic     b"EFB\0"
+//! version   u16
+//! count     u32                number of functions
+//! count times:
+//!     name_len  u32
+//!     name      [u8; name_len]  utf-8, empty for an anonymous object
+//!     body_len  u32
+//!     body      [u8; body_len]  msgpack of the CodeObject (litpool, labels, code...)
+//!     hash      [u8; HASH_SIZE] hash of the CodeObject, checked on load
+//! ```
+//!
+//! Version 1 is the same, but its bodies are msgpack of `CodeObject` from before it
+//! had `is_void`, and hashed as such.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::db::decode_before_is_void;
+use crate::vm::CodeObject;
+use crate::{Hash, HASH_SIZE};
+
+const MAGIC: &[u8; 4] = b"EFB\0";
+pub const EFB_VERSION: u16 = 2;
+
+/// Write named code objects to an .efb file
+pub fn write_efb<W: Write>(mut w: W, functions: &[(String, CodeObject)]) -> Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&EFB_VERSION.to_le_bytes())?;
+    w.write_all(&(functions.len() as u32).to_le_bytes())?;
+
+    for (name, obj) in functions {
+        let body = rmp_serde::to_vec(obj)?;
+        w.write_all(&(name.len() as u32).to_le_bytes())?;
+        w.write_all(name.as_bytes())?;
+        w.write_all(&(body.len() as u32).to_le_bytes())?;
+        w.write_all(&body)?;
+        w.write_all(obj.hash()?.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Read all named code objects from an .efb file, verifying their hashes
+pub fn read_efb<R: Read>(mut r: R) -> Result<Vec<(String, CodeObject)>> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not an efb file: bad magic number");
+    }
+
+    let version = u16::from_le_bytes(read_array(&mut r)?);
+    if !(1..=EFB_VERSION).contains(&version) {
+        bail!("unsupported efb version {version} (expected up to {EFB_VERSION})");
+    }
+
+    let count = u32::from_le_bytes(read_array(&mut r)?);
+    (0..count)
+        .map(|_| {
+            let name = String::from_utf8(read_section(&mut r)?)?;
+            let body = read_section(&mut r)?;
+            let hash = Hash::from(read_array::<_, HASH_SIZE>(&mut r)?);
+
+            let (obj, body_hash) = match version {
+                1 => (decode_before_is_void(&body)?, Hash::digest(&body)),
+                _ => {
+                    let obj = rmp_serde::from_slice::<CodeObject>(&body)?;
+                    let hash = obj.hash()?;
+                    (obj, hash)
+                }
+            };
+            if body_hash != hash {
+                bail!("corrupt efb file: hash mismatch for function '{name}'");
+            }
+            Ok((name, obj))
+        })
+        .collect()
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read a u32 length followed by that many bytes
+fn read_section<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    let len = u32::from_le_bytes(read_array(r)?);
+    let mut buf = vec![];
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        bail!("truncated efb file");
+    }
+    Ok(buf)
+}
+
+impl CodeObject {
+    /// Serialize a single anonymous code object in the .efb format
+    pub fn to_efb(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        write_efb(&mut buf, &[(String::new(), self.clone())])?;
+        Ok(buf)
+    }
+
+    /// Load a code object from an .efb file containing exactly one function
+    pub fn from_efb(bytes: &[u8]) -> Result<CodeObject> {
+        let mut functions = read_efb(bytes)?;
+        if functions.len() != 1 {
+            bail!(
+                "expected one function in efb file, found {}",
+                functions.len()
+            );
+        }
+        Ok(functions.remove(0).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::{init_code_obj, init_nondet_code_obj};
+
+    #[test]
+    fn test_efb_roundtrip() {
+        let obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::ReturnVal]);
+        let loaded = CodeObject::from_efb(&obj.to_efb().unwrap()).unwrap();
+        assert_eq!(loaded.hash().unwrap(), obj.hash().unwrap());
+
+        let functions = vec![
+            ("foo".to_string(), obj.clone()),
+            (
+                "bar".to_string(),
+                init_nondet_code_obj(bytecode![Instr::Return]),
+            ),
+        ];
+        let mut buf = vec![];
+        write_efb(&mut buf, &functions).unwrap();
+        let loaded = read_efb(buf.as_slice()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].0, "bar");
+        assert_eq!(loaded[1].1.hash().unwrap(), functions[1].1.hash().unwrap());
+    }
+
+    #[test]
+    fn test_efb_v1() {
+        // `$noop 0: ret` and `$main 0: .lit 7; load_lit 0; ret_val`, emitted before
+        // code objects had is_void
+        let bytes = b"EFB\0\x01\0\x02\0\0\0\x04\0\0\0main#\0\0\0\x97\x91\x81\xa3I32\x07\0\x90\
+                      \x90\x01\xc0\x91\x92\x81\xa7LoadLit\0\xa9ReturnValv\xc7\x15\xd0b\xad|\
+                      \xa6\tgv\r1\x07\xb3\x9f\x04\0\0\0noop\x10\0\0\0\x97\x90\0\x90\x90\0\xc0\
+                      \x91\x91\xa6Return\xfd#g\xedF\xc9\xf7\xc5\xae\xc7\xca<\x01\x93ST";
+        let functions = read_efb(&bytes[..]).unwrap();
+        assert_eq!(functions.len(), 2);
+        let (main, noop) = (&functions[0].1, &functions[1].1);
+        assert_eq!(functions[0].0, "main");
+        assert!(!main.is_void);
+        assert_eq!(main.code.to_vec(), [Instr::LoadLit(0), Instr::ReturnVal]);
+        assert_eq!(main.max_stack_depth, Some(1));
+        assert!(noop.is_void);
+
+        // A corrupt body fails the hash check
+        let mut bytes = bytes.to_vec();
+        let seven = bytes.iter().position(|&b| b == 0x07).unwrap();
+        bytes[seven] = 0x08;
+        assert!(read_efb(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_efb_invalid() {
+        let obj = init_code_obj(bytecode![Instr::Nop]);
+        let mut bytes = obj.to_efb().unwrap();
+
+        // Truncated
+        assert!(CodeObject::from_efb(&bytes[..bytes.len() - 4]).is_err());
+
+        // Corrupted hash
+        let n = bytes.len();
+        bytes[n - 1] ^= 0xff;
+        assert!(CodeObject::from_efb(&bytes).is_err());
+
+        // Bad magic
+        assert!(CodeObject::from_efb(b"ELF\0").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+
+pub const HASH_SIZE: usize = 16;
+
+/// Number of bytes shown by `Hash::abbrev`
+const ABBREV_SIZE: usize = 4;
+
+/// The content address of a code object: SHA-512 truncated to `HASH_SIZE` bytes.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Hash([u8; HASH_SIZE]);
+
+impl Hash {
+    /// Hash arbitrary bytes
+    pub fn digest(data: &[u8]) -> Hash {
+        let mut hasher = Sha512::new();
+        hasher.update(data);
+        let mut hash = [0; HASH_SIZE];
+        hash.copy_from_slice(&hasher.finalize()[..HASH_SIZE]);
+        Hash(hash)
+    }
+
+    /// Build a hash from a slice of exactly `HASH_SIZE` bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Hash> {
+        let hash: [u8; HASH_SIZE] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("failed to build hash from {bytes:?}"))?;
+        Ok(Hash(hash))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; HASH_SIZE] {
+        &self.0
+    }
+
+    /// The hash as a hex string, without the `0x` prefix
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// A short form of the hash for human-readable output, e.g. `0xdeadbeef`
+    pub fn abbrev(&self) -> String {
+        format!("0x{}", hex::encode(&self.0[..ABBREV_SIZE]))
+    }
+}
+
+impl From<[u8; HASH_SIZE]> for Hash {
+    fn from(bytes: [u8; HASH_SIZE]) -> Self {
+        Hash(bytes)
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", self.to_hex())
+    }
+}
+
+/// Parse a full hash. The `0x` prefix is optional.
+impl FromStr for Hash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let stripped = s.strip_prefix("0x").unwrap_or(s);
+        let bytes = hex::decode(stripped)
+            .map_err(|e| anyhow!("failed to build hash '{s}': {e}"))?;
+        Hash::from_slice(&bytes).map_err(|_| {
+            anyhow!("failed to build hash '{s}': expected {HASH_SIZE} bytes")
+        })
+    }
+}
+
+impl ToSql for Hash {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for Hash {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        Hash::from_slice(blob).map_err(|_| FromSqlError::InvalidBlobSize {
+            expected_size: HASH_SIZE,
+            blob_size: blob.len(),
+        })
+    }
+}
+
+/// An abbreviated hash, as typed by a user. Resolve it to a full `Hash` with
+/// `Database::resolve_hash_prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashPrefix(String);
+
+impl HashPrefix {
+    /// Determine if `hash` starts with this prefix
+    pub fn matches(&self, hash: &Hash) -> bool {
+        hash.to_hex().starts_with(&self.0)
+    }
+
+    /// The prefix as lowercase hex, without the `0x` prefix
+    pub fn as_hex(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for HashPrefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let stripped = s.strip_prefix("0x").unwrap_or(s);
+        if stripped.is_empty() || stripped.len() > HASH_SIZE * 2 {
+            bail!("invalid hash prefix '{s}': bad length");
+        }
+        if !stripped.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid hash prefix '{s}': not hex");
+        }
+        Ok(HashPrefix(stripped.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for HashPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str() {
+        let hash = "0xdeadbeefdeadbeefcafebabecafebabe"
+            .parse::<Hash>()
+            .unwrap();
+        assert_eq!(hash.to_string(), "0xdeadbeefdeadbeefcafebabecafebabe");
+        assert_eq!(hash.abbrev(), "0xdeadbeef");
+        assert_eq!(
+            "deadbeefdeadbeefcafebabecafebabe".parse::<Hash>().unwrap(),
+            hash
+        );
+        assert!("0xdeadbeefdeadbeef".parse::<Hash>().is_err());
+        assert!("0xnothex".parse::<Hash>().is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let hash = "0xdeadbeefdeadbeefcafebabecafebabe"
+            .parse::<Hash>()
+            .unwrap();
+        assert!("0xDEAD".parse::<HashPrefix>().unwrap().matches(&hash));
+        assert!("dead".parse::<HashPrefix>().unwrap().matches(&hash));
+        assert!(!"0xbeef".parse::<HashPrefix>().unwrap().matches(&hash));
+        assert!("0x".parse::<HashPrefix>().is_err());
+        assert!("0xzz".parse::<HashPrefix>().is_err());
+    }
+}
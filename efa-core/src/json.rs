@@ -0,0 +1,145 @@
+//! JSON interchange for values and code objects.
+//!
+//! Values map onto plain JSON so that other languages can produce inputs and read
+//! results: integers and floats become numbers, strings, chars, and hashes become
+//! strings, containers become arrays, and maps with string keys become objects.
+//! This mapping is lossy (e.g. every JSON integer reads back as the narrowest of
+//! I32/I64/U64). Code objects instead use their serde representation, which keeps
+//! every value tagged with its type.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value as Json};
+
+use crate::vm::{CodeObject, Value};
+
+/// Convert a value to plain JSON. Integers that do not fit in an i64 or u64 are
+/// written as decimal strings.
+pub fn to_json(value: &Value) -> Result<Json> {
+    Ok(match value {
+        Value::I8(i) => Json::from(*i),
+        Value::U8(i) => Json::from(*i),
+        Value::I16(i) => Json::from(*i),
+        Value::U16(i) => Json::from(*i),
+        Value::I32(i) => Json::from(*i),
+        Value::U32(i) => Json::from(*i),
+        Value::I64(i) => Json::from(*i),
+        Value::U64(i) => Json::from(*i),
+        Value::Isize(i) => Json::from(*i),
+        Value::Usize(i) => Json::from(*i),
+        Value::I128(_) | Value::U128(_) | Value::BigInt(_) => {
+            let int = value.as_bigint().unwrap();
+            match (i64::try_from(&int), u64::try_from(&int)) {
+                (Ok(i), _) => Json::from(i),
+                (_, Ok(u)) => Json::from(u),
+                _ => Json::String(int.to_string()),
+            }
+        }
+
+        Value::F32(f) => float_to_json(*f as f64)?,
+        Value::F64(f) => float_to_json(*f)?,
+
+        Value::Char(c) => Json::String(c.to_string()),
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Hash(h) => Json::String(h.to_string()),
+        Value::String(s) => Json::String(s.clone()),
+
+        Value::Container(c) => Json::Array(c.iter().map(to_json).collect::<Result<_>>()?),
+        Value::Map(m) => Json::Object(
+            m.iter()
+                .map(|(k, v)| match k {
+                    Value::String(k) => Ok((k.clone(), to_json(v)?)),
+                    k => bail!(
+                        "cannot convert map to JSON: key {k} is a {}, not a string",
+                        k.type_name()
+                    ),
+                })
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+    })
+}
+
+fn float_to_json(f: f64) -> Result<Json> {
+    Number::from_f64(f)
+        .map(Json::Number)
+        .ok_or_else(|| anyhow!("cannot convert {f} to JSON: not a finite number"))
+}
+
+/// Convert plain JSON to a value. Integers become the narrowest of I32, I64, and
+/// U64, other numbers become F64, and objects become maps with string keys.
+pub fn from_json(json: &Json) -> Result<Value> {
+    Ok(match json {
+        Json::Null => bail!("cannot convert JSON null to a value"),
+        Json::Bool(b) => Value::Bool(*b),
+        Json::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i32::try_from(i).map(Value::I32).unwrap_or(Value::I64(i))
+            } else if let Some(u) = n.as_u64() {
+                Value::U64(u)
+            } else {
+                Value::F64(n.as_f64().ok_or_else(|| anyhow!("invalid number {n}"))?)
+            }
+        }
+        Json::String(s) => Value::String(s.clone()),
+        Json::Array(a) => {
+            Value::Container(a.iter().map(from_json).collect::<Result<_>>()?)
+        }
+        Json::Object(o) => Value::Map(
+            o.iter()
+                .map(|(k, v)| Ok((Value::String(k.clone()), from_json(v)?)))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+impl CodeObject {
+    /// Dump the code object as JSON, preserving the exact type of every literal
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<CodeObject> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_value_json() {
+        let value = Value::Map(vec![
+            (Value::string("n"), Value::I32(-4)),
+            (Value::string("f"), Value::F64(0.5)),
+            (
+                Value::string("xs"),
+                Value::Container(vec![Value::Bool(true), Value::string("s")]),
+            ),
+        ]);
+        let json = to_json(&value).unwrap();
+        assert_eq!(json.to_string(), r#"{"n":-4,"f":0.5,"xs":[true,"s"]}"#);
+        assert_eq!(from_json(&json).unwrap(), value);
+
+        assert_eq!(
+            to_json(&Value::U128(u128::MAX)).unwrap(),
+            Json::String(u128::MAX.to_string())
+        );
+        assert_eq!(
+            from_json(&Json::from(u64::MAX)).unwrap(),
+            Value::U64(u64::MAX)
+        );
+        assert!(to_json(&Value::F64(f64::NAN)).is_err());
+        assert!(to_json(&Value::Map(vec![(Value::I32(1), Value::I32(1))])).is_err());
+        assert!(from_json(&Json::Null).is_err());
+    }
+
+    #[test]
+    fn test_code_object_json() {
+        let obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::ReturnVal]);
+        let json = obj.to_json().unwrap();
+        let loaded = CodeObject::from_json(&json).unwrap();
+        assert_eq!(loaded.hash().unwrap(), obj.hash().unwrap());
+    }
+}
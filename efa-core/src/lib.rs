@@ -1,18 +1,21 @@
-use anyhow::{anyhow, bail, Result};
-
 #[macro_use]
 pub mod bytecode;
+pub mod analysis;
 pub mod asm;
+pub mod builder;
 pub mod cli;
 pub mod db;
-#[allow(dead_code)]
+pub mod efb;
+mod hash;
+pub mod json;
+pub mod lsp;
+pub mod opt;
 pub mod solver;
+pub mod sync;
+pub mod verify;
 pub mod vm;
 
-pub const HASH_SIZE: usize = 16;
-
-// TODO: consider making a wrapper, since impl FromStr would be nice
-pub type Hash = [u8; HASH_SIZE];
+pub use hash::{Hash, HashPrefix, HASH_SIZE};
 
 /// Determine if `name` is a valid name for a code object or type.
 fn is_valid_name(name: &str) -> bool {
@@ -20,25 +23,9 @@ fn is_valid_name(name: &str) -> bool {
     syn::parse_str::<syn::Ident>(name).is_ok()
 }
 
-// TODO: convert all grep ..HASH_SIZE] to use this method
-fn hash_from_vec(hash: Vec<u8>) -> Result<Hash> {
-    let trunc: [u8; HASH_SIZE] = (&hash[0..HASH_SIZE])
-        .try_into()
-        .map_err(|_| anyhow!("failed to build hash from {hash:?}"))?;
-
-    Ok(trunc)
-}
-
-/// Build hash from hex string of the form 0xHASH.
-fn hash_from_str(hash_str: &str) -> Result<Hash> {
-    if let Some(stripped) = hash_str.strip_prefix("0x") {
-        let hash_b = hex::decode(stripped)?;
-        hash_b
-            .try_into()
-            .map_err(|_| anyhow!("failed to build hash '{hash_str}': invalid hash"))
-    } else {
-        bail!("failed to build hash '{hash_str}': does not start with '0x'")
-    }
+/// Determine if `name` is a valid name, optionally in a module, like `math::isqrt`
+fn is_valid_qualified_name(name: &str) -> bool {
+    name.split("::").all(is_valid_name)
 }
 
 #[cfg(test)]
@@ -53,8 +40,10 @@ mod tests {
     }
 
     #[test]
-    fn test_build_hash() {
-        assert!(hash_from_str("0xdeadbeefdeadbeefcafebabecafebabe").is_ok());
-        assert!(hash_from_str("0xdeadbeefdeadbeef").is_err());
+    fn test_is_valid_qualified_name() {
+        assert!(is_valid_qualified_name("math::isqrt"));
+        assert!(is_valid_qualified_name("isqrt"));
+        assert!(!is_valid_qualified_name("math::"));
+        assert!(!is_valid_qualified_name("math:isqrt"));
     }
 }
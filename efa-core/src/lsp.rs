@@ -0,0 +1,666 @@
+//! A language server for efa assembly. Diagnostics come from the parser and the
+//! verifier, definitions and hovers are found in the open document, and hovers
+//! also look functions up in a code database if the server was given one.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+    PublishDiagnostics,
+};
+use lsp_types::request::{Completion, GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionParams, CompletionResponse, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use serde::de::DeserializeOwned;
+
+use crate::asm::dis::{disassemble_function_with, DisOptions};
+use crate::asm::parser::{ParseError, Parser};
+use crate::db::{Database, Metadata};
+use crate::verify;
+use crate::vm::CodeObject;
+use crate::Hash;
+
+/// Every instruction mnemonic the assembler accepts
+const MNEMONICS: &[&str] = &[
+    "add",
+    "and",
+    "call",
+    "call_self",
+    "car",
+    "cdr",
+    "cmp",
+    "cont_ext",
+    "cont_get",
+    "cont_ins",
+    "cont_len",
+    "cont_make",
+    "cont_set",
+    "dbg",
+    "div",
+    "dup",
+    "eq",
+    "jmp",
+    "jmp_eq",
+    "jmp_f",
+    "jmp_ge",
+    "jmp_gt",
+    "jmp_le",
+    "jmp_lt",
+    "jmp_ne",
+    "jmp_rel",
+    "jmp_rel_f",
+    "jmp_rel_t",
+    "jmp_t",
+    "load_arg",
+    "load_data",
+    "load_dyn",
+    "load_func",
+    "load_lit",
+    "load_loc",
+    "map_del",
+    "map_get",
+    "map_keys",
+    "map_len",
+    "map_new",
+    "map_set",
+    "mod",
+    "mul",
+    "neg",
+    "nop",
+    "not",
+    "or",
+    "pop",
+    "ret",
+    "ret_val",
+    "shl",
+    "shr",
+    "store_loc",
+    "sub",
+];
+
+const DIRECTIVES: &[&str] = &[
+    ".arg",
+    ".author",
+    ".const",
+    ".data",
+    ".doc",
+    ".endmacro",
+    ".extern",
+    ".lit",
+    ".local",
+    ".macro",
+    ".module",
+    ".tag",
+    "#include",
+];
+
+/// Run the language server over stdin and stdout until the client shuts it down
+pub fn run(db_path: Option<&str>) -> Result<()> {
+    let db = db_path.map(Database::open).transpose()?;
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::FULL,
+        )),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(Default::default()),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    let mut documents = HashMap::new();
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    break;
+                }
+                let resp = handle_request(&documents, db.as_ref(), req);
+                connection.sender.send(Message::Response(resp))?;
+            }
+            Message::Notification(not) => {
+                if let Some(uri) = handle_notification(&mut documents, not)? {
+                    let diagnostics = documents
+                        .get(&uri)
+                        .map(|text| diagnostics(&uri_path(&uri), text))
+                        .unwrap_or_default();
+                    let params = PublishDiagnosticsParams {
+                        uri,
+                        diagnostics,
+                        version: None,
+                    };
+                    connection
+                        .sender
+                        .send(Message::Notification(Notification::new(
+                            PublishDiagnostics::METHOD.to_string(),
+                            params,
+                        )))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+/// Update the open documents, returning the document whose diagnostics changed
+fn handle_notification(
+    documents: &mut HashMap<Url, String>,
+    not: Notification,
+) -> Result<Option<Url>> {
+    let uri = match not.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = params(not.params)?;
+            let doc = params.text_document;
+            documents.insert(doc.uri.clone(), doc.text);
+            doc.uri
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = params(not.params)?;
+            // The whole document is synced on every change
+            if let Some(change) = params.content_changes.into_iter().last() {
+                documents.insert(params.text_document.uri.clone(), change.text);
+            }
+            params.text_document.uri
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = params(not.params)?;
+            documents.remove(&params.text_document.uri);
+            params.text_document.uri
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(uri))
+}
+
+fn handle_request(
+    documents: &HashMap<Url, String>,
+    db: Option<&Database>,
+    req: Request,
+) -> Response {
+    let id = req.id.clone();
+    let result = (|| -> Result<serde_json::Value> {
+        let value = match req.method.as_str() {
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = params(req.params)?;
+                let doc = params.text_document_position_params;
+                let range = documents
+                    .get(&doc.text_document.uri)
+                    .and_then(|text| definition(text, doc.position));
+                serde_json::to_value(range.map(|range| {
+                    GotoDefinitionResponse::Scalar(Location {
+                        uri: doc.text_document.uri,
+                        range,
+                    })
+                }))?
+            }
+            HoverRequest::METHOD => {
+                let params: HoverParams = params(req.params)?;
+                let doc = params.text_document_position_params;
+                let uri = doc.text_document.uri;
+                let value = documents
+                    .get(&uri)
+                    .and_then(|text| hover(&uri_path(&uri), text, doc.position, db));
+                serde_json::to_value(value.map(|value| Hover {
+                    contents: HoverContents::Markup(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    }),
+                    range: None,
+                }))?
+            }
+            Completion::METHOD => {
+                let _: CompletionParams = params(req.params)?;
+                serde_json::to_value(CompletionResponse::Array(completions()))?
+            }
+            _ => serde_json::Value::Null,
+        };
+        Ok(value)
+    })();
+
+    match result {
+        Ok(value) => Response::new_ok(id, value),
+        Err(e) => Response::new_err(
+            id,
+            lsp_server::ErrorCode::InvalidParams as i32,
+            e.to_string(),
+        ),
+    }
+}
+
+fn params<T: DeserializeOwned>(value: serde_json::Value) -> Result<T> {
+    Ok(serde_json::from_value(value)?)
+}
+
+fn uri_path(uri: &Url) -> PathBuf {
+    uri.to_file_path()
+        .unwrap_or_else(|_| PathBuf::from(uri.path()))
+}
+
+/// The parse and verification errors in `text`, the contents of the file at `path`
+pub fn diagnostics(path: &Path, text: &str) -> Vec<Diagnostic> {
+    let file = path.display().to_string();
+    let parses = match Parser::parse_source_at(text, path) {
+        Ok(parses) => parses,
+        Err(e) => {
+            let Some(error) = e.downcast_ref::<ParseError>() else {
+                return vec![diagnostic(Range::default(), e.to_string())];
+            };
+            let errors = match error {
+                ParseError::Multiple(errors) => errors.iter().collect(),
+                error => vec![error],
+            };
+            return errors
+                .into_iter()
+                .map(|error| match error {
+                    ParseError::Spanned {
+                        file: f,
+                        span,
+                        error,
+                        ..
+                    } if *f == file => {
+                        let start =
+                            Position::new(span.line as u32 - 1, span.column as u32 - 1);
+                        let end =
+                            Position::new(start.line, start.character + span.len as u32);
+                        diagnostic(Range::new(start, end), error.message())
+                    }
+                    // Errors in included files are reported at the top
+                    ParseError::Spanned {
+                        file, span, error, ..
+                    } => diagnostic(
+                        Range::default(),
+                        format!("{file}:{}: {}", span.line, error.message()),
+                    ),
+                    error => diagnostic(Range::default(), error.message()),
+                })
+                .collect();
+        }
+    };
+
+    // Functions from included files carry that file in their debug info
+    parses
+        .iter()
+        .filter_map(|parse| {
+            let info = parse.code_obj.debug_info.as_ref()?;
+            (info.file.as_deref() == Some(file.as_str())).then_some((parse, info))
+        })
+        .flat_map(|(parse, info)| {
+            verify::diagnose(&parse.code_obj)
+                .into_iter()
+                .map(move |error| {
+                    let line = error
+                        .offset()
+                        .and_then(|offset| info.line(offset))
+                        .or_else(|| def_line(text, &parse.func_name))
+                        .unwrap_or(1);
+                    let source_line = text.lines().nth(line - 1).unwrap_or_default();
+                    let start = source_line.len() - source_line.trim_start().len();
+                    let range = Range::new(
+                        Position::new(line as u32 - 1, start as u32),
+                        Position::new(
+                            line as u32 - 1,
+                            source_line.trim_end().chars().count() as u32,
+                        ),
+                    );
+                    diagnostic(range, error.to_string())
+                })
+        })
+        .collect()
+}
+
+fn diagnostic(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("efa".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+/// A function definition in a document
+struct FuncDef {
+    /// The name, qualified by the function's module
+    name: String,
+    /// 0-based line
+    line: usize,
+}
+
+/// The function definitions in `text`, in order
+fn func_defs(text: &str) -> Vec<FuncDef> {
+    let mut module = None;
+    let mut defs = vec![];
+    let mut in_block = false;
+    for (line, source_line) in text.lines().enumerate() {
+        let code = Parser::strip_comment(&Parser::blank_block_comments(
+            source_line,
+            &mut in_block,
+        ));
+        let mut words = code.split_whitespace();
+        if words.next() == Some(".module") {
+            module = words.next().map(str::to_string);
+        } else if let Some(Ok((name, _, _))) = Parser::is_func_def(&code) {
+            let name = match &module {
+                Some(module) => format!("{module}::{name}"),
+                None => name,
+            };
+            defs.push(FuncDef { name, line });
+        }
+    }
+    defs
+}
+
+/// The 1-based line of the definition of the function named `name`
+fn def_line(text: &str, name: &str) -> Option<usize> {
+    func_defs(text)
+        .into_iter()
+        .find(|def| def.name == name)
+        .map(|def| def.line + 1)
+}
+
+/// The word at `position`, and the range it covers
+fn word_at(text: &str, position: Position) -> Option<(String, Range)> {
+    let line = text.lines().nth(position.line as usize)?;
+    // Comments are stripped along with the indentation
+    let indent = line.len() - line.trim_start().len();
+    let code = line[..indent + Parser::strip_comment(line).len()]
+        .chars()
+        .collect::<Vec<_>>();
+    let is_word = |c: &char| c.is_alphanumeric() || matches!(c, '_' | '$' | ':' | '.');
+    let at = position.character as usize;
+    if !code.get(at).is_some_and(is_word) {
+        return None;
+    }
+    let start = code[..at]
+        .iter()
+        .rposition(|c| !is_word(c))
+        .map_or(0, |i| i + 1);
+    let end = code[at..]
+        .iter()
+        .position(|c| !is_word(c))
+        .map_or(code.len(), |i| at + i);
+    let range = Range::new(
+        Position::new(position.line, start as u32),
+        Position::new(position.line, end as u32),
+    );
+    Some((code[start..end].iter().collect(), range))
+}
+
+/// The function named by `name` when used on `line`: a name without a module is
+/// looked up in the module it is used in first
+fn resolve_function<'a>(
+    defs: &'a [FuncDef],
+    name: &str,
+    line: usize,
+) -> Option<&'a FuncDef> {
+    let enclosing = defs.iter().rev().find(|def| def.line <= line);
+    let qualified = enclosing
+        .and_then(|def| def.name.rsplit_once("::"))
+        .map(|(module, _)| format!("{module}::{name}"));
+    qualified
+        .and_then(|qualified| defs.iter().find(|def| def.name == qualified))
+        .or_else(|| defs.iter().find(|def| def.name == name))
+}
+
+/// The name of the function referred to at `position`, qualified by its module
+fn function_at(text: &str, position: Position) -> Option<String> {
+    let (word, _) = word_at(text, position)?;
+    let name = word.strip_prefix('$')?.trim_end_matches(':');
+    let defs = func_defs(text);
+    match resolve_function(&defs, name, position.line as usize) {
+        Some(def) => Some(def.name.clone()),
+        None => Some(name.to_string()),
+    }
+}
+
+/// Where the function or label at `position` is defined
+pub fn definition(text: &str, position: Position) -> Option<Range> {
+    let (word, _) = word_at(text, position)?;
+    let defs = func_defs(text);
+    let line = position.line as usize;
+
+    let target = match word.strip_prefix('$') {
+        Some(name) => resolve_function(&defs, name.trim_end_matches(':'), line)?.line,
+        None => {
+            // Labels are local to the function they are in
+            let label = format!("{}:", word.trim_end_matches(':'));
+            let start = defs.iter().rev().find(|def| def.line <= line)?.line;
+            let end = defs
+                .iter()
+                .find(|def| def.line > line)
+                .map_or(usize::MAX, |def| def.line);
+            text.lines()
+                .enumerate()
+                .take(end)
+                .skip(start + 1)
+                .find(|(_, l)| Parser::strip_comment(l).trim() == label)?
+                .0
+        }
+    };
+
+    let source_line = text.lines().nth(target)?;
+    let start = source_line.len() - source_line.trim_start().len();
+    Some(Range::new(
+        Position::new(target as u32, start as u32),
+        Position::new(target as u32, source_line.trim_end().chars().count() as u32),
+    ))
+}
+
+/// Describe the function at `position`, from `db` if it has a function by that
+/// name, or else from the document
+pub fn hover(
+    path: &Path,
+    text: &str,
+    position: Position,
+    db: Option<&Database>,
+) -> Option<String> {
+    let name = function_at(text, position)?;
+    let names = |hash: &Hash| {
+        db.and_then(|db| db.get_names_of_hash(hash).ok())
+            .unwrap_or_default()
+    };
+    let (hash, obj, metadata) = db
+        .and_then(|db| {
+            let (hash, obj) = db.get_code_object_by_name(&name).ok()?;
+            Some((hash, obj, db.get_metadata(&hash).ok().flatten()))
+        })
+        .or_else(|| {
+            Parser::parse_source_at(text, path)
+                .ok()?
+                .into_iter()
+                .find(|parse| parse.func_name == name)
+                .and_then(|parse| {
+                    let hash = parse.code_obj.hash().ok()?;
+                    Some((hash, parse.code_obj, Some(parse.metadata)))
+                })
+        })?;
+    Some(describe(&name, &hash, &obj, metadata.as_ref(), &names))
+}
+
+/// Describe a function, with its documentation, its other names, and the names
+/// of the functions it loads as given by `names`
+fn describe(
+    name: &str,
+    hash: &Hash,
+    obj: &CodeObject,
+    metadata: Option<&Metadata>,
+    names: &dyn Fn(&Hash) -> Vec<String>,
+) -> String {
+    let mut out = format!("**${name}** (arity {})\n\n`{hash}`\n", obj.argcount);
+    if let Some(doc) = metadata.and_then(|m| m.doc.as_ref()) {
+        out.push_str(&format!("\n{doc}\n"));
+    }
+    let aliases = names(hash)
+        .into_iter()
+        .filter(|alias| alias != name)
+        .map(|alias| format!("`${alias}`"))
+        .collect::<Vec<_>>();
+    if !aliases.is_empty() {
+        out.push_str(&format!("\nAlso named {}\n", aliases.join(", ")));
+    }
+    let dis = disassemble_function_with(
+        name,
+        hash,
+        obj,
+        metadata,
+        names,
+        DisOptions::default(),
+    );
+    if let Ok(dis) = dis {
+        out.push_str(&format!("\n```\n{}\n```\n", dis.trim_end()));
+    }
+    out
+}
+
+/// Instruction mnemonics and directives
+pub fn completions() -> Vec<CompletionItem> {
+    let items = |words: &'static [&str], kind| {
+        words.iter().map(move |word| CompletionItem {
+            label: word.to_string(),
+            kind: Some(kind),
+            ..Default::default()
+        })
+    };
+    items(MNEMONICS, CompletionItemKind::KEYWORD)
+        .chain(items(DIRECTIVES, CompletionItemKind::KEYWORD))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+$inc 1:
+    .lit 1
+    load_arg 0
+    load_lit 0
+    add
+    ret_val
+
+$main 0:
+    .lit 1
+    load_lit 0
+    load_dyn $inc # call it
+    call
+top:
+    jmp top
+";
+
+    #[test]
+    fn test_diagnostics() {
+        let path = Path::new("test.asm");
+        assert!(diagnostics(path, SOURCE).is_empty());
+
+        // Parse errors are reported where they are
+        let diags =
+            diagnostics(path, "$main 0:\n    ret\n    bogus 1\n    jmp nowhere\n");
+        let ranges = diags.iter().map(|d| d.range).collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                Range::new(Position::new(2, 4), Position::new(2, 11)),
+                Range::new(Position::new(3, 8), Position::new(3, 15)),
+            ]
+        );
+
+        // Verification errors are reported at the instruction
+        let diags = diagnostics(path, "$main 0:\n    add\n    ret\n");
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].range.start, Position::new(1, 4));
+        assert!(diags[0].message.contains("verification failed"));
+    }
+
+    #[test]
+    fn test_definition() {
+        // Function names
+        let range = definition(SOURCE, Position::new(10, 16)).unwrap();
+        assert_eq!(range.start, Position::new(0, 0));
+
+        // Labels
+        let range = definition(SOURCE, Position::new(13, 9)).unwrap();
+        assert_eq!(
+            range,
+            Range::new(Position::new(12, 0), Position::new(12, 4))
+        );
+
+        // Not a name
+        assert_eq!(definition(SOURCE, Position::new(10, 26)), None);
+
+        // Names without a module are looked up in their own module first
+        let source = ".module a\n$f 0:\n    ret\n$g 0:\n    load_dyn $f\n    ret\n.module\n$f 0:\n    ret\n";
+        let range = definition(source, Position::new(4, 14)).unwrap();
+        assert_eq!(range.start, Position::new(1, 0));
+    }
+
+    #[test]
+    fn test_hover() {
+        let path = Path::new("test.asm");
+        let text = hover(path, SOURCE, Position::new(10, 15), None).unwrap();
+        assert!(text.contains("**$inc** (arity 1)"));
+        assert!(text.contains("load_arg"));
+
+        // The database is preferred
+        let db = Database::temp().unwrap();
+        let obj = Parser::parse_str("$inc 1:\n    load_arg 0\n    ret_val\n")
+            .unwrap()
+            .remove(0)
+            .code_obj;
+        let hash = db.insert_code_object_with_name(&obj, "inc").unwrap();
+        let text = hover(path, SOURCE, Position::new(10, 15), Some(&db)).unwrap();
+        assert!(text.contains(&hash.to_string()));
+        assert!(!text.contains("Also named"));
+
+        db.create_alias("increment", &hash).unwrap();
+        let text = hover(path, SOURCE, Position::new(10, 15), Some(&db)).unwrap();
+        assert!(text.contains("Also named `$increment`"));
+
+        // With its documentation
+        let metadata = Metadata {
+            doc: Some("Adds one".to_string()),
+            ..Default::default()
+        };
+        db.set_metadata(&hash, &metadata).unwrap();
+        let text = hover(path, SOURCE, Position::new(10, 15), Some(&db)).unwrap();
+        assert!(text.contains("`\n\nAdds one\n"));
+        assert!(text.contains(".doc \"Adds one\""));
+
+        assert_eq!(hover(path, SOURCE, Position::new(2, 6), None), None);
+    }
+
+    #[test]
+    fn test_completions() {
+        let labels = completions()
+            .into_iter()
+            .map(|item| item.label)
+            .collect::<Vec<_>>();
+        assert!(labels.contains(&"load_dyn".to_string()));
+        assert!(labels.contains(&".extern".to_string()));
+
+        // Every mnemonic is one the parser knows, with some argument
+        for mnemonic in MNEMONICS {
+            let known = ["", " 0", " $main", " top"].iter().any(|arg| {
+                let source = format!("$main 0:\ntop:\n    {mnemonic}{arg}\n    ret\n");
+                Parser::parse_str(&source).is_ok_and(|parses| !parses.is_empty())
+                    || Parser::parse_str(&source)
+                        .is_err_and(|e| !e.to_string().contains("unknown instruction"))
+            });
+            assert!(known, "{mnemonic}");
+        }
+    }
+}
@@ -0,0 +1,677 @@
+//! Optimization passes over code objects. Every pass must preserve the observable
+//! behavior of the code, including runtime errors.
+
+use std::collections::HashSet;
+use std::mem::discriminant;
+
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::bytecode::{BinOp, Bytecode, Instr, UnaryOp};
+use crate::vm::{CodeObject, Value};
+
+/// Fold operations on literals into a single literal, e.g.
+/// `load_lit 0; load_lit 1; add` becomes `load_lit 2`. Chains fold repeatedly.
+/// Operations that would fail or overflow at runtime are left alone, as are loads
+/// of literals that are out of range.
+pub fn fold_constants(obj: &mut CodeObject) {
+    let mut code = obj.code.to_vec();
+    let mut folded = false;
+    let mut i = 0;
+
+    while i < code.len() {
+        let (len, value) = match &code[i..] {
+            [Instr::LoadLit(a), Instr::LoadLit(b), Instr::BinOp(op), ..] => {
+                match (obj.litpool.get(*a), obj.litpool.get(*b)) {
+                    (Some(a), Some(b)) => (3, eval_binop(op, a, b)),
+                    _ => (0, None),
+                }
+            }
+            [Instr::LoadLit(a), Instr::UnaryOp(op), ..] => match obj.litpool.get(*a) {
+                Some(a) => (2, eval_unaryop(op, a)),
+                None => (0, None),
+            },
+            _ => (0, None),
+        };
+
+        // Can't fold if something jumps into the middle of the sequence
+        let jumped_into = obj
+            .labels
+            .iter()
+            .copied()
+            .chain(relative_targets(&code))
+            .any(|target| target > i && target < i + len);
+
+        match value {
+            Some(value) if !jumped_into => {
+                let new_offset = |offset: usize| match offset > i {
+                    true => offset - (len - 1),
+                    false => offset,
+                };
+                fix_relative_jumps(&mut code, new_offset);
+
+                code[i] = Instr::LoadLit(add_literal(&mut obj.litpool, value));
+                code.drain(i + 1..i + len);
+                if let Some(debug_info) = &mut obj.debug_info {
+                    debug_info.lines.drain(i + 1..i + len);
+                }
+                for target in obj.labels.iter_mut().filter(|t| **t > i) {
+                    *target -= len - 1;
+                }
+                folded = true;
+
+                // The new literal may be an operand of the previous instruction
+                i = i.saturating_sub(1);
+            }
+            _ => i += 1,
+        }
+    }
+
+    if folded {
+        obj.code = Bytecode::new(code);
+        compact_litpool(obj);
+    }
+}
+
+/// Remove instructions that can never execute, then drop labels and literals that
+/// are no longer used, renumbering the rest. Expects labels to be in bounds, i.e. a
+/// verified code object.
+pub fn eliminate_dead_code(obj: &mut CodeObject) {
+    let code_len = obj.code.len();
+    let mut reachable = vec![false; code_len];
+    let mut worklist = vec![0];
+
+    while let Some(offset) = worklist.pop() {
+        if offset >= code_len || reachable[offset] {
+            continue;
+        }
+        reachable[offset] = true;
+        worklist.extend(obj.successors(offset));
+    }
+
+    // new_offsets[i] is the offset of old instruction i after removal, or where it
+    // would have been. The extra entry handles labels pointing at the end.
+    let new_offsets = reachable
+        .iter()
+        .scan(0, |next, &live| {
+            let offset = *next;
+            *next += live as usize;
+            Some(offset)
+        })
+        .chain(std::iter::once(reachable.iter().filter(|&&r| r).count()))
+        .collect::<Vec<usize>>();
+
+    let mut code = obj.code.to_vec();
+    fix_relative_jumps(&mut code, |offset| new_offsets[offset]);
+    let mut code = code
+        .into_iter()
+        .zip(&reachable)
+        .filter(|(_, &live)| live)
+        .map(|(instr, _)| instr)
+        .collect::<Vec<Instr>>();
+
+    // Keep only the labels that a remaining jump uses, numbered in offset order
+    let mut used = code
+        .iter()
+        .filter_map(Instr::jump_label)
+        .collect::<Vec<usize>>();
+    used.sort_by_key(|&label| (obj.labels[label], label));
+    used.dedup();
+
+    let mut label_remap = vec![0; obj.labels.len()];
+    let labels = used
+        .iter()
+        .enumerate()
+        .map(|(new_label, &label)| {
+            label_remap[label] = new_label;
+            new_offsets[obj.labels[label]]
+        })
+        .collect();
+
+    for instr in code.iter_mut() {
+        if let Some(label) = instr.jump_label() {
+            instr.set_jump_label(label_remap[label]);
+        }
+    }
+
+    if let Some(debug_info) = &mut obj.debug_info {
+        debug_info.lines = debug_info
+            .lines
+            .iter()
+            .zip(&reachable)
+            .filter(|(_, &live)| live)
+            .map(|(line, _)| *line)
+            .collect();
+    }
+
+    obj.code = Bytecode::new(code);
+    obj.labels = labels;
+    compact_litpool(obj);
+}
+
+/// The locals (by `LoadLocal`/`StoreLocal` index) that are live before each
+/// instruction, i.e. may be loaded before they are next stored
+pub fn live_locals(obj: &CodeObject) -> Vec<HashSet<usize>> {
+    let code = &obj.code;
+    let mut live_in = vec![HashSet::new(); code.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for offset in (0..code.len()).rev() {
+            let mut live = obj
+                .successors(offset)
+                .into_iter()
+                .filter(|&s| s < code.len())
+                .flat_map(|s| live_in[s].iter().copied())
+                .collect::<HashSet<usize>>();
+
+            match code[offset] {
+                Instr::StoreLocal(i) => {
+                    live.remove(&i);
+                }
+                Instr::LoadLocal(i) => {
+                    live.insert(i);
+                }
+                _ => (),
+            }
+
+            if live != live_in[offset] {
+                live_in[offset] = live;
+                changed = true;
+            }
+        }
+    }
+
+    live_in
+}
+
+/// Renumber locals so that locals which are never live at the same time share a
+/// slot, and drop locals that are never used, shrinking `localnames`. The first
+/// `named` locals were named in the source, so they keep their slots and names.
+pub fn reuse_local_slots(obj: &mut CodeObject, named: usize) {
+    let num_locals = obj.localnames.len().saturating_sub(obj.argcount);
+    let named = named.min(num_locals);
+    let live_in = live_locals(obj);
+    let used = obj
+        .code
+        .iter()
+        .filter_map(|instr| match *instr {
+            Instr::LoadLocal(i) | Instr::StoreLocal(i) => Some(i),
+            _ => None,
+        })
+        .chain(0..named)
+        .collect::<HashSet<usize>>();
+    let mut interferes = vec![HashSet::new(); num_locals];
+
+    // Named locals share with nothing, so the greedy pass below gives each its
+    // own slot
+    for (i, others) in interferes.iter_mut().enumerate() {
+        match i < named {
+            true => others.extend((0..num_locals).filter(|&j| j != i)),
+            false => others.extend(0..named),
+        }
+    }
+
+    // A local that may be loaded before it is stored must keep its own slot, so
+    // that the load still fails instead of seeing another local's value
+    let uninit = live_in.first().cloned().unwrap_or_default();
+    for &i in &uninit {
+        interferes[i].extend((0..num_locals).filter(|&j| j != i));
+    }
+
+    // A store interferes with every other local that is live after it
+    for (offset, instr) in obj.code.iter().enumerate() {
+        if let Instr::StoreLocal(i) = *instr {
+            for succ in obj.successors(offset) {
+                for &j in live_in.get(succ).into_iter().flatten() {
+                    if j != i {
+                        interferes[i].insert(j);
+                        interferes[j].insert(i);
+                    }
+                }
+            }
+        }
+    }
+
+    // Greedily give each local the lowest slot not used by one it interferes with
+    let mut slots: Vec<Option<usize>> = vec![None; num_locals];
+    for i in (0..num_locals).filter(|i| used.contains(i)) {
+        let taken = interferes[i]
+            .iter()
+            .filter_map(|&j| slots[j])
+            .collect::<HashSet<usize>>();
+        slots[i] = (0..).find(|slot| !taken.contains(slot));
+    }
+
+    let num_slots = slots.iter().flatten().map(|s| s + 1).max().unwrap_or(0);
+    if num_slots == num_locals {
+        return;
+    }
+
+    let code = obj
+        .code
+        .iter()
+        .map(|instr| match instr {
+            Instr::LoadLocal(i) => Instr::LoadLocal(slots[*i].unwrap()),
+            Instr::StoreLocal(i) => Instr::StoreLocal(slots[*i].unwrap()),
+            instr => instr.clone(),
+        })
+        .collect();
+
+    // Each slot keeps the name of the first local given it
+    let locals = obj.localnames.split_off(obj.argcount);
+    for slot in 0..num_slots {
+        let first = slots.iter().position(|s| *s == Some(slot)).unwrap();
+        obj.localnames.push(locals[first].clone());
+    }
+    obj.code = Bytecode::new(code);
+}
+
+/// The offsets that relative jumps in `code` may jump to
+fn relative_targets(code: &[Instr]) -> impl Iterator<Item = usize> + '_ {
+    code.iter()
+        .enumerate()
+        .filter_map(|(offset, instr)| offset.checked_add_signed(instr.jump_delta()?))
+}
+
+/// Update relative jumps for instructions moving, given where each instruction
+/// (and the end of the code) moves to. Must be called before the code is moved.
+fn fix_relative_jumps(code: &mut [Instr], new_offset: impl Fn(usize) -> usize) {
+    for (offset, instr) in code.iter_mut().enumerate() {
+        if let Some(delta) = instr.jump_delta() {
+            let target = offset.saturating_add_signed(delta);
+            instr.set_jump_delta(
+                new_offset(target) as isize - new_offset(offset) as isize,
+            );
+        }
+    }
+}
+
+/// Add a literal to the pool, reusing an equal one if it exists
+fn add_literal(litpool: &mut Vec<Value>, value: Value) -> usize {
+    match litpool.iter().position(|lit| *lit == value) {
+        Some(index) => index,
+        None => {
+            litpool.push(value);
+            litpool.len() - 1
+        }
+    }
+}
+
+/// Remove literals that are no longer loaded, renumbering the ones that are. Does
+/// nothing if a literal is loaded that is out of range.
+fn compact_litpool(obj: &mut CodeObject) {
+    let in_range = obj.code.iter().all(|instr| match instr {
+        Instr::LoadLit(index) | Instr::DbgMsg(index) => *index < obj.litpool.len(),
+        _ => true,
+    });
+    if !in_range {
+        return;
+    }
+
+    let mut remap = vec![None; obj.litpool.len()];
+    let mut litpool = vec![];
+    let mut new_index = |index: usize| {
+        *remap[index].get_or_insert_with(|| {
+            litpool.push(obj.litpool[index].clone());
+            litpool.len() - 1
+        })
+    };
+
+    let code = obj
+        .code
+        .iter()
+        .map(|instr| match *instr {
+            Instr::LoadLit(index) => Instr::LoadLit(new_index(index)),
+            Instr::DbgMsg(index) => Instr::DbgMsg(new_index(index)),
+            ref instr => instr.clone(),
+        })
+        .collect();
+
+    obj.code = Bytecode::new(code);
+    obj.litpool = litpool;
+}
+
+/// Evaluate a binary operation the way the VM would, or `None` if it can't be
+/// done at compile time without changing behavior
+fn eval_binop(op: &BinOp, lhs: &Value, rhs: &Value) -> Option<Value> {
+    let (lhs, rhs) = (lhs.clone(), rhs.clone());
+
+    match op {
+        BinOp::Eq => return Some(Value::Bool(lhs.equals(&rhs))),
+        BinOp::And => return Some(lhs.and(rhs)),
+        BinOp::Or => return Some(lhs.or(rhs)),
+        // The result depends on the width of the operands in ways that are easy to
+        // get subtly wrong, so leave shifts to the VM
+        BinOp::Shl | BinOp::Shr => return None,
+        _ => (),
+    }
+
+    match (&lhs, &rhs) {
+        (Value::F32(_), Value::F32(_)) | (Value::F64(_), Value::F64(_)) => {
+            Some(apply_arith(op, lhs, rhs))
+        }
+        (Value::String(_), Value::String(_)) if *op == BinOp::Add => Some(lhs + rhs),
+        _ => {
+            let (x, y) = (lhs.as_bigint()?, rhs.as_bigint()?);
+            if matches!(op, BinOp::Div | BinOp::Mod) && y.is_zero() {
+                return None;
+            }
+
+            if matches!(lhs, Value::BigInt(_)) || matches!(rhs, Value::BigInt(_)) {
+                Some(apply_arith(op, lhs, rhs))
+            } else if discriminant(&lhs) == discriminant(&rhs) {
+                // Compute exactly, then only fold if the result doesn't overflow
+                let result = apply_arith(op, Value::BigInt(x), Value::BigInt(y));
+                narrow_like(&lhs, result.as_bigint()?)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn apply_arith(op: &BinOp, lhs: Value, rhs: Value) -> Value {
+    match op {
+        BinOp::Add => lhs + rhs,
+        BinOp::Sub => lhs - rhs,
+        BinOp::Mul => lhs * rhs,
+        BinOp::Div => lhs / rhs,
+        BinOp::Mod => lhs % rhs,
+        _ => unreachable!("not an arithmetic operation"),
+    }
+}
+
+fn eval_unaryop(op: &UnaryOp, arg: &Value) -> Option<Value> {
+    match (op, arg) {
+        (UnaryOp::Not, Value::Bool(_)) => Some(!arg.clone()),
+        (UnaryOp::Not, _) if arg.as_bigint().is_some() => Some(!arg.clone()),
+        (UnaryOp::Neg, Value::F32(_) | Value::F64(_) | Value::BigInt(_)) => {
+            Some(-arg.clone())
+        }
+        (
+            UnaryOp::Neg,
+            Value::I8(_)
+            | Value::I16(_)
+            | Value::I32(_)
+            | Value::I64(_)
+            | Value::I128(_)
+            | Value::Isize(_),
+        ) => narrow_like(arg, -arg.as_bigint()?),
+        _ => None,
+    }
+}
+
+/// Convert `n` to the same integer type as `template`, if it fits
+fn narrow_like(template: &Value, n: BigInt) -> Option<Value> {
+    match template {
+        Value::I8(_) => n.to_i8().map(Value::I8),
+        Value::U8(_) => n.to_u8().map(Value::U8),
+        Value::I16(_) => n.to_i16().map(Value::I16),
+        Value::U16(_) => n.to_u16().map(Value::U16),
+        Value::I32(_) => n.to_i32().map(Value::I32),
+        Value::U32(_) => n.to_u32().map(Value::U32),
+        Value::I64(_) => n.to_i64().map(Value::I64),
+        Value::U64(_) => n.to_u64().map(Value::U64),
+        Value::I128(_) => n.to_i128().map(Value::I128),
+        Value::U128(_) => n.to_u128().map(Value::U128),
+        Value::Isize(_) => n.to_isize().map(Value::Isize),
+        Value::Usize(_) => n.to_usize().map(Value::Usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::init_code_obj_with_pool;
+
+    #[test]
+    fn test_fold_chain() {
+        let mut obj = init_code_obj_with_pool(
+            bytecode![
+                Instr::LoadLit(0),
+                Instr::LoadLit(1),
+                Instr::BinOp(BinOp::Add),
+                Instr::LoadLit(2),
+                Instr::BinOp(BinOp::Mul),
+                Instr::UnaryOp(UnaryOp::Neg),
+                Instr::ReturnVal
+            ],
+            vec![Value::I32(2), Value::I32(3), Value::I32(4)],
+        );
+        fold_constants(&mut obj);
+
+        assert_eq!(obj.code.to_vec(), vec![Instr::LoadLit(0), Instr::ReturnVal]);
+        assert_eq!(obj.litpool, vec![Value::I32(-20)]);
+    }
+
+    #[test]
+    fn test_no_fold() {
+        let code = bytecode![
+            Instr::LoadLit(0),
+            Instr::LoadLit(1),
+            Instr::BinOp(BinOp::Add),
+            Instr::LoadLit(2),
+            Instr::LoadLit(3),
+            Instr::BinOp(BinOp::Div),
+            Instr::LoadLit(0),
+            Instr::LoadLit(2),
+            Instr::BinOp(BinOp::Add)
+        ];
+        let litpool = vec![
+            Value::I32(i32::MAX),
+            Value::I32(1),
+            Value::I64(1),
+            Value::I64(0),
+        ];
+        let mut obj = init_code_obj_with_pool(code.clone(), litpool.clone());
+        fold_constants(&mut obj);
+
+        // Overflow, division by zero and mismatched types are left to the VM
+        assert_eq!(obj.code.to_vec(), code.to_vec());
+        assert_eq!(obj.litpool, litpool);
+    }
+
+    #[test]
+    fn test_fold_out_of_range() {
+        let code = bytecode![
+            Instr::LoadLit(0),
+            Instr::LoadLit(5),
+            Instr::BinOp(BinOp::Add),
+            Instr::LoadLit(7),
+            Instr::UnaryOp(UnaryOp::Neg),
+            Instr::LoadLit(0),
+            Instr::LoadLit(1),
+            Instr::BinOp(BinOp::Add)
+        ];
+        let mut obj = init_code_obj_with_pool(code, vec![Value::I32(1), Value::I32(2)]);
+        fold_constants(&mut obj);
+
+        // Loads out of range are left to the VM, and the pool isn't renumbered
+        // under them
+        assert_eq!(
+            obj.code.to_vec()[..5],
+            [
+                Instr::LoadLit(0),
+                Instr::LoadLit(5),
+                Instr::BinOp(BinOp::Add),
+                Instr::LoadLit(7),
+                Instr::UnaryOp(UnaryOp::Neg),
+            ]
+        );
+        assert_eq!(obj.code.to_vec()[5], Instr::LoadLit(2));
+        assert_eq!(obj.litpool[2], Value::I32(3));
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let mut obj = init_code_obj_with_pool(
+            bytecode![
+                Instr::LoadLit(2),
+                Instr::JumpT(1),
+                Instr::Jump(2),
+                Instr::LoadLit(0), // unreachable
+                Instr::Return,
+                Instr::LoadLit(1),
+                Instr::ReturnVal,
+                Instr::Return // unreachable
+            ],
+            vec![Value::I32(0), Value::I32(1), Value::Bool(true)],
+        );
+        // Label 0 is never jumped to
+        obj.labels = vec![3, 5, 6];
+        eliminate_dead_code(&mut obj);
+
+        assert_eq!(
+            obj.code.to_vec(),
+            vec![
+                Instr::LoadLit(0),
+                Instr::JumpT(0),
+                Instr::Jump(1),
+                Instr::LoadLit(1),
+                Instr::ReturnVal
+            ]
+        );
+        assert_eq!(obj.labels, vec![3, 4]);
+        assert_eq!(obj.litpool, vec![Value::Bool(true), Value::I32(1)]);
+    }
+
+    #[test]
+    fn test_reuse_local_slots() {
+        let mut obj = init_code_obj_with_pool(
+            bytecode![
+                Instr::LoadLit(0),
+                Instr::StoreLocal(0),
+                Instr::LoadLocal(0),
+                Instr::StoreLocal(1),
+                Instr::LoadLit(0),
+                Instr::StoreLocal(2),
+                Instr::LoadLocal(1),
+                Instr::LoadLocal(2),
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal
+            ],
+            vec![Value::I32(1)],
+        );
+        obj.localnames = vec!["a0", "a1", "x0", "x1", "x2"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let live = live_locals(&obj);
+        assert_eq!(live[3], HashSet::new());
+        assert_eq!(live[5], HashSet::from([1]));
+        assert_eq!(live[6], HashSet::from([1, 2]));
+
+        // x1 is live while x2 is stored, but x0 is dead by the time x1 is stored
+        let named = obj.clone();
+        reuse_local_slots(&mut obj, 0);
+        assert_eq!(
+            obj.code.to_vec(),
+            vec![
+                Instr::LoadLit(0),
+                Instr::StoreLocal(0),
+                Instr::LoadLocal(0),
+                Instr::StoreLocal(0),
+                Instr::LoadLit(0),
+                Instr::StoreLocal(1),
+                Instr::LoadLocal(0),
+                Instr::LoadLocal(1),
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal
+            ]
+        );
+        assert_eq!(obj.localnames.len(), 4);
+
+        // Locals that are never used are dropped
+        obj.localnames.push("x3".to_string());
+        reuse_local_slots(&mut obj, 0);
+        assert_eq!(obj.localnames.len(), 4);
+
+        // Named locals keep their slots, even when unused
+        let mut obj = named.clone();
+        reuse_local_slots(&mut obj, 3);
+        assert_eq!(obj.code.to_vec(), named.code.to_vec());
+        assert_eq!(obj.localnames, named.localnames);
+
+        // x1 can't take the slot of a named x0
+        let mut obj = named;
+        reuse_local_slots(&mut obj, 1);
+        assert_eq!(obj.code.to_vec()[3], Instr::StoreLocal(1));
+        assert_eq!(obj.code.to_vec()[5], Instr::StoreLocal(2));
+        assert_eq!(obj.localnames.len(), 5);
+    }
+
+    #[test]
+    fn test_reuse_uninit_local() {
+        let code = bytecode![
+            Instr::LoadLit(0),
+            Instr::StoreLocal(0),
+            Instr::LoadLocal(1),
+            Instr::ReturnVal
+        ];
+        let mut obj = init_code_obj_with_pool(code.clone(), vec![Value::I32(1)]);
+        obj.localnames.push("w".to_string());
+
+        // Local 1 is never stored, so it can't borrow local 0's slot
+        reuse_local_slots(&mut obj, 0);
+        assert_eq!(obj.code.to_vec(), code.to_vec());
+    }
+
+    #[test]
+    fn test_fold_labels() {
+        let mut obj = init_code_obj_with_pool(
+            bytecode![
+                Instr::LoadLit(0),
+                Instr::LoadLit(0),
+                Instr::BinOp(BinOp::Add),
+                Instr::LoadLit(0),
+                Instr::UnaryOp(UnaryOp::Neg),
+                Instr::Return
+            ],
+            vec![Value::I32(1)],
+        );
+        // Jumps into the middle of the negation, and to the return
+        obj.labels = vec![4, 5];
+        fold_constants(&mut obj);
+
+        assert_eq!(
+            obj.code.to_vec(),
+            vec![
+                Instr::LoadLit(0),
+                Instr::LoadLit(1),
+                Instr::UnaryOp(UnaryOp::Neg),
+                Instr::Return
+            ]
+        );
+        assert_eq!(obj.litpool, vec![Value::I32(2), Value::I32(1)]);
+        assert_eq!(obj.labels, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        let mut obj = init_code_obj_with_pool(
+            bytecode![
+                Instr::LoadArg(0),
+                Instr::JumpRelT(5),
+                Instr::LoadLit(0),
+                Instr::LoadLit(0),
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal,
+                Instr::JumpRel(-4),
+                Instr::Nop // unreachable
+            ],
+            vec![Value::I32(1)],
+        );
+        fold_constants(&mut obj);
+        assert_eq!(obj.code[1], Instr::JumpRelT(3));
+        assert_eq!(obj.code[4], Instr::JumpRel(-2));
+
+        eliminate_dead_code(&mut obj);
+        assert_eq!(obj.code.len(), 5);
+        assert_eq!(obj.code[4], Instr::JumpRel(-2));
+    }
+}
@@ -0,0 +1,208 @@
+//! Checking every function of a file or database before it is run: the bytecode
+//! verifier, and the link checks that its calls go to functions that exist
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use super::dataflow::Callee;
+use super::link::{arity, arity_with, undefined, LinkError};
+use super::{unresolved_calls, Unresolved};
+use crate::asm::parser::Parse;
+use crate::db::Database;
+use crate::verify::diagnose;
+
+/// The problems found with one function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCheck {
+    pub name: String,
+    pub problems: Vec<String>,
+}
+
+impl FunctionCheck {
+    /// Whether nothing is wrong
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the functions parsed from a file, sorted by name. Functions declared
+/// `.extern` are assumed to exist, and a function defined more than once is
+/// checked as first defined.
+pub fn check_parses(parses: &[Parse]) -> Vec<FunctionCheck> {
+    let mut objs = HashMap::new();
+    let mut problems = HashMap::<_, Vec<_>>::new();
+    for p in parses {
+        let name = &p.func_name;
+        if let Some(found) = problems.get_mut(name) {
+            found.push(format!("function '{name}' is defined more than once"));
+            continue;
+        }
+        objs.insert(name.clone(), p.code_obj.clone());
+        problems.insert(name.clone(), to_strings(diagnose(&p.code_obj)));
+    }
+    let declared = parses
+        .iter()
+        .flat_map(|p| p.externs.iter().cloned())
+        .collect::<HashSet<_>>();
+    let callees = objs.iter().map(|(name, obj)| (name.clone(), obj)).collect();
+
+    let mut errors = undefined(&objs, &declared);
+    errors.extend(arity(&objs, &callees));
+    for error in errors {
+        let (LinkError::Undefined { function, .. } | LinkError::Arity { function, .. }) =
+            &error;
+        problems.get_mut(function).unwrap().push(error.to_string());
+    }
+    sorted(problems)
+}
+
+/// Check the named functions of a database, sorted by name
+pub fn check_database(db: &Database) -> Result<Vec<FunctionCheck>> {
+    let mut objs = HashMap::new();
+    let mut problems = HashMap::new();
+    for (name, hash) in db.get_functions()? {
+        let found = match db.get_code_object(&hash) {
+            Ok(obj) => {
+                let mut found = to_strings(diagnose(&obj));
+                let missing = unresolved_calls(db, &obj)?.into_iter().filter(|call| {
+                    matches!(
+                        call.reason,
+                        Unresolved::NoSuchName(_) | Unresolved::NoSuchHash(_)
+                    )
+                });
+                found.extend(to_strings(missing));
+                objs.insert(name.clone(), obj);
+                found
+            }
+            Err(e) => vec![format!("cannot load code object {hash}: {e}")],
+        };
+        problems.insert(name, found);
+    }
+
+    // Calls to functions that can't be loaded are reported as unresolved above
+    let callee = |callee: &Callee| {
+        let (name, obj) = match callee {
+            Callee::Name(name) => {
+                (name.clone(), db.get_code_object_by_name(name).ok()?.1)
+            }
+            Callee::Hash(hash) => {
+                let obj = db.get_code_object(hash).ok()?;
+                let name = db.get_name_of_hash(hash).ok().flatten();
+                (name.unwrap_or_else(|| hash.to_string()), obj)
+            }
+        };
+        Some((name, obj.argcount, obj.is_void))
+    };
+    for error in arity_with(&objs, &callee) {
+        let LinkError::Arity { function, .. } = &error else {
+            continue;
+        };
+        problems.get_mut(function).unwrap().push(error.to_string());
+    }
+    Ok(sorted(problems))
+}
+
+fn to_strings<T: ToString>(errors: impl IntoIterator<Item = T>) -> Vec<String> {
+    errors.into_iter().map(|e| e.to_string()).collect()
+}
+
+fn sorted(problems: HashMap<String, Vec<String>>) -> Vec<FunctionCheck> {
+    let mut checks = problems
+        .into_iter()
+        .map(|(name, problems)| FunctionCheck { name, problems })
+        .collect::<Vec<_>>();
+    checks.sort_by(|a, b| a.name.cmp(&b.name));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::parser::Parser;
+    use crate::bytecode::Instr;
+    use crate::vm::tests::init_code_obj;
+    use crate::vm::CodeObject;
+
+    #[test]
+    fn test_check_parses() {
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$main 0:",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $missing",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let checks = check_parses(&Parser::parse_str(&source).unwrap());
+
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].name, "main");
+        assert_eq!(checks[0].problems.len(), 2);
+        assert!(checks[0].problems[0].contains("undefined function 'missing'"));
+        assert!(checks[0].problems[1].contains("passes 0 arguments to 'square'"));
+        assert!(checks[1].is_ok());
+
+        // A second definition is reported, not dropped
+        let mut twice = Parser::parse_str(&source).unwrap();
+        twice.extend(Parser::parse_str("$square 0:\n    ret\n").unwrap());
+        let checks = check_parses(&twice);
+        assert_eq!(checks.len(), 2);
+        assert_eq!(
+            checks[1].problems,
+            ["function 'square' is defined more than once"]
+        );
+    }
+
+    #[test]
+    fn test_check_database() {
+        let db = Database::temp().unwrap();
+        let leaf = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![Instr::Return])
+        };
+        db.insert_code_object_with_name(&leaf, "leaf").unwrap();
+        let call = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![
+                Instr::LoadDyn("gone".to_string()),
+                Instr::Call,
+                Instr::Return
+            ])
+        };
+        db.insert_code_object_with_name(&call, "main").unwrap();
+        let one = CodeObject {
+            argcount: 1,
+            ..init_code_obj(bytecode![Instr::LoadArg(0), Instr::ReturnVal])
+        };
+        let one = db.insert_code_object_with_name(&one, "one").unwrap();
+        let short = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![
+                Instr::LoadFunc(one),
+                Instr::Call,
+                Instr::ReturnVal
+            ])
+        };
+        db.insert_code_object_with_name(&short, "short").unwrap();
+
+        let checks = check_database(&db).unwrap();
+        assert!(checks[0].is_ok());
+        assert_eq!(checks[1].name, "main");
+        assert_eq!(
+            checks[1].problems,
+            ["call at offset 1 is unresolved: no function named 'gone'"]
+        );
+        assert!(checks[2].is_ok());
+        assert_eq!(
+            checks[3].problems,
+            ["$short: call at offset 1 passes 0 arguments to 'one', which takes 1"]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Finding what each `call` in a function calls, by following function values
+//! through the stack and locals along every path through the code
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::bytecode::Instr;
+use crate::vm::CodeObject;
+use crate::Hash;
+
+/// A function value the solver can follow, as it was loaded
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Callee {
+    /// From `load_func`
+    Hash(Hash),
+    /// From `load_dyn`
+    Name(String),
+}
+
+/// The arity and voidness of a callee, if they are known, so the stack after a
+/// call to it can be followed
+pub type Shape<'a> = dyn Fn(&Callee) -> Option<(usize, bool)> + 'a;
+
+/// The function values known before an instruction. The stack is the top of the
+/// real one: values below it are unknown, e.g. after a call whose callee isn't.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    stack: Vec<Option<Callee>>,
+    locals: HashMap<usize, Callee>,
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Callee> {
+        self.stack.pop().flatten()
+    }
+
+    fn pop_n(&mut self, n: usize) {
+        let len = self.stack.len().saturating_sub(n);
+        self.stack.truncate(len);
+    }
+
+    /// Where paths meet, only the values they agree on are known
+    fn merge(&self, other: &State) -> State {
+        let len = self.stack.len().min(other.stack.len());
+        let stack = self.stack[self.stack.len() - len..]
+            .iter()
+            .zip(&other.stack[other.stack.len() - len..])
+            .map(|(a, b)| a.clone().filter(|a| Some(a) == b.as_ref()))
+            .collect();
+        let locals = self
+            .locals
+            .iter()
+            .filter(|(i, callee)| other.locals.get(i) == Some(callee))
+            .map(|(i, callee)| (*i, callee.clone()))
+            .collect();
+        State { stack, locals }
+    }
+
+    /// The state after `instr`, which must not be a return
+    fn step(mut self, obj: &CodeObject, instr: &Instr, shape: &Shape) -> State {
+        match instr {
+            Instr::LoadFunc(hash) => self.stack.push(Some(Callee::Hash(*hash))),
+            Instr::LoadDyn(name) => self.stack.push(Some(Callee::Name(name.clone()))),
+            Instr::LoadLocal(i) => self.stack.push(self.locals.get(i).cloned()),
+            Instr::StoreLocal(i) => match self.pop() {
+                Some(callee) => {
+                    self.locals.insert(*i, callee);
+                }
+                None => {
+                    self.locals.remove(i);
+                }
+            },
+            Instr::Dup | Instr::Dbg | Instr::DbgMsg(_) => {
+                let top = self.pop();
+                let copies = if matches!(instr, Instr::Dup) { 2 } else { 1 };
+                self.stack.extend(std::iter::repeat_n(top, copies));
+            }
+            Instr::Call => match self.pop().and_then(|callee| shape(&callee)) {
+                Some((argcount, is_void)) => {
+                    self.pop_n(argcount);
+                    if !is_void {
+                        self.stack.push(None);
+                    }
+                }
+                None => self.stack.clear(),
+            },
+            Instr::CallSelf => {
+                self.pop_n(obj.argcount);
+                if !obj.is_void {
+                    self.stack.push(None);
+                }
+            }
+            instr => match instr.stack_effect() {
+                Some((pops, pushes)) => {
+                    self.pop_n(pops);
+                    self.stack.extend(std::iter::repeat_n(None, pushes));
+                }
+                None => {
+                    self.stack.clear();
+                    self.stack.push(None);
+                }
+            },
+        }
+        self
+    }
+}
+
+/// The offset of each reachable `call` in `obj`, with the function it calls on
+/// every path that reaches it, or `None` if that isn't known
+pub fn call_targets(obj: &CodeObject, shape: &Shape) -> BTreeMap<usize, Option<Callee>> {
+    let code = &obj.code;
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut worklist = vec![];
+
+    if !code.is_empty() {
+        states[0] = Some(State::default());
+        worklist.push(0);
+    }
+
+    while let Some(offset) = worklist.pop() {
+        let instr = &code[offset];
+        let successors = obj.successors(offset);
+        if successors.is_empty() {
+            continue;
+        }
+        let after = states[offset].clone().unwrap().step(obj, instr, shape);
+
+        for succ in successors.into_iter().filter(|&s| s < code.len()) {
+            let merged = match &states[succ] {
+                None => after.clone(),
+                Some(other) => other.merge(&after),
+            };
+            if states[succ].as_ref() != Some(&merged) {
+                states[succ] = Some(merged);
+                worklist.push(succ);
+            }
+        }
+    }
+
+    code.iter()
+        .zip(states)
+        .enumerate()
+        .filter_map(|(offset, (instr, state))| match (instr, state) {
+            (Instr::Call, Some(mut state)) => Some((offset, state.pop())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether a `call_self` in `obj` can run
+pub fn calls_self(obj: &CodeObject) -> bool {
+    let code = &obj.code;
+    let mut reached = vec![false; code.len()];
+    let mut worklist = vec![];
+    if !code.is_empty() {
+        reached[0] = true;
+        worklist.push(0);
+    }
+    while let Some(offset) = worklist.pop() {
+        if code[offset] == Instr::CallSelf {
+            return true;
+        }
+        for succ in obj.successors(offset) {
+            if succ < code.len() && !reached[succ] {
+                reached[succ] = true;
+                worklist.push(succ);
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::init_code_obj;
+
+    fn targets(obj: &CodeObject) -> Vec<(usize, Option<Callee>)> {
+        call_targets(obj, &|_| Some((0, false)))
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_call_targets() {
+        let f = Hash::digest(b"f");
+        let g = Hash::digest(b"g");
+
+        // Through a local, a dup, and a jump
+        let obj = init_code_obj(bytecode![
+            Instr::LoadFunc(f),
+            Instr::StoreLocal(0),
+            Instr::LoadDyn("g".to_string()),
+            Instr::Dup,
+            Instr::JumpRel(2),
+            Instr::Nop,
+            Instr::Call,
+            Instr::Pop,
+            Instr::Call,
+            Instr::LoadLocal(0),
+            Instr::Call,
+            Instr::ReturnVal
+        ]);
+        assert_eq!(
+            targets(&obj),
+            vec![
+                (6, Some(Callee::Name("g".to_string()))),
+                (8, Some(Callee::Name("g".to_string()))),
+                (10, Some(Callee::Hash(f))),
+            ]
+        );
+
+        // Paths that load different functions, and values that aren't functions
+        let obj = init_code_obj(bytecode![
+            Instr::LoadArg(0),
+            Instr::JumpRelT(3),
+            Instr::LoadFunc(f),
+            Instr::JumpRel(2),
+            Instr::LoadFunc(g),
+            Instr::Call,
+            Instr::LoadArg(1),
+            Instr::Call,
+            Instr::LoadFunc(g),
+            Instr::Call,
+            Instr::ReturnVal
+        ]);
+        assert_eq!(
+            targets(&obj),
+            vec![(5, None), (7, None), (9, Some(Callee::Hash(g)))]
+        );
+
+        // Unreachable calls aren't listed
+        let obj = init_code_obj(bytecode![
+            Instr::Return,
+            Instr::LoadFunc(f),
+            Instr::Call,
+            Instr::CallSelf
+        ]);
+        assert!(targets(&obj).is_empty());
+        assert!(!calls_self(&obj));
+    }
+}
@@ -0,0 +1,149 @@
+//! The call graph in Graphviz dot, e.g. to pipe into `dot -Tpng`
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::node::{Node, NodeStore};
+use super::DepGraph;
+use crate::analysis::escape;
+
+impl<S: NodeStore> DepGraph<S> {
+    /// Render the graph in Graphviz dot, labeling each node with its name and short
+    /// hash. With a `root`, only the nodes it reaches in at most `depth` calls are
+    /// drawn, and the calls out of the deepest ones aren't.
+    pub fn to_dot(&self, root: Option<&Node>, depth: Option<usize>) -> String {
+        let export = self.export_within(root, depth);
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph calls {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for node in &export.nodes {
+            writeln!(
+                dot,
+                "    \"{}\" [label=\"{}\\n{}\"];",
+                escape(&node.name),
+                escape(&node.name),
+                node.hash.abbrev()
+            )
+            .unwrap();
+        }
+        for edge in &export.edges {
+            writeln!(
+                dot,
+                "    \"{}\" -> \"{}\";",
+                escape(&edge.caller),
+                escape(&edge.callee)
+            )
+            .unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    /// The fewest calls from `root` to each node it reaches in at most `depth`, or
+    /// every node without a root
+    pub(super) fn within(
+        &self,
+        root: Option<&Node>,
+        depth: Option<usize>,
+    ) -> HashMap<&Node, usize> {
+        let Some(root) = root else {
+            return self.graph.keys().map(|node| (node, 0)).collect();
+        };
+        let Some((root, _)) = self.graph.get_key_value(root) else {
+            return HashMap::new();
+        };
+        let mut distances = HashMap::from([(root, 0)]);
+        let mut frontier = vec![root];
+        let mut distance = 0;
+        while !frontier.is_empty() && depth.is_none_or(|depth| distance < depth) {
+            distance += 1;
+            frontier = frontier
+                .into_iter()
+                .flat_map(|node| &self.graph[node])
+                .filter_map(|dep| self.graph.get_key_value(dep))
+                .map(|(dep, _)| dep)
+                .filter(|dep| !distances.contains_key(dep))
+                .collect();
+            frontier.sort();
+            frontier.dedup();
+            for node in &frontier {
+                distances.insert(node, distance);
+            }
+        }
+        distances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::db::Database;
+
+    #[test]
+    fn test_to_dot() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$cube 1:",
+            "    load_arg 0",
+            "    dup",
+            "    load_dyn $square",
+            "    call",
+            "    mul",
+            "    ret_val",
+            "$count 1:",
+            "    load_arg 0",
+            "    call_self",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $cube",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let functions = Assembler::new(&db).assemble_str(&source).unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        let lines = |dot: String| {
+            dot.lines()
+                .filter(|line| line.contains("->"))
+                .map(|line| line.trim().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let dot = g.to_dot(None, None);
+        assert!(dot.starts_with("digraph calls {\n"));
+        let square = functions
+            .iter()
+            .find(|(name, _)| name == "square")
+            .unwrap()
+            .1;
+        assert!(dot.contains(&format!(
+            "\"square\" [label=\"square\\n{}\"];",
+            square.abbrev()
+        )));
+        assert_eq!(
+            lines(dot),
+            [
+                "\"count\" -> \"count\";",
+                "\"cube\" -> \"square\";",
+                "\"main\" -> \"cube\";"
+            ]
+        );
+
+        // Only what `main` reaches, one call deep
+        let main = g.node("main").unwrap();
+        let dot = g.to_dot(Some(main), Some(1));
+        assert!(dot.contains("\"cube\" [label"));
+        assert!(!dot.contains("\"square\" [label"));
+        assert_eq!(lines(dot), ["\"main\" -> \"cube\";"]);
+        assert_eq!(lines(g.to_dot(Some(main), None)).len(), 2);
+    }
+}
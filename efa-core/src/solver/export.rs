@@ -0,0 +1,150 @@
+//! The dependence graph as plain data, for tools that read it, e.g. as JSON
+
+use serde::{Deserialize, Serialize};
+
+use super::node::{Node, NodeStore};
+use super::DepGraph;
+use crate::Hash;
+
+/// A solved graph's nodes and edges, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphExport {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphNode {
+    pub name: String,
+    /// Written as a `0x` hex string
+    #[serde(with = "hash_string")]
+    pub hash: Hash,
+}
+
+/// A call from one function to another, by name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct GraphEdge {
+    pub caller: String,
+    pub callee: String,
+}
+
+impl<S: NodeStore> DepGraph<S> {
+    /// The nodes and edges found by `solve_static`
+    pub fn export(&self) -> GraphExport {
+        self.export_within(None, None)
+    }
+
+    /// With a `root`, only the nodes it reaches in at most `depth` calls, and the
+    /// calls out of them except the deepest ones
+    pub fn export_within(
+        &self,
+        root: Option<&Node>,
+        depth: Option<usize>,
+    ) -> GraphExport {
+        let within = self.within(root, depth);
+        let mut nodes = within
+            .keys()
+            .map(|node| GraphNode {
+                name: node.name.clone(),
+                hash: node.hash,
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut edges = self
+            .graph
+            .iter()
+            .filter(|(node, _)| {
+                within
+                    .get(node)
+                    .is_some_and(|&distance| depth.is_none_or(|depth| distance < depth))
+            })
+            .flat_map(|(node, deps)| {
+                deps.iter().map(|dep| GraphEdge {
+                    caller: node.name.clone(),
+                    callee: dep.name.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        edges.sort();
+
+        GraphExport { nodes, edges }
+    }
+}
+
+mod hash_string {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::Hash;
+
+    pub fn serialize<S: Serializer>(hash: &Hash, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(hash)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Hash, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::db::Database;
+    use crate::solver::node::DatabaseNodeStore;
+
+    #[test]
+    fn test_export() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let functions = Assembler::new(&db).assemble_str(&source).unwrap();
+        let store = DatabaseNodeStore::new(&db);
+        let mut g = DepGraph::new(store.clone());
+        g.solve_static().unwrap();
+
+        let export = g.export();
+        assert_eq!(
+            export.nodes,
+            functions
+                .iter()
+                .map(|(name, hash)| GraphNode {
+                    name: name.clone(),
+                    hash: *hash
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            export.edges,
+            vec![GraphEdge {
+                caller: "main".to_string(),
+                callee: "square".to_string()
+            }]
+        );
+
+        let json = serde_json::to_value(&export).unwrap();
+        assert_eq!(json["nodes"][1]["hash"], functions[1].1.to_string());
+        let back: GraphExport = serde_json::from_value(json).unwrap();
+        assert_eq!(back, export);
+
+        // Only `main` is within no calls of itself
+        let main = g.node("main").unwrap();
+        let within = g.export_within(Some(main), Some(0));
+        assert_eq!(within.nodes.len(), 1);
+        assert!(within.edges.is_empty());
+        assert_eq!(g.export_within(Some(main), None), export);
+    }
+}
@@ -0,0 +1,108 @@
+//! What editing a function invalidates. Callers embed the hashes of the functions
+//! they load with `load_func`, so changing a function changes their hashes too, and
+//! their callers' in turn.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use super::references;
+use super::toposort::linearize;
+use crate::db::Database;
+use crate::Hash;
+
+/// A stored code object that must be rebuilt and re-inserted after a change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Impacted {
+    pub hash: Hash,
+    /// Its first name, if it has one
+    pub name: Option<String>,
+}
+
+/// The code objects that change if the function `name` does: itself, then each
+/// code object that loads a changed one with `load_func`, after the ones it loads
+pub fn impact(db: &Database, name: &str) -> Result<Vec<Impacted>> {
+    let (changed, _) = db.get_code_object_by_name(name)?;
+
+    let mut closure = HashSet::from([changed]);
+    let mut worklist = vec![changed];
+    while let Some(hash) = worklist.pop() {
+        for referrer in db.get_referrers(&hash)? {
+            if closure.insert(referrer) {
+                worklist.push(referrer);
+            }
+        }
+    }
+
+    // Each code object's edges go to the changed ones it loads
+    let graph = closure
+        .iter()
+        .map(|hash| {
+            let obj = db.get_code_object(hash)?;
+            let deps = references(&obj).intersection(&closure).copied().collect();
+            Ok((*hash, deps))
+        })
+        .collect::<Result<HashMap<_, HashSet<_>>>>()?;
+
+    linearize(&graph)?
+        .into_iter()
+        .map(|hash| {
+            Ok(Impacted {
+                hash,
+                name: db.get_name_of_hash(&hash)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+
+    #[test]
+    fn test_impact() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$cube 1:",
+            "    load_arg 0",
+            "    dup",
+            "    load_dyn $square",
+            "    call",
+            "    mul",
+            "    ret_val",
+            "$both 1:",
+            "    load_arg 0",
+            "    load_dyn $square",
+            "    call",
+            "    load_arg 0",
+            "    load_dyn $cube",
+            "    call",
+            "    add",
+            "    ret_val",
+            "$other 1:",
+            "    load_arg 0",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let names = |name| {
+            impact(&db, name)
+                .unwrap()
+                .into_iter()
+                .map(|impacted| impacted.name.unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // `both` loads `square` directly and through `cube`, and is rebuilt last
+        assert_eq!(names("square"), ["square", "cube", "both"]);
+        assert_eq!(names("cube"), ["cube", "both"]);
+        assert_eq!(names("other"), ["other"]);
+        assert!(impact(&db, "missing").is_err());
+    }
+}
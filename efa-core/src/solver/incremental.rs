@@ -0,0 +1,174 @@
+//! Keeping a solved graph up to date as its store changes, without solving every
+//! node again. Nodes are keyed by content hash, so only the node of a name that
+//! changed, and the nodes whose calls went to or looked for it, are solved again.
+
+use anyhow::Result;
+
+use super::node::{Node, NodeStore};
+use super::{DepGraph, SolverError, Unresolved};
+use crate::db::Change;
+use crate::Hash;
+
+impl<S: NodeStore> DepGraph<S> {
+    /// Update the graph after the name `name` was added, removed, or pointed at
+    /// another code object
+    pub fn update(&mut self, name: &str) -> Result<(), SolverError> {
+        let old = self.graph.keys().find(|node| node.name == name).cloned();
+        let new = self
+            .node_store
+            .get_code_object_by_name(name)
+            .ok()
+            .map(|(hash, _)| Node {
+                name: name.to_string(),
+                hash,
+            });
+        if old == new {
+            return Ok(());
+        }
+
+        let mut affected = self.affected(|call| match call {
+            Unresolved::NoSuchName(missing) => missing == name,
+            Unresolved::Unnamed(hash) => {
+                new.as_ref().is_some_and(|new| new.hash == *hash)
+            }
+            _ => false,
+        });
+        if let Some(old) = &old {
+            affected.extend(self.dependents(old));
+            self.graph.remove(old);
+            self.unresolved.remove(old);
+        }
+        if let Some(new) = new {
+            affected.push(new);
+        }
+        self.solve_again(affected, old.as_ref())
+    }
+
+    /// Update the graph after a code object was stored or removed
+    pub fn update_hash(&mut self, hash: &Hash) -> Result<(), SolverError> {
+        let mut affected = self.affected(|call| match call {
+            Unresolved::NoSuchHash(missing) | Unresolved::Unnamed(missing) => {
+                missing == hash
+            }
+            _ => false,
+        });
+        let callers = self
+            .graph
+            .iter()
+            .filter(|(_, deps)| deps.iter().any(|dep| dep.hash == *hash))
+            .map(|(node, _)| node.clone());
+        affected.extend(callers);
+        self.solve_again(affected, None)
+    }
+
+    /// Update the graph after a change to the database it was solved from, e.g.
+    /// one received from `Database::subscribe`
+    pub fn apply(&mut self, change: &Change) -> Result<(), SolverError> {
+        match change {
+            Change::Named { name, .. } | Change::Unnamed(name) => self.update(name),
+            Change::Inserted(hash) | Change::Removed(hash) => self.update_hash(hash),
+        }
+    }
+
+    /// The nodes with an unresolved call that `changed` says may now resolve
+    fn affected(&self, changed: impl Fn(&Unresolved) -> bool) -> Vec<Node> {
+        self.unresolved
+            .iter()
+            .filter(|(_, calls)| calls.iter().any(|call| changed(&call.reason)))
+            .map(|(node, _)| node.clone())
+            .collect()
+    }
+
+    /// Solve each of `nodes` again, except `removed`
+    fn solve_again(
+        &mut self,
+        mut nodes: Vec<Node>,
+        removed: Option<&Node>,
+    ) -> Result<(), SolverError> {
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        nodes.dedup();
+        for node in nodes {
+            if Some(&node) != removed {
+                self.insert_solved(node)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instr;
+    use crate::db::Database;
+    use crate::solver::node::DatabaseNodeStore;
+    use crate::vm::tests::init_code_obj;
+    use crate::vm::CodeObject;
+
+    fn call(name: &str) -> CodeObject {
+        CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![
+                Instr::LoadDyn(name.to_string()),
+                Instr::Call,
+                Instr::Return
+            ])
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let db = Database::temp().unwrap();
+        let changes = db.subscribe();
+        let leaf = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![Instr::Return])
+        };
+        db.insert_code_object_with_name(&leaf, "leaf").unwrap();
+        db.insert_code_object_with_name(&call("leaf"), "main")
+            .unwrap();
+        db.insert_code_object_with_name(&call("later"), "other")
+            .unwrap();
+
+        let store = DatabaseNodeStore::new(&db);
+        let mut g = DepGraph::new(store.clone());
+        g.solve_static().unwrap();
+        changes.try_iter().for_each(drop);
+        let solved = |g: &DepGraph<_>| {
+            let mut fresh = DepGraph::new(store.clone());
+            fresh.solve_static().unwrap();
+            assert_eq!(g.graph, fresh.graph);
+            assert_eq!(g.unresolved(), fresh.unresolved());
+        };
+        let apply = |g: &mut DepGraph<_>| {
+            for change in changes.try_iter() {
+                g.apply(&change).unwrap();
+            }
+        };
+        assert_eq!(g.unresolved().len(), 1);
+
+        // A name that was missing
+        db.insert_code_object_with_name(&call("main"), "later")
+            .unwrap();
+        apply(&mut g);
+        solved(&g);
+        assert!(g.unresolved().is_empty());
+
+        // A callee that changed: its callers point at the new node
+        let leaf = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![Instr::Nop, Instr::Return])
+        };
+        db.insert_code_object_with_name(&leaf, "leaf").unwrap();
+        apply(&mut g);
+        solved(&g);
+
+        // Removed names
+        db.remove_name("later", true).unwrap();
+        apply(&mut g);
+        solved(&g);
+        assert_eq!(g.unresolved().len(), 1);
+        g.update("missing").unwrap();
+        solved(&g);
+    }
+}
@@ -0,0 +1,173 @@
+//! Checking the dyn calls of parsed functions before they are hashed or run: every
+//! `load_dyn` must name a function, and calls must pass their callees enough
+//! arguments
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use super::dataflow::{call_targets, Callee};
+use crate::bytecode::Instr;
+use crate::verify::{stack_depths, Depth};
+use crate::vm::CodeObject;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// `load_dyn` of a name that isn't defined in the file or declared `.extern`
+    Undefined {
+        function: String,
+        offset: usize,
+        name: String,
+    },
+    /// A call that provably passes fewer arguments than its callee takes
+    Arity {
+        function: String,
+        offset: usize,
+        callee: String,
+        argcount: usize,
+        passed: usize,
+    },
+}
+
+/// The name, arity, and voidness of a callee, if it is known
+pub(super) type Lookup<'a> = dyn Fn(&Callee) -> Option<(String, usize, bool)> + 'a;
+
+/// Every link error found, sorted by function and offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkErrors(pub Vec<LinkError>);
+
+/// The `load_dyn`s in `objs` of names that aren't in `objs` or `declared`
+pub(super) fn undefined(
+    objs: &HashMap<String, CodeObject>,
+    declared: &HashSet<String>,
+) -> Vec<LinkError> {
+    objs.iter()
+        .flat_map(|(function, obj)| {
+            obj.code
+                .iter()
+                .enumerate()
+                .filter_map(move |(offset, instr)| match instr {
+                    Instr::LoadDyn(name)
+                        if !objs.contains_key(name) && !declared.contains(name) =>
+                    {
+                        Some(LinkError::Undefined {
+                            function: function.clone(),
+                            offset,
+                            name: name.clone(),
+                        })
+                    }
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+/// The calls in `objs` of the functions in `callees`, by name, that pass too few
+/// arguments. Calls whose stack depth isn't known are skipped.
+pub(super) fn arity(
+    objs: &HashMap<String, CodeObject>,
+    callees: &HashMap<String, &CodeObject>,
+) -> Vec<LinkError> {
+    arity_with(objs, &|callee| match callee {
+        Callee::Name(name) => callees
+            .get(name)
+            .map(|obj| (name.clone(), obj.argcount, obj.is_void)),
+        Callee::Hash(_) => None,
+    })
+}
+
+/// Like `arity`, with the name, argument count, and voidness of each callee that
+/// is known given by `callee`, e.g. from a database
+pub(super) fn arity_with(
+    objs: &HashMap<String, CodeObject>,
+    callee: &Lookup,
+) -> Vec<LinkError> {
+    let shape = |c: &Callee| callee(c).map(|(_, argcount, is_void)| (argcount, is_void));
+
+    let mut errors = vec![];
+    for (function, obj) in objs {
+        // A stack that doesn't verify is reported when the function is inserted
+        let Ok(depths) = stack_depths(obj) else {
+            continue;
+        };
+        for (offset, target) in call_targets(obj, &shape) {
+            let (Some((name, argcount, _)), Some(Depth::Known(depth))) =
+                (target.as_ref().and_then(callee), depths[offset])
+            else {
+                continue;
+            };
+            // The function value is on top of the arguments
+            let passed = depth.saturating_sub(1);
+            if passed < argcount {
+                errors.push(LinkError::Arity {
+                    function: function.clone(),
+                    offset,
+                    callee: name,
+                    argcount,
+                    passed,
+                });
+            }
+        }
+    }
+    errors
+}
+
+impl LinkError {
+    fn key(&self) -> (&str, usize) {
+        match self {
+            LinkError::Undefined {
+                function, offset, ..
+            }
+            | LinkError::Arity {
+                function, offset, ..
+            } => (function, *offset),
+        }
+    }
+}
+
+impl LinkErrors {
+    /// `Err` with the errors, sorted, if there are any
+    pub(super) fn check(mut errors: Vec<LinkError>) -> Result<(), LinkErrors> {
+        if errors.is_empty() {
+            return Ok(());
+        }
+        errors.sort_by(|a, b| a.key().cmp(&b.key()));
+        Err(LinkErrors(errors))
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Undefined {
+                function,
+                offset,
+                name,
+            } => write!(
+                f,
+                "${function}: load_dyn at offset {offset} of undefined function '{name}'"
+            ),
+            LinkError::Arity {
+                function,
+                offset,
+                callee,
+                argcount,
+                passed,
+            } => write!(
+                f,
+                "${function}: call at offset {offset} passes {passed} arguments to '{callee}', which takes {argcount}"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for LinkErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "link failed:")?;
+        for error in &self.0 {
+            write!(f, "\n  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LinkErrors {}
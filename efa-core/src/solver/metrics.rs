@@ -0,0 +1,170 @@
+//! Numbers that summarize a solved graph, e.g. to spot functions that too much
+//! depends on, or to keep generated code within a budget
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::node::{Node, NodeStore};
+use super::toposort::components;
+use super::DepGraph;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphMetrics {
+    /// Each node's metrics, sorted by name
+    pub nodes: Vec<NodeMetrics>,
+    pub edges: usize,
+    /// Strongly connected components: groups of nodes that can all reach each
+    /// other, or single nodes
+    pub components: usize,
+    /// Components that are recursive, by one function or several
+    pub cycles: usize,
+    /// The most calls in a chain from the root, where a recursive group of
+    /// functions counts once. `None` without a root, or if it isn't in the graph.
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeMetrics {
+    pub name: String,
+    /// How many functions call it
+    pub fan_in: usize,
+    /// How many functions it calls
+    pub fan_out: usize,
+}
+
+impl<S: NodeStore> DepGraph<S> {
+    /// The metrics of the nodes found by `solve_static`, with the call depth
+    /// measured from `root`, e.g. the node of the database's entry point
+    pub fn metrics(&self, root: Option<&Node>) -> GraphMetrics {
+        let mut graph = self.graph.clone();
+        for dep in self.graph.values().flatten() {
+            graph.entry(dep.clone()).or_default();
+        }
+
+        let mut fan_in = HashMap::<&Node, usize>::new();
+        for dep in self.graph.values().flatten() {
+            *fan_in.entry(dep).or_default() += 1;
+        }
+        let nodes = self
+            .nodes()
+            .into_iter()
+            .map(|node| NodeMetrics {
+                name: node.name.clone(),
+                fan_in: fan_in.get(node).copied().unwrap_or(0),
+                fan_out: self.graph[node].len(),
+            })
+            .collect();
+
+        // Components come after the ones they call, so the depth of each one's
+        // callees is known before its own
+        let components = components(&graph).unwrap_or_default();
+        let component_of = components
+            .iter()
+            .enumerate()
+            .flat_map(|(i, component)| component.iter().map(move |node| (node, i)))
+            .collect::<HashMap<_, _>>();
+        let mut depths = vec![0; components.len()];
+        for (i, component) in components.iter().enumerate() {
+            depths[i] = component
+                .iter()
+                .flat_map(|node| &graph[node])
+                .map(|dep| component_of[dep])
+                .filter(|&j| j != i)
+                .map(|j| depths[j] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        let cycles = components
+            .iter()
+            .filter(|component| match &component[..] {
+                [node] => graph[node].contains(node),
+                _ => true,
+            })
+            .count();
+
+        GraphMetrics {
+            nodes,
+            edges: self.graph.values().map(|deps| deps.len()).sum(),
+            components: components.len(),
+            cycles,
+            max_depth: root
+                .and_then(|root| component_of.get(root))
+                .map(|&i| depths[i]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::db::{Database, DEFAULT_ENTRY_POINT};
+
+    #[test]
+    fn test_metrics() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$cube 1:",
+            "    load_arg 0",
+            "    dup",
+            "    load_dyn $square",
+            "    call",
+            "    mul",
+            "    ret_val",
+            "$count 1:",
+            "    load_arg 0",
+            "    call_self",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $cube",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        let metrics = g.metrics(g.node("main"));
+
+        let fans = metrics
+            .nodes
+            .iter()
+            .map(|n| (n.name.as_str(), n.fan_in, n.fan_out))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fans,
+            [
+                ("count", 1, 1),
+                ("cube", 1, 1),
+                ("main", 0, 2),
+                ("square", 2, 0)
+            ]
+        );
+        assert_eq!(metrics.edges, 4);
+        assert_eq!(metrics.components, 4);
+        assert_eq!(metrics.cycles, 1);
+        assert_eq!(metrics.max_depth, Some(2));
+
+        let json = serde_json::to_value(&metrics).unwrap();
+        assert_eq!(json["max_depth"], 2);
+
+        // From an entry point that isn't `main`
+        let hash = db
+            .set_named_entry_point(DEFAULT_ENTRY_POINT, "cube")
+            .unwrap();
+        let (entry, _) = db.get_entry_point().unwrap();
+        assert_eq!(entry, hash);
+        let root = g.nodes().into_iter().find(|node| node.hash == entry);
+        assert_eq!(g.metrics(root).max_depth, Some(1));
+        assert_eq!(g.metrics(None).max_depth, None);
+    }
+}
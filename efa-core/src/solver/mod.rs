@@ -2,109 +2,349 @@
 //! Nodes are functions, directed edges are calls, and the root node is a main function.
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use anyhow::Result;
 
 use crate::bytecode::Instr;
+use crate::db::Database;
+use crate::vm::CodeObject;
+use crate::Hash;
 
+mod check;
+mod dataflow;
+mod dot;
+mod export;
+mod impact;
+mod incremental;
+mod link;
+mod metrics;
 mod node;
+mod reachability;
 pub mod resolve_dyn;
 mod toposort;
 
-use node::{Node, NodeStore};
+pub use check::{check_database, check_parses, FunctionCheck};
+pub use export::{GraphEdge, GraphExport, GraphNode};
+pub use impact::{impact, Impacted};
+pub use link::{LinkError, LinkErrors};
+pub use metrics::{GraphMetrics, NodeMetrics};
+pub use node::{DatabaseNodeStore, LayeredNodeStore, Node, NodeStore, ParseNodeStore};
+pub use reachability::{dead_functions, dead_functions_from};
+pub use toposort::Cycle;
 
+use toposort::linearize;
+
+use dataflow::{call_targets, calls_self, Callee};
+
+/// A `call` whose callee the solver can't determine, so it has no edge in the graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnresolvedCall {
+    pub offset: usize,
+    pub reason: Unresolved,
+}
+
+/// Why a call is unresolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unresolved {
+    /// The called value isn't the same function on every path, or isn't loaded
+    /// with `load_func` or `load_dyn`, e.g. an argument
+    Indirect,
+    /// `load_dyn` of a name that isn't defined
+    NoSuchName(String),
+    /// `load_func` of a code object that isn't stored
+    NoSuchHash(Hash),
+    /// `load_func` of a code object that has no name to give its node
+    Unnamed(Hash),
+}
+
+/// The functions in a store and the calls between them. Solve it with
+/// `solve_static`, or build a solved one with `from_store` or `from_database`.
 #[derive(Debug)]
-pub struct DepGraph<'s, S: NodeStore> {
+pub struct DepGraph<S: NodeStore> {
     graph: HashMap<Node, HashSet<Node>>,
-    node_store: &'s S,
+    /// The calls of each node that have no edge
+    unresolved: HashMap<Node, Vec<UnresolvedCall>>,
+    node_store: S,
+}
+
+impl<'a> DepGraph<DatabaseNodeStore<'a>> {
+    /// The solved graph of the named functions in `db`
+    pub fn from_database(db: &'a Database) -> Result<Self, SolverError> {
+        DepGraph::from_store(DatabaseNodeStore::new(db))
+    }
 }
 
-impl<'s, S> DepGraph<'_, S>
+impl<S> DepGraph<S>
 where
     S: NodeStore,
 {
-    pub fn new(store: &'s S) -> DepGraph<'s, S> {
+    /// An empty graph of the functions in `store`
+    pub fn new(store: S) -> DepGraph<S> {
         DepGraph {
             graph: HashMap::new(),
+            unresolved: HashMap::new(),
             node_store: store,
         }
     }
 
-    pub fn solve_static(&mut self) -> Result<()> {
-        let nodes = self.node_store.nodes()?;
+    /// The solved graph of the functions in `store`
+    pub fn from_store(store: S) -> Result<DepGraph<S>, SolverError> {
+        let mut g = DepGraph::new(store);
+        g.solve_static()?;
+        Ok(g)
+    }
 
-        // Seen nodes
-        let mut solved = HashSet::<Node>::new();
+    /// Solve every node in the store, returning the calls that have no edge
+    pub fn solve_static(&mut self) -> Result<Vec<(Node, UnresolvedCall)>, SolverError> {
+        let nodes = self.node_store.nodes()?;
+        for node in nodes {
+            self.insert_solved(node)?;
+        }
+        Ok(self.unresolved())
+    }
 
-        // TODO: remove these clones
-        nodes.into_iter().try_for_each(|node| {
-            if !solved.contains(&node) {
-                let deps = self.solve_node(&node)?;
-                solved.insert(node.clone());
-                self.graph.insert(node.clone(), deps);
-            }
-            Ok::<(), anyhow::Error>(())
-        })?;
+    /// The calls that have no edge, by node name and offset
+    pub fn unresolved(&self) -> Vec<(Node, UnresolvedCall)> {
+        let mut unresolved = self
+            .unresolved
+            .iter()
+            .flat_map(|(node, calls)| {
+                calls.iter().map(|call| (node.clone(), call.clone()))
+            })
+            .collect::<Vec<_>>();
+        unresolved
+            .sort_by(|(a, x), (b, y)| (&a.name, x.offset).cmp(&(&b.name, y.offset)));
+        unresolved
+    }
 
+    /// Solve `node` and put it in the graph, replacing its old edges
+    fn insert_solved(&mut self, node: Node) -> Result<(), SolverError> {
+        let (deps, calls) = self.solve_node(&node)?;
+        if calls.is_empty() {
+            self.unresolved.remove(&node);
+        } else {
+            self.unresolved.insert(node.clone(), calls);
+        }
+        self.graph.insert(node, deps);
         Ok(())
     }
 
-    /// Return the dependences of the given node
-    fn solve_node(&self, node: &Node) -> Result<HashSet<Node>> {
+    /// Return the dependences of the given node: the functions its calls are known
+    /// to call, following function values through its stack and locals. Also
+    /// returns the calls whose callees aren't known.
+    fn solve_node(&self, node: &Node) -> Result<(HashSet<Node>, Vec<UnresolvedCall>)> {
         let obj = self.node_store.get_code_object(&node.hash)?;
-        let code = obj
-            .code
+        let (mut deps, unresolved) = solve_calls(&self.node_store, &obj)?;
+        if calls_self(&obj) {
+            deps.insert(node.clone());
+        }
+
+        Ok((deps, unresolved))
+    }
+
+    /// The node named `name`, if it has been solved
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.graph.keys().find(|node| node.name == name)
+    }
+
+    /// The solved nodes, sorted by name
+    pub fn nodes(&self) -> Vec<&Node> {
+        let mut nodes = self.graph.keys().collect::<Vec<_>>();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        nodes
+    }
+
+    /// The nodes `node` has an edge to, i.e. the functions it calls, sorted by
+    /// name. `None` if `node` hasn't been solved.
+    pub fn deps(&self, node: &Node) -> Option<Vec<Node>> {
+        let mut deps = self.graph.get(node)?.iter().cloned().collect::<Vec<_>>();
+        deps.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(deps)
+    }
+
+    /// The store the graph was solved from
+    pub fn store(&self) -> &S {
+        &self.node_store
+    }
+
+    /// The nodes with an edge to `node`, i.e. the functions that call it, sorted
+    /// by name. Only what `solve_static` found is searched.
+    pub fn dependents(&self, node: &Node) -> Vec<Node> {
+        let mut dependents = self
+            .graph
             .iter()
-            .filter(|instr| {
-                matches!(
-                    instr,
-                    Instr::Call
-                        | Instr::CallSelf
-                        | Instr::LoadFunc(_)
-                        | Instr::LoadDyn(_)
-                )
-            })
-            .collect::<Vec<&Instr>>();
-
-        // Check that each Instr::Call is preceded by a LoadFunc/LoadDyn
-        let mut deps = code[..]
-            .windows(2)
-            .filter_map(|pair| match (pair[0], pair[1]) {
-                // Want to return dependences (name, hash)
-                (Instr::LoadFunc(hash), Instr::Call) => {
-                    // Result<Option<String>>
-                    let name = self.node_store.get_name_of_hash(hash);
-                    Some((name, Ok(*hash)))
-                }
-                (Instr::LoadDyn(name), Instr::Call) => {
-                    let hash = self
-                        .node_store
-                        .get_code_object_by_name(name)
-                        .map(|(x, _)| x);
-                    Some((Ok(Some(name.to_string())), hash))
+            .filter(|(_, deps)| deps.contains(node))
+            .map(|(dependent, _)| dependent.clone())
+            .collect::<Vec<_>>();
+        dependents.sort_by(|a, b| a.name.cmp(&b.name));
+        dependents
+    }
+
+    /// The nodes with a path to `node`, i.e. everything that would be affected by
+    /// changing it, sorted by name. Includes `node` only if it is recursive.
+    pub fn all_dependents(&self, node: &Node) -> Vec<Node> {
+        let mut reverse = HashMap::<&Node, Vec<&Node>>::new();
+        for (dependent, deps) in &self.graph {
+            for dep in deps {
+                reverse.entry(dep).or_default().push(dependent);
+            }
+        }
+
+        let mut found = HashSet::new();
+        let mut worklist = vec![node];
+        while let Some(node) = worklist.pop() {
+            for &dependent in reverse.get(node).into_iter().flatten() {
+                if found.insert(dependent) {
+                    worklist.push(dependent);
                 }
-                _ => None,
-            })
-            .map(|(name, hash)| {
-                let h = hash?;
-                let n = name?.ok_or_else(|| {
-                    anyhow::anyhow!("hash 0x{} has no name", hex::encode(h))
-                })?;
-                Ok(Node { name: n, hash: h })
-            })
-            .collect::<Result<HashSet<_>>>()?;
+            }
+        }
 
-        if code.contains(&&Instr::CallSelf) {
-            deps.insert(node.clone());
+        let mut dependents = found.into_iter().cloned().collect::<Vec<_>>();
+        dependents.sort_by(|a, b| a.name.cmp(&b.name));
+        dependents
+    }
+
+    /// The solved nodes in an order to process them bottom-up: each after the
+    /// nodes it calls. Recursion through `call_self` is allowed, but mutual
+    /// recursion is a `SolverError::Cycle`.
+    pub fn linearize(&self) -> Result<Vec<Node>, SolverError> {
+        let mut graph = self.graph.clone();
+        // Callees that weren't solved themselves, e.g. old versions of a name
+        for dep in self.graph.values().flatten() {
+            graph.entry(dep.clone()).or_default();
+        }
+        let order = linearize(&graph).map_err(|e| match e.downcast::<Cycle<Node>>() {
+            Ok(cycle) => SolverError::Cycle(cycle),
+            Err(e) => SolverError::Store(e),
+        })?;
+        Ok(order
+            .into_iter()
+            .filter(|node| self.graph.contains_key(node))
+            .collect())
+    }
+}
+
+/// The functions the calls in `obj` call, and the calls whose callees can't be
+/// found in `store`
+fn solve_calls<S: NodeStore>(
+    store: &S,
+    obj: &CodeObject,
+) -> Result<(HashSet<Node>, Vec<UnresolvedCall>)> {
+    let load = |callee: &Callee| match callee {
+        Callee::Hash(hash) => store.get_code_object(hash).map(|obj| (*hash, obj)),
+        Callee::Name(name) => store.get_code_object_by_name(name),
+    };
+    let shape = |callee: &Callee| {
+        let (_, obj) = load(callee).ok()?;
+        Some((obj.argcount, obj.is_void))
+    };
+
+    let mut deps = HashSet::new();
+    let mut unresolved = vec![];
+    for (offset, callee) in call_targets(obj, &shape) {
+        let node = match callee {
+            None => Err(Unresolved::Indirect),
+            Some(Callee::Name(name)) => match load(&Callee::Name(name.clone())) {
+                Ok((hash, _)) => Ok(Node { name, hash }),
+                Err(_) => Err(Unresolved::NoSuchName(name)),
+            },
+            Some(Callee::Hash(hash)) => match load(&Callee::Hash(hash)) {
+                Ok(_) => match store.get_name_of_hash(&hash)? {
+                    Some(name) => Ok(Node { name, hash }),
+                    None => Err(Unresolved::Unnamed(hash)),
+                },
+                Err(_) => Err(Unresolved::NoSuchHash(hash)),
+            },
+        };
+        match node {
+            Ok(node) => {
+                deps.insert(node);
+            }
+            Err(reason) => unresolved.push(UnresolvedCall { offset, reason }),
+        }
+    }
+
+    Ok((deps, unresolved))
+}
+
+/// The calls in `obj` whose callees can't be found in `db`, e.g. to warn about
+/// after inserting it
+pub fn unresolved_calls(db: &Database, obj: &CodeObject) -> Result<Vec<UnresolvedCall>> {
+    Ok(solve_calls(&DatabaseNodeStore::new(db), obj)?.1)
+}
+
+/// The hashes that a code object loads with `load_func`
+pub fn references(obj: &CodeObject) -> HashSet<Hash> {
+    obj.code
+        .iter()
+        .filter_map(|instr| match instr {
+            Instr::LoadFunc(hash) => Some(*hash),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The stored code objects that load `hash` with `load_func`, whether or not they
+/// are named
+pub fn referrers(db: &Database, hash: &Hash) -> Result<Vec<Hash>> {
+    db.get_referrers(hash)
+}
+
+/// Why the solver couldn't build or order a graph
+#[derive(Debug)]
+pub enum SolverError {
+    /// Functions that call each other, other than through `call_self`
+    Cycle(Cycle<Node>),
+    /// A function that is defined more than once in the parses of a store
+    Duplicate(String),
+    /// A lookup in the node store that failed, e.g. a database error
+    Store(anyhow::Error),
+}
+
+impl From<anyhow::Error> for SolverError {
+    fn from(e: anyhow::Error) -> Self {
+        SolverError::Store(e)
+    }
+}
+
+impl fmt::Display for SolverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolverError::Cycle(cycle) => write!(f, "{cycle}"),
+            SolverError::Duplicate(name) => {
+                write!(f, "function '{name}' is defined more than once")
+            }
+            SolverError::Store(e) => write!(f, "{e}"),
         }
+    }
+}
 
-        Ok(deps)
+impl std::error::Error for SolverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SolverError::Store(e) => Some(e.as_ref()),
+            _ => None,
+        }
     }
+}
 
-    // fn linearize(&self) ->
+impl std::error::Error for UnresolvedCall {}
+
+impl fmt::Display for UnresolvedCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "call at offset {} is unresolved: ", self.offset)?;
+        match &self.reason {
+            Unresolved::Indirect => write!(f, "the called function isn't known"),
+            Unresolved::NoSuchName(name) => write!(f, "no function named '{name}'"),
+            Unresolved::NoSuchHash(hash) => write!(f, "no code object with hash {hash}"),
+            Unresolved::Unnamed(hash) => write!(f, "code object {hash} has no name"),
+        }
+    }
 }
 
-impl<'a, T> std::fmt::Display for DepGraph<'a, T>
+impl<T> std::fmt::Display for DepGraph<T>
 where
     T: NodeStore,
 {
@@ -127,14 +367,19 @@ where
 mod tests {
     use super::node::DatabaseNodeStore;
     use super::*;
-    use crate::bytecode::Instr;
+    use crate::bytecode::{Bytecode, Instr};
     use crate::db::Database;
     use crate::vm::tests::init_code_obj;
 
     fn mock_db() -> Result<Database> {
         let db = Database::temp()?;
 
-        let foo = init_code_obj(bytecode![Instr::CallSelf, Instr::Return]);
+        let foo = init_code_obj(bytecode![
+            Instr::LoadArg(0),
+            Instr::LoadArg(1),
+            Instr::CallSelf,
+            Instr::Return
+        ]);
 
         let hash_foo = db.insert_code_object_with_name(&foo, "foo")?;
 
@@ -153,10 +398,233 @@ mod tests {
     fn test_solver() {
         let db = mock_db().unwrap();
         let store = DatabaseNodeStore::new(&db);
-        let mut g = DepGraph::new(&store);
+        let mut g = DepGraph::new(store.clone());
 
         g.solve_static().unwrap();
 
         println!("{g}");
     }
+
+    #[test]
+    fn test_from_database() {
+        let db = mock_db().unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        let names =
+            |nodes: Vec<Node>| nodes.into_iter().map(|n| n.name).collect::<Vec<_>>();
+
+        let main = g.node("main").unwrap();
+        assert_eq!(main.hash, db.get_code_object_by_name("main").unwrap().0);
+        assert_eq!(names(g.deps(main).unwrap()), ["foo", "main"]);
+        assert_eq!(
+            g.nodes().into_iter().map(|n| &n.name).collect::<Vec<_>>(),
+            ["foo", "main"]
+        );
+        assert!(g.node("bar").is_none());
+        assert!(g
+            .deps(&Node {
+                name: "main".to_string(),
+                hash: Hash::digest(b"")
+            })
+            .is_none());
+    }
+
+    /// A database of `n` functions, each calling the two before it
+    fn chain_db(n: usize) -> Result<Database> {
+        let db = Database::temp()?;
+        let functions = (0..n).map(|i| {
+            let calls = (i.saturating_sub(2)..i).flat_map(|j| {
+                [
+                    Instr::LoadArg(0),
+                    Instr::LoadDyn(format!("f{j}")),
+                    Instr::Call,
+                    Instr::Pop,
+                ]
+            });
+            let code = calls
+                .chain([Instr::LoadArg(0), Instr::ReturnVal])
+                .collect::<Vec<_>>();
+            (
+                format!("f{i}"),
+                CodeObject {
+                    argcount: 1,
+                    ..init_code_obj(Bytecode::new(code))
+                },
+            )
+        });
+        db.insert_parses(functions)?;
+        Ok(db)
+    }
+
+    #[test]
+    fn test_solve_chain() {
+        let db = chain_db(300).unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        assert_eq!(g.graph.len(), 300);
+        assert_eq!(g.graph.values().map(HashSet::len).sum::<usize>(), 597);
+    }
+
+    #[ignore]
+    #[test]
+    // Time solving a few thousand functions
+    fn bench_solve_static() {
+        let db = chain_db(4000).unwrap();
+        let start = std::time::Instant::now();
+        DepGraph::from_database(&db).unwrap();
+        println!("solve_static: {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_linearize() {
+        let db = mock_db().unwrap();
+        let bar = init_code_obj(bytecode![
+            Instr::LoadDyn("main".to_string()),
+            Instr::Call,
+            Instr::Return
+        ]);
+        db.insert_code_object_with_name(&bar, "bar").unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        let order = g.linearize().unwrap();
+        assert_eq!(
+            order.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(),
+            ["foo", "main", "bar"]
+        );
+
+        // Mutual recursion has no order
+        let baz = init_code_obj(bytecode![
+            Instr::LoadDyn("qux".to_string()),
+            Instr::Call,
+            Instr::Return
+        ]);
+        let qux = init_code_obj(bytecode![
+            Instr::LoadDyn("baz".to_string()),
+            Instr::Call,
+            Instr::Return
+        ]);
+        db.insert_code_object_with_name(&baz, "baz").unwrap();
+        db.insert_code_object_with_name(&qux, "qux").unwrap();
+        let g = DepGraph::from_database(&db).unwrap();
+        let SolverError::Cycle(cycle) = g.linearize().unwrap_err() else {
+            panic!("expected a cycle");
+        };
+        let names = cycle.0.iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["baz", "qux", "baz"]);
+    }
+
+    #[test]
+    fn test_dependents() {
+        let db = mock_db().unwrap();
+        let (foo, _) = db.get_code_object_by_name("foo").unwrap();
+        let bar = init_code_obj(bytecode![
+            Instr::LoadDyn("main".to_string()),
+            Instr::Call,
+            Instr::Return
+        ]);
+        db.insert_code_object_with_name(&bar, "bar").unwrap();
+        let store = DatabaseNodeStore::new(&db);
+        let mut g = DepGraph::new(store.clone());
+        g.solve_static().unwrap();
+
+        let names =
+            |nodes: Vec<Node>| nodes.into_iter().map(|n| n.name).collect::<Vec<_>>();
+        let foo = Node {
+            name: "foo".to_string(),
+            hash: foo,
+        };
+        assert_eq!(names(g.dependents(&foo)), ["foo", "main"]);
+        assert_eq!(names(g.all_dependents(&foo)), ["bar", "foo", "main"]);
+        let main = g.dependents(&foo)[1].clone();
+        assert_eq!(names(g.dependents(&main)), ["bar", "main"]);
+        let bar = g.dependents(&main)[0].clone();
+        assert!(g.dependents(&bar).is_empty());
+        assert!(g.all_dependents(&bar).is_empty());
+
+        // The database knows the same, from what each code object loads
+        assert_eq!(db.get_referrers(&foo.hash).unwrap(), vec![main.hash]);
+    }
+
+    #[test]
+    fn test_solve_through_locals() {
+        let db = mock_db().unwrap();
+        let (foo, _) = db.get_code_object_by_name("foo").unwrap();
+        let bar = init_code_obj(bytecode![
+            Instr::LoadFunc(foo),
+            Instr::StoreLocal(0),
+            Instr::LoadArg(0),
+            Instr::LoadArg(1),
+            Instr::LoadLocal(0),
+            Instr::JumpRel(1),
+            Instr::Call,
+            Instr::Return
+        ]);
+        let bar = db.insert_code_object_with_name(&bar, "bar").unwrap();
+
+        let store = DatabaseNodeStore::new(&db);
+        let g = DepGraph::new(store.clone());
+        let (deps, unresolved) = g
+            .solve_node(&Node {
+                name: "bar".to_string(),
+                hash: bar,
+            })
+            .unwrap();
+        assert_eq!(
+            deps,
+            HashSet::from([Node {
+                name: "foo".to_string(),
+                hash: foo
+            }])
+        );
+        assert!(unresolved.is_empty());
+    }
+
+    #[test]
+    fn test_unresolved_calls() {
+        let db = mock_db().unwrap();
+        let missing = Hash::digest(b"");
+        let obj = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![
+                Instr::LoadLit(0),
+                Instr::Call,
+                Instr::LoadDyn("missing".to_string()),
+                Instr::Call,
+                Instr::LoadFunc(missing),
+                Instr::Call,
+                Instr::Return
+            ])
+        };
+        let unresolved = unresolved_calls(&db, &obj).unwrap();
+        assert_eq!(
+            unresolved,
+            vec![
+                UnresolvedCall {
+                    offset: 1,
+                    reason: Unresolved::Indirect
+                },
+                UnresolvedCall {
+                    offset: 3,
+                    reason: Unresolved::NoSuchName("missing".to_string())
+                },
+                UnresolvedCall {
+                    offset: 5,
+                    reason: Unresolved::NoSuchHash(missing)
+                },
+            ]
+        );
+        assert_eq!(
+            unresolved[1].to_string(),
+            "call at offset 3 is unresolved: no function named 'missing'"
+        );
+
+        // The graph leaves them out, and says so
+        let hash = db.insert_code_object_with_name(&obj, "calls").unwrap();
+        let store = DatabaseNodeStore::new(&db);
+        let mut g = DepGraph::new(store.clone());
+        let unresolved = g.solve_static().unwrap();
+        assert_eq!(unresolved.len(), 3);
+        assert!(unresolved.iter().all(|(node, _)| node.hash == hash));
+        assert!(g
+            .graph
+            .values()
+            .all(|deps| deps.iter().all(|dep| dep.name != "missing")));
+    }
 }
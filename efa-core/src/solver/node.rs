@@ -1,19 +1,37 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::Hash as StdHash;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use derivative::Derivative;
 
+use super::SolverError;
+use crate::asm::parser::Parse;
 use crate::db::Database;
 use crate::vm::CodeObject;
 use crate::Hash;
 
+/// A function in a dependence graph: a name, and the code object it had when the
+/// graph was solved
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Node {
     pub hash: Hash,
     pub name: String,
 }
 
+/// Nodes are ordered by name first
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.name, &self.hash).cmp(&(&other.name, &other.hash))
+    }
+}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 pub trait NodeStore: Clone + StdHash + PartialEq + Eq {
     fn get_code_object(&self, hash: &Hash) -> Result<CodeObject>;
     fn get_name_of_hash(&self, hash: &Hash) -> Result<Option<String>>;
@@ -21,9 +39,69 @@ pub trait NodeStore: Clone + StdHash + PartialEq + Eq {
     fn nodes(&self) -> Result<HashSet<Node>>;
 }
 
-/// A node from a file currently being analyzed, whose code object is stored in a `Parse`
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-struct FreeNodeStore {}
+/// Nodes from files currently being analyzed, whose code objects are stored in
+/// `Parse`s, so they can be analyzed before being inserted into a database. The
+/// code objects are hashed as parsed, before their dyn calls are resolved, and
+/// calls to `.extern` functions are left unresolved.
+#[derive(Derivative)]
+#[derivative(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct ParseNodeStore {
+    /// The hash of each function, by name
+    hashes: BTreeMap<String, Hash>,
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    objs: HashMap<Hash, CodeObject>,
+}
+
+impl ParseNodeStore {
+    pub fn new(parses: &[Parse]) -> Result<Self, SolverError> {
+        let mut hashes = BTreeMap::new();
+        let mut objs = HashMap::new();
+        for parse in parses {
+            let hash = parse.code_obj.hash()?;
+            if hashes.insert(parse.func_name.clone(), hash).is_some() {
+                return Err(SolverError::Duplicate(parse.func_name.clone()));
+            }
+            objs.insert(hash, parse.code_obj.clone());
+        }
+        Ok(Self { hashes, objs })
+    }
+}
+
+impl NodeStore for ParseNodeStore {
+    fn get_code_object(&self, hash: &Hash) -> Result<CodeObject> {
+        self.objs
+            .get(hash)
+            .cloned()
+            .ok_or_else(|| anyhow!("query failed: no parsed function with hash {hash}"))
+    }
+
+    fn get_name_of_hash(&self, hash: &Hash) -> Result<Option<String>> {
+        Ok(self
+            .hashes
+            .iter()
+            .find(|(_, h)| *h == hash)
+            .map(|(name, _)| name.clone()))
+    }
+
+    fn get_code_object_by_name(&self, name: &str) -> Result<(Hash, CodeObject)> {
+        let hash = self
+            .hashes
+            .get(name)
+            .ok_or_else(|| anyhow!("query failed: no parsed function named '{name}'"))?;
+        Ok((*hash, self.get_code_object(hash)?))
+    }
+
+    fn nodes(&self) -> Result<HashSet<Node>> {
+        Ok(self
+            .hashes
+            .iter()
+            .map(|(name, hash)| Node {
+                name: name.clone(),
+                hash: *hash,
+            })
+            .collect())
+    }
+}
 
 /// A node whose code object resides in a database.
 #[derive(Derivative)]
@@ -39,6 +117,91 @@ impl<'a> DatabaseNodeStore<'a> {
     }
 }
 
+/// Nodes from a stack of databases, e.g. a project's over a standard library's. Each
+/// lookup tries the databases in order, so names in earlier ones shadow the same
+/// names in later ones.
+#[derive(Derivative)]
+#[derivative(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct LayeredNodeStore<'a> {
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    dbs: Vec<&'a Database>,
+}
+
+impl<'a> LayeredNodeStore<'a> {
+    /// Look things up in `dbs`, first to last
+    pub fn new(dbs: Vec<&'a Database>) -> Self {
+        Self { dbs }
+    }
+
+    /// The lookup in the first database that `has` what is looked for, or `None`
+    /// if none do. A failed lookup isn't retried in later databases.
+    fn first<T>(
+        &self,
+        has: impl Fn(&Database) -> Result<bool>,
+        lookup: impl Fn(&Database) -> Result<T>,
+    ) -> Result<Option<T>> {
+        for db in &self.dbs {
+            if has(db)? {
+                return lookup(db).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether a database before the `i`th one has `name`, hiding it in the `i`th
+    fn shadowed(&self, i: usize, name: &str) -> Result<bool> {
+        for db in &self.dbs[..i] {
+            if db.has_name(name)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl NodeStore for LayeredNodeStore<'_> {
+    fn get_code_object(&self, hash: &Hash) -> Result<CodeObject> {
+        self.first(|db| db.has_code_object(hash), |db| db.get_code_object(hash))?
+            .ok_or_else(|| anyhow!("query failed: no code object with hash {hash}"))
+    }
+
+    /// The first name of `hash` in the first database where it has a name that
+    /// isn't shadowed
+    fn get_name_of_hash(&self, hash: &Hash) -> Result<Option<String>> {
+        for (i, db) in self.dbs.iter().enumerate() {
+            let names = db.get_name_of_hash(hash)?.into_iter();
+            for name in names.chain(db.get_names_of_hash(hash)?) {
+                if !self.shadowed(i, &name)? {
+                    return Ok(Some(name));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_code_object_by_name(&self, name: &str) -> Result<(Hash, CodeObject)> {
+        self.first(
+            |db| db.has_name(name),
+            |db| db.get_code_object_by_name(name),
+        )?
+        .ok_or_else(|| anyhow!("query failed: no code object with name '{name}'"))
+    }
+
+    /// The named functions of every database, except shadowed names
+    fn nodes(&self) -> Result<HashSet<Node>> {
+        let mut names = HashSet::new();
+        let mut nodes = HashSet::new();
+        for db in &self.dbs {
+            for (name, hash) in db.get_functions()? {
+                if names.insert(name.clone()) {
+                    nodes.insert(Node { name, hash });
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
 impl NodeStore for DatabaseNodeStore<'_> {
     fn get_code_object(&self, hash: &Hash) -> Result<CodeObject> {
         self.db.get_code_object(hash)
@@ -62,3 +225,124 @@ impl NodeStore for DatabaseNodeStore<'_> {
         self.db.get_code_object_by_name(name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::asm::parser::Parser;
+    use crate::solver::resolve_dyn::DynCallResolver;
+    use crate::solver::DepGraph;
+
+    #[test]
+    fn test_layered() {
+        let std = Database::temp().unwrap();
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$version 0:",
+            "    .lit 1",
+            "    load_lit 0",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&std).assemble_str(&source).unwrap();
+
+        let project = Database::temp().unwrap();
+        let source = [
+            ".extern square",
+            "$version 0:",
+            "    .lit 2",
+            "    load_lit 0",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+
+        // Externs are found in any layer
+        let store = LayeredNodeStore::new(vec![&project, &std]);
+        let mut resolver =
+            DynCallResolver::new(Parser::parse_str(&source).unwrap()).unwrap();
+        assert!(resolver.resolve_externs(&project).is_err());
+        resolver.resolve_externs_in(&store).unwrap();
+        project
+            .insert_parses(resolver.resolve_dyn_calls().unwrap())
+            .unwrap();
+
+        // The project's version shadows the library's
+        let version = project.get_code_object_by_name("version").unwrap().0;
+        assert_eq!(store.get_code_object_by_name("version").unwrap().0, version);
+        let old = std.get_code_object_by_name("version").unwrap().0;
+        assert_eq!(store.get_code_object(&old).unwrap().litpool.len(), 1);
+        assert_eq!(store.get_name_of_hash(&old).unwrap(), None);
+        std.create_alias("version1", &old).unwrap();
+        assert_eq!(store.get_name_of_hash(&old).unwrap().unwrap(), "version1");
+        let g = DepGraph::from_store(store).unwrap();
+        let names = g
+            .nodes()
+            .into_iter()
+            .map(|n| n.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["main", "square", "version", "version1"]);
+        assert_eq!(g.node("version").unwrap().hash, version);
+        let main = g.node("main").unwrap();
+        assert_eq!(g.deps(main).unwrap()[0].name, "square");
+
+        assert!(LayeredNodeStore::new(vec![])
+            .get_code_object_by_name("main")
+            .is_err());
+    }
+
+    #[test]
+    fn test_parses() {
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$unused 0:",
+            "    .lit 1",
+            "    load_lit 0",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let parses = Parser::parse_str(&source).unwrap();
+        let store = ParseNodeStore::new(&parses).unwrap();
+        let (hash, obj) = store.get_code_object_by_name("main").unwrap();
+        assert_eq!(hash, obj.hash().unwrap());
+        assert_eq!(store.get_name_of_hash(&hash).unwrap().unwrap(), "main");
+
+        // Analysis without a database
+        let g = DepGraph::from_store(store).unwrap();
+        assert!(g.unresolved().is_empty());
+        let order = g.linearize().unwrap();
+        let names = order.iter().map(|n| n.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["square", "main", "unused"]);
+        let main = g.node("main").unwrap().clone();
+        let dead = g.unreachable(&[main]);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].name, "unused");
+
+        let mut twice = parses;
+        twice.extend(Parser::parse_str(&source).unwrap());
+        assert!(matches!(
+            ParseNodeStore::new(&twice),
+            Err(SolverError::Duplicate(name)) if name == "square"
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+//! Which functions can run, starting from some roots: the rest are dead code, and
+//! can be removed from a database
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+
+use super::node::{Node, NodeStore};
+use super::DepGraph;
+use crate::bytecode::Instr;
+use crate::db::Database;
+use crate::vm::{CodeObject, Value};
+use crate::Hash;
+
+impl<S: NodeStore> DepGraph<S> {
+    /// The nodes with a path from one of `roots`, including the roots
+    pub fn reachable(&self, roots: &[Node]) -> HashSet<Node> {
+        let mut reached = roots.iter().collect::<HashSet<_>>();
+        let mut worklist = roots.iter().collect::<Vec<_>>();
+        while let Some(node) = worklist.pop() {
+            for dep in self.graph.get(node).into_iter().flatten() {
+                if reached.insert(dep) {
+                    worklist.push(dep);
+                }
+            }
+        }
+        reached.into_iter().cloned().collect()
+    }
+
+    /// The nodes that no call from `roots` can reach, sorted by name. A node is
+    /// reached if any name of its code object is.
+    pub fn unreachable(&self, roots: &[Node]) -> Vec<Node> {
+        let reached = self
+            .reachable(roots)
+            .into_iter()
+            .map(|node| node.hash)
+            .collect::<HashSet<_>>();
+        let mut dead = self
+            .graph
+            .keys()
+            .filter(|node| !reached.contains(&node.hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        dead.sort_by(|a, b| a.name.cmp(&b.name));
+        dead
+    }
+}
+
+/// The named functions in `db` that can't be reached from any of its entry points,
+/// sorted by name
+pub fn dead_functions(db: &Database) -> Result<Vec<(String, Hash)>> {
+    let mut roots = db
+        .get_entry_points()?
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect::<Vec<_>>();
+    if let Ok((hash, _)) = db.get_entry_point() {
+        roots.push(hash);
+    }
+    if roots.is_empty() {
+        bail!("cannot find dead functions: the database has no entry point");
+    }
+    dead_functions_from(db, &roots)
+}
+
+/// The named functions in `db` that can't be reached from the code objects
+/// `roots`, sorted by name. Every function a reached code object loads is
+/// reached, whether or not the solver can tell that it is called, so that
+/// functions passed around as values are kept.
+pub fn dead_functions_from(db: &Database, roots: &[Hash]) -> Result<Vec<(String, Hash)>> {
+    let functions = db.get_functions()?;
+    let names = functions.iter().cloned().collect::<HashMap<_, _>>();
+
+    let mut reached = roots.iter().copied().collect::<HashSet<_>>();
+    let mut worklist = roots.to_vec();
+    while let Some(hash) = worklist.pop() {
+        let Ok(obj) = db.get_code_object(&hash) else {
+            continue;
+        };
+        for dep in loaded(&obj, &names) {
+            if reached.insert(dep) {
+                worklist.push(dep);
+            }
+        }
+    }
+
+    Ok(functions
+        .into_iter()
+        .filter(|(_, hash)| !reached.contains(hash))
+        .collect())
+}
+
+/// The code objects that `obj` could call: those it loads with `load_func` or
+/// `load_dyn`, and the hashes in its literals
+fn loaded(obj: &CodeObject, names: &HashMap<String, Hash>) -> Vec<Hash> {
+    fn literal(value: &Value, hashes: &mut Vec<Hash>) {
+        match value {
+            Value::Hash(hash) => hashes.push(*hash),
+            Value::Container(values) => values.iter().for_each(|v| literal(v, hashes)),
+            Value::Map(pairs) => pairs.iter().for_each(|(k, v)| {
+                literal(k, hashes);
+                literal(v, hashes);
+            }),
+            _ => (),
+        }
+    }
+
+    let mut hashes = vec![];
+    for instr in obj.code.iter() {
+        match instr {
+            Instr::LoadFunc(hash) => hashes.push(*hash),
+            Instr::LoadDyn(name) => hashes.extend(names.get(name)),
+            _ => (),
+        }
+    }
+    obj.litpool.iter().for_each(|v| literal(v, &mut hashes));
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+
+    #[test]
+    fn test_dead_functions() {
+        let db = Database::temp().unwrap();
+        assert!(dead_functions(&db).is_err());
+
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$cube 1:",
+            "    load_arg 0",
+            "    dup",
+            "    load_dyn $square",
+            "    call",
+            "    mul",
+            "    ret_val",
+            "$unused 1:",
+            "    load_arg 0",
+            "    load_dyn $cube",
+            "    call",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let functions = Assembler::new(&db).assemble_str(&source).unwrap();
+        let hash = |name: &str| functions.iter().find(|(n, _)| n == name).unwrap().1;
+        let names = |dead: Vec<(String, Hash)>| {
+            dead.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(dead_functions(&db).unwrap()), ["cube", "unused"]);
+
+        // Aliases of reached code objects are reached too
+        db.create_alias("sq", &hash("square")).unwrap();
+        assert_eq!(names(dead_functions(&db).unwrap()), ["cube", "unused"]);
+
+        // Every entry point is a root
+        db.set_named_entry_point("bench", "cube").unwrap();
+        assert_eq!(names(dead_functions(&db).unwrap()), ["unused"]);
+        assert_eq!(
+            names(dead_functions_from(&db, &[hash("unused")]).unwrap()),
+            ["main"]
+        );
+        assert_eq!(dead_functions_from(&db, &[]).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_functions_as_values() {
+        let db = Database::temp().unwrap();
+        let source = [
+            "$double 1:",
+            "    load_arg 0",
+            "    dup",
+            "    add",
+            "    ret_val",
+            "$apply 2:",
+            "    load_arg 0",
+            "    load_arg 1",
+            "    call",
+            "    ret_val",
+            "$unused 0:",
+            "    ret",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $double",
+            "    load_dyn $apply",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        Assembler::new(&db).assemble_str(&source).unwrap();
+
+        // `double` is only passed to `apply`, which calls it indirectly
+        let dead = dead_functions(&db).unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].0, "unused");
+    }
+}
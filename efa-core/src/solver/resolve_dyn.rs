@@ -1,24 +1,34 @@
 use std::collections::{HashMap, HashSet};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 
 use crate::asm::parser::Parse;
 use crate::bytecode::{Bytecode, Instr};
+use crate::db::Database;
 use crate::vm::CodeObject;
 use crate::Hash;
 
-use super::toposort::toposort;
+use super::link::{arity, undefined, LinkErrors};
+use super::node::{DatabaseNodeStore, NodeStore};
+use super::toposort::components;
 
 #[derive(Debug)]
 pub struct DynCallResolver {
     objs: HashMap<String, CodeObject>,
     deps: HashMap<String, HashSet<String>>,
+    /// Functions declared with `.extern`, and their hashes once resolved
+    externs: HashMap<String, Option<Hash>>,
 
-    hash_order: Vec<String>,
+    /// Groups of functions that call each other, each after the groups it calls
+    hash_order: Vec<Vec<String>>,
 }
 
 impl DynCallResolver {
     pub fn new(nodes: Vec<Parse>) -> Result<Self> {
+        let externs = nodes
+            .iter()
+            .flat_map(|p| p.externs.iter().map(|name| (name.clone(), None)))
+            .collect();
         let objs = nodes
             .into_iter()
             .map(|p| (p.func_name, p.code_obj))
@@ -27,69 +37,100 @@ impl DynCallResolver {
         let mut s = Self {
             objs,
             deps: HashMap::new(),
+            externs,
             hash_order: vec![],
         };
 
+        // Link errors in the file are found before its call graph is needed
+        let declared = s.externs.keys().cloned().collect();
+        let in_file = s
+            .objs
+            .iter()
+            .map(|(name, obj)| (name.clone(), obj))
+            .collect();
+        let mut errors = undefined(&s.objs, &declared);
+        errors.extend(arity(&s.objs, &in_file));
+        LinkErrors::check(errors)?;
+
         s.deps = s.solve()?;
-        s.hash_order = toposort(&s.deps)?;
+        s.hash_order = components(&s.deps)?;
         Ok(s)
     }
 
+    /// Look up the functions declared with `.extern` by name in `db`, and check
+    /// that they are passed enough arguments
+    pub fn resolve_externs(&mut self, db: &Database) -> Result<()> {
+        self.resolve_externs_in(&DatabaseNodeStore::new(db))
+    }
+
+    /// Like `resolve_externs`, looking in any store, e.g. several databases
+    pub fn resolve_externs_in<S: NodeStore>(&mut self, store: &S) -> Result<()> {
+        let mut found = HashMap::new();
+        for (name, hash) in self.externs.iter_mut() {
+            let (extern_hash, obj) =
+                store.get_code_object_by_name(name).map_err(|_| {
+                    anyhow!("extern function '{name}' is not in the database")
+                })?;
+            *hash = Some(extern_hash);
+            found.insert(name.clone(), obj);
+        }
+        let callees = found
+            .iter()
+            .map(|(name, obj)| (name.clone(), obj))
+            .collect();
+        LinkErrors::check(arity(&self.objs, &callees))?;
+        Ok(())
+    }
+
     /// Compute the hashes of the code objects, replacing `LoadDyn` instructions with
     /// `LoadHash` when possible. Takes ownership since the modified code objects are
     /// returned back.
+    ///
+    /// Functions that call each other, like mutually recursive ones, can't embed
+    /// each other's hashes, since each hash depends on the other. They are hashed
+    /// together, keeping the `LoadDyn`s between them, so they find each other by
+    /// name when they run.
     pub fn resolve_dyn_calls(self) -> Result<HashMap<String, CodeObject>> {
         /*
            already_hashed = Map<Name, Hash>
-           for name in hash_order.rev()
-               dyns = hash[name].code.filter(LoadDyn)
-               for dyn in dyns:
-                   if dyn.name in already_hashed:
+           for group in hash_order
+               for name in group
+                   dyns = hash[name].code.filter(LoadDyn)
+                   for dyn in dyns not in group:
                        dyn.name = already_hashed[dyn.name]
-               hash = objs[name].hash()
-               already_hashed[name] = hash
-               (name, new_obj)
+               for name in group
+                   already_hashed[name] = objs[name].hash()
            collect into map
         */
 
-        // Keep track of the code objects we've already hashed
+        // Keep track of the code objects we've already hashed, starting with the
+        // externs
         let mut hashed = HashMap::<String, Hash>::new();
+        for (name, hash) in &self.externs {
+            match hash {
+                Some(hash) => hashed.insert(name.clone(), *hash),
+                None => bail!("extern function '{name}' needs a database to resolve"),
+            };
+        }
 
-        let new_objs = self
-            .hash_order
-            .into_iter()
-            .rev()
-            .map(|name| {
-                let obj = self
-                    .objs
-                    .get(&name)
-                    .ok_or_else(|| anyhow!("object '{name}' not present"))?;
-
-                let new_instrs: Vec<Instr> = obj
-                    .code
-                    .iter()
-                    .map(|instr| match instr {
-                        Instr::LoadDyn(dyn_name) => {
-                            let hash = hashed.get(dyn_name.as_str())
-                                .ok_or_else(|| anyhow!("dyn_name '{name}' should have already been hashed"))?;
-
-                            Ok(Instr::LoadFunc(*hash))
-                        }
-                        e => Ok(e.clone()),
-                    })
-                    .collect::<Result<_>>()?;
-
-                let new_obj = {
-                    let mut c = obj.clone();
-                    c.code = Bytecode::new(new_instrs);
-                    c
-                };
+        let mut new_objs = HashMap::new();
+        for group in self.hash_order {
+            let resolved = group
+                .iter()
+                .map(|name| {
+                    let obj = self
+                        .objs
+                        .get(name)
+                        .ok_or_else(|| anyhow!("object '{name}' not present"))?;
+                    Ok((name.clone(), resolve_obj(obj, &group, &hashed)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
 
+            for (name, new_obj) in resolved {
                 hashed.insert(name.clone(), new_obj.hash()?);
-
-                Ok((name, new_obj))
-            })
-            .collect::<Result<_>>()?;
+                new_objs.insert(name, new_obj);
+            }
+        }
 
         Ok(new_objs)
     }
@@ -125,7 +166,9 @@ impl DynCallResolver {
                 obj.code
                     .iter()
                     .filter_map(|instr| match instr {
-                        Instr::LoadDyn(name) => Some(name.to_string()),
+                        Instr::LoadDyn(name) if !self.externs.contains_key(name) => {
+                            Some(name.to_string())
+                        }
                         _ => None,
                     })
                     .collect()
@@ -133,11 +176,41 @@ impl DynCallResolver {
     }
 }
 
+/// `obj` with the `LoadDyn`s of functions outside its group replaced with their
+/// hashes
+fn resolve_obj(
+    obj: &CodeObject,
+    group: &[String],
+    hashed: &HashMap<String, Hash>,
+) -> Result<CodeObject> {
+    let new_instrs: Vec<Instr> = obj
+        .code
+        .iter()
+        .map(|instr| match instr {
+            Instr::LoadDyn(dyn_name) if group.contains(dyn_name) => Ok(instr.clone()),
+            Instr::LoadDyn(dyn_name) => {
+                let hash = hashed.get(dyn_name.as_str()).ok_or_else(|| {
+                    anyhow!("dyn_name '{dyn_name}' should have already been hashed")
+                })?;
+
+                Ok(Instr::LoadFunc(*hash))
+            }
+            e => Ok(e.clone()),
+        })
+        .collect::<Result<_>>()?;
+
+    let mut c = obj.clone();
+    c.code = Bytecode::new(new_instrs);
+    Ok(c)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use crate::asm::parser::Parser;
+    use crate::solver::LinkError;
+    use crate::vm::Vm;
 
     #[test]
     fn test_resolver() {
@@ -147,4 +220,126 @@ mod tests {
         let resolved = resolver.resolve_dyn_calls().unwrap();
         dbg!(resolved);
     }
+
+    #[test]
+    fn test_link_errors() {
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$main 0:",
+            "    load_dyn $square",
+            "    call",
+            "    load_dyn $missing",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let error =
+            DynCallResolver::new(Parser::parse_str(&source).unwrap()).unwrap_err();
+        let errors = error.downcast::<LinkErrors>().unwrap();
+        assert_eq!(
+            errors.to_string(),
+            [
+                "link failed:",
+                "  $main: call at offset 1 passes 0 arguments to 'square', which takes 1",
+                "  $main: load_dyn at offset 2 of undefined function 'missing'",
+            ]
+            .join("\n")
+        );
+
+        // Externs are checked once they are found
+        let db = Database::temp().unwrap();
+        let lib =
+            Parser::parse_str(&source.lines().take(5).collect::<Vec<_>>().join("\n"));
+        let resolved = DynCallResolver::new(lib.unwrap())
+            .unwrap()
+            .resolve_dyn_calls()
+            .unwrap();
+        db.insert_parses(resolved).unwrap();
+        let source = [
+            ".extern square",
+            "$main 0:",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let mut resolver =
+            DynCallResolver::new(Parser::parse_str(&source).unwrap()).unwrap();
+        let error = resolver.resolve_externs(&db).unwrap_err();
+        assert!(matches!(
+            &error.downcast::<LinkErrors>().unwrap().0[..],
+            [LinkError::Arity {
+                argcount: 1,
+                passed: 0,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let source = [
+            "$is_even 1:",
+            "    .lit 0",
+            "    .lit 1",
+            "    .lit 7",
+            "    load_arg 0",
+            "    load_lit 0",
+            "    eq",
+            "    jmp_t L0",
+            "    load_arg 0",
+            "    load_lit 1",
+            "    sub",
+            "    load_dyn $is_odd",
+            "    call",
+            "    ret_val",
+            "L0:",
+            "    load_lit 2",
+            "    ret_val",
+            "$is_odd 1:",
+            "    .lit 0",
+            "    .lit 1",
+            "    .lit 3",
+            "    load_arg 0",
+            "    load_lit 0",
+            "    eq",
+            "    jmp_t L0",
+            "    load_arg 0",
+            "    load_lit 1",
+            "    sub",
+            "    load_dyn $is_even",
+            "    call",
+            "    ret_val",
+            "L0:",
+            "    load_lit 2",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 6",
+            "    load_lit 0",
+            "    load_dyn $is_even",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let resolver = DynCallResolver::new(Parser::parse_str(&source).unwrap()).unwrap();
+        let resolved = resolver.resolve_dyn_calls().unwrap();
+
+        // The two find each other by name, and main embeds the hash of one
+        let is_even = resolved["is_even"].hash().unwrap();
+        assert!(resolved["is_even"]
+            .code
+            .contains(&Instr::LoadDyn("is_odd".to_string())));
+        assert!(resolved["is_odd"]
+            .code
+            .contains(&Instr::LoadDyn("is_even".to_string())));
+        assert!(resolved["main"].code.contains(&Instr::LoadFunc(is_even)));
+
+        let mut vm = Vm::new().unwrap();
+        vm.db.insert_parses(resolved).unwrap();
+        assert_eq!(vm.run_main_function().unwrap(), 7);
+    }
 }
@@ -1,51 +1,182 @@
 use std::collections::{HashMap, HashSet};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::hash::Hash;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, Result};
 
 type Graph<T> = HashMap<T, HashSet<T>>;
 
+/// The error when a graph has a cycle: its nodes in edge order, starting and
+/// ending with the same one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle<T>(pub Vec<T>);
+
+/// Order the nodes so that each comes before the nodes it has edges to. The order
+/// only depends on the graph: nodes and their edges are searched in sorted order.
+/// Every node an edge points to must be in the graph.
 pub fn toposort<T>(graph: &Graph<T>) -> Result<Vec<T>>
 where
-    T: Hash + Eq + PartialEq + Clone + Debug,
+    T: Hash + Eq + Ord + Clone + Debug + Send + Sync + 'static,
+{
+    let mut roots = graph.keys().collect::<Vec<_>>();
+    roots.sort();
+
+    // Depth-first, with an explicit stack of the path from the root and the edges
+    // of each node on it still to search
+    let mut done = HashSet::<&T>::new();
+    let mut postorder = Vec::with_capacity(graph.len());
+    for root in roots {
+        if done.contains(root) {
+            continue;
+        }
+        let mut path = vec![(root, sorted_edges(graph, root)?)];
+        let mut on_path = HashSet::from([root]);
+
+        while let Some((node, edges)) = path.last_mut() {
+            let node = *node;
+            match edges.next() {
+                Some(next) if done.contains(next) => (),
+                Some(next) if on_path.contains(next) => {
+                    let start = path.iter().position(|(n, _)| *n == next).unwrap();
+                    let mut cycle = path[start..]
+                        .iter()
+                        .map(|(n, _)| (*n).clone())
+                        .collect::<Vec<_>>();
+                    cycle.push(next.clone());
+                    return Err(Cycle(cycle).into());
+                }
+                Some(next) => {
+                    path.push((next, sorted_edges(graph, next)?));
+                    on_path.insert(next);
+                }
+                None => {
+                    path.pop();
+                    on_path.remove(node);
+                    done.insert(node);
+                    postorder.push(node.clone());
+                }
+            }
+        }
+    }
+
+    postorder.reverse();
+    Ok(postorder)
+}
+
+/// Order the nodes so that each comes after the nodes it has edges to, e.g. callees
+/// before their callers. Edges from a node to itself are ignored, but any other
+/// cycle is an error.
+pub fn linearize<T>(graph: &Graph<T>) -> Result<Vec<T>>
+where
+    T: Hash + Eq + Ord + Clone + Debug + Send + Sync + 'static,
+{
+    let acyclic = graph
+        .iter()
+        .map(|(node, edges)| {
+            let edges = edges.iter().filter(|&edge| edge != node).cloned();
+            (node.clone(), edges.collect())
+        })
+        .collect();
+    let mut order = toposort(&acyclic)?;
+    order.reverse();
+    Ok(order)
+}
+
+/// The strongly connected components of the graph, each sorted, in an order where
+/// each comes after the components it has edges to. A component is a cycle if it
+/// has more than one node, or a node with an edge to itself.
+pub fn components<T>(graph: &Graph<T>) -> Result<Vec<Vec<T>>>
+where
+    T: Hash + Eq + Ord + Clone + Debug,
 {
-    let soln = graph.iter().try_fold(vec![], |acc, (node, _)| {
-        visit_node(graph, node, vec![], acc.clone())
-    })?;
-    Ok(soln)
+    let mut roots = graph.keys().collect::<Vec<_>>();
+    roots.sort();
+
+    // Tarjan's algorithm, with an explicit stack of the path from the root as in
+    // `toposort`. `low` is the lowest index a node is known to reach on `stack`.
+    let mut index = HashMap::<&T, usize>::new();
+    let mut low = HashMap::<&T, usize>::new();
+    let mut stack = vec![];
+    let mut on_stack = HashSet::new();
+    let mut components = vec![];
+    for root in roots {
+        if index.contains_key(root) {
+            continue;
+        }
+        let mut path = vec![];
+        let mut next = Some(root);
+
+        loop {
+            if let Some(node) = next.take() {
+                index.insert(node, index.len());
+                low.insert(node, index[node]);
+                stack.push(node);
+                on_stack.insert(node);
+                path.push((node, sorted_edges(graph, node)?));
+            }
+            let Some((node, edges)) = path.last_mut() else {
+                break;
+            };
+            let node = *node;
+            match edges.next() {
+                Some(edge) if !index.contains_key(edge) => next = Some(edge),
+                Some(edge) if on_stack.contains(edge) => {
+                    low.insert(node, low[node].min(index[edge]));
+                }
+                Some(_) => (),
+                None => {
+                    path.pop();
+                    if let Some((parent, _)) = path.last() {
+                        low.insert(parent, low[parent].min(low[node]));
+                    }
+                    if low[node] == index[node] {
+                        let start = stack.iter().position(|&n| n == node).unwrap();
+                        let mut component = stack
+                            .drain(start..)
+                            .map(|n| {
+                                on_stack.remove(n);
+                                n.clone()
+                            })
+                            .collect::<Vec<_>>();
+                        component.sort();
+                        components.push(component);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(components)
 }
 
-fn visit_node<T>(
-    graph: &Graph<T>,
-    node: &T,
-    path: Vec<T>,
-    visited: Vec<T>,
-) -> Result<Vec<T>>
+fn sorted_edges<'g, T>(graph: &'g Graph<T>, node: &T) -> Result<std::vec::IntoIter<&'g T>>
 where
-    T: Hash + Eq + PartialEq + Clone + Debug,
+    T: Hash + Eq + Ord + Debug,
 {
-    if path.contains(node) {
-        bail!("toposort: cycle found");
-    } else if visited.contains(node) {
-        Ok(visited)
-    } else {
-        let edges = graph
-            .get(node)
-            .ok_or_else(|| anyhow!("toposort: node '{node:?}' not present in graph"))?;
-
-        let mut new_path = path.clone();
-        new_path.insert(0, node.clone());
-
-        let mut new_visited = edges.iter().try_fold(visited.clone(), |acc, edge| {
-            visit_node(graph, edge, new_path.clone(), acc.clone())
-        })?;
-
-        new_visited.insert(0, node.clone());
-        Ok(new_visited)
+    let mut edges = graph
+        .get(node)
+        .ok_or_else(|| anyhow!("toposort: node '{node:?}' not present in graph"))?
+        .iter()
+        .collect::<Vec<_>>();
+    edges.sort();
+    Ok(edges.into_iter())
+}
+
+impl<T: Debug> fmt::Display for Cycle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "toposort: cycle found: ")?;
+        for (i, node) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{node:?}")?;
+        }
+        Ok(())
     }
 }
 
+impl<T: Debug> std::error::Error for Cycle<T> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +194,86 @@ mod tests {
             vec!["a", "b", "c", "d"]
         );
     }
+
+    #[test]
+    fn test_linearize() {
+        let graph = HashMap::from([
+            ("main", HashSet::from(["fib", "print"])),
+            ("fib", HashSet::from(["fib"])),
+            ("print", HashSet::new()),
+        ]);
+        assert_eq!(linearize(&graph).unwrap(), vec!["fib", "print", "main"]);
+
+        let graph =
+            HashMap::from([("a", HashSet::from(["b"])), ("b", HashSet::from(["a"]))]);
+        let error = linearize(&graph).unwrap_err();
+        assert_eq!(
+            error.downcast::<Cycle<&str>>().unwrap(),
+            Cycle(vec!["a", "b", "a"])
+        );
+    }
+
+    #[test]
+    fn test_components() {
+        let graph = HashMap::from([
+            ("d", HashSet::from(["a"])),
+            ("b", HashSet::from(["a", "c"])),
+            ("a", HashSet::from(["b"])),
+            ("c", HashSet::from(["c"])),
+            ("e", HashSet::new()),
+        ]);
+        assert_eq!(
+            components(&graph).unwrap(),
+            vec![vec!["c"], vec!["a", "b"], vec!["d"], vec!["e"]]
+        );
+    }
+
+    #[test]
+    fn test_toposort_order() {
+        // Nodes with no order between them are sorted, whatever the hashing
+        let graph = HashMap::from([
+            ("e", HashSet::from(["c", "a"])),
+            ("d", HashSet::new()),
+            ("c", HashSet::new()),
+            ("b", HashSet::from(["d"])),
+            ("a", HashSet::new()),
+        ]);
+        for _ in 0..8 {
+            let graph = graph.clone().into_iter().collect();
+            assert_eq!(toposort(&graph).unwrap(), ["e", "c", "b", "d", "a"]);
+        }
+
+        assert!(toposort(&HashMap::from([("a", HashSet::from(["b"]))])).is_err());
+    }
+
+    #[test]
+    fn test_toposort_deep() {
+        // A chain far deeper than a recursive search could go
+        let n = 200_000;
+        let graph = (0..n)
+            .map(|i| (i, (i + 1..n).take(1).collect()))
+            .collect::<Graph<u32>>();
+        let sorted = toposort(&graph).unwrap();
+        assert_eq!(sorted, (0..n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_cycle() {
+        let graph = HashMap::from([
+            ("main", HashSet::from(["a"])),
+            ("a", HashSet::from(["b"])),
+            ("b", HashSet::from(["c", "d"])),
+            ("c", HashSet::from(["a"])),
+            ("d", HashSet::new()),
+        ]);
+        let err = toposort(&graph).unwrap_err();
+        let Cycle(cycle) = err.downcast_ref::<Cycle<&str>>().unwrap();
+
+        // The search starts at `a`, the first node
+        assert_eq!(cycle, &["a", "b", "c", "a"]);
+        assert!(err.to_string().contains(" -> "));
+
+        let err = toposort(&HashMap::from([("a", HashSet::from(["a"]))])).unwrap_err();
+        assert_eq!(err.to_string(), r#"toposort: cycle found: "a" -> "a""#);
+    }
 }
@@ -0,0 +1,536 @@
+//! Copying code objects and names between databases over HTTP.
+//!
+//! A database is served with `serve`, and another database pushes to or pulls
+//! from it with `push` and `pull`. Both sides first exchange the hashes they have,
+//! so only missing code objects are sent, as a pack (see `db::pack`). The routes
+//! are:
+//!
+//! ```text
+//! GET  /hashes          the server's hashes, one per line
+//! GET  /names           the server's names, as `hash name` lines
+//! POST /missing         the body lists the client's hashes; responds with a pack
+//!                       of every other object on the server
+//! POST /objects         imports the pack in the body
+//! POST /names[?force]   points names in the body at stored objects, replacing
+//!                       taken names if forced; responds with the names changed
+//! ```
+//!
+//! The POST routes that write are only served with a token, which clients send as
+//! `Authorization: Bearer <token>`. Without one the database is served read-only.
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::db::{Database, NameConflict};
+use crate::Hash;
+
+/// The largest request or response body that is read, in bytes
+pub const MAX_BODY_SIZE: usize = 64 << 20;
+
+/// The longest request line, status line, or header line that is read, in bytes
+const MAX_LINE_SIZE: usize = 8 << 10;
+
+/// The most header lines of a request or response that are read
+const MAX_HEADERS: usize = 100;
+
+/// How long to wait for the other side to send or receive data
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a push or pull changed on the receiving side
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Code objects that were sent
+    pub objects: usize,
+    /// Names that now point somewhere new
+    pub names: Vec<(String, Hash)>,
+}
+
+/// Serve `db` to clients on `listener`, one connection at a time. Clients can
+/// only write to it if they send `token`, and not at all without one. Errors in
+/// a connection are sent back to the client and do not stop the server.
+pub fn serve(db: &Database, listener: &TcpListener, token: Option<&str>) -> Result<()> {
+    for stream in listener.incoming() {
+        if let Err(e) = handle(db, stream?, token) {
+            eprintln!("sync: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Send every code object and name in `db` that the server at `url` lacks,
+/// authorized by the server's `token`. Names taken on the server fail the push
+/// unless `force` is set.
+pub fn push(db: &Database, url: &str, token: &str, force: bool) -> Result<SyncReport> {
+    let mut remote = Remote::parse(url)?;
+    remote.token = Some(token.to_string());
+    let have = parse_hashes(&remote.request("GET", "/hashes", &[])?)?;
+
+    let mut pack = vec![];
+    let objects = db.export_missing(&have, &mut pack)?;
+    if objects > 0 {
+        remote.request("POST", "/objects", &pack)?;
+    }
+
+    let path = match force {
+        true => "/names?force",
+        false => "/names",
+    };
+    let names = format_names(&db.get_functions()?);
+    let names = parse_names(&remote.request("POST", path, names.as_bytes())?)?;
+    Ok(SyncReport { objects, names })
+}
+
+/// Fetch every code object and name from the server at `url` that `db` lacks.
+/// Nothing is changed if any of it can't be imported.
+pub fn pull(db: &Database, url: &str, on_conflict: NameConflict) -> Result<SyncReport> {
+    let remote = Remote::parse(url)?;
+    let have = db.get_hashes()?;
+
+    // Names first, so that the pack has every object they point at
+    let names = parse_names(&remote.request("GET", "/names", &[])?)?;
+    let pack = remote.request("POST", "/missing", format_hashes(&have).as_bytes())?;
+    let names = db.import_pack_with_names(pack.as_slice(), &names, on_conflict)?;
+    let objects = db.get_hashes()?.len() - have.len();
+    Ok(SyncReport { objects, names })
+}
+
+/// The server at an `http://host[:port]` URL
+struct Remote {
+    host: String,
+    port: u16,
+    /// Sent to authorize writes
+    token: Option<String>,
+}
+
+impl Remote {
+    fn parse(url: &str) -> Result<Remote> {
+        let addr = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("invalid url '{url}': expected http://host[:port]"))?;
+        let addr = addr.strip_suffix('/').unwrap_or(addr);
+        if addr.is_empty() || addr.contains('/') {
+            bail!("invalid url '{url}': expected http://host[:port]");
+        }
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("invalid url '{url}': bad port '{port}'"))?,
+            ),
+            None => (addr, 80),
+        };
+        Ok(Remote {
+            host: host.to_string(),
+            port,
+            token: None,
+        })
+    }
+
+    /// Make one request and return the body of a successful response
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let auth = match &self.token {
+            Some(token) => format!("Authorization: Bearer {token}\r\n"),
+            None => String::new(),
+        };
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: {}:{}\r\n{auth}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.host,
+            self.port,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        let mut r = BufReader::new(stream);
+        let (Some(status), Some(headers)) = (read_line(&mut r)?, read_headers(&mut r)?)
+        else {
+            bail!("{method} {path} failed: the response headers are too large");
+        };
+        let mut body = vec![];
+        match headers.length {
+            Some(length) if length > MAX_BODY_SIZE => {
+                bail!(
+                    "{method} {path} failed: the response of {length} bytes is too large"
+                )
+            }
+            Some(length) => {
+                body.resize(length, 0);
+                r.read_exact(&mut body)?;
+            }
+            None => {
+                r.take(MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body)?;
+                if body.len() > MAX_BODY_SIZE {
+                    bail!("{method} {path} failed: the response is too large");
+                }
+            }
+        }
+
+        let status = status.trim_end();
+        if status.split_whitespace().nth(1) != Some("200") {
+            bail!(
+                "{method} {path} failed: {status}: {}",
+                String::from_utf8_lossy(&body).trim_end()
+            );
+        }
+        Ok(body)
+    }
+}
+
+/// Answer one request
+fn handle(db: &Database, stream: TcpStream, token: Option<&str>) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut r = BufReader::new(&stream);
+    let Some(line) = read_line(&mut r)? else {
+        respond(&stream, "400 Bad Request", b"request line is too long")?;
+        bail!("refused a request line of over {MAX_LINE_SIZE} bytes");
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return respond(&stream, "400 Bad Request", b"malformed request line");
+    };
+    let Some(headers) = read_headers(&mut r)? else {
+        respond(
+            &stream,
+            "431 Request Header Fields Too Large",
+            b"request headers are too large",
+        )?;
+        bail!(
+            "refused request headers over {MAX_HEADERS} lines of {MAX_LINE_SIZE} bytes"
+        );
+    };
+    let length = headers.length.unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        respond(
+            &stream,
+            "413 Payload Too Large",
+            b"request body is too large",
+        )?;
+        bail!("refused a request body of {length} bytes");
+    }
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+
+    let writes = matches!(
+        (method, path),
+        ("POST", "/objects" | "/names" | "/names?force")
+    );
+    if writes {
+        match (token, &headers.token) {
+            (None, _) => {
+                return respond(&stream, "403 Forbidden", b"the database is read-only")
+            }
+            (Some(token), Some(sent)) if same_token(token, sent) => (),
+            _ => return respond(&stream, "401 Unauthorized", b"missing or wrong token"),
+        }
+    }
+
+    let response = match (method, path) {
+        ("GET", "/hashes") => Ok(format_hashes(&db.get_hashes()?).into_bytes()),
+        ("GET", "/names") => Ok(format_names(&db.get_functions()?).into_bytes()),
+        ("POST", "/missing") => parse_hashes(&body).and_then(|have| {
+            let mut pack = vec![];
+            db.export_missing(&have, &mut pack)?;
+            Ok(pack)
+        }),
+        ("POST", "/objects") => db
+            .import_pack(body.as_slice(), NameConflict::Fail)
+            .map(|_| vec![]),
+        ("POST", "/names" | "/names?force") => {
+            let on_conflict = match path {
+                "/names?force" => NameConflict::Replace,
+                _ => NameConflict::Fail,
+            };
+            parse_names(&body)
+                .and_then(|names| db.import_names(&names, on_conflict))
+                .map(|names| format_names(&names).into_bytes())
+        }
+        _ => return respond(&stream, "404 Not Found", b"no such route"),
+    };
+
+    match response {
+        Ok(body) => respond(&stream, "200 OK", &body),
+        Err(e) => {
+            respond(&stream, "409 Conflict", e.to_string().as_bytes())?;
+            Err(e)
+        }
+    }
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &[u8]) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// The headers of a request or response that are used
+#[derive(Debug, Default)]
+struct Headers {
+    /// Content-Length
+    length: Option<usize>,
+    /// A bearer token from Authorization
+    token: Option<String>,
+}
+
+/// Read a line of at most `MAX_LINE_SIZE` bytes, or `None` if it is longer
+fn read_line<R: BufRead>(r: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    r.take(MAX_LINE_SIZE as u64 + 1).read_line(&mut line)?;
+    Ok((line.len() <= MAX_LINE_SIZE).then_some(line))
+}
+
+/// Read the headers of a request or response, or `None` if there are more than
+/// `MAX_HEADERS` lines or one is too long
+fn read_headers<R: BufRead>(r: &mut R) -> Result<Option<Headers>> {
+    let mut headers = Headers::default();
+    for _ in 0..=MAX_HEADERS {
+        let Some(line) = read_line(r)? else {
+            return Ok(None);
+        };
+        if line.is_empty() {
+            bail!("connection closed in headers");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(Some(headers));
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            if key.eq_ignore_ascii_case("content-length") {
+                headers.length = Some(value.trim().parse()?);
+            } else if key.eq_ignore_ascii_case("authorization") {
+                headers.token = value.trim().strip_prefix("Bearer ").map(str::to_string);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Compare tokens in time that depends only on their lengths
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn format_hashes(hashes: &[Hash]) -> String {
+    hashes.iter().map(|hash| format!("{hash}\n")).collect()
+}
+
+fn parse_hashes(body: &[u8]) -> Result<HashSet<Hash>> {
+    std::str::from_utf8(body)?.lines().map(str::parse).collect()
+}
+
+fn format_names(names: &[(String, Hash)]) -> String {
+    names
+        .iter()
+        .map(|(name, hash)| format!("{hash} {name}\n"))
+        .collect()
+}
+
+fn parse_names(body: &[u8]) -> Result<Vec<(String, Hash)>> {
+    std::str::from_utf8(body)?
+        .lines()
+        .map(|line| {
+            let (hash, name) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("malformed name line '{line}'"))?;
+            Ok((name.to_string(), hash.parse()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::asm::assembler::Assembler;
+
+    const TOKEN: &str = "secret";
+
+    /// Serve a new database in the background, returning it and its URL
+    fn remote(token: Option<&'static str>) -> (Arc<Database>, String) {
+        let db = Arc::new(Database::temp().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = db.clone();
+        std::thread::spawn(move || serve(&server, &listener, token));
+        (db, url)
+    }
+
+    fn assemble(db: &Database, source: &[&str]) {
+        Assembler::new(db).assemble_str(&source.join("\n")).unwrap();
+    }
+
+    #[test]
+    fn test_push_pull() {
+        let (remote, url) = remote(Some(TOKEN));
+        let local = Database::temp().unwrap();
+        assemble(
+            &local,
+            &[
+                "$square 1:",
+                "    load_arg 0",
+                "    dup",
+                "    mul",
+                "    ret_val",
+                "$quad 1:",
+                "    load_arg 0",
+                "    load_dyn $square",
+                "    call",
+                "    load_dyn $square",
+                "    call",
+                "    ret_val",
+            ],
+        );
+
+        let report = push(&local, &url, TOKEN, false).unwrap();
+        assert_eq!(report.objects, 2);
+        assert_eq!(report.names.len(), 2);
+        assert_eq!(
+            remote.get_functions().unwrap(),
+            local.get_functions().unwrap()
+        );
+
+        // Only what is missing is sent
+        assert_eq!(
+            push(&local, &url, TOKEN, false).unwrap(),
+            SyncReport::default()
+        );
+        assemble(&remote, &["$cube 1:", "    load_arg 0", "    ret_val"]);
+        let report = pull(&local, &url, NameConflict::Fail).unwrap();
+        assert_eq!(report.objects, 1);
+        let (cube, _) = remote.get_code_object_by_name("cube").unwrap();
+        assert_eq!(report.names, vec![("cube".to_string(), cube)]);
+        assert_eq!(local.get_code_object_by_name("cube").unwrap().0, cube);
+
+        // A name that points elsewhere on the server is only replaced by force
+        assemble(
+            &local,
+            &["$cube 1:", "    load_arg 0", "    dup", "    ret_val"],
+        );
+        let (new_cube, _) = local.get_code_object_by_name("cube").unwrap();
+        assert!(push(&local, &url, TOKEN, false).is_err());
+        assert_eq!(remote.get_code_object_by_name("cube").unwrap().0, cube);
+        let report = push(&local, &url, TOKEN, true).unwrap();
+        assert_eq!(report.objects, 0);
+        assert_eq!(report.names, vec![("cube".to_string(), new_cube)]);
+        assert_eq!(remote.get_code_object_by_name("cube").unwrap().0, new_cube);
+
+        assert!(pull(&local, "http://127.0.0.1:0/db", NameConflict::Fail).is_err());
+        assert!(pull(&local, "ftp://localhost", NameConflict::Fail).is_err());
+    }
+
+    #[test]
+    fn test_push_needs_token() {
+        let local = Database::temp().unwrap();
+        assemble(
+            &local,
+            &["$one 0:", "    .lit 1", "    load_lit 0", "    ret_val"],
+        );
+
+        let (server, url) = remote(Some(TOKEN));
+        let err = push(&local, &url, "wrong", false).unwrap_err();
+        assert!(err.to_string().contains("401"), "{err}");
+
+        // Without a token the server is read-only
+        let (read_only, url) = remote(None);
+        let err = push(&local, &url, TOKEN, false).unwrap_err();
+        assert!(err.to_string().contains("403"), "{err}");
+        assert!(server.get_hashes().unwrap().is_empty());
+        assert!(read_only.get_hashes().unwrap().is_empty());
+
+        // But can still be pulled from
+        assemble(
+            &read_only,
+            &["$two 0:", "    .lit 2", "    load_lit 0", "    ret_val"],
+        );
+        assert_eq!(pull(&local, &url, NameConflict::Fail).unwrap().objects, 1);
+    }
+
+    #[test]
+    fn test_body_too_large() {
+        let (_, url) = remote(Some(TOKEN));
+        let mut stream =
+            TcpStream::connect(url.strip_prefix("http://").unwrap()).unwrap();
+        write!(
+            stream,
+            "POST /objects HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        )
+        .unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        assert!(response.contains("413"), "{response}");
+    }
+
+    #[test]
+    fn test_headers_too_large() {
+        let (_, url) = remote(Some(TOKEN));
+        let send = |request: &[u8]| {
+            let mut stream =
+                TcpStream::connect(url.strip_prefix("http://").unwrap()).unwrap();
+            // The server may answer before reading all of it
+            let _ = stream.write_all(request);
+            let mut response = String::new();
+            BufReader::new(stream).read_line(&mut response).unwrap();
+            response
+        };
+
+        // A request line that never ends
+        let mut request = b"GET /".to_vec();
+        request.resize(MAX_LINE_SIZE + 2, b'a');
+        let response = send(&request);
+        assert!(response.contains("400"), "{response}");
+
+        let mut request = b"GET /hashes HTTP/1.1\r\n".to_vec();
+        request.extend(b"X-Padding: a\r\n".repeat(MAX_HEADERS + 1));
+        let response = send(&request);
+        assert!(response.contains("431"), "{response}");
+
+        // The server still answers
+        assert!(send(b"GET /hashes HTTP/1.1\r\n\r\n").contains("200"));
+    }
+
+    #[test]
+    fn test_pull_is_atomic() {
+        let (remote, url) = remote(Some(TOKEN));
+        assemble(
+            &remote,
+            &["$f 0:", "    .lit 1", "    load_lit 0", "    ret_val"],
+        );
+        assemble(
+            &remote,
+            &["$g 0:", "    .lit 2", "    load_lit 0", "    ret_val"],
+        );
+        let local = Database::temp().unwrap();
+        assemble(
+            &local,
+            &["$g 0:", "    .lit 3", "    load_lit 0", "    ret_val"],
+        );
+
+        // The name taken locally fails the pull, and no code object is kept
+        let have = local.get_hashes().unwrap();
+        assert!(pull(&local, &url, NameConflict::Fail).is_err());
+        assert_eq!(local.get_hashes().unwrap(), have);
+        assert!(local.get_code_object_by_name("f").is_err());
+
+        let report = pull(&local, &url, NameConflict::Keep).unwrap();
+        assert_eq!(report.objects, 2);
+        assert_eq!(report.names.len(), 1);
+    }
+}
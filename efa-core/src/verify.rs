@@ -0,0 +1,629 @@
+//! Static verification of code objects, so malformed bytecode is rejected before it
+//! is stored or run instead of when the VM indexes out of bounds.
+
+use std::fmt;
+
+use crate::bytecode::{BinOp, Instr, Operand};
+use crate::vm::{CodeObject, TypeTag};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// There are fewer local names than arguments
+    TooFewLocalNames {
+        argcount: usize,
+        names: usize,
+    },
+    /// A void function returns a value, or a non-void function doesn't
+    WrongReturn {
+        offset: usize,
+        is_void: bool,
+    },
+    /// The signature has a different number of parameters than the arity
+    SignatureArity {
+        argcount: usize,
+        params: usize,
+    },
+    LitOutOfBounds {
+        offset: usize,
+        index: usize,
+    },
+    ArgOutOfBounds {
+        offset: usize,
+        index: usize,
+    },
+    LocalOutOfBounds {
+        offset: usize,
+        index: usize,
+    },
+    LabelOutOfBounds {
+        offset: usize,
+        label: usize,
+    },
+    /// A label points past the end of the code
+    JumpOutOfBounds {
+        label: usize,
+        target: usize,
+    },
+    /// A relative jump lands before the start or past the end of the code
+    RelativeJumpOutOfBounds {
+        offset: usize,
+        delta: isize,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    /// Two paths reach the same instruction with different stack depths
+    InconsistentStack {
+        offset: usize,
+        depths: (usize, usize),
+    },
+}
+
+/// Stack depth before an instruction. `Unknown` after instructions whose effect
+/// depends on runtime state, like calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Depth {
+    Known(usize),
+    Unknown,
+}
+
+/// Verify `obj`, returning the first problem found.
+pub fn verify(obj: &CodeObject) -> Result<(), VerifyError> {
+    match diagnose(obj).into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Find every problem with `obj`.
+pub fn diagnose(obj: &CodeObject) -> Vec<VerifyError> {
+    let mut errors = check_indices(obj);
+
+    // The stack analysis relies on labels being valid
+    if errors.is_empty() {
+        if let Err(e) = stack_depths(obj) {
+            errors.push(e);
+        }
+    }
+
+    errors
+}
+
+fn check_indices(obj: &CodeObject) -> Vec<VerifyError> {
+    let mut errors = vec![];
+    let num_locals = obj.localnames.len().saturating_sub(obj.argcount);
+
+    if obj.localnames.len() < obj.argcount {
+        errors.push(VerifyError::TooFewLocalNames {
+            argcount: obj.argcount,
+            names: obj.localnames.len(),
+        });
+    }
+
+    if let Some(signature) = &obj.signature {
+        if signature.params.len() != obj.argcount {
+            errors.push(VerifyError::SignatureArity {
+                argcount: obj.argcount,
+                params: signature.params.len(),
+            });
+        }
+    }
+
+    errors.extend(
+        obj.labels
+            .iter()
+            .enumerate()
+            .filter_map(|(label, &target)| {
+                (target > obj.code.len())
+                    .then_some(VerifyError::JumpOutOfBounds { label, target })
+            }),
+    );
+
+    errors.extend(obj.code.iter().enumerate().filter_map(|(offset, instr)| {
+        match instr.operand()? {
+            Operand::Lit(index) if index >= obj.litpool.len() => {
+                Some(VerifyError::LitOutOfBounds { offset, index })
+            }
+            Operand::Arg(index) if index >= obj.argcount => {
+                Some(VerifyError::ArgOutOfBounds { offset, index })
+            }
+            Operand::Local(index) if index >= num_locals => {
+                Some(VerifyError::LocalOutOfBounds { offset, index })
+            }
+            Operand::Label(label) if label >= obj.labels.len() => {
+                Some(VerifyError::LabelOutOfBounds { offset, label })
+            }
+            Operand::Relative(delta)
+                if offset
+                    .checked_add_signed(delta)
+                    .is_none_or(|target| target > obj.code.len()) =>
+            {
+                Some(VerifyError::RelativeJumpOutOfBounds { offset, delta })
+            }
+            _ => None,
+        }
+    }));
+
+    errors.extend(obj.code.iter().enumerate().filter_map(|(offset, instr)| {
+        let returns_value = match instr {
+            Instr::Return => false,
+            Instr::ReturnVal => true,
+            _ => return None,
+        };
+        (returns_value == obj.is_void).then_some(VerifyError::WrongReturn {
+            offset,
+            is_void: obj.is_void,
+        })
+    }));
+
+    errors
+}
+
+/// Simulate the stack depth along every path through the code, returning the depth
+/// before each instruction (`None` if unreachable). Assumes labels are in bounds.
+pub(crate) fn stack_depths(obj: &CodeObject) -> Result<Vec<Option<Depth>>, VerifyError> {
+    let code = &obj.code;
+    let mut depths: Vec<Option<Depth>> = vec![None; code.len()];
+    let mut worklist = vec![];
+
+    if !code.is_empty() {
+        depths[0] = Some(Depth::Known(0));
+        worklist.push(0);
+    }
+
+    while let Some(offset) = worklist.pop() {
+        let instr = &code[offset];
+        let depth = depths[offset].unwrap();
+
+        let after = match (instr, depth) {
+            (Instr::CallSelf, Depth::Known(n)) if n < obj.argcount => {
+                return Err(VerifyError::StackUnderflow { offset });
+            }
+            // Pops the function hash, then a callee-dependent number of arguments
+            (Instr::Call | Instr::ContMake, Depth::Known(0)) => {
+                return Err(VerifyError::StackUnderflow { offset });
+            }
+            (instr, depth) => match (instr.stack_effect(), depth) {
+                (Some((pops, _)), Depth::Known(n)) if n < pops => {
+                    return Err(VerifyError::StackUnderflow { offset });
+                }
+                (Some((pops, pushes)), Depth::Known(n)) => {
+                    Depth::Known(n - pops + pushes)
+                }
+                _ => Depth::Unknown,
+            },
+        };
+
+        for succ in obj
+            .successors(offset)
+            .into_iter()
+            .filter(|&s| s < code.len())
+        {
+            let merged = match (depths[succ], after) {
+                (None, d) => d,
+                (Some(Depth::Known(a)), Depth::Known(b)) if a != b => {
+                    return Err(VerifyError::InconsistentStack {
+                        offset: succ,
+                        depths: (a, b),
+                    });
+                }
+                (Some(Depth::Known(_)), Depth::Known(b)) => Depth::Known(b),
+                (Some(_), _) => Depth::Unknown,
+            };
+
+            if depths[succ] != Some(merged) {
+                depths[succ] = Some(merged);
+                worklist.push(succ);
+            }
+        }
+    }
+
+    Ok(depths)
+}
+
+/// The types of the values on the stack before each instruction, bottom first, as
+/// type names like `i32`, or `None` for a value whose type isn't known until
+/// runtime. An entry is `None` if the instruction is unreachable or the stack depth
+/// before it is unknown. Assumes `obj` has been verified.
+pub(crate) fn stack_kinds(obj: &CodeObject) -> Vec<Option<Vec<Option<&'static str>>>> {
+    let code = &obj.code;
+    let mut kinds: Vec<Option<Vec<Option<&str>>>> = vec![None; code.len()];
+    let mut worklist = vec![];
+
+    if !code.is_empty() {
+        kinds[0] = Some(vec![]);
+        worklist.push(0);
+    }
+
+    while let Some(offset) = worklist.pop() {
+        let mut stack = kinds[offset].clone().unwrap();
+        let instr = &code[offset];
+        let Some((pops, pushes)) = instr.stack_effect() else {
+            continue;
+        };
+        let Some(rest) = stack.len().checked_sub(pops) else {
+            continue;
+        };
+        let popped = stack.split_off(rest);
+        let top = popped.last().copied().flatten();
+        let pushed = match instr {
+            Instr::LoadLit(i) => obj.litpool.get(*i).map(|lit| lit.type_name()),
+            Instr::LoadArg(i) => obj
+                .signature
+                .as_ref()
+                .and_then(|s| s.params.get(*i))
+                .filter(|param| **param != TypeTag::Any)
+                .map(TypeTag::name),
+            Instr::LoadFunc(_) | Instr::LoadDyn(_) => Some("hash"),
+            Instr::Dup | Instr::Dbg | Instr::DbgMsg(_) | Instr::UnaryOp(_) => top,
+            Instr::BinOp(BinOp::Eq) => Some("bool"),
+            Instr::Cmp => Some("i32"),
+            Instr::ContLen | Instr::MapLen => Some("usize"),
+            Instr::ContMakeS(_)
+            | Instr::ContSetS(_)
+            | Instr::ContSet
+            | Instr::ContTail
+            | Instr::ContExt
+            | Instr::MapKeys => Some("container"),
+            Instr::MapNew | Instr::MapSet | Instr::MapDel => Some("map"),
+            _ => None,
+        };
+        stack.extend(std::iter::repeat_n(pushed, pushes));
+
+        // Where paths meet, only the types they agree on are known
+        for succ in obj
+            .successors(offset)
+            .into_iter()
+            .filter(|&s| s < code.len())
+        {
+            let merged = match &kinds[succ] {
+                None => stack.clone(),
+                Some(other) if other.len() != stack.len() => continue,
+                Some(other) => other
+                    .iter()
+                    .zip(&stack)
+                    .map(|(a, b)| a.filter(|a| Some(*a) == *b))
+                    .collect(),
+            };
+            if kinds[succ].as_ref() != Some(&merged) {
+                kinds[succ] = Some(merged);
+                worklist.push(succ);
+            }
+        }
+    }
+
+    kinds
+}
+
+/// An upper bound on the operand stack depth of `obj`, or `None` if there isn't
+/// one, e.g. if a loop pushes a call result on every iteration. Assumes `obj` has
+/// been verified.
+pub fn max_stack_depth(obj: &CodeObject) -> Option<usize> {
+    let code = &obj.code;
+    let mut bounds: Vec<Option<usize>> = vec![None; code.len()];
+    if let Some(first) = bounds.first_mut() {
+        *first = Some(0);
+    }
+
+    // Bellman-Ford: without a cycle that keeps growing the stack, the bounds settle
+    // within one pass per instruction
+    for _ in 0..=code.len() {
+        let mut changed = false;
+
+        for offset in 0..code.len() {
+            let Some(before) = bounds[offset] else {
+                continue;
+            };
+            let after = bound_after(obj, &code[offset], before);
+
+            for succ in obj
+                .successors(offset)
+                .into_iter()
+                .filter(|&s| s < code.len())
+            {
+                if bounds[succ].is_none_or(|b| b < after) {
+                    bounds[succ] = Some(after);
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed {
+            return Some(
+                bounds
+                    .iter()
+                    .zip(code.iter())
+                    .filter_map(|(b, instr)| b.map(|b| b.max(bound_after(obj, instr, b))))
+                    .max()
+                    .unwrap_or(0),
+            );
+        }
+    }
+
+    None
+}
+
+/// Upper bound on the stack depth after `instr`, given a bound before it
+fn bound_after(obj: &CodeObject, instr: &Instr, before: usize) -> usize {
+    match (instr, instr.stack_effect()) {
+        (_, Some((pops, pushes))) => before.saturating_sub(pops) + pushes,
+        // Pops at least the function, pushes at most the return value
+        (Instr::CallSelf, None) => before.saturating_sub(obj.argcount) + 1,
+        // Calls pop at least the function hash, and ContMake at least the element
+        // count, and both push at most one value
+        (_, None) => before,
+    }
+}
+
+impl VerifyError {
+    /// The offset of the instruction the error is at, if it is about one
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            VerifyError::WrongReturn { offset, .. }
+            | VerifyError::LitOutOfBounds { offset, .. }
+            | VerifyError::ArgOutOfBounds { offset, .. }
+            | VerifyError::LocalOutOfBounds { offset, .. }
+            | VerifyError::LabelOutOfBounds { offset, .. }
+            | VerifyError::RelativeJumpOutOfBounds { offset, .. }
+            | VerifyError::StackUnderflow { offset }
+            | VerifyError::InconsistentStack { offset, .. } => Some(offset),
+            VerifyError::TooFewLocalNames { .. }
+            | VerifyError::SignatureArity { .. }
+            | VerifyError::JumpOutOfBounds { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "verification failed: ")?;
+        match self {
+            VerifyError::TooFewLocalNames { argcount, names } => {
+                write!(f, "{argcount} arguments but only {names} local names")
+            }
+            VerifyError::WrongReturn {
+                offset,
+                is_void: true,
+            } => {
+                write!(f, "void function returns a value at offset {offset}")
+            }
+            VerifyError::WrongReturn {
+                offset,
+                is_void: false,
+            } => {
+                write!(
+                    f,
+                    "non-void function returns without a value at offset {offset}"
+                )
+            }
+            VerifyError::SignatureArity { argcount, params } => {
+                write!(f, "{argcount} arguments but the signature has {params}")
+            }
+            VerifyError::LitOutOfBounds { offset, index } => {
+                write!(f, "literal index {index} out of bounds at offset {offset}")
+            }
+            VerifyError::ArgOutOfBounds { offset, index } => {
+                write!(f, "argument index {index} out of bounds at offset {offset}")
+            }
+            VerifyError::LocalOutOfBounds { offset, index } => {
+                write!(f, "local index {index} out of bounds at offset {offset}")
+            }
+            VerifyError::LabelOutOfBounds { offset, label } => {
+                write!(f, "label {label} does not exist at offset {offset}")
+            }
+            VerifyError::JumpOutOfBounds { label, target } => {
+                write!(
+                    f,
+                    "label {label} points past the end of the code ({target})"
+                )
+            }
+            VerifyError::RelativeJumpOutOfBounds { offset, delta } => {
+                write!(
+                    f,
+                    "relative jump by {delta} out of bounds at offset {offset}"
+                )
+            }
+            VerifyError::StackUnderflow { offset } => {
+                write!(f, "stack underflow at offset {offset}")
+            }
+            VerifyError::InconsistentStack {
+                offset,
+                depths: (a, b),
+            } => write!(
+                f,
+                "inconsistent stack depth at offset {offset}: reached with {a} and {b}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::BinOp;
+    use crate::vm::tests::init_code_obj;
+
+    #[test]
+    fn test_valid() {
+        let obj = init_code_obj(bytecode![
+            Instr::LoadArg(1),
+            Instr::LoadLit(1),
+            Instr::StoreLocal(0),
+            Instr::LoadLocal(0),
+            Instr::BinOp(BinOp::Add),
+            Instr::ReturnVal
+        ]);
+        assert_eq!(verify(&obj), Ok(()));
+    }
+
+    #[test]
+    fn test_indices() {
+        let obj = init_code_obj(bytecode![
+            Instr::LoadLit(2),
+            Instr::LoadArg(2),
+            Instr::LoadLocal(1),
+            Instr::Jump(0)
+        ]);
+        assert_eq!(
+            diagnose(&obj),
+            vec![
+                VerifyError::LitOutOfBounds {
+                    offset: 0,
+                    index: 2
+                },
+                VerifyError::ArgOutOfBounds {
+                    offset: 1,
+                    index: 2
+                },
+                VerifyError::LocalOutOfBounds {
+                    offset: 2,
+                    index: 1
+                },
+                VerifyError::LabelOutOfBounds {
+                    offset: 3,
+                    label: 0
+                },
+            ]
+        );
+
+        let mut obj = init_code_obj(bytecode![Instr::Jump(0)]);
+        obj.labels.push(5);
+        assert_eq!(
+            verify(&obj),
+            Err(VerifyError::JumpOutOfBounds {
+                label: 0,
+                target: 5
+            })
+        );
+
+        let obj = init_code_obj(bytecode![Instr::JumpRel(1), Instr::JumpRel(-2)]);
+        assert_eq!(
+            diagnose(&obj),
+            vec![VerifyError::RelativeJumpOutOfBounds {
+                offset: 1,
+                delta: -2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_returns() {
+        let mut obj = init_code_obj(bytecode![
+            Instr::LoadArg(0),
+            Instr::JumpT(0),
+            Instr::Return,
+            Instr::LoadArg(0),
+            Instr::ReturnVal
+        ]);
+        obj.labels.push(3);
+        assert_eq!(
+            verify(&obj),
+            Err(VerifyError::WrongReturn {
+                offset: 2,
+                is_void: false
+            })
+        );
+
+        obj.is_void = true;
+        assert_eq!(
+            verify(&obj),
+            Err(VerifyError::WrongReturn {
+                offset: 4,
+                is_void: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack() {
+        let obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::BinOp(BinOp::Add)]);
+        assert_eq!(verify(&obj), Err(VerifyError::StackUnderflow { offset: 1 }));
+
+        let obj = init_code_obj(bytecode![Instr::ReturnVal]);
+        assert_eq!(verify(&obj), Err(VerifyError::StackUnderflow { offset: 0 }));
+
+        // A loop that pushes a value on every iteration
+        let mut obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::Jump(0)]);
+        obj.labels.push(0);
+        assert_eq!(
+            verify(&obj),
+            Err(VerifyError::InconsistentStack {
+                offset: 0,
+                depths: (0, 1)
+            })
+        );
+
+        // Calls make the depth unknown rather than failing
+        let obj = init_code_obj(bytecode![
+            Instr::LoadDyn("f".to_string()),
+            Instr::Call,
+            Instr::Pop,
+            Instr::Pop
+        ]);
+        assert_eq!(verify(&obj), Ok(()));
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let obj = init_code_obj(bytecode![
+            Instr::LoadLit(0),
+            Instr::LoadLit(0),
+            Instr::LoadLit(0),
+            Instr::BinOp(BinOp::Add),
+            Instr::BinOp(BinOp::Add),
+            Instr::ReturnVal
+        ]);
+        assert_eq!(max_stack_depth(&obj), Some(3));
+
+        let obj = init_code_obj(bytecode![
+            Instr::LoadArg(0),
+            Instr::LoadArg(1),
+            Instr::CallSelf,
+            Instr::Return
+        ]);
+        assert_eq!(max_stack_depth(&obj), Some(2));
+
+        // Every iteration may leave a call result on the stack
+        let mut obj = init_code_obj(bytecode![
+            Instr::LoadDyn("f".to_string()),
+            Instr::Call,
+            Instr::Jump(0)
+        ]);
+        obj.labels.push(0);
+        assert_eq!(max_stack_depth(&obj), None);
+    }
+
+    #[test]
+    fn test_stack_kinds() {
+        let mut obj = init_code_obj(bytecode![
+            Instr::LoadLit(0),
+            Instr::LoadArg(0),
+            Instr::JumpT(0),
+            Instr::LoadLit(1),
+            Instr::Jump(1),
+            Instr::LoadLit(0),
+            Instr::Dup,
+            Instr::Cmp,
+            Instr::LoadDyn("f".to_string()),
+            Instr::Call,
+            Instr::ReturnVal
+        ]);
+        obj.labels.extend([5, 6]);
+        assert_eq!(verify(&obj), Ok(()));
+
+        // The branches push different types, so the merged top is unknown
+        let kinds = stack_kinds(&obj);
+        assert_eq!(kinds[2], Some(vec![Some("i32"), None]));
+        assert_eq!(kinds[4], Some(vec![Some("i32"), Some("string")]));
+        assert_eq!(kinds[7], Some(vec![Some("i32"), None, None]));
+        assert_eq!(kinds[8], Some(vec![Some("i32"), Some("i32")]));
+        assert_eq!(kinds[9], Some(vec![Some("i32"), Some("i32"), Some("hash")]));
+        assert_eq!(kinds[10], None);
+
+        // Arguments have the types in the signature
+        obj.signature = Some("(bool, any) -> any".parse().unwrap());
+        assert_eq!(stack_kinds(&obj)[2], Some(vec![Some("i32"), Some("bool")]));
+    }
+}
@@ -0,0 +1,210 @@
+//! Conversions between `Value` and Rust primitives, for host code embedding the VM
+
+use std::fmt;
+
+use num_traits::ToPrimitive;
+
+use super::Value;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The value is not of the requested type
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The value is an integer, but does not fit in the requested type
+    OutOfRange { target: &'static str, value: String },
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::WrongType { expected, found } => {
+                write!(
+                    f,
+                    "cannot convert value: expected {expected}, found {found}"
+                )
+            }
+            ConversionError::OutOfRange { target, value } => {
+                write!(f, "cannot convert value: {value} does not fit in {target}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+fn wrong_type(expected: &'static str, value: &Value) -> ConversionError {
+    ConversionError::WrongType {
+        expected,
+        found: value.type_name(),
+    }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let int = value
+            .as_bigint()
+            .ok_or_else(|| wrong_type("an integer", &value))?;
+        int.to_i64().ok_or(ConversionError::OutOfRange {
+            target: "i64",
+            value: int.to_string(),
+        })
+    }
+}
+
+impl TryFrom<Value> for u64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let int = value
+            .as_bigint()
+            .ok_or_else(|| wrong_type("an integer", &value))?;
+        int.to_u64().ok_or(ConversionError::OutOfRange {
+            target: "u64",
+            value: int.to_string(),
+        })
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::F32(f) => Ok(f as f64),
+            Value::F64(f) => Ok(f),
+            v => Err(wrong_type("a float", &v)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::String(s) => Ok(s),
+            v => Err(wrong_type("string", &v)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            v => Err(wrong_type("bool", &v)),
+        }
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = ConversionError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Container(c) => Ok(c),
+            v => Err(wrong_type("container", &v)),
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(i: i32) -> Self {
+        Value::I32(i)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::I64(i)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(u: u64) -> Self {
+        Value::U64(u)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::F64(f)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(c: Vec<Value>) -> Self {
+        Value::Container(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(i64::try_from(Value::U8(7)), Ok(7));
+        assert_eq!(i64::try_from(Value::I128(-3)), Ok(-3));
+        assert_eq!(u64::try_from(Value::Usize(3)), Ok(3));
+        assert_eq!(f64::try_from(Value::F32(0.5)), Ok(0.5));
+        assert_eq!(String::try_from(Value::string("s")), Ok("s".to_string()));
+        assert_eq!(bool::try_from(Value::Bool(true)), Ok(true));
+        assert_eq!(
+            Vec::<Value>::try_from(Value::Container(vec![Value::I32(1)])),
+            Ok(vec![Value::I32(1)])
+        );
+
+        assert_eq!(
+            i64::try_from(Value::string("5")),
+            Err(ConversionError::WrongType {
+                expected: "an integer",
+                found: "string"
+            })
+        );
+        assert_eq!(
+            u64::try_from(Value::I32(-1)),
+            Err(ConversionError::OutOfRange {
+                target: "u64",
+                value: "-1".to_string()
+            })
+        );
+        assert!(i64::try_from(Value::U128(u128::MAX)).is_err());
+        assert!(bool::try_from(Value::I32(1)).is_err());
+    }
+
+    #[test]
+    fn test_from() {
+        assert_eq!(Value::from(5i64), Value::I64(5));
+        assert_eq!(Value::from("x"), Value::string("x"));
+        assert_eq!(
+            Value::from(vec![true.into()]),
+            Value::Container(vec![Value::Bool(true)])
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+/// Maps instruction offsets back to the assembly source they came from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    /// The source file, if the code was assembled from one
+    pub file: Option<String>,
+    /// The 1-based source line of each instruction, indexed by offset
+    pub lines: Vec<usize>,
+}
+
+impl DebugInfo {
+    /// The source line of the instruction at `offset`
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.lines.get(offset).copied()
+    }
+
+    /// A `file:line` location for the instruction at `offset`, for error messages
+    pub fn location(&self, offset: usize) -> Option<String> {
+        let line = self.line(offset)?;
+        Some(match &self.file {
+            Some(file) => format!("{file}:{line}"),
+            None => format!("line {line}"),
+        })
+    }
+}
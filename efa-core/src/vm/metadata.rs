@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// Descriptive metadata about a code object, e.g. from source annotations. It is
+/// not part of the code object, so it does not change the hash.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// What the function does
+    pub doc: Option<String>,
+    pub author: Option<String>,
+    /// The signature as written in the source
+    pub signature: Option<String>,
+    pub tags: Vec<String>,
+    /// The file the function was assembled from
+    pub source: Option<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+}
@@ -1,31 +1,85 @@
-use std::cmp::{Ord, Ordering};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::{Add, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub};
 use std::path::Path;
 
 use anyhow::{anyhow, bail, Result};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha512};
 
 use crate::bytecode::{BinOp, Bytecode, Instr, UnaryOp};
-use crate::db::Database;
-use crate::{hash_from_vec, Hash, HASH_SIZE};
+use crate::db::{Database, SignaturePolicy, DEFAULT_ENTRY_POINT};
+use crate::Hash;
+
+mod convert;
+mod debug_info;
+mod metadata;
+mod observe;
+mod profile;
+mod signature;
+mod snapshot;
+mod trace;
+mod typedef;
+
+pub use convert::ConversionError;
+pub use debug_info::DebugInfo;
+pub use metadata::Metadata;
+pub use observe::Observer;
+use profile::Profiler;
+pub use profile::RunProfile;
+pub use signature::{Signature, TypeTag};
+pub use trace::{TraceOptions, Tracer};
+pub use typedef::{FieldType, TypeDef};
+
+/// Default for `Vm::set_data_stack_cap`
+pub const DEFAULT_DATA_STACK_CAP: usize = 1 << 16;
+
+/// The most bits a big integer can be shifted by
+pub const MAX_BIGINT_SHIFT: usize = 1 << 16;
 
 #[derive(Debug)]
 pub struct Vm {
     call_stack: Vec<StackFrame>,
     pub db: Database, // TODO: should not be pub
+    data_stack_cap: usize,
+    signature_policy: SignaturePolicy,
+    /// Set while profiling
+    profiler: Option<Profiler>,
+    observer: Option<Box<dyn Observer>>,
+    dbg_output: DbgOutput,
+}
+
+/// Where `dbg` and `dbg_msg` write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbgOutput {
+    Stdout,
+    /// E.g. to keep stdout for a caller's own output
+    Stderr,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodeObject {
     pub(crate) litpool: Vec<Value>,
     pub(crate) argcount: usize,
+    /// Void functions return with `Return`, others with `ReturnVal`
+    pub(crate) is_void: bool,
     // TODO: change to be num_locals? then the stack frame locals could be vec<value>
     // Worse debuggability
     pub(crate) localnames: Vec<String>,
     /// Map from label index to an offset in the bytecode
     pub(crate) labels: Vec<usize>,
+    /// Upper bound on the operand stack depth, if known. Computed by the assembler.
+    #[serde(default)]
+    pub(crate) max_stack_depth: Option<usize>,
+    /// Declared parameter and return types, checked by `Call` if present
+    #[serde(default)]
+    pub(crate) signature: Option<Signature>,
+    /// Source locations of the instructions. Not part of the hash, so the same code
+    /// assembled from different places is the same object.
+    #[serde(skip)]
+    pub(crate) debug_info: Option<DebugInfo>,
 
     pub(crate) code: Bytecode,
 }
@@ -44,6 +98,59 @@ struct StackFrame {
     // maybe add some debug info like a name
 }
 
+impl StackFrame {
+    /// Set up a frame to run `code_obj`, preallocating its operand stack
+    fn new(
+        code_obj: CodeObject,
+        locals: HashMap<String, Value>,
+        data_stack_cap: usize,
+    ) -> Result<StackFrame> {
+        let depth = code_obj.max_stack_depth.unwrap_or(0);
+        if depth > data_stack_cap {
+            bail!(
+                "cannot call function: needs a stack depth of {depth}, but the cap is {data_stack_cap}"
+            );
+        }
+
+        Ok(StackFrame {
+            code_obj,
+            stack: Vec::with_capacity(depth),
+            locals,
+            instruction: 0,
+        })
+    }
+}
+
+/// Check call arguments against the callee's signature, if it has one, and turn
+/// them into the callee's locals
+fn check_params(
+    code_obj: &CodeObject,
+    params: Vec<(String, Value)>,
+) -> Result<HashMap<String, Value>> {
+    if let Some(signature) = &code_obj.signature {
+        signature
+            .check_args(params.iter().map(|(_, arg)| arg))
+            .map_err(|e| anyhow!("cannot call function: {e}"))?;
+    }
+    Ok(params.into_iter().collect())
+}
+
+/// The exit code of a main function that returned `returned`
+pub fn exit_code(returned: Option<Value>) -> Result<i32> {
+    match returned {
+        Some(Value::I32(code)) => Ok(code),
+        Some(_) => bail!("main function can only return integers"),
+        None => Ok(0),
+    }
+}
+
+/// The target of a relative jump by `delta` from the instruction at `offset`
+fn relative_target(offset: usize, delta: isize) -> Result<usize> {
+    offset.checked_add_signed(delta).ok_or_else(|| {
+        anyhow!("relative jump by {delta} from offset {offset} is out of bounds")
+    })
+}
+
 /// A value that can be on the stack.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Value {
@@ -59,6 +166,7 @@ pub enum Value {
     U128(u128),
     Isize(isize),
     Usize(usize),
+    BigInt(BigInt),
 
     F32(f32),
     F64(f64),
@@ -70,6 +178,8 @@ pub enum Value {
     String(String), // TODO: make a borrowed version?
 
     Container(Vec<Value>),
+    /// Key-value pairs, kept in insertion order
+    Map(Vec<(Value, Value)>),
 }
 
 impl Value {
@@ -82,8 +192,7 @@ impl Value {
     }
 
     pub fn hash(hash: Vec<u8>) -> Result<Value> {
-        let trunc = hash_from_vec(hash)?;
-        Ok(Value::Hash(trunc))
+        Ok(Value::Hash(Hash::from_slice(&hash)?))
     }
 
     pub fn as_int(&self) -> Option<i64> {
@@ -100,9 +209,120 @@ impl Value {
             Value::U128(i) => Some(*i as i64),
             Value::Isize(i) => Some(*i as i64),
             Value::Usize(i) => Some(*i as i64),
+            Value::BigInt(i) => i.to_i64(),
+            _ => None,
+        }
+    }
+
+    /// Losslessly convert any integer value to a `BigInt`
+    pub fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::I8(i) => Some(BigInt::from(*i)),
+            Value::U8(i) => Some(BigInt::from(*i)),
+            Value::I16(i) => Some(BigInt::from(*i)),
+            Value::U16(i) => Some(BigInt::from(*i)),
+            Value::I32(i) => Some(BigInt::from(*i)),
+            Value::U32(i) => Some(BigInt::from(*i)),
+            Value::I64(i) => Some(BigInt::from(*i)),
+            Value::U64(i) => Some(BigInt::from(*i)),
+            Value::I128(i) => Some(BigInt::from(*i)),
+            Value::U128(i) => Some(BigInt::from(*i)),
+            Value::Isize(i) => Some(BigInt::from(*i)),
+            Value::Usize(i) => Some(BigInt::from(*i)),
+            Value::BigInt(i) => Some(i.clone()),
             _ => None,
         }
     }
+
+    /// The name of the value's type, as used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::I8(_) => "i8",
+            Value::U8(_) => "u8",
+            Value::I16(_) => "i16",
+            Value::U16(_) => "u16",
+            Value::I32(_) => "i32",
+            Value::U32(_) => "u32",
+            Value::I64(_) => "i64",
+            Value::U64(_) => "u64",
+            Value::I128(_) => "i128",
+            Value::U128(_) => "u128",
+            Value::Isize(_) => "isize",
+            Value::Usize(_) => "usize",
+            Value::BigInt(_) => "bigint",
+            Value::F32(_) => "f32",
+            Value::F64(_) => "f64",
+            Value::Char(_) => "char",
+            Value::Bool(_) => "bool",
+            Value::Hash(_) => "hash",
+            Value::String(_) => "string",
+            Value::Container(_) => "container",
+            Value::Map(_) => "map",
+        }
+    }
+
+    /// Render the value as it should be shown to a user, e.g. as program output
+    pub fn to_display_string(&self) -> String {
+        self.to_string()
+    }
+
+    /// Format a value nested inside a container or map, quoting strings and chars so
+    /// that element boundaries stay readable
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{s:?}"),
+            Value::Char(c) => write!(f, "{c:?}"),
+            v => write!(f, "{v}"),
+        }
+    }
+
+    fn is_bigint(&self) -> bool {
+        matches!(self, Value::BigInt(_))
+    }
+
+    /// This value as the amount to shift a big integer by
+    fn shift_amount(&self) -> Result<usize> {
+        let amount = self
+            .as_bigint()
+            .ok_or_else(|| anyhow!("cannot shift by a {}", self.type_name()))?;
+        match amount.to_usize() {
+            Some(n) if n <= MAX_BIGINT_SHIFT => Ok(n),
+            _ => bail!("shift amount {amount} is not between 0 and {MAX_BIGINT_SHIFT}"),
+        }
+    }
+
+    /// Shift left, promoting to a big integer if either side is one. Fails instead of
+    /// panicking on a bad big shift amount.
+    pub fn checked_shl(self, other: Self) -> Result<Self> {
+        if !self.is_bigint() && !other.is_bigint() {
+            return Ok(self << other);
+        }
+        match self.as_bigint() {
+            Some(x) => Ok(Value::BigInt(x << other.shift_amount()?)),
+            None => bail!("cannot shift a {} left", self.type_name()),
+        }
+    }
+
+    /// Shift right, like `checked_shl`
+    pub fn checked_shr(self, other: Self) -> Result<Self> {
+        if !self.is_bigint() && !other.is_bigint() {
+            return Ok(self >> other);
+        }
+        match self.as_bigint() {
+            Some(x) => Ok(Value::BigInt(x >> other.shift_amount()?)),
+            None => bail!("cannot shift a {} right", self.type_name()),
+        }
+    }
+
+    /// Equality by value, as `partial_compare` sees it: integers of any width are
+    /// equal when numerically equal, -0.0 equals 0.0, and NaN equals nothing.
+    /// Values that cannot be compared, like maps, fall back to structural equality.
+    pub fn equals(&self, other: &Value) -> bool {
+        match self.partial_compare(other) {
+            Ok(ord) => ord == Some(Ordering::Equal),
+            Err(_) => self == other,
+        }
+    }
 }
 
 impl Vm {
@@ -111,6 +331,11 @@ impl Vm {
         Ok(Vm {
             call_stack: Vec::new(),
             db: Database::temp()?,
+            data_stack_cap: DEFAULT_DATA_STACK_CAP,
+            signature_policy: SignaturePolicy::default(),
+            profiler: None,
+            observer: None,
+            dbg_output: DbgOutput::Stdout,
         })
     }
 
@@ -119,6 +344,11 @@ impl Vm {
         Ok(Vm {
             call_stack: Vec::new(),
             db: Database::open(path)?,
+            data_stack_cap: DEFAULT_DATA_STACK_CAP,
+            signature_policy: SignaturePolicy::default(),
+            profiler: None,
+            observer: None,
+            dbg_output: DbgOutput::Stdout,
         })
     }
 
@@ -127,27 +357,191 @@ impl Vm {
         Ok(Vm {
             call_stack: Vec::new(),
             db: Database::new(path)?,
+            data_stack_cap: DEFAULT_DATA_STACK_CAP,
+            signature_policy: SignaturePolicy::default(),
+            profiler: None,
+            observer: None,
+            dbg_output: DbgOutput::Stdout,
+        })
+    }
+
+    /// A VM backed by the existing database at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Vm> {
+        Ok(Vm {
+            call_stack: Vec::new(),
+            db: Database::open(path)?,
+            data_stack_cap: DEFAULT_DATA_STACK_CAP,
+            signature_policy: SignaturePolicy::default(),
+            profiler: None,
+            observer: None,
+            dbg_output: DbgOutput::Stdout,
         })
     }
 
-    /// Return exit code
+    /// A VM backed by an already open database
+    pub fn from_database(db: Database) -> Vm {
+        Vm {
+            call_stack: Vec::new(),
+            db,
+            data_stack_cap: DEFAULT_DATA_STACK_CAP,
+            signature_policy: SignaturePolicy::default(),
+            profiler: None,
+            observer: None,
+            dbg_output: DbgOutput::Stdout,
+        }
+    }
+
+    /// Limit the operand stack of each frame. Code objects that declare a larger
+    /// `max_stack_depth` are rejected when called, and a frame whose stack grows past
+    /// the cap fails, whether or not its code object declares a depth.
+    pub fn set_data_stack_cap(&mut self, cap: usize) {
+        self.data_stack_cap = cap;
+    }
+
+    /// Only run code objects, including the main function, that `policy` allows
+    pub fn set_signature_policy(&mut self, policy: SignaturePolicy) {
+        self.signature_policy = policy;
+    }
+
+    /// Count the calls, instructions and time of each code object that runs, and
+    /// add them to the database's profiles after each run
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiler = profiling.then(Profiler::default);
+    }
+
+    /// What was profiled in the last run, if profiling
+    pub fn last_profile(&self) -> Option<&RunProfile> {
+        self.profiler.as_ref()?.last()
+    }
+
+    /// Write what `dbg` and `dbg_msg` print to `output`
+    pub fn set_dbg_output(&mut self, output: DbgOutput) {
+        self.dbg_output = output;
+    }
+
+    /// Tell `observer` about each step of the runs that follow
+    pub fn set_observer(&mut self, observer: impl Observer + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Stop telling the observer about runs, returning it
+    pub fn take_observer(&mut self) -> Option<Box<dyn Observer>> {
+        self.observer.take()
+    }
+
+    /// Run the default entry point, returning its exit code
     /// TODO: does not handle locals yet
     pub fn run_main_function(&mut self) -> Result<i32> {
-        let (_, code_obj) = self.db.get_main_object()?;
+        self.run_entry_point(DEFAULT_ENTRY_POINT)
+    }
 
-        let main = StackFrame {
-            code_obj,
-            stack: Vec::new(),
-            locals: HashMap::new(),
-            instruction: 0,
-        };
-        self.call_stack.push(main);
-        self.exec(false)
+    /// Run the entry point with this name, returning its exit code
+    pub fn run_entry_point(&mut self, entry: &str) -> Result<i32> {
+        let (hash, code_obj) = self.db.get_named_entry_point(entry)?;
+        let returned = self.call(hash, code_obj, vec![])?;
+        exit_code(returned)
+    }
+
+    /// Run the entry point or, if there is none with this name, the function named
+    /// `name`, passing it `args`. Returns what it returned, if anything.
+    pub fn run_function(
+        &mut self,
+        name: &str,
+        args: Vec<Value>,
+    ) -> Result<Option<Value>> {
+        let (hash, code_obj) = self
+            .db
+            .get_named_entry_point(name)
+            .or_else(|_| self.db.get_code_object_by_name(name))?;
+        self.call_with_args(&format!("'{name}'"), hash, code_obj, args)
+    }
+
+    /// Run the stored code object with this hash, passing it `args`. Returns what
+    /// it returned, if anything.
+    pub fn run_code_object(
+        &mut self,
+        hash: &Hash,
+        args: Vec<Value>,
+    ) -> Result<Option<Value>> {
+        let code_obj = self.db.get_code_object(hash)?;
+        self.call_with_args(&hash.to_string(), *hash, code_obj, args)
+    }
+
+    /// Check that `args` fit the code object `what`, then run it
+    fn call_with_args(
+        &mut self,
+        what: &str,
+        hash: Hash,
+        code_obj: CodeObject,
+        args: Vec<Value>,
+    ) -> Result<Option<Value>> {
+        if args.len() != code_obj.argcount {
+            bail!(
+                "cannot call {what}: it takes {} arguments but {} were given",
+                code_obj.argcount,
+                args.len()
+            );
+        }
+        self.call(hash, code_obj, args)
+    }
+
+    /// Run a code object with `args` as its arguments, in order
+    fn call(
+        &mut self,
+        hash: Hash,
+        code_obj: CodeObject,
+        args: Vec<Value>,
+    ) -> Result<Option<Value>> {
+        self.db.check_signatures(&hash, &self.signature_policy)?;
+
+        let params = code_obj.localnames.iter().cloned().zip(args).collect();
+        let params = check_params(&code_obj, params)?;
+        let frame = StackFrame::new(code_obj, params, self.data_stack_cap)?;
+        // Drop what is left of a previous run, e.g. one that failed
+        self.call_stack.clear();
+        self.call_stack.push(frame);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(hash);
+        }
+        if let Some(observer) = &mut self.observer {
+            observer.enter(&hash);
+        }
+        self.run()
     }
 
-    /// With debug=true, the final frame will stay on the call stack.
-    fn exec(&mut self, debug: bool) -> Result<i32> {
-        let mut status_code = 0;
+    /// Run the call stack, then record what was profiled, even if the run failed
+    fn run(&mut self) -> Result<Option<Value>> {
+        let returned = self.exec(false);
+        if let Some(profiler) = &mut self.profiler {
+            // The profile is kept in memory either way, so don't fail the run
+            if let Err(e) = self.db.record_profiles(&profiler.finish().profiles) {
+                eprintln!("warning: cannot record profiles: {e}");
+            }
+        }
+        returned
+    }
+
+    /// Run the call stack, returning the value returned by its first frame. With
+    /// debug=true, the final frame will stay on the call stack.
+    fn exec(&mut self, debug: bool) -> Result<Option<Value>> {
+        let mut failed_at = None;
+        let returned = self.exec_instrs(debug, &mut failed_at);
+        if let (Err(e), Some(observer), Some((offset, instr))) =
+            (&returned, &mut self.observer, failed_at)
+        {
+            observer.error(offset, &instr, e);
+        }
+        returned
+    }
+
+    /// The body of `exec`. While an observer is set, `failed_at` is the offset and
+    /// instruction being run, so that it can be told which one failed.
+    fn exec_instrs(
+        &mut self,
+        debug: bool,
+        failed_at: &mut Option<(usize, Instr)>,
+    ) -> Result<Option<Value>> {
+        let mut returned = None;
 
         while !self.call_stack.is_empty() {
             let call_depth = self.call_stack.len();
@@ -159,9 +553,17 @@ impl Vm {
             }
             let instr = frame.code_obj.code[frame.instruction].clone();
             let mut next_instr_ptr = frame.instruction + 1; // Default
+            if self.observer.is_some() {
+                *failed_at = Some((frame.instruction, instr.clone()));
+            }
+            if let Some(profiler) = &mut self.profiler {
+                profiler.step();
+            }
 
             let mut return_value = None;
             let mut next_frame: Option<StackFrame> = None;
+            // The code object of `next_frame`, if an observer needs it
+            let mut entered = None;
             //println!("{instr:?}");
             match instr {
                 Instr::LoadArg(i) => {
@@ -224,9 +626,10 @@ impl Vm {
                     if let Some(Value::Hash(hash)) = stack.pop() {
                         // Find the right code object by looking up the hash in the database
                         let code_obj = self.db.get_code_object(&hash)?;
+                        self.db.check_signatures(&hash, &self.signature_policy)?;
 
                         // Set up parameters
-                        let params: Result<_> = code_obj
+                        let params: Result<Vec<_>> = code_obj
                             .localnames
                             .iter()
                             .take(code_obj.argcount)
@@ -236,17 +639,18 @@ impl Vm {
                                 }
                                 Ok((name.to_owned(), stack.pop().unwrap()))
                             }).collect();
+                        let params = check_params(&code_obj, params?)?;
 
                         // println!("argc = {:?}", code_obj.argcount);
                         // println!("params = {:?}", params);
 
                         // Construct a new stackframe
-                        let new_frame = StackFrame {
-                            stack: Vec::new(),
-                            code_obj,
-                            locals: params?,
-                            instruction: 0,
-                        };
+                        let new_frame =
+                            StackFrame::new(code_obj, params, self.data_stack_cap)?;
+                        if let Some(profiler) = &mut self.profiler {
+                            profiler.enter(hash);
+                        }
+                        entered = Some(hash);
 
                         next_frame = Some(new_frame);
                     } else {
@@ -259,7 +663,7 @@ impl Vm {
                     let code_obj = frame.code_obj.clone();
 
                     // Set up parameters
-                    let params: Result<_> = code_obj
+                    let params: Result<Vec<_>> = code_obj
                         .localnames
                         .iter()
                         .take(code_obj.argcount)
@@ -273,29 +677,48 @@ impl Vm {
                             Ok((name.to_owned(), stack.pop().unwrap()))
                         })
                         .collect();
+                    let params = check_params(&code_obj, params?)?;
 
-                    let new_frame = StackFrame {
-                        stack: Vec::new(),
-                        code_obj: frame.code_obj.clone(),
-                        locals: params?,
-                        instruction: 0,
-                    };
+                    let new_frame =
+                        StackFrame::new(code_obj, params, self.data_stack_cap)?;
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.enter_self();
+                    }
+                    if self.observer.is_some() {
+                        entered = Some(frame.code_obj.hash()?);
+                    }
 
                     next_frame = Some(new_frame);
                 }
 
                 Instr::Return => {
+                    if !frame.code_obj.is_void {
+                        bail!("non-void function must return a value with ret_val");
+                    }
                     return_value = Some(None);
                 }
                 Instr::ReturnVal => {
                     // Return value is whatever is on the top of the stack
                     // If we have `return x`, then we (the compiler) LOAD x to push it to the top of the stack
                     // Get the return value from the top of current frame's stack
+                    if frame.code_obj.is_void {
+                        bail!("void function cannot return a value");
+                    }
                     if stack.is_empty() {
                         bail!("non-void function requires a return value on the stack");
-                    } else {
-                        return_value = Some(Some(stack.pop().unwrap()));
                     }
+                    let value = stack.pop().unwrap();
+                    if let Some(ret) =
+                        frame.code_obj.signature.as_ref().and_then(|s| s.ret)
+                    {
+                        if !ret.matches(&value) {
+                            bail!(
+                                "function returned {}, but its signature returns {ret}",
+                                value.type_name()
+                            );
+                        }
+                    }
+                    return_value = Some(Some(value));
                 }
 
                 Instr::Jump(label) => next_instr_ptr = frame.code_obj.labels[label],
@@ -335,7 +758,7 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs == rhs {
+                    if lhs.equals(&rhs) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
@@ -347,7 +770,7 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs != rhs {
+                    if !lhs.equals(&rhs) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
@@ -359,7 +782,7 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs > rhs {
+                    if matches!(lhs.partial_compare(&rhs)?, Some(Ordering::Greater)) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
@@ -371,7 +794,10 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs >= rhs {
+                    if matches!(
+                        lhs.partial_compare(&rhs)?,
+                        Some(Ordering::Greater | Ordering::Equal)
+                    ) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
@@ -383,7 +809,7 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs < rhs {
+                    if matches!(lhs.partial_compare(&rhs)?, Some(Ordering::Less)) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
@@ -395,11 +821,36 @@ impl Vm {
                     let rhs = stack.pop().unwrap();
                     let lhs = stack.pop().unwrap();
 
-                    if lhs <= rhs {
+                    if matches!(
+                        lhs.partial_compare(&rhs)?,
+                        Some(Ordering::Less | Ordering::Equal)
+                    ) {
                         next_instr_ptr = frame.code_obj.labels[label];
                     }
                 }
 
+                Instr::JumpRel(delta) => {
+                    next_instr_ptr = relative_target(frame.instruction, delta)?;
+                }
+                Instr::JumpRelT(delta) => {
+                    if stack.is_empty() {
+                        bail!("cannot perform jump: stack underflow");
+                    }
+
+                    if let Value::Bool(true) = stack.pop().unwrap() {
+                        next_instr_ptr = relative_target(frame.instruction, delta)?;
+                    }
+                }
+                Instr::JumpRelF(delta) => {
+                    if stack.is_empty() {
+                        bail!("cannot perform jump: stack underflow");
+                    }
+
+                    if let Value::Bool(false) = stack.pop().unwrap() {
+                        next_instr_ptr = relative_target(frame.instruction, delta)?;
+                    }
+                }
+
                 Instr::BinOp(op) => {
                     if stack.len() < 2 {
                         bail!("cannot perform binary operation: stack underflow");
@@ -414,13 +865,27 @@ impl Vm {
                         BinOp::Div => stack.push(lhs / rhs),
                         BinOp::Sub => stack.push(lhs - rhs),
                         BinOp::Mod => stack.push(lhs % rhs),
-                        BinOp::Shl => stack.push(lhs << rhs),
-                        BinOp::Shr => stack.push(lhs >> rhs),
+                        BinOp::Shl => stack.push(lhs.checked_shl(rhs)?),
+                        BinOp::Shr => stack.push(lhs.checked_shr(rhs)?),
                         BinOp::And => stack.push(lhs.and(rhs)),
-                        BinOp::Eq => stack.push(Value::Bool(lhs == rhs)),
+                        BinOp::Eq => stack.push(Value::Bool(lhs.equals(&rhs))),
                         BinOp::Or => stack.push(lhs.or(rhs)),
                     }
                 }
+                Instr::Cmp => {
+                    if stack.len() < 2 {
+                        bail!("cannot perform comparison: stack underflow");
+                    }
+
+                    let rhs = stack.pop().unwrap();
+                    let lhs = stack.pop().unwrap();
+
+                    stack.push(Value::I32(match lhs.compare(&rhs)? {
+                        Ordering::Less => -1,
+                        Ordering::Equal => 0,
+                        Ordering::Greater => 1,
+                    }));
+                }
                 Instr::UnaryOp(op) => {
                     if stack.is_empty() {
                         bail!("cannot perform binary operation: stack underflow");
@@ -599,17 +1064,123 @@ impl Vm {
                     }
                 }
 
+                /*
+                 * Map instructions
+                 */
+                Instr::MapNew => {
+                    stack.push(Value::Map(Vec::new()));
+                }
+
+                Instr::MapGet => {
+                    let key = stack.pop().ok_or_else(|| anyhow!("no key on stack"))?;
+                    let map = stack.pop().ok_or_else(|| anyhow!("no map on stack"))?;
+
+                    if let Value::Map(map) = map {
+                        let (_, val) = map
+                            .into_iter()
+                            .find(|(k, _)| *k == key)
+                            .ok_or_else(|| anyhow!("key {key:?} not present in map"))?;
+                        stack.push(val);
+                    } else {
+                        bail!("cannot get: no map on stack");
+                    }
+                }
+
+                Instr::MapSet => {
+                    let key = stack.pop().ok_or_else(|| anyhow!("no key on stack"))?;
+                    let val = stack.pop().ok_or_else(|| anyhow!("no value given"))?;
+                    let map = stack.pop().ok_or_else(|| anyhow!("no map on stack"))?;
+
+                    if let Value::Map(mut map) = map {
+                        match map.iter_mut().find(|(k, _)| *k == key) {
+                            Some(entry) => entry.1 = val,
+                            None => map.push((key, val)),
+                        }
+                        stack.push(Value::Map(map));
+                    } else {
+                        bail!("cannot set: no map on stack");
+                    }
+                }
+
+                Instr::MapDel => {
+                    let key = stack.pop().ok_or_else(|| anyhow!("no key on stack"))?;
+                    let map = stack.pop().ok_or_else(|| anyhow!("no map on stack"))?;
+
+                    if let Value::Map(mut map) = map {
+                        let i =
+                            map.iter().position(|(k, _)| *k == key).ok_or_else(|| {
+                                anyhow!("cannot delete: key {key:?} not present in map")
+                            })?;
+                        map.remove(i);
+                        stack.push(Value::Map(map));
+                    } else {
+                        bail!("cannot delete: no map on stack");
+                    }
+                }
+
+                Instr::MapLen => {
+                    let map = stack.pop().ok_or_else(|| anyhow!("no map on stack"))?;
+
+                    if let Value::Map(map) = map {
+                        stack.push(Value::Usize(map.len()));
+                    } else {
+                        bail!("cannot get length: no map on stack");
+                    }
+                }
+
+                Instr::MapKeys => {
+                    let map = stack.pop().ok_or_else(|| anyhow!("no map on stack"))?;
+
+                    if let Value::Map(map) = map {
+                        let keys = map.into_iter().map(|(k, _)| k).collect();
+                        stack.push(Value::Container(keys));
+                    } else {
+                        bail!("cannot get keys: no map on stack");
+                    }
+                }
+
                 Instr::Dbg => {
                     let tos = stack.last().ok_or_else(|| {
                         anyhow!("stack underflow: cannot 'dbg' with empty stack")
                     })?;
-                    println!("{tos:?} ");
+                    self.dbg_output.write(format_args!("{tos}"));
+                }
+                Instr::DbgMsg(i) => {
+                    let tos = stack.last().ok_or_else(|| {
+                        anyhow!("stack underflow: cannot 'dbg' with empty stack")
+                    })?;
+                    let message = frame.code_obj.litpool.get(i).ok_or_else(|| {
+                        anyhow!("literal {i} is out of bounds for 'dbg'")
+                    })?;
+                    // Functions are named if they are in the database
+                    let hash = frame.code_obj.hash()?;
+                    let function = match self.db.get_name_of_hash(&hash)? {
+                        Some(name) => format!("${name}"),
+                        None => hash.to_string(),
+                    };
+                    self.dbg_output.write(format_args!(
+                        "[{function} @ {}] {message}: {tos}",
+                        frame.instruction
+                    ));
                 }
                 Instr::Nop => {}
 
                 e => unimplemented!("unimplemented instruction: {e}"),
             }
 
+            if frame.stack.len() > self.data_stack_cap {
+                bail!(
+                    "stack overflow: operand stack exceeds the cap of {}",
+                    self.data_stack_cap
+                );
+            }
+
+            if let Some(observer) = &mut self.observer {
+                *failed_at = None;
+                let instr = &frame.code_obj.code[frame.instruction];
+                observer.step(frame.instruction, instr, &frame.stack)?;
+            }
+
             // Update program counter for this frame
             frame.instruction = next_instr_ptr;
 
@@ -617,6 +1188,9 @@ impl Vm {
             if let Some(frame) = next_frame {
                 self.call_stack.push(frame);
             }
+            if let (Some(observer), Some(hash)) = (&mut self.observer, entered) {
+                observer.enter(&hash);
+            }
 
             // Handle a return
             match return_value {
@@ -624,20 +1198,35 @@ impl Vm {
                     // If the main function returns
                     if call_depth == 1 {
                         // Note: this case keeps the main function's frame around
-                        if let Value::I32(code) = val {
-                            status_code = code;
-                        } else {
-                            bail!("main function can only return integers");
-                        }
+                        returned = Some(val);
                         break;
                     }
 
                     self.call_stack.pop();
                     // Push the returning function's return value onto the caller's stack
-                    self.call_stack[call_depth - 2].stack.push(val);
+                    let caller = &mut self.call_stack[call_depth - 2];
+                    if caller.stack.len() >= self.data_stack_cap {
+                        bail!(
+                            "stack overflow: operand stack exceeds the cap of {}",
+                            self.data_stack_cap
+                        );
+                    }
+                    caller.stack.push(val);
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.exit();
+                    }
+                    if let Some(observer) = &mut self.observer {
+                        observer.exit();
+                    }
                 }
                 Some(None) => {
                     self.call_stack.pop();
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.exit();
+                    }
+                    if let Some(observer) = &mut self.observer {
+                        observer.exit();
+                    }
                 }
                 // Instruction was not a return
                 None => {}
@@ -648,55 +1237,150 @@ impl Vm {
             self.call_stack.pop();
         }
 
-        Ok(status_code)
+        Ok(returned)
+    }
+}
+
+impl DbgOutput {
+    fn write(self, line: fmt::Arguments) {
+        match self {
+            DbgOutput::Stdout => println!("{line}"),
+            DbgOutput::Stderr => eprintln!("{line}"),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::I8(i) => write!(f, "{i}"),
+            Value::U8(i) => write!(f, "{i}"),
+            Value::I16(i) => write!(f, "{i}"),
+            Value::U16(i) => write!(f, "{i}"),
+            Value::I32(i) => write!(f, "{i}"),
+            Value::U32(i) => write!(f, "{i}"),
+            Value::I64(i) => write!(f, "{i}"),
+            Value::U64(i) => write!(f, "{i}"),
+            Value::I128(i) => write!(f, "{i}"),
+            Value::U128(i) => write!(f, "{i}"),
+            Value::Isize(i) => write!(f, "{i}"),
+            Value::Usize(i) => write!(f, "{i}"),
+            Value::BigInt(i) => write!(f, "{i}"),
+
+            Value::F32(x) => write!(f, "{x}"),
+            Value::F64(x) => write!(f, "{x}"),
+
+            Value::Char(c) => write!(f, "{c}"),
+            Value::Bool(b) => write!(f, "{b}"),
+
+            Value::Hash(h) => write!(f, "{h}"),
+            Value::String(s) => write!(f, "{s}"),
+
+            Value::Container(c) => {
+                write!(f, "[")?;
+                for (i, v) in c.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    v.fmt_nested(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(m) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    k.fmt_nested(f)?;
+                    write!(f, ": ")?;
+                    v.fmt_nested(f)?;
+                }
+                write!(f, "}}")
+            }
+        }
     }
 }
 
 impl CodeObject {
     pub fn hash(&self) -> Result<Hash> {
         let obj = rmp_serde::to_vec(&self)?;
-        let mut hasher = Sha512::new();
-        hasher.update(obj);
-        (&hasher.finalize().to_vec()[..HASH_SIZE])
-            .try_into()
-            .map_err(|_| anyhow!("failed to hash CodeObject"))
+        Ok(Hash::digest(&obj))
     }
 
     pub fn hash_str(&self) -> Result<String> {
-        let hash = self.hash()?;
-        Ok(format!("0x{}", hex::encode(hash)))
+        Ok(self.hash()?.to_string())
+    }
+
+    /// The offsets that can execute after the instruction at `offset`. May include
+    /// `code.len()` when execution runs off the end.
+    pub(crate) fn successors(&self, offset: usize) -> Vec<usize> {
+        self.code[offset].successors(offset, &self.labels)
+    }
+}
+
+impl Value {
+    /// Order two values, failing instead of panicking when they are not comparable.
+    /// Integers of any width compare by numeric value, and containers compare
+    /// lexicographically. Fails on NaN, which has no place in an ordering.
+    pub fn compare(&self, other: &Value) -> Result<Ordering> {
+        self.partial_compare(other)?
+            .ok_or_else(|| anyhow!("cannot order {self} and {other}: NaN is unordered"))
+    }
+
+    /// Like `compare`, but floats follow IEEE 754: a comparison involving NaN is
+    /// unordered (`None`), -0.0 equals 0.0, and infinities order as usual. F32 and
+    /// F64 compare with each other by widening to F64. Ordered jumps are not taken
+    /// when their operands are unordered.
+    pub fn partial_compare(&self, other: &Value) -> Result<Option<Ordering>> {
+        Ok(Some(match (self, other) {
+            // One-to-one comparisons
+            (Value::I8(x), Value::I8(y)) => x.cmp(y),
+            (Value::U8(x), Value::U8(y)) => x.cmp(y),
+            (Value::I16(x), Value::I16(y)) => x.cmp(y),
+            (Value::U16(x), Value::U16(y)) => x.cmp(y),
+            (Value::I32(x), Value::I32(y)) => x.cmp(y),
+            (Value::U32(x), Value::U32(y)) => x.cmp(y),
+            (Value::I64(x), Value::I64(y)) => x.cmp(y),
+            (Value::U64(x), Value::U64(y)) => x.cmp(y),
+            (Value::I128(x), Value::I128(y)) => x.cmp(y),
+            (Value::U128(x), Value::U128(y)) => x.cmp(y),
+            (Value::Isize(x), Value::Isize(y)) => x.cmp(y),
+            (Value::Usize(x), Value::Usize(y)) => x.cmp(y),
+            (Value::BigInt(x), Value::BigInt(y)) => x.cmp(y),
+            (Value::Char(x), Value::Char(y)) => x.cmp(y),
+            (Value::Bool(x), Value::Bool(y)) => x.cmp(y),
+            (Value::Hash(x), Value::Hash(y)) => x.cmp(y),
+            (Value::String(x), Value::String(y)) => x.cmp(y),
+
+            // Floats
+            (Value::F32(x), Value::F32(y)) => return Ok(x.partial_cmp(y)),
+            (Value::F64(x), Value::F64(y)) => return Ok(x.partial_cmp(y)),
+            (Value::F32(x), Value::F64(y)) => return Ok((*x as f64).partial_cmp(y)),
+            (Value::F64(x), Value::F32(y)) => return Ok(x.partial_cmp(&(*y as f64))),
+
+            (Value::Container(x), Value::Container(y)) => {
+                for (a, b) in x.iter().zip(y) {
+                    match a.partial_compare(b)? {
+                        Some(Ordering::Equal) => continue,
+                        ord => return Ok(ord),
+                    }
+                }
+                x.len().cmp(&y.len())
+            }
+
+            // Int-to-int comparisons across widths
+            (x, y) => match (x.as_bigint(), y.as_bigint()) {
+                (Some(x), Some(y)) => x.cmp(&y),
+                _ => bail!("cannot compare {} with {}", x.type_name(), y.type_name()),
+            },
+        }))
     }
 }
 
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self, other) {
-            // One-to-one comparisons
-            (Value::I8(x), Value::I8(y)) => Some(x.cmp(y)),
-            (Value::U8(x), Value::U8(y)) => Some(x.cmp(y)),
-            (Value::I16(x), Value::I16(y)) => Some(x.cmp(y)),
-            (Value::U16(x), Value::U16(y)) => Some(x.cmp(y)),
-            (Value::I32(x), Value::I32(y)) => Some(x.cmp(y)),
-            (Value::U32(x), Value::U32(y)) => Some(x.cmp(y)),
-            (Value::I64(x), Value::I64(y)) => Some(x.cmp(y)),
-            (Value::U64(x), Value::U64(y)) => Some(x.cmp(y)),
-            (Value::I128(x), Value::I128(y)) => Some(x.cmp(y)),
-            (Value::U128(x), Value::U128(y)) => Some(x.cmp(y)),
-            (Value::Isize(x), Value::Isize(y)) => Some(x.cmp(y)),
-            (Value::Usize(x), Value::Usize(y)) => Some(x.cmp(y)),
-            (Value::Char(x), Value::Char(y)) => Some(x.cmp(y)),
-            (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
-            (Value::Hash(x), Value::Hash(y)) => Some(x.cmp(y)),
-            (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
-
-            // Int-to-int comparisons
-
-            // TODO: Safer casts
-            (Value::Usize(x), Value::I32(y)) => Some(x.cmp(&(*y as usize))),
-            (Value::I32(x), Value::Usize(y)) => Some(x.cmp(&(*y as i32))),
-
-            e => panic!("cannot compare {e:?}"),
-        }
+        self.partial_compare(other).ok().flatten()
     }
 }
 
@@ -728,6 +1412,14 @@ impl Add for Value {
             // Strings
             (Value::String(x), Value::String(y)) => Value::String(x + &y),
 
+            // Big integers, promoting the other operand if it is an integer
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                match (x.as_bigint(), y.as_bigint()) {
+                    (Some(x), Some(y)) => Value::BigInt(x + y),
+                    _ => panic!("cannot add values of different types"),
+                }
+            }
+
             _ => panic!("cannot add values of different types"),
         }
     }
@@ -758,6 +1450,14 @@ impl Sub for Value {
             (Value::F32(x), Value::F32(y)) => Value::F32(x - y),
             (Value::F64(x), Value::F64(y)) => Value::F64(x - y),
 
+            // Big integers, promoting the other operand if it is an integer
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                match (x.as_bigint(), y.as_bigint()) {
+                    (Some(x), Some(y)) => Value::BigInt(x - y),
+                    _ => panic!("cannot subtract values of different types"),
+                }
+            }
+
             _ => panic!("cannot subtract values of different types"),
         }
     }
@@ -788,6 +1488,14 @@ impl Mul for Value {
             (Value::F32(x), Value::F32(y)) => Value::F32(x * y),
             (Value::F64(x), Value::F64(y)) => Value::F64(x * y),
 
+            // Big integers, promoting the other operand if it is an integer
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                match (x.as_bigint(), y.as_bigint()) {
+                    (Some(x), Some(y)) => Value::BigInt(x * y),
+                    _ => panic!("cannot multiply values of different types"),
+                }
+            }
+
             _ => panic!("cannot multiply values of different types"),
         }
     }
@@ -818,6 +1526,14 @@ impl Div for Value {
             (Value::F32(x), Value::F32(y)) => Value::F32(x / y),
             (Value::F64(x), Value::F64(y)) => Value::F64(x / y),
 
+            // Big integers, promoting the other operand if it is an integer
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                match (x.as_bigint(), y.as_bigint()) {
+                    (Some(x), Some(y)) => Value::BigInt(x / y),
+                    _ => panic!("cannot divide values of different types"),
+                }
+            }
+
             _ => panic!("cannot divide values of different types"),
         }
     }
@@ -848,6 +1564,14 @@ impl Rem for Value {
             (Value::F32(x), Value::F32(y)) => Value::F32(x % y),
             (Value::F64(x), Value::F64(y)) => Value::F64(x % y),
 
+            // Big integers, promoting the other operand if it is an integer
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                match (x.as_bigint(), y.as_bigint()) {
+                    (Some(x), Some(y)) => Value::BigInt(x % y),
+                    _ => panic!("cannot perform modulo on values of different types"),
+                }
+            }
+
             _ => panic!("cannot perform modulo on values of different types"),
         }
     }
@@ -874,6 +1598,11 @@ impl Shl for Value {
             (Value::U128(x), Value::U128(y)) => Value::U128(x << y),
             (Value::Usize(x), Value::Usize(y)) => Value::Usize(x << y),
 
+            // Big integers, shifted by any integer amount
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                x.checked_shl(y).unwrap_or_else(|e| panic!("{e}"))
+            }
+
             _ => panic!("cannot perform left shift on values of different types"),
         }
     }
@@ -900,6 +1629,11 @@ impl Shr for Value {
             (Value::U128(x), Value::U128(y)) => Value::U128(x >> y),
             (Value::Usize(x), Value::Usize(y)) => Value::Usize(x >> y),
 
+            // Big integers, shifted by any integer amount
+            (x, y) if x.is_bigint() || y.is_bigint() => {
+                x.checked_shr(y).unwrap_or_else(|e| panic!("{e}"))
+            }
+
             _ => panic!("cannot perform right shift on values of different types"),
         }
     }
@@ -917,6 +1651,7 @@ impl Neg for Value {
             Value::I64(x) => Value::I64(-x),
             Value::I128(x) => Value::I128(-x),
             Value::Isize(x) => Value::Isize(-x),
+            Value::BigInt(x) => Value::BigInt(-x),
 
             // Floats
             Value::F32(x) => Value::F32(-x),
@@ -941,6 +1676,7 @@ impl Not for Value {
             Value::I64(x) => Value::I64(!x),
             Value::I128(x) => Value::I128(!x),
             Value::Isize(x) => Value::Isize(!x),
+            Value::BigInt(x) => Value::BigInt(!x),
 
             Value::U8(x) => Value::U8(!x),
             Value::U16(x) => Value::U16(!x),
@@ -971,6 +1707,7 @@ impl Value {
             Value::U64(x) => *x != 0,
             Value::U128(x) => *x != 0,
             Value::Usize(x) => *x != 0,
+            Value::BigInt(x) => !x.is_zero(),
 
             // Floats: 0.0 is falsy, non-zero is truthy (including NaN and infinity)
             Value::F32(x) => *x != 0.0,
@@ -984,10 +1721,11 @@ impl Value {
 
             // String: empty is falsy, non-empty is truthy
             Value::String(s) => !s.is_empty(),
-            Value::Hash(h) => !h.is_empty(), // Assuming Hash has is_empty()
+            Value::Hash(_) => true,
 
             // Container: empty is falsy, non-empty is truthy
             Value::Container(v) => !v.is_empty(),
+            Value::Map(m) => !m.is_empty(),
         }
     }
 
@@ -1021,6 +1759,7 @@ impl Value {
 pub mod tests {
     use super::*;
 
+    use crate::db::SigningKey;
     use rand::{distr::Alphanumeric, Rng};
 
     /// Debugging methods
@@ -1038,7 +1777,7 @@ pub mod tests {
                 instruction: 0,
             };
             self.call_stack.push(main);
-            self.exec(false)
+            exit_code(self.exec(false)?)
         }
 
         /// Run the given frame and return the final state of the frame.
@@ -1053,14 +1792,21 @@ pub mod tests {
         CodeObject {
             litpool: vec![Value::int(5), Value::string("hello")],
             argcount: 2, // x and y
+            is_void: code.is_void(),
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             localnames: vec!["x".into(), "y".into(), "z".into()],
             code,
         }
     }
 
     fn init_frame(code: Bytecode) -> StackFrame {
-        let code_obj = init_code_obj(code);
+        init_frame_from(init_code_obj(code))
+    }
+
+    fn init_frame_from(code_obj: CodeObject) -> StackFrame {
         StackFrame {
             code_obj,
             stack: Vec::new(),
@@ -1097,17 +1843,25 @@ pub mod tests {
         CodeObject {
             litpool: vec![Value::int(5), Value::String(s)],
             argcount: 2, // x and y
+            is_void: code.is_void(),
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             localnames: vec!["x".into(), "y".into(), "z".into()],
             code,
         }
     }
 
-    fn init_code_obj_with_pool(code: Bytecode, litpool: Vec<Value>) -> CodeObject {
+    pub fn init_code_obj_with_pool(code: Bytecode, litpool: Vec<Value>) -> CodeObject {
         CodeObject {
             litpool,
             argcount: 2, // x and y
+            is_void: code.is_void(),
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             localnames: vec!["x".into(), "y".into(), "z".into()],
             code,
         }
@@ -1156,10 +1910,11 @@ pub mod tests {
         let frame = vm.run_frame(main).unwrap();
 
         // Check
-        assert_eq!(frame.locals.get("z".into()).unwrap().to_owned(), v);
+        assert_eq!(frame.locals.get("z").unwrap().to_owned(), v);
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_ops() {
         let mut main = init_frame(bytecode![
             Instr::BinOp(BinOp::Add),
@@ -1287,8 +2042,12 @@ pub mod tests {
         let func_b = CodeObject {
             litpool: vec![Value::int(4), Value::int(3)],
             argcount: 0,
+            is_void: false,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
 
             code: bytecode![
                 Instr::LoadLit(0), // 4
@@ -1306,8 +2065,12 @@ pub mod tests {
         let func_a = CodeObject {
             litpool: vec![Value::I32(10)],
             argcount: 0,
+            is_void: false,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
 
             code: bytecode![
                 Instr::LoadFunc(hash),
@@ -1330,8 +2093,12 @@ pub mod tests {
         let func_b = CodeObject {
             litpool: vec![Value::int(4), Value::int(3)],
             argcount: 0,
+            is_void: true,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
 
             code: bytecode![
                 Instr::LoadLit(0), // 4
@@ -1349,8 +2116,12 @@ pub mod tests {
         let func_a = CodeObject {
             litpool: vec![],
             argcount: 0,
+            is_void: true,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             code: bytecode![Instr::LoadFunc(hash), Instr::Call, Instr::Return],
         };
         vm.db.insert_code_object_with_name(&func_a, "main").unwrap();
@@ -1359,18 +2130,45 @@ pub mod tests {
         assert_eq!(code, 0);
     }
 
+    #[test]
+    fn test_is_void_enforced() {
+        let run = |obj: CodeObject| Vm::new().unwrap().run_frame(init_frame_from(obj));
+
+        let mut obj = init_code_obj(bytecode![Instr::LoadLit(0), Instr::ReturnVal]);
+        obj.is_void = true;
+        assert!(run(obj.clone()).is_err());
+        obj.is_void = false;
+        assert!(run(obj).is_ok());
+
+        let mut obj = init_code_obj(bytecode![Instr::Return]);
+        obj.is_void = false;
+        assert!(run(obj).is_err());
+    }
+
     #[test]
     fn test_main_returns_1() {
         let mut vm = Vm::new().unwrap();
         let func = CodeObject {
             litpool: vec![],
             argcount: 0,
+            is_void: false,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             code: bytecode![Instr::ReturnVal],
         };
-        vm.db.insert_code_object_with_name(&func, "main").unwrap();
+        // Rejected by the verifier, and fails at runtime if it gets through anyway
+        assert!(vm.db.insert_code_object_with_name(&func, "main").is_err());
         assert!(vm.run_main_function().is_err());
+        let frame = StackFrame {
+            code_obj: func,
+            stack: Vec::new(),
+            locals: HashMap::new(),
+            instruction: 0,
+        };
+        assert!(vm.run_frame(frame).is_err());
     }
 
     #[test]
@@ -1379,22 +2177,193 @@ pub mod tests {
         let func = CodeObject {
             litpool: vec![Value::string("break")],
             argcount: 0,
+            is_void: false,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             code: bytecode![Instr::LoadLit(0), Instr::ReturnVal],
         };
         vm.db.insert_code_object_with_name(&func, "main").unwrap();
         assert!(vm.run_main_function().is_err());
     }
 
+    #[test]
+    fn test_data_stack_cap() {
+        let mut vm = Vm::new().unwrap();
+        let func = CodeObject {
+            litpool: vec![Value::int(1)],
+            argcount: 0,
+            is_void: false,
+            localnames: vec![],
+            labels: Vec::new(),
+            max_stack_depth: Some(2),
+            signature: None,
+            debug_info: None,
+            code: bytecode![
+                Instr::LoadLit(0),
+                Instr::LoadLit(0),
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal
+            ],
+        };
+        vm.db.insert_code_object_with_name(&func, "main").unwrap();
+
+        vm.set_data_stack_cap(1);
+        assert!(vm.run_main_function().is_err());
+        vm.set_data_stack_cap(2);
+        assert_eq!(vm.run_main_function().unwrap(), 2);
+
+        // Without a declared depth, the cap is enforced as the stack grows
+        let mut vm = Vm::new().unwrap();
+        let func = CodeObject {
+            max_stack_depth: None,
+            ..func
+        };
+        vm.db.insert_code_object_with_name(&func, "main").unwrap();
+        vm.set_data_stack_cap(1);
+        let e = vm.run_main_function().unwrap_err();
+        assert!(e.to_string().contains("stack overflow"), "{e}");
+        vm.set_data_stack_cap(2);
+        assert_eq!(vm.run_main_function().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_signature_policy() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let obj = |code: Bytecode| CodeObject {
+            litpool: vec![Value::int(3)],
+            argcount: 0,
+            is_void: false,
+            localnames: vec![],
+            labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
+            code,
+        };
+        let three = obj(bytecode![Instr::LoadLit(0), Instr::ReturnVal]);
+        let main = obj(bytecode![
+            Instr::LoadDyn("three".to_string()),
+            Instr::Call,
+            Instr::ReturnVal
+        ]);
+        let run = |sign_three: bool, policy: SignaturePolicy| {
+            let mut vm = Vm::new().unwrap();
+            let hash = vm.db.insert_code_object_with_name(&three, "three").unwrap();
+            if sign_three {
+                vm.db.sign(&hash, &key).unwrap();
+            }
+            vm.db.insert_signed(&main, "main", &key).unwrap();
+            vm.set_signature_policy(policy);
+            vm.run_main_function()
+        };
+
+        assert_eq!(run(false, SignaturePolicy::Any).unwrap(), 3);
+        // Every function called is checked, not just main
+        assert!(run(false, SignaturePolicy::Signed).is_err());
+        assert_eq!(run(true, SignaturePolicy::Signed).unwrap(), 3);
+        let trusted =
+            |key: &SigningKey| SignaturePolicy::Trusted(vec![key.verifying_key()]);
+        assert_eq!(run(true, trusted(&key)).unwrap(), 3);
+        let other = SigningKey::from_bytes(&[2; 32]);
+        assert!(run(true, trusted(&other)).is_err());
+    }
+
+    #[test]
+    fn test_readonly_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("code.db");
+        let three = CodeObject {
+            argcount: 0,
+            ..init_code_obj_with_pool(
+                bytecode![Instr::LoadLit(0), Instr::ReturnVal],
+                vec![Value::int(3)],
+            )
+        };
+        let main = init_code_obj(bytecode![
+            Instr::LoadDyn("three".to_string()),
+            Instr::Call,
+            Instr::ReturnVal
+        ]);
+        let db = Database::new(&path).unwrap();
+        db.insert_code_object_with_name(&three, "three").unwrap();
+        db.insert_code_object_with_name(&main, "main").unwrap();
+        drop(db);
+
+        let db = Database::open_readonly(&path).unwrap();
+        assert!(db.insert_code_object_with_name(&three, "four").is_err());
+        assert!(db.remove_name("three", false).is_err());
+        let mut vm = Vm::from_database(db);
+        vm.set_signature_policy(SignaturePolicy::Signed);
+        assert!(vm.run_main_function().is_err());
+        let mut vm = Vm::from_database(Database::open_readonly(&path).unwrap());
+        assert_eq!(vm.run_main_function().unwrap(), 3);
+
+        // Profiles can't be recorded, but the run still succeeds
+        vm.set_profiling(true);
+        assert_eq!(vm.run_main_function().unwrap(), 3);
+        assert_eq!(vm.last_profile().unwrap().profiles.len(), 2);
+
+        assert!(Database::open_readonly(dir.path().join("missing.db")).is_err());
+    }
+
+    #[test]
+    fn test_signature_checked() {
+        let run_with = |arg: Value, signature: &str| {
+            let mut vm = Vm::new().unwrap();
+            let mut double = init_code_obj(bytecode![
+                Instr::LoadArg(0),
+                Instr::LoadArg(0),
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal
+            ]);
+            double.argcount = 1;
+            double.localnames = vec!["x".to_string()];
+            double.signature = Some(signature.parse().unwrap());
+            let hash = vm
+                .db
+                .insert_code_object_with_name(&double, "double")
+                .unwrap();
+
+            let main = CodeObject {
+                litpool: vec![arg],
+                argcount: 0,
+                is_void: false,
+                localnames: vec![],
+                labels: Vec::new(),
+                max_stack_depth: None,
+                signature: None,
+                debug_info: None,
+                code: bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadFunc(hash),
+                    Instr::Call,
+                    Instr::ReturnVal
+                ],
+            };
+            vm.db.insert_code_object_with_name(&main, "main").unwrap();
+            vm.run_main_function()
+        };
+
+        assert_eq!(run_with(Value::I32(4), "(i32) -> i32").unwrap(), 8);
+        assert!(run_with(Value::Bool(true), "(i32) -> i32").is_err());
+        assert!(run_with(Value::I32(4), "(i32) -> bool").is_err());
+    }
+
     #[test]
     fn test_main_returns_3() {
         let mut vm = Vm::new().unwrap();
         let func = CodeObject {
             litpool: vec![Value::I32(0)],
             argcount: 0,
+            is_void: false,
             localnames: vec![],
             labels: Vec::new(),
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             code: bytecode![Instr::LoadLit(0), Instr::ReturnVal],
         };
         vm.db.insert_code_object_with_name(&func, "main").unwrap();
@@ -1407,8 +2376,12 @@ pub mod tests {
         let fib = CodeObject {
             litpool: vec![Value::I32(0), Value::I32(1), Value::I32(2)],
             argcount: 1,
+            is_void: false,
             localnames: vec!["n".into()],
             labels: vec![18],
+            max_stack_depth: None,
+            signature: None,
+            debug_info: None,
             code: bytecode![
                 Instr::LoadArg(0),       // load n
                 Instr::LoadLit(0),       // load 0
@@ -1443,8 +2416,12 @@ pub mod tests {
             let main = CodeObject {
                 litpool: vec![Value::I32(n)],
                 argcount: 0,
+                is_void: false,
                 localnames: vec![],
                 labels: Vec::new(),
+                max_stack_depth: None,
+                signature: None,
+                debug_info: None,
                 code: bytecode![
                     Instr::LoadLit(0),
                     Instr::LoadFunc(hash),
@@ -1707,4 +2684,314 @@ pub mod tests {
         let tos = vm.call_stack.pop().unwrap().stack.pop().unwrap();
         assert_eq!(tos, Value::Usize(3));
     }
+
+    #[test]
+    fn test_map() {
+        let mut vm = Vm::new().unwrap();
+
+        vm.run_frame(init_frame_with_pool(
+            bytecode![
+                // Build {"a": 1, "b": 2}
+                Instr::MapNew,
+                Instr::LoadLit(1),
+                Instr::LoadLit(0),
+                Instr::MapSet,
+                Instr::LoadLit(3),
+                Instr::LoadLit(2),
+                Instr::MapSet,
+                // Overwrite "a"
+                Instr::LoadLit(3),
+                Instr::LoadLit(0),
+                Instr::MapSet,
+                Instr::StoreLocal(0),
+                // Get
+                Instr::LoadLocal(0),
+                Instr::LoadLit(0),
+                Instr::MapGet,
+                Instr::LoadLocal(0),
+                Instr::MapLen,
+                Instr::LoadLocal(0),
+                Instr::MapKeys,
+                // Delete "a"
+                Instr::LoadLocal(0),
+                Instr::LoadLit(0),
+                Instr::MapDel
+            ],
+            vec![
+                Value::string("a"),
+                Value::I32(1),
+                Value::string("b"),
+                Value::I32(2),
+            ],
+        ))
+        .unwrap();
+
+        let mut stack = vm.call_stack.pop().unwrap().stack;
+        assert_eq!(
+            stack.pop().unwrap(),
+            Value::Map(vec![(Value::string("b"), Value::I32(2))])
+        );
+        assert_eq!(
+            stack.pop().unwrap(),
+            Value::Container(vec![Value::string("a"), Value::string("b")])
+        );
+        assert_eq!(stack.pop().unwrap(), Value::Usize(2));
+        assert_eq!(stack.pop().unwrap(), Value::I32(2));
+
+        // Missing key
+        let t = vm.run_frame(init_frame(bytecode![
+            Instr::MapNew,
+            Instr::LoadLit(0),
+            Instr::MapGet
+        ]));
+        assert!(t.is_err());
+    }
+
+    #[test]
+    fn test_bigint() {
+        let big = |s: &str| Value::BigInt(s.parse().unwrap());
+        let max = Value::I128(i128::MAX);
+
+        // Promotion of the other integer operand
+        assert_eq!(
+            big(&i128::MAX.to_string()) + Value::I32(1),
+            big("170141183460469231731687303715884105728")
+        );
+        assert_eq!(
+            max.clone() * big("2"),
+            big("340282366920938463463374607431768211454")
+        );
+        assert_eq!(big("7") % Value::U8(4), big("3"));
+        assert_eq!(
+            big("1") << Value::I32(130),
+            big("1361129467683753853853498429727072845824")
+        );
+        assert_eq!(-big("5"), big("-5"));
+        assert_eq!(!big("5"), big("-6"));
+
+        // Comparisons
+        assert!(big("170141183460469231731687303715884105728") > max);
+        assert!(Value::I32(-1) < big("0"));
+        assert!(big("3") == big("3"));
+        assert!(big("3").equals(&Value::I32(3)));
+        assert!(!big("0").is_truthy());
+
+        // Shifts
+        assert_eq!(Value::I32(1).checked_shl(big("3")).unwrap(), big("8"));
+        assert_eq!(big("256").checked_shr(Value::U8(4)).unwrap(), big("16"));
+        assert!(big("1").checked_shl(Value::I32(-1)).is_err());
+        assert!(big("1").checked_shr(big("18446744073709551617")).is_err());
+        assert!(big("1").checked_shl(Value::string("x")).is_err());
+
+        // Through the VM
+        let mut vm = Vm::new().unwrap();
+        let mut frame = vm
+            .run_frame(init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(0),
+                    Instr::BinOp(BinOp::Mul),
+                    Instr::LoadLit(1),
+                    Instr::BinOp(BinOp::Sub)
+                ],
+                vec![big("100000000000000000000"), Value::I32(1)],
+            ))
+            .unwrap();
+        assert_eq!(
+            frame.stack.pop().unwrap(),
+            big("9999999999999999999999999999999999999999")
+        );
+
+        let mut shift = |amount| {
+            vm.run_frame(init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    Instr::BinOp(BinOp::Shl)
+                ],
+                vec![big("1"), amount],
+            ))
+        };
+        assert!(shift(Value::I64(-1)).is_err());
+        assert!(shift(Value::Usize(MAX_BIGINT_SHIFT + 1)).is_err());
+        let mut frame = shift(Value::I32(4)).unwrap();
+        assert_eq!(frame.stack.pop().unwrap(), big("16"));
+
+        // Equality across big and fixed widths
+        let mut frame = vm
+            .run_frame(init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    Instr::BinOp(BinOp::Eq)
+                ],
+                vec![big("3"), Value::I32(3)],
+            ))
+            .unwrap();
+        assert_eq!(frame.stack.pop().unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_display_value() {
+        assert_eq!(Value::I32(-5).to_display_string(), "-5");
+        assert_eq!(Value::string("hi there").to_display_string(), "hi there");
+        assert_eq!(Value::F64(1.5).to_display_string(), "1.5");
+        assert_eq!(
+            Value::Container(vec![
+                Value::U8(1),
+                Value::string("x"),
+                Value::Char('c'),
+                Value::Container(vec![Value::Bool(true)]),
+            ])
+            .to_display_string(),
+            r#"[1, "x", 'c', [true]]"#
+        );
+        assert_eq!(
+            Value::Map(vec![(Value::string("k"), Value::Container(vec![]))])
+                .to_display_string(),
+            r#"{"k": []}"#
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(
+            Value::I32(3).compare(&Value::Usize(3)).unwrap(),
+            Ordering::Equal
+        );
+        assert_eq!(
+            Value::I32(-1).compare(&Value::Usize(0)).unwrap(),
+            Ordering::Less
+        );
+        assert_eq!(
+            Value::Container(vec![Value::I32(1), Value::I32(2)])
+                .compare(&Value::Container(vec![Value::I32(1)]))
+                .unwrap(),
+            Ordering::Greater
+        );
+        assert!(Value::I32(1).compare(&Value::string("1")).is_err());
+        assert!(Value::Map(vec![])
+            .partial_cmp(&Value::Map(vec![]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_cmp() {
+        let mut vm = Vm::new().unwrap();
+
+        let mut frame = vm
+            .run_frame(init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    Instr::Cmp,
+                    Instr::LoadLit(1),
+                    Instr::LoadLit(0),
+                    Instr::Cmp,
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(0),
+                    Instr::Cmp
+                ],
+                vec![Value::I32(1), Value::I32(2)],
+            ))
+            .unwrap();
+        assert_eq!(frame.stack.pop().unwrap(), Value::I32(0));
+        assert_eq!(frame.stack.pop().unwrap(), Value::I32(1));
+        assert_eq!(frame.stack.pop().unwrap(), Value::I32(-1));
+
+        // Incomparable values are an error, not a panic
+        let t = vm.run_frame(init_frame(bytecode![
+            Instr::LoadLit(0),
+            Instr::LoadLit(1),
+            Instr::JumpGt(0)
+        ]));
+        assert!(t.is_err());
+    }
+
+    #[test]
+    fn test_float_compare() {
+        let mut vm = Vm::new().unwrap();
+
+        // Whether `jump` is taken with `lhs` and `rhs` on the stack
+        let mut taken = |jump: fn(usize) -> Instr, lhs: f64, rhs: f64| -> bool {
+            let mut frame = init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    jump(0),
+                    Instr::LoadLit(0)
+                ],
+                vec![Value::F64(lhs), Value::F64(rhs)],
+            );
+            frame.code_obj.labels.push(4);
+            vm.run_frame(frame).unwrap().stack.is_empty()
+        };
+
+        let nan = f64::NAN;
+        let inf = f64::INFINITY;
+
+        // NaN is unordered: ordered jumps and jmp_eq are never taken, jmp_ne is
+        for jump in [Instr::JumpGt, Instr::JumpGe, Instr::JumpLt, Instr::JumpLe] {
+            assert!(!taken(jump, nan, 1.0));
+            assert!(!taken(jump, 1.0, nan));
+            assert!(!taken(jump, nan, nan));
+        }
+        assert!(!taken(Instr::JumpEq, nan, nan));
+        assert!(taken(Instr::JumpNe, nan, nan));
+
+        // Infinities
+        assert!(taken(Instr::JumpGt, inf, f64::MAX));
+        assert!(taken(Instr::JumpLt, -inf, f64::MIN));
+        assert!(taken(Instr::JumpGe, inf, inf));
+
+        // Signed zeros are equal
+        assert!(taken(Instr::JumpEq, -0.0, 0.0));
+        assert!(taken(Instr::JumpLe, -0.0, 0.0));
+        assert!(!taken(Instr::JumpLt, -0.0, 0.0));
+
+        // Mixed widths, and cmp refuses to order NaN
+        assert_eq!(
+            Value::F32(1.5).compare(&Value::F64(2.0)).unwrap(),
+            Ordering::Less
+        );
+        assert!(Value::F64(nan).compare(&Value::F64(1.0)).is_err());
+        assert!(Value::F64(1.0).compare(&Value::I32(1)).is_err());
+    }
+
+    #[test]
+    fn test_mixed_width_equality() {
+        let mut vm = Vm::new().unwrap();
+
+        // Whether `jump` is taken with `lhs` and `rhs` on the stack
+        let mut taken = |jump: fn(usize) -> Instr, lhs: Value, rhs: Value| -> bool {
+            let mut frame = init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    jump(0),
+                    Instr::LoadLit(0)
+                ],
+                vec![lhs, rhs],
+            );
+            frame.code_obj.labels.push(4);
+            vm.run_frame(frame).unwrap().stack.is_empty()
+        };
+
+        assert!(taken(Instr::JumpEq, Value::I32(3), Value::Usize(3)));
+        assert!(!taken(Instr::JumpNe, Value::I32(3), Value::Usize(3)));
+        assert!(taken(Instr::JumpNe, Value::I32(-1), Value::U8(255)));
+        assert!(taken(Instr::JumpNe, Value::I32(1), Value::string("1")));
+
+        let mut frame = vm
+            .run_frame(init_frame_with_pool(
+                bytecode![
+                    Instr::LoadLit(0),
+                    Instr::LoadLit(1),
+                    Instr::BinOp(BinOp::Eq)
+                ],
+                vec![Value::I32(3), Value::Usize(3)],
+            ))
+            .unwrap();
+        assert_eq!(frame.stack.pop().unwrap(), Value::Bool(true));
+    }
 }
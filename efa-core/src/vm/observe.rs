@@ -0,0 +1,35 @@
+//! Hooks for watching a run from outside the VM, e.g. to trace it
+
+use std::fmt;
+
+use anyhow::Result;
+
+use super::Value;
+use crate::bytecode::Instr;
+use crate::Hash;
+
+/// Told about each call, instruction, and return of a run. Set with
+/// `Vm::set_observer`.
+pub trait Observer: Send {
+    /// A call of the code object `hash` began
+    fn enter(&mut self, _hash: &Hash) {}
+
+    /// The instruction at `offset` of the running code object ran, leaving `stack`.
+    /// An error stops the run.
+    fn step(&mut self, _offset: usize, _instr: &Instr, _stack: &[Value]) -> Result<()> {
+        Ok(())
+    }
+
+    /// The running code object returned
+    fn exit(&mut self) {}
+
+    /// The instruction at `offset` of the running code object failed with `error`,
+    /// stopping the run
+    fn error(&mut self, _offset: usize, _instr: &Instr, _error: &anyhow::Error) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
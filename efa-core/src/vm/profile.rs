@@ -0,0 +1,216 @@
+//! Gathering execution statistics per code object, for `Vm::set_profiling`
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use super::StackFrame;
+use crate::db::Profile;
+use crate::Hash;
+
+/// What was profiled in the last run, from `Vm::last_profile`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunProfile {
+    /// The statistics of each code object that ran, as added to the database
+    pub profiles: HashMap<Hash, Profile>,
+    /// Time spent in each code object itself, not in what it calls
+    pub self_time: HashMap<Hash, Duration>,
+    /// Instructions run with each call stack, outermost code object first
+    pub stacks: HashMap<Vec<Hash>, u64>,
+}
+
+impl RunProfile {
+    /// The stacks in the folded format read by flame graph tools, e.g.
+    /// `main;fib;fib 12`, naming each code object with `name`
+    pub fn folded(&self, name: impl Fn(&Hash) -> String) -> String {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, instructions)| {
+                let names = stack.iter().map(&name).collect::<Vec<_>>();
+                format!("{} {instructions}\n", names.join(";"))
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Profiler {
+    /// A frame for each one on the call stack
+    frames: Vec<Frame>,
+    run: RunProfile,
+    last: Option<RunProfile>,
+}
+
+#[derive(Debug)]
+struct Frame {
+    hash: Hash,
+    start: Instant,
+    /// Instructions run in this frame itself
+    instructions: u64,
+    /// Time spent in the frames this one called
+    callees: Duration,
+}
+
+impl Profiler {
+    /// Start following an existing call stack, e.g. one loaded from a snapshot,
+    /// without counting its calls
+    pub fn follow(&mut self, call_stack: &[StackFrame]) -> Result<()> {
+        self.frames = call_stack
+            .iter()
+            .map(|frame| Ok(Frame::new(frame.code_obj.hash()?)))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    pub fn enter(&mut self, hash: Hash) {
+        self.frames.push(Frame::new(hash));
+        self.run.profiles.entry(hash).or_default().calls += 1;
+    }
+
+    /// Enter the code object that is running again
+    pub fn enter_self(&mut self) {
+        if let Some(frame) = self.frames.last() {
+            self.enter(frame.hash);
+        }
+    }
+
+    /// Count an instruction of the code object that is running
+    pub fn step(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.instructions += 1;
+        }
+    }
+
+    pub fn exit(&mut self) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        let elapsed = frame.start.elapsed();
+        let profile = self.run.profiles.entry(frame.hash).or_default();
+        profile.instructions += frame.instructions;
+        // The outermost call of a recursive function covers the inner ones
+        if self.frames.iter().all(|other| other.hash != frame.hash) {
+            profile.time += elapsed;
+        }
+        *self.run.self_time.entry(frame.hash).or_default() +=
+            elapsed.saturating_sub(frame.callees);
+
+        if frame.instructions > 0 {
+            let stack = self.frames.iter().map(|f| f.hash).chain([frame.hash]);
+            *self.run.stacks.entry(stack.collect()).or_default() += frame.instructions;
+        }
+        if let Some(caller) = self.frames.last_mut() {
+            caller.callees += elapsed;
+        }
+    }
+
+    /// Exit every frame, and return what was profiled since the last time
+    pub fn finish(&mut self) -> &RunProfile {
+        while !self.frames.is_empty() {
+            self.exit();
+        }
+        self.last.insert(std::mem::take(&mut self.run))
+    }
+
+    /// What was profiled until the last `finish`
+    pub fn last(&self) -> Option<&RunProfile> {
+        self.last.as_ref()
+    }
+}
+
+impl Frame {
+    fn new(hash: Hash) -> Frame {
+        Frame {
+            hash,
+            start: Instant::now(),
+            instructions: 0,
+            callees: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_profiling() {
+        let source = [
+            "$fib 1:",
+            "    .lit 0",
+            "    .lit 1",
+            "    .lit 2",
+            "    load_arg 0",
+            "    load_lit 0",
+            "    eq",
+            "    load_arg 0",
+            "    load_lit 1",
+            "    eq",
+            "    or",
+            "    jmp_t L0",
+            "    load_arg 0",
+            "    load_lit 1",
+            "    sub",
+            "    call_self",
+            "    load_arg 0",
+            "    load_lit 2",
+            "    sub",
+            "    call_self",
+            "    add",
+            "    ret_val",
+            "L0:",
+            "    load_arg 0",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 6",
+            "    load_lit 0",
+            "    load_dyn $fib",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let mut vm = Vm::new().unwrap();
+        Assembler::new(&vm.db).assemble_str(&source).unwrap();
+        let (fib, _) = vm.db.get_code_object_by_name("fib").unwrap();
+        let (main, _) = vm.db.get_code_object_by_name("main").unwrap();
+
+        // Only profiled runs are recorded
+        assert_eq!(vm.run_main_function().unwrap(), 8);
+        assert!(vm.db.get_profiles().unwrap().is_empty());
+
+        vm.set_profiling(true);
+        assert_eq!(vm.run_main_function().unwrap(), 8);
+        let profiles = vm.db.get_profiles().unwrap();
+        assert_eq!(profiles.len(), 2);
+        let (hottest, profile) = profiles[0];
+        assert_eq!(hottest, fib);
+        // fib(6) makes 25 calls, counting the first
+        assert_eq!(profile.calls, 25);
+        let main_profile = vm.db.get_profile(&main).unwrap().unwrap();
+        assert_eq!(main_profile.calls, 1);
+        assert_eq!(main_profile.instructions, 4);
+        assert!(main_profile.time >= profile.time);
+
+        let last = vm.last_profile().unwrap();
+        assert_eq!(last.profiles[&fib], profile);
+        assert!(last.self_time[&main] <= main_profile.time);
+        let name = |hash: &Hash| vm.db.get_name_of_hash(hash).unwrap().unwrap();
+        let folded = last.folded(name);
+        assert!(folded.starts_with("main 4\n"));
+        assert!(folded.contains("\nmain;fib;fib;fib;fib;fib "));
+        let total = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum::<u64>();
+        assert_eq!(total, main_profile.instructions + profile.instructions);
+
+        assert_eq!(vm.run_main_function().unwrap(), 8);
+        assert_eq!(vm.db.get_profile(&fib).unwrap().unwrap().calls, 50);
+    }
+}
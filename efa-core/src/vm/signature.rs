@@ -0,0 +1,202 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Value;
+
+/// The type of a value, as used in function signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypeTag {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    I128,
+    U128,
+    Isize,
+    Usize,
+    BigInt,
+    F32,
+    F64,
+    Char,
+    Bool,
+    Hash,
+    String,
+    Container,
+    Map,
+    /// Any value
+    Any,
+}
+
+const TYPE_TAGS: [TypeTag; 22] = [
+    TypeTag::I8,
+    TypeTag::U8,
+    TypeTag::I16,
+    TypeTag::U16,
+    TypeTag::I32,
+    TypeTag::U32,
+    TypeTag::I64,
+    TypeTag::U64,
+    TypeTag::I128,
+    TypeTag::U128,
+    TypeTag::Isize,
+    TypeTag::Usize,
+    TypeTag::BigInt,
+    TypeTag::F32,
+    TypeTag::F64,
+    TypeTag::Char,
+    TypeTag::Bool,
+    TypeTag::Hash,
+    TypeTag::String,
+    TypeTag::Container,
+    TypeTag::Map,
+    TypeTag::Any,
+];
+
+impl TypeTag {
+    /// The name of the type, matching `Value::type_name`
+    pub fn name(&self) -> &'static str {
+        match self {
+            TypeTag::I8 => "i8",
+            TypeTag::U8 => "u8",
+            TypeTag::I16 => "i16",
+            TypeTag::U16 => "u16",
+            TypeTag::I32 => "i32",
+            TypeTag::U32 => "u32",
+            TypeTag::I64 => "i64",
+            TypeTag::U64 => "u64",
+            TypeTag::I128 => "i128",
+            TypeTag::U128 => "u128",
+            TypeTag::Isize => "isize",
+            TypeTag::Usize => "usize",
+            TypeTag::BigInt => "bigint",
+            TypeTag::F32 => "f32",
+            TypeTag::F64 => "f64",
+            TypeTag::Char => "char",
+            TypeTag::Bool => "bool",
+            TypeTag::Hash => "hash",
+            TypeTag::String => "string",
+            TypeTag::Container => "container",
+            TypeTag::Map => "map",
+            TypeTag::Any => "any",
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        *self == TypeTag::Any || self.name() == value.type_name()
+    }
+}
+
+impl FromStr for TypeTag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        TYPE_TAGS
+            .into_iter()
+            .find(|tag| tag.name() == s)
+            .ok_or_else(|| anyhow!("unknown type '{s}'"))
+    }
+}
+
+impl fmt::Display for TypeTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parameter and return types of a function, written `(i32, i32) -> i32`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub params: Vec<TypeTag>,
+    /// `None` if the return type is not declared
+    pub ret: Option<TypeTag>,
+}
+
+impl Signature {
+    /// Check arguments, in parameter order, against the signature
+    pub fn check_args<'a>(
+        &self,
+        args: impl IntoIterator<Item = &'a Value>,
+    ) -> Result<()> {
+        for (i, (param, arg)) in self.params.iter().zip(args).enumerate() {
+            if !param.matches(arg) {
+                bail!(
+                    "argument {i} has type {}, but the signature {self} expects {param}",
+                    arg.type_name()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (params, ret) = match s.split_once("->") {
+            Some((params, ret)) => (params.trim(), Some(ret.trim().parse()?)),
+            None => (s.trim(), None),
+        };
+
+        let params = params
+            .strip_prefix('(')
+            .and_then(|p| p.strip_suffix(')'))
+            .ok_or_else(|| anyhow!("invalid signature '{s}': expected parameter list"))?
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<TypeTag>>>()?;
+
+        Ok(Signature { params, ret })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = self
+            .params
+            .iter()
+            .map(TypeTag::name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "({params})")?;
+        if let Some(ret) = self.ret {
+            write!(f, " -> {ret}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signature() {
+        let sig = "(i32, string) -> bool".parse::<Signature>().unwrap();
+        assert_eq!(sig.params, vec![TypeTag::I32, TypeTag::String]);
+        assert_eq!(sig.ret, Some(TypeTag::Bool));
+        assert_eq!(sig.to_string(), "(i32, string) -> bool");
+
+        let sig = "()".parse::<Signature>().unwrap();
+        assert!(sig.params.is_empty() && sig.ret.is_none());
+
+        assert!("(i32".parse::<Signature>().is_err());
+        assert!("(int) -> i32".parse::<Signature>().is_err());
+    }
+
+    #[test]
+    fn test_check_args() {
+        let sig = "(i32, any)".parse::<Signature>().unwrap();
+        assert!(sig.check_args(&[Value::I32(1), Value::Bool(true)]).is_ok());
+        assert!(sig.check_args(&[Value::I64(1), Value::Bool(true)]).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Saving a VM's call stack in its database, so that a run that stopped part way
+//! can be picked up later, by another VM on the same database. Frames refer to
+//! their code objects by hash, so the code must still be stored to load them.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{exit_code, StackFrame, Value, Vm};
+use crate::Hash;
+
+/// A stack frame as it is saved
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedFrame {
+    code_obj: Hash,
+    stack: Vec<Value>,
+    locals: HashMap<String, Value>,
+    instruction: usize,
+}
+
+impl Vm {
+    /// Save the call stack under `name`, replacing any snapshot with that name. A
+    /// run that fails keeps its call stack, e.g. at a `load_dyn` of a function
+    /// that isn't defined yet.
+    pub fn save_snapshot(&self, name: &str) -> Result<()> {
+        let frames = self
+            .call_stack
+            .iter()
+            .map(|frame| {
+                Ok(SavedFrame {
+                    code_obj: frame.code_obj.hash()?,
+                    stack: frame.stack.clone(),
+                    locals: frame.locals.clone(),
+                    instruction: frame.instruction,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.db.put_snapshot(name, &rmp_serde::to_vec(&frames)?)
+    }
+
+    /// Replace the call stack with the one saved under `name`. Continue running it
+    /// with `resume`. Each frame is checked as a call would be, against the
+    /// signature policy and the stack cap.
+    pub fn load_snapshot(&mut self, name: &str) -> Result<()> {
+        let frames: Vec<SavedFrame> =
+            rmp_serde::from_slice(&self.db.get_snapshot(name)?)?;
+        self.call_stack = frames
+            .into_iter()
+            .map(|frame| {
+                let hash = frame.code_obj;
+                self.db.check_signatures(&hash, &self.signature_policy)?;
+                let code_obj = self.db.get_code_object(&hash)?;
+                if frame.instruction >= code_obj.code.len() {
+                    bail!(
+                        "cannot load snapshot '{name}': instruction {} is out of range in {hash}",
+                        frame.instruction
+                    );
+                }
+                if frame.stack.len() > self.data_stack_cap {
+                    bail!(
+                        "cannot load snapshot '{name}': a stack of {} exceeds the cap of {}",
+                        frame.stack.len(),
+                        self.data_stack_cap
+                    );
+                }
+                let mut loaded = StackFrame::new(code_obj, frame.locals, self.data_stack_cap)?;
+                loaded.stack.extend(frame.stack);
+                loaded.instruction = frame.instruction;
+                Ok(loaded)
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Continue running the call stack from where it stopped, returning the exit
+    /// code of the main function
+    pub fn resume(&mut self) -> Result<i32> {
+        if self.call_stack.is_empty() {
+            bail!("cannot resume: nothing is running");
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.follow(&self.call_stack)?;
+        }
+        if let Some(observer) = &mut self.observer {
+            for frame in &self.call_stack {
+                observer.enter(&frame.code_obj.hash()?);
+            }
+        }
+        exit_code(self.run()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{BinOp, Instr};
+    use crate::db::SignaturePolicy;
+    use crate::vm::tests::init_code_obj_with_pool;
+    use crate::vm::{CodeObject, DEFAULT_DATA_STACK_CAP};
+
+    #[test]
+    fn test_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.db");
+        let func = |code, lit| CodeObject {
+            argcount: 0,
+            ..init_code_obj_with_pool(code, vec![Value::int(lit)])
+        };
+
+        // Main stops at the call to `two`, which doesn't exist yet
+        let mut vm = Vm::persistent(&path).unwrap();
+        let main = func(
+            bytecode![
+                Instr::LoadLit(0),
+                Instr::StoreLocal(0),
+                Instr::LoadLocal(0),
+                Instr::LoadDyn("two".to_string()),
+                Instr::Call,
+                Instr::BinOp(BinOp::Add),
+                Instr::ReturnVal
+            ],
+            40,
+        );
+        vm.db.insert_code_object_with_name(&main, "main").unwrap();
+        assert!(vm.run_main_function().is_err());
+        vm.save_snapshot("job").unwrap();
+        drop(vm);
+
+        let mut vm = Vm::open(&path).unwrap();
+        assert!(vm.resume().is_err());
+        assert!(vm.load_snapshot("missing").is_err());
+        vm.load_snapshot("job").unwrap();
+        let two = func(bytecode![Instr::LoadLit(0), Instr::ReturnVal], 2);
+        vm.db.insert_code_object_with_name(&two, "two").unwrap();
+        assert_eq!(vm.resume().unwrap(), 42);
+        assert!(vm.resume().is_err());
+
+        // Snapshots need their code
+        vm.db.remove_name("main", false).unwrap();
+        assert!(vm.load_snapshot("job").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_checks() {
+        let mut vm = Vm::new().unwrap();
+        let main = CodeObject {
+            argcount: 0,
+            ..init_code_obj_with_pool(
+                bytecode![Instr::LoadLit(0), Instr::ReturnVal],
+                vec![Value::int(1)],
+            )
+        };
+        let hash = vm.db.insert_code_object_with_name(&main, "main").unwrap();
+        let save = |vm: &Vm, instruction, stack: Vec<Value>| {
+            let frames = vec![SavedFrame {
+                code_obj: hash,
+                stack,
+                locals: HashMap::new(),
+                instruction,
+            }];
+            vm.db
+                .put_snapshot("job", &rmp_serde::to_vec(&frames).unwrap())
+                .unwrap();
+        };
+
+        save(&vm, 1, vec![Value::int(2)]);
+        vm.load_snapshot("job").unwrap();
+        assert_eq!(vm.resume().unwrap(), 2);
+
+        // A frame past the end of its code
+        save(&vm, 2, vec![]);
+        let err = vm.load_snapshot("job").unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{err}");
+
+        // A stack over the cap
+        vm.set_data_stack_cap(1);
+        save(&vm, 1, vec![Value::int(2), Value::int(3)]);
+        assert!(vm.load_snapshot("job").is_err());
+
+        // Code that the signature policy wouldn't run
+        vm.set_data_stack_cap(DEFAULT_DATA_STACK_CAP);
+        vm.set_signature_policy(SignaturePolicy::Signed);
+        save(&vm, 1, vec![Value::int(2)]);
+        let err = vm.load_snapshot("job").unwrap_err();
+        assert!(err.to_string().contains("not signed"), "{err}");
+    }
+}
@@ -0,0 +1,211 @@
+//! An observer that writes each instruction that runs, for `efa trace`
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::Result;
+
+use super::{Observer, Value};
+use crate::bytecode::Instr;
+use crate::db::Database;
+use crate::Hash;
+
+/// Which instructions a `Tracer` writes
+#[derive(Debug, Clone, Default)]
+pub struct TraceOptions {
+    /// Only those of this function, by name
+    pub function: Option<String>,
+    /// Stop writing after this many
+    pub limit: Option<usize>,
+}
+
+/// Writes a line for each instruction that runs, with its function, offset, and the
+/// top of the stack after it
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    /// A name of each named code object
+    names: HashMap<Hash, String>,
+    function: Option<Hash>,
+    limit: Option<usize>,
+    /// The code object of each frame on the call stack
+    frames: Vec<Hash>,
+    traced: usize,
+}
+
+impl Tracer {
+    /// A tracer of runs of the functions in `db`, writing to `out`
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        db: &Database,
+        opts: TraceOptions,
+    ) -> Result<Tracer> {
+        let mut names = HashMap::new();
+        for (name, hash) in db.get_functions()? {
+            names.entry(hash).or_insert(name);
+        }
+        let function = match opts.function {
+            Some(name) => Some(db.get_code_object_by_name(&name)?.0),
+            None => None,
+        };
+        Ok(Tracer {
+            out,
+            names,
+            function,
+            limit: opts.limit,
+            frames: vec![],
+            traced: 0,
+        })
+    }
+}
+
+impl Tracer {
+    /// How the running function is written, if its instructions are traced
+    fn traced_function(&self) -> Option<String> {
+        let hash = self.frames.last()?;
+        if self.function.is_some_and(|function| function != *hash) {
+            return None;
+        }
+        Some(match self.names.get(hash) {
+            Some(name) => format!("${name}"),
+            None => hash.abbrev(),
+        })
+    }
+}
+
+impl Observer for Tracer {
+    fn enter(&mut self, hash: &Hash) {
+        self.frames.push(*hash);
+    }
+
+    fn step(&mut self, offset: usize, instr: &Instr, stack: &[Value]) -> Result<()> {
+        if self.limit.is_some_and(|limit| self.traced >= limit) {
+            return Ok(());
+        }
+        let Some(function) = self.traced_function() else {
+            return Ok(());
+        };
+        self.traced += 1;
+
+        let top = match stack.last() {
+            Some(top) => top.to_string(),
+            None => "(empty)".to_string(),
+        };
+        writeln!(self.out, "[{function} @ {offset}] {instr} -> {top}")?;
+        Ok(())
+    }
+
+    fn exit(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Written even past the limit, since it is what ends the run
+    fn error(&mut self, offset: usize, instr: &Instr, error: &anyhow::Error) {
+        if let Some(function) = self.traced_function() {
+            // The run already failed, so a failed write has nowhere to go
+            let _ = writeln!(self.out, "[{function} @ {offset}] {instr} failed: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::asm::assembler::Assembler;
+    use crate::vm::tests::init_code_obj;
+    use crate::vm::{CodeObject, Vm};
+
+    /// Output that can be read after the tracer is handed to the VM
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(opts: TraceOptions) -> Vec<String> {
+        let source = [
+            "$square 1:",
+            "    load_arg 0",
+            "    dup",
+            "    mul",
+            "    ret_val",
+            "$main 0:",
+            "    .lit 3",
+            "    load_lit 0",
+            "    load_dyn $square",
+            "    call",
+            "    ret_val",
+        ]
+        .join("\n");
+        let mut vm = Vm::new().unwrap();
+        Assembler::new(&vm.db).assemble_str(&source).unwrap();
+        let out = Shared::default();
+        let tracer = Tracer::new(Box::new(out.clone()), &vm.db, opts).unwrap();
+        vm.set_observer(tracer);
+        assert_eq!(vm.run_main_function().unwrap(), 9);
+
+        out.lines()
+    }
+
+    impl Shared {
+        fn lines(&self) -> Vec<String> {
+            let out = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            out.lines().map(str::to_string).collect()
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let lines = trace(TraceOptions::default());
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "[$main @ 0] load_lit 0 -> 3");
+        assert_eq!(lines[2], "[$main @ 2] call -> (empty)");
+        assert_eq!(lines[4], "[$square @ 1] dup -> 3");
+        assert_eq!(lines[7], "[$main @ 3] ret_val -> (empty)");
+
+        let opts = TraceOptions {
+            function: Some("square".to_string()),
+            limit: Some(2),
+        };
+        assert_eq!(
+            trace(opts),
+            ["[$square @ 0] load_arg 0 -> 3", "[$square @ 1] dup -> 3"]
+        );
+    }
+
+    #[test]
+    fn test_trace_error() {
+        let mut vm = Vm::new().unwrap();
+        let main = CodeObject {
+            argcount: 0,
+            ..init_code_obj(bytecode![
+                Instr::LoadDyn("missing".to_string()),
+                Instr::Call,
+                Instr::Return
+            ])
+        };
+        vm.db.insert_code_object_with_name(&main, "main").unwrap();
+        let out = Shared::default();
+        let opts = TraceOptions {
+            limit: Some(0),
+            ..Default::default()
+        };
+        let tracer = Tracer::new(Box::new(out.clone()), &vm.db, opts).unwrap();
+        vm.set_observer(tracer);
+        assert!(vm.run_main_function().is_err());
+
+        // The failed instruction is written, even past the limit
+        assert_eq!(
+            out.lines(),
+            ["[$main @ 0] load_dyn $missing failed: query failed: no code object with name 'missing'"]
+        );
+    }
+}
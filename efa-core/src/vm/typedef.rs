@@ -0,0 +1,116 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::TypeTag;
+use crate::{is_valid_name, is_valid_qualified_name, Hash};
+
+/// The type of a struct field: a value type, or another struct by the hash of its
+/// definition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+    Tag(TypeTag),
+    Struct(Hash),
+}
+
+/// A user-defined struct type, written `struct point { x: i32, y: i32 }`. Like
+/// code objects, definitions are content-addressed, so instructions that build or
+/// read structs refer to them by hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeDef {
+    pub name: String,
+    /// Field names and types, in order
+    pub fields: Vec<(String, FieldType)>,
+}
+
+impl TypeDef {
+    pub fn hash(&self) -> Result<Hash> {
+        let def = rmp_serde::to_vec(&self)?;
+        Ok(Hash::digest(&def))
+    }
+
+    /// Check that the name and field names are valid, and the field names are
+    /// distinct
+    pub fn check(&self) -> Result<()> {
+        if !is_valid_qualified_name(&self.name) {
+            bail!("invalid type name '{}'", self.name);
+        }
+        for (i, (field, _)) in self.fields.iter().enumerate() {
+            if !is_valid_name(field) {
+                bail!("invalid field name '{field}' in type '{}'", self.name);
+            }
+            if self.fields[..i].iter().any(|(other, _)| other == field) {
+                bail!("duplicate field '{field}' in type '{}'", self.name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of a field, for instructions that access fields by position
+    pub fn field_index(&self, field: &str) -> Option<usize> {
+        self.fields.iter().position(|(name, _)| name == field)
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldType::Tag(tag) => write!(f, "{tag}"),
+            FieldType::Struct(hash) => write!(f, "{}", hash.abbrev()),
+        }
+    }
+}
+
+impl fmt::Display for TypeDef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(name, ty)| format!("{name}: {ty}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match fields.as_str() {
+            "" => write!(f, "struct {} {{}}", self.name),
+            fields => write!(f, "struct {} {{ {fields} }}", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point() -> TypeDef {
+        TypeDef {
+            name: "point".to_string(),
+            fields: vec![
+                ("x".to_string(), FieldType::Tag(TypeTag::I32)),
+                ("y".to_string(), FieldType::Tag(TypeTag::I32)),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_typedef() {
+        let point = point();
+        point.check().unwrap();
+        assert_eq!(point.to_string(), "struct point { x: i32, y: i32 }");
+        assert_eq!(point.field_index("y"), Some(1));
+        assert_eq!(point.field_index("z"), None);
+
+        // Field order is part of the type
+        let mut swapped = point.clone();
+        swapped.fields.reverse();
+        assert_ne!(point.hash().unwrap(), swapped.hash().unwrap());
+        assert_eq!(point.hash().unwrap(), self::point().hash().unwrap());
+
+        let mut bad = point.clone();
+        bad.fields
+            .push(("x".to_string(), FieldType::Tag(TypeTag::Any)));
+        assert!(bad.check().is_err());
+        bad.fields.pop();
+        bad.name = "a point".to_string();
+        assert!(bad.check().is_err());
+    }
+}